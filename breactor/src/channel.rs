@@ -0,0 +1,297 @@
+//! A bounded channel for passing messages between tasks.
+//!
+//! ```no_run
+//! # #![feature(const_fn)]
+//! # extern crate breactor;
+//! # use breactor::channel::Channel;
+//! static mut EVENTS: Channel<u32, [u32; 8]> = Channel::new([0; 8]);
+//!
+//! # pub fn main() {
+//! let (sender, receiver) = unsafe { EVENTS.split() };
+//! // `sender` is a `Sink` for one task, `receiver` is a `Stream` for
+//! // another one.
+//! # }
+//! ```
+use core::array::FixedSizeArray;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use futures::task::Context;
+use futures::{Poll, Sink, Stream};
+
+use crate::circular_buffer::CircularBuffer;
+use crate::REACTOR;
+
+/// Storage for a single-producer, single-consumer channel.
+///
+/// The channel holds up to `len - 1` items of the backing array, as
+/// one slot is used to distinguish a full buffer from an empty one.
+#[allow(missing_debug_implementations)]
+pub struct Channel<T, A> {
+    buffer: CircularBuffer<T, A>,
+    sender_task_mask: AtomicU32,
+    receiver_task_mask: AtomicU32,
+    sender_dropped: AtomicBool,
+    receiver_dropped: AtomicBool,
+}
+
+/// The receiver has been dropped, so items can't be delivered.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Disconnected;
+
+impl<T: Clone, A: FixedSizeArray<T>> Channel<T, A> {
+    pub const fn new(init: A) -> Channel<T, A> {
+        Channel {
+            buffer: CircularBuffer::new(init),
+            sender_task_mask: AtomicU32::new(0),
+            receiver_task_mask: AtomicU32::new(0),
+            sender_dropped: AtomicBool::new(false),
+            receiver_dropped: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the sending and receiving halves of the channel.
+    ///
+    /// Items left from the previous split are kept.
+    pub fn split(&mut self) -> (Sender<'_, T, A>, Receiver<'_, T, A>) {
+        self.sender_dropped.store(false, Ordering::SeqCst);
+        self.receiver_dropped.store(false, Ordering::SeqCst);
+
+        let channel: &Channel<T, A> = self;
+        (Sender { channel }, Receiver { channel })
+    }
+}
+
+fn wake(task_mask: &AtomicU32) {
+    REACTOR.set_ready_task_mask(task_mask.swap(0, Ordering::SeqCst));
+}
+
+/// The sending half of a `Channel`.
+///
+/// `poll_ready` is pending while the channel is full and the task is
+/// woken when the receiver takes an item. Dropping the sender ends
+/// the receiving stream.
+#[allow(missing_debug_implementations)]
+pub struct Sender<'a, T, A> {
+    channel: &'a Channel<T, A>,
+}
+
+impl<'a, T: Clone, A: FixedSizeArray<T>> Sink<T> for Sender<'a, T, A> {
+    type SinkError = Disconnected;
+
+    fn poll_ready(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::SinkError>> {
+        if self.channel.receiver_dropped.load(Ordering::SeqCst) {
+            return Poll::Ready(Err(Disconnected));
+        }
+
+        self.channel
+            .sender_task_mask
+            .store(REACTOR.get_current_task_mask(), Ordering::SeqCst);
+
+        if self.channel.buffer.was_full() {
+            Poll::Pending
+        } else {
+            self.channel.sender_task_mask.store(0, Ordering::SeqCst);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Self::SinkError> {
+        if self.channel.receiver_dropped.load(Ordering::SeqCst) {
+            return Err(Disconnected);
+        }
+
+        if self.channel.buffer.push(item) {
+            wake(&self.channel.receiver_task_mask);
+            Ok(())
+        } else {
+            panic!("Sender: start_send was called, but the channel is full");
+        }
+    }
+
+    /// Items are delivered as soon as they are in the channel, so
+    /// there is nothing to flush.
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::SinkError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::SinkError>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl<'a, T, A> Drop for Sender<'a, T, A> {
+    fn drop(&mut self) {
+        self.channel.sender_dropped.store(true, Ordering::SeqCst);
+        wake(&self.channel.receiver_task_mask);
+    }
+}
+
+/// The receiving half of a `Channel`.
+///
+/// The stream ends once the sender is dropped and all items are
+/// taken.
+#[allow(missing_debug_implementations)]
+pub struct Receiver<'a, T, A> {
+    channel: &'a Channel<T, A>,
+}
+
+impl<'a, T: Clone, A: FixedSizeArray<T>> Stream for Receiver<'a, T, A> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.channel
+            .receiver_task_mask
+            .store(REACTOR.get_current_task_mask(), Ordering::SeqCst);
+
+        // Checked before popping, so an item sent right before the
+        // sender is dropped is not lost.
+        let sender_dropped = self.channel.sender_dropped.load(Ordering::SeqCst);
+
+        match self.channel.buffer.pop() {
+            Some(x) => {
+                self.channel.receiver_task_mask.store(0, Ordering::SeqCst);
+                wake(&self.channel.sender_task_mask);
+                Poll::Ready(Some(x))
+            }
+            None if sender_dropped => {
+                self.channel.receiver_task_mask.store(0, Ordering::SeqCst);
+                Poll::Ready(None)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<'a, T, A> Drop for Receiver<'a, T, A> {
+    fn drop(&mut self) {
+        self.channel.receiver_dropped.store(true, Ordering::SeqCst);
+        wake(&self.channel.sender_task_mask);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::task::noop_waker;
+
+    const SENDER_TASK: u32 = 1;
+    const RECEIVER_TASK: u32 = 2;
+
+    /// Runs `f` as if it was called from the task `task_id`.
+    fn in_task<R>(task_id: u32, f: impl FnOnce(&mut Context) -> R) -> R {
+        REACTOR
+            .current_task_mask
+            .store(1 << task_id, Ordering::SeqCst);
+        let waker = noop_waker();
+        let res = f(&mut Context::from_waker(&waker));
+        REACTOR.current_task_mask.store(0, Ordering::SeqCst);
+        res
+    }
+
+    fn take_ready_mask() -> u32 {
+        REACTOR.ready_mask.swap(0, Ordering::SeqCst)
+    }
+
+    fn try_send<S: Sink<u8> + Unpin>(sender: &mut S, item: u8) -> bool {
+        in_task(SENDER_TASK, |cx| {
+            match Pin::new(&mut *sender).poll_ready(cx) {
+                Poll::Ready(Ok(())) => Pin::new(&mut *sender).start_send(item).is_ok(),
+                _ => false,
+            }
+        })
+    }
+
+    fn recv<S: Stream<Item = u8> + Unpin>(receiver: &mut S) -> Poll<Option<u8>> {
+        in_task(RECEIVER_TASK, |cx| Pin::new(receiver).poll_next(cx))
+    }
+
+    #[test]
+    fn test_items_are_received_in_order() {
+        let _guard = crate::ReactorGuard::acquire();
+        let mut channel = Channel::new([0; 8]);
+        let (mut sender, mut receiver) = channel.split();
+
+        assert_eq!(Poll::Pending, recv(&mut receiver));
+
+        for &x in b"abc" {
+            assert!(try_send(&mut sender, x));
+        }
+        // The receiver is woken by the first item.
+        assert_eq!(1 << RECEIVER_TASK, take_ready_mask());
+
+        assert_eq!(Poll::Ready(Some(b'a')), recv(&mut receiver));
+        assert!(try_send(&mut sender, b'd'));
+        assert_eq!(Poll::Ready(Some(b'b')), recv(&mut receiver));
+        assert_eq!(Poll::Ready(Some(b'c')), recv(&mut receiver));
+        assert_eq!(Poll::Ready(Some(b'd')), recv(&mut receiver));
+        assert_eq!(Poll::Pending, recv(&mut receiver));
+    }
+
+    #[test]
+    fn test_backpressure_when_full() {
+        let _guard = crate::ReactorGuard::acquire();
+        let mut channel = Channel::new([0; 4]);
+        let (mut sender, mut receiver) = channel.split();
+
+        // The channel holds 3 items.
+        for &x in b"abc" {
+            assert!(try_send(&mut sender, x));
+        }
+        assert!(!try_send(&mut sender, b'd'));
+        take_ready_mask();
+
+        // Taking an item wakes the sender.
+        assert_eq!(Poll::Ready(Some(b'a')), recv(&mut receiver));
+        assert_eq!(1 << SENDER_TASK, take_ready_mask());
+
+        assert!(try_send(&mut sender, b'd'));
+        assert!(!try_send(&mut sender, b'e'));
+
+        let received: Vec<u8> = (0..3)
+            .map(|_| match recv(&mut receiver) {
+                Poll::Ready(Some(x)) => x,
+                _ => panic!("item is not received"),
+            })
+            .collect();
+        assert_eq!(b"bcd", &received[..]);
+    }
+
+    #[test]
+    fn test_stream_ends_when_sender_is_dropped() {
+        let _guard = crate::ReactorGuard::acquire();
+        let mut channel = Channel::new([0; 4]);
+        let (mut sender, mut receiver) = channel.split();
+
+        assert_eq!(Poll::Pending, recv(&mut receiver));
+        assert!(try_send(&mut sender, b'a'));
+        drop(sender);
+        assert_eq!(1 << RECEIVER_TASK, take_ready_mask());
+
+        // Items sent before the drop are still delivered.
+        assert_eq!(Poll::Ready(Some(b'a')), recv(&mut receiver));
+        assert_eq!(Poll::Ready(None), recv(&mut receiver));
+    }
+
+    #[test]
+    fn test_send_fails_when_receiver_is_dropped() {
+        let _guard = crate::ReactorGuard::acquire();
+        let mut channel = Channel::new([0; 4]);
+        let (mut sender, receiver) = channel.split();
+
+        drop(receiver);
+        in_task(SENDER_TASK, |cx| {
+            assert_eq!(
+                Poll::Ready(Err(Disconnected)),
+                Pin::new(&mut sender).poll_ready(cx)
+            );
+        });
+        assert_eq!(Err(Disconnected), Pin::new(&mut sender).start_send(b'a'));
+    }
+}
@@ -58,6 +58,33 @@ impl<T: Clone, A: FixedSizeArray<T>> CircularBuffer<T, A> {
         }
     }
 
+    /// Pushes as many items from the start of `items` as there is room
+    /// for. The consumer sees them all at once.
+    ///
+    /// Returns the number of items pushed.
+    pub fn push_slice(&self, items: &[T]) -> usize {
+        let current_head = self.head.load(Ordering::Acquire);
+        let mut tail = self.tail.load(Ordering::Relaxed);
+
+        let mut pushed = 0;
+        for item in items {
+            let next_tail = self.increment(tail);
+            if next_tail == current_head {
+                // Queue is full
+                break;
+            }
+
+            unsafe {
+                (*self.array.get()).as_mut_slice()[tail] = item.clone();
+            }
+            tail = next_tail;
+            pushed += 1;
+        }
+        self.tail.store(tail, Ordering::Release);
+
+        pushed
+    }
+
     /// Pops element from the buffer.
     ///
     /// `None` means the buffer was empty.
@@ -74,6 +101,38 @@ impl<T: Clone, A: FixedSizeArray<T>> CircularBuffer<T, A> {
         }
     }
 
+    /// Returns the items that are stored contiguously from the head of
+    /// the buffer, without popping them.
+    ///
+    /// When the items wrap around the end of the array, only the ones
+    /// before the end are returned. Must only be called by the
+    /// consumer: the producer doesn't touch the items until they are
+    /// `consume`d, so the slice can be handed to a DMA controller.
+    pub fn peek_contiguous(&self) -> &[T] {
+        let current_head = self.head.load(Ordering::Relaxed);
+        let current_tail = self.tail.load(Ordering::Acquire);
+        let array = unsafe { &*self.array.get() }.as_slice();
+
+        let end = if current_tail >= current_head {
+            current_tail
+        } else {
+            array.len()
+        };
+        &array[current_head..end]
+    }
+
+    /// Pops `n` items without reading them, e.g., after a DMA transfer
+    /// of `peek_contiguous` has completed.
+    ///
+    /// Must only be called by the consumer.
+    pub fn consume(&self, n: usize) {
+        let current_head = self.head.load(Ordering::Relaxed);
+        debug_assert!(n <= self.peek_contiguous().len());
+
+        let len = unsafe { (*self.array.get()).as_slice().len() };
+        self.head.store((current_head + n) % len, Ordering::Release);
+    }
+
     /// If the buffer was empty at the time of querying.
     ///
     /// Note that the status may have already changed by the time the
@@ -112,4 +171,40 @@ mod test {
         assert_eq!(true, cb.push(5));
         assert_eq!(Some(5), cb.pop());
     }
+
+    #[test]
+    fn test_push_slice() {
+        let cb = CircularBuffer::new([0; 4]);
+        assert_eq!(2, cb.push_slice(&[1, 2]));
+        assert_eq!(Some(1), cb.pop());
+
+        // Wraps around, and stops when the buffer is full.
+        assert_eq!(2, cb.push_slice(&[3, 4, 5]));
+        assert_eq!(0, cb.push_slice(&[5]));
+        assert_eq!(Some(2), cb.pop());
+        assert_eq!(Some(3), cb.pop());
+        assert_eq!(Some(4), cb.pop());
+        assert_eq!(None, cb.pop());
+    }
+
+    #[test]
+    fn test_peek_contiguous_wraps() {
+        let cb = CircularBuffer::new([0; 4]);
+        for x in 1..=3 {
+            assert!(cb.push(x));
+        }
+        assert_eq!(&[1, 2, 3], cb.peek_contiguous());
+
+        cb.consume(2);
+        assert!(cb.push(4));
+        assert!(cb.push(5));
+        // 5 is stored at the start of the array.
+        assert_eq!(&[3, 4], cb.peek_contiguous());
+
+        cb.consume(2);
+        assert_eq!(&[5], cb.peek_contiguous());
+        cb.consume(1);
+        assert!(cb.was_empty());
+        assert!(cb.peek_contiguous().is_empty());
+    }
 }
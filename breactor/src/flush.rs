@@ -0,0 +1,42 @@
+use core::marker::PhantomData;
+use core::pin::Pin;
+use futures::task::Context;
+use futures::{Future, Poll, Sink};
+
+/// Future that flushes the sink and returns it back.
+///
+/// Unlike `SinkExt::flush`, this takes ownership of the sink, so it
+/// can be chained after `StartSendAll` or `StartSendAllString`.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Flush<T, Item> {
+    sink: Option<T>,
+    __phantom: PhantomData<fn(Item)>,
+}
+
+impl<T, Item> Unpin for Flush<T, Item> where T: Sink<Item> + Unpin {}
+
+impl<T, Item> Flush<T, Item>
+where
+    T: Sink<Item> + Unpin,
+{
+    pub fn new(sink: T) -> Flush<T, Item> {
+        Flush {
+            sink: Some(sink),
+            __phantom: PhantomData,
+        }
+    }
+}
+
+impl<T, Item> Future for Flush<T, Item>
+where
+    T: Sink<Item> + Unpin,
+{
+    type Output = Result<T, T::SinkError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        try_ready!(Pin::new(self.sink.as_mut().expect("")).poll_flush(cx));
+
+        Poll::Ready(Ok(self.sink.take().expect("")))
+    }
+}
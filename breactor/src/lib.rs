@@ -1,39 +1,93 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(integer_atomics)]
 #![feature(const_fn)]
+#![feature(fixed_size_array)]
 
 #[macro_use]
 extern crate futures;
 
 extern crate stm32f4;
 
+pub mod channel;
+pub mod circular_buffer;
+pub mod flush;
+pub mod mask;
+pub mod merge;
 pub mod mutex;
 pub mod promise;
+pub mod quiesce;
+pub mod ready_bytes;
 pub mod start_send_all;
+pub mod start_send_all_bytes;
 pub mod start_send_all_string;
-mod waker;
+pub mod start_send_bytes;
+pub mod tee;
+pub mod throttle;
+pub mod time;
+pub mod timeout;
+pub mod waker;
+pub mod yield_now;
 
-use crate::waker::new_task_waker;
+pub use crate::mask::Mask;
+pub use crate::merge::merge;
+pub use crate::tee::Tee;
+pub use crate::timeout::{TimedOut, Timeout};
+pub use crate::yield_now::yield_now;
+
+use crate::waker::{new_task_waker, ReadyMask};
+use core::array::FixedSizeArray;
 use core::cell::UnsafeCell;
+use core::marker::PhantomData;
 use core::pin::Pin;
-use core::sync::atomic::{AtomicU32, Ordering};
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::task::Context;
-use core::u32;
 
 use futures::{Future, Poll};
 
 pub static REACTOR: Reactor = Reactor::new();
 
-// Id is stored internally as a mask.
+/// Serializes tests that use the global reactor or tick counter.
+///
+/// The reactor is reset on acquisition, so each test starts with no
+/// tasks and no ready bits left from the previous one.
+#[cfg(test)]
+pub(crate) struct ReactorGuard(());
+
+#[cfg(test)]
+static REACTOR_BUSY: ::core::sync::atomic::AtomicBool =
+    ::core::sync::atomic::AtomicBool::new(false);
+
+#[cfg(test)]
+impl ReactorGuard {
+    pub fn acquire() -> ReactorGuard {
+        while REACTOR_BUSY.compare_and_swap(false, true, Ordering::SeqCst) {
+            ::std::thread::yield_now();
+        }
+
+        unsafe { REACTOR.reset() };
+        ReactorGuard(())
+    }
+}
+
+#[cfg(test)]
+impl Drop for ReactorGuard {
+    fn drop(&mut self) {
+        REACTOR_BUSY.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Maximum number of tasks of any reactor.
+pub const MAX_TASKS: u32 = 64;
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub struct TaskId(u32);
 
 impl TaskId {
     /// Creates new unchecked task id.
     ///
-    /// The argument must be lower than 32.
+    /// The argument must be lower than `MAX_TASKS`.
     pub const unsafe fn unsafe_new(id: u32) -> TaskId {
-        TaskId(1 << id)
+        TaskId(id)
     }
 
     /// Creates new checked TaskId from priority.
@@ -41,19 +95,26 @@ impl TaskId {
     /// # Return values
     /// Returns `None` if id is too high.
     /// ```
-    /// assert_eq!(None, breactor::TaskId::new(32));
+    /// assert_eq!(None, breactor::TaskId::new(64));
     /// ```
     ///
     /// On success, returns some value.
     /// ```
-    /// assert!(breactor::TaskId::new(31).is_some());
+    /// assert!(breactor::TaskId::new(63).is_some());
     /// ```
+    ///
+    /// Use `Reactor::task_id` to validate the id against a reactor
+    /// with fewer slots or a narrower mask.
     pub fn new(id: u32) -> Option<TaskId> {
-        1_u32.checked_shl(id).map(TaskId)
+        if id < MAX_TASKS {
+            Some(TaskId(id))
+        } else {
+            None
+        }
     }
 
-    const fn get_mask(self) -> u32 {
-        self.0
+    fn get_mask<M: Mask>(self) -> M {
+        M::bit(self.0)
     }
 }
 
@@ -66,8 +127,29 @@ impl TaskId {
 /// Each task has an ID assigned. The ID plays two roles. First, it
 /// distinguishes tasks, therefore it must be unique. Second, it
 /// determines the priority. Higher ids mean higher priority.
+///
+/// The reactor has 32 task slots by default. Small applications can
+/// save RAM by using a smaller array of slots:
+///
+/// ```
+/// # #![feature(const_fn)]
+/// # use core::cell::UnsafeCell;
+/// # use breactor::{Reactor, TaskSlot};
+/// static REACTOR: Reactor<[TaskSlot; 4]> = Reactor::from_array([
+///     UnsafeCell::new(None),
+///     UnsafeCell::new(None),
+///     UnsafeCell::new(None),
+///     UnsafeCell::new(None),
+/// ]);
+///
+/// assert!(REACTOR.task_id(3).is_some());
+/// assert_eq!(None, REACTOR.task_id(4));
+/// ```
+///
+/// The number of tasks is limited by the width of the ready mask `M`.
+/// Use `Reactor64` for more than 32 tasks.
 #[allow(missing_debug_implementations)]
-pub struct Reactor<'a> {
+pub struct Reactor<'a, A = [TaskSlot<'a>; 32], M: Mask = u32> {
     // TODO(rasen): should this be atomic?
     //
     // As far as I see, this must only be read from the system thread
@@ -76,12 +158,12 @@ pub struct Reactor<'a> {
     // On the other hand, if we're going for task preemption, a switch
     // might occur right when the value is changed (or tasks reads its
     // id), leading to inconsistencies.
-    current_task_mask: AtomicU32,
-    tasks: [UnsafeCell<Option<Pin<&'a mut dyn Future<Output = ()>>>>; 32],
+    current_task_mask: M::Atomic,
+    tasks: A,
 
     /// This is a bread and butter of the reactor.
     ///
-    /// This variable holds 32 individual bits, each representing a
+    /// This variable holds individual bits, each representing a
     /// readiness state of the task with id equal to the bit
     /// number. (e.g., 0x05, binary 101, means tasks with id 0 and 2
     /// are ready to run.)
@@ -90,15 +172,37 @@ pub struct Reactor<'a> {
     /// priority by counting leading zeros (which is extremely
     /// efficient operation), and setting/resetting task status
     /// atomically. This all makes this reactor lock-free.
-    ready_mask: AtomicU32,
+    ready_mask: ReadyMask<M>,
+
+    /// New tasks are rejected while the reactor is quiescing.
+    quiescing: AtomicBool,
+
+    __phantom: PhantomData<TaskSlot<'a>>,
 }
 
-unsafe impl<'a> Sync for Reactor<'a> {}
+/// A reactor with up to 64 tasks.
+///
+/// It has no `new`, as it would clash with `Reactor::new`. Use
+/// `Reactor::from_array` instead.
+pub type Reactor64<'a, A = [TaskSlot<'a>; 64]> = Reactor<'a, A, u64>;
+
+/// A slot for a single reactor task.
+pub type TaskSlot<'a> = UnsafeCell<Option<Task<'a>>>;
+
+/// A task added to the reactor.
+#[allow(missing_debug_implementations)]
+pub struct Task<'a> {
+    /// Only used for debugging.
+    name: &'static str,
+    future: Pin<&'a mut dyn Future<Output = ()>>,
+}
+
+unsafe impl<'a, A, M: Mask> Sync for Reactor<'a, A, M> {}
 
 impl<'a> Reactor<'a> {
     pub const fn new() -> Reactor<'a> {
         Reactor {
-            current_task_mask: AtomicU32::new(0),
+            current_task_mask: u32::ATOMIC_EMPTY,
             // Because the trait Copy is not implemented for &mut
             // Future<Item=(), Error=()>
             tasks: [
@@ -135,56 +239,69 @@ impl<'a> Reactor<'a> {
                 UnsafeCell::new(None),
                 UnsafeCell::new(None),
             ],
-            ready_mask: AtomicU32::new(0),
+            ready_mask: ReadyMask(u32::ATOMIC_EMPTY),
+            quiescing: AtomicBool::new(false),
+            __phantom: PhantomData,
         }
     }
+}
 
+impl<'a, A: FixedSizeArray<TaskSlot<'a>>, M: Mask> Reactor<'a, A, M> {
     /// Creates a reactor with a predefined set of tasks.
-    pub const fn from_array(
-        tasks: [UnsafeCell<Option<Pin<&'a mut dyn Future<Output = ()>>>>; 32],
-    ) -> Reactor<'a> {
+    ///
+    /// The number of task slots is determined by the array size and
+    /// must not exceed the width of the mask.
+    pub const fn from_array(tasks: A) -> Reactor<'a, A, M> {
         Reactor {
-            current_task_mask: AtomicU32::new(0),
+            current_task_mask: M::ATOMIC_EMPTY,
             tasks,
 
             // All tasks are ready.
             //
             // TODO(rasen): maybe allow user to specify the mask?
-            ready_mask: AtomicU32::new(u32::MAX),
+            ready_mask: ReadyMask(M::ATOMIC_FULL),
+            quiescing: AtomicBool::new(false),
+            __phantom: PhantomData,
+        }
+    }
+
+    /// Returns the number of task slots.
+    pub fn task_count(&self) -> usize {
+        self.tasks.as_slice().len()
+    }
+
+    /// Creates a task id, checking it fits the reactor.
+    ///
+    /// Returns `None` if the reactor has no slot for the id.
+    pub fn task_id(&self, id: u32) -> Option<TaskId> {
+        if (id as usize) < self.task_count() && id < M::BITS {
+            TaskId::new(id)
+        } else {
+            None
         }
     }
 
     /// Marks the given task as ready.
     pub fn set_task_ready(&self, id: TaskId) {
-        self.ready_mask.fetch_or(id.get_mask(), Ordering::SeqCst);
-        unsafe { stm32f4::__set_event() };
+        self.ready_mask.set_ready(id.get_mask());
     }
 
-    pub fn get_current_task_mask(&self) -> u32 {
-        self.current_task_mask.load(Ordering::SeqCst)
+    pub fn get_current_task_mask(&self) -> M {
+        M::load(&self.current_task_mask)
     }
 
-    pub fn set_ready_task_mask(&self, mask: u32) {
-        if mask != 0 {
-            self.ready_mask.fetch_or(mask, Ordering::SeqCst);
-            unsafe { stm32f4::__set_event() };
-        }
+    pub fn set_ready_task_mask(&self, mask: M) {
+        self.ready_mask.set_ready(mask);
     }
 
     /// Returns true if any task is ready to be polled.
     pub fn is_ready(&self) -> bool {
-        self.ready_mask.load(Ordering::SeqCst) != 0
+        M::load(&self.ready_mask) != M::EMPTY
     }
 
     /// Returns next task to run.
     fn select_next_task(&self) -> Option<u32> {
-        let mask = self.ready_mask.load(Ordering::SeqCst);
-        let zeros = mask.leading_zeros();
-        if zeros == 32 {
-            None
-        } else {
-            Some(31 - zeros)
-        }
+        M::load(&self.ready_mask).highest()
     }
 
     /// Runs until all tasks get blocked.
@@ -192,51 +309,496 @@ impl<'a> Reactor<'a> {
     /// This allows putting processor into sleep when there is no job
     /// to do.
     ///
+    /// Returns the number of polls, counting each poll of the same
+    /// task. Zero means the reactor was woken up for nothing, while a
+    /// large number might be a task that keeps marking itself ready.
+    ///
     /// This function is unsafe because the caller must ensure that
-    /// only a single thread calls run at the same time.
-    pub unsafe fn run(&self) {
+    /// only a single thread calls run at the same time. Task wakers
+    /// point to the reactor, so it must outlive them as well.
+    pub unsafe fn run(&self) -> usize {
+        let mut polls = 0;
         while let Some(task_id) = self.select_next_task() {
-            let task_mask = 1_u32 << task_id;
-            self.ready_mask.fetch_and(!task_mask, Ordering::SeqCst);
-            self.current_task_mask.store(task_mask, Ordering::SeqCst);
-
-            let mtask = &mut *self.tasks[task_id as usize].get();
-            *mtask = match *mtask {
-                Some(ref mut task) => {
-                    let waker = new_task_waker(task_mask);
-                    let mut cx = Context::from_waker(&waker);
-                    let res = task.as_mut().poll(&mut cx);
-                    match res {
-                        Poll::Pending => continue,
-                        // Remove task if has finished
-                        Poll::Ready(()) => None,
+            if self.poll_task(task_id) {
+                polls += 1;
+            }
+        }
+        polls
+    }
+
+    /// Runs until all tasks get blocked, or `max_polls` tasks have
+    /// been polled.
+    ///
+    /// Unlike `run`, this always returns even if some tasks never
+    /// block, so the caller can interleave housekeeping (e.g., feeding
+    /// a watchdog). Use `is_ready` to check whether any tasks are
+    /// left ready.
+    ///
+    /// Returns the number of polled tasks.
+    ///
+    /// This function is unsafe because the caller must ensure that
+    /// only a single thread calls run at the same time.
+    pub unsafe fn run_budget(&self, max_polls: usize) -> usize {
+        let mut polls = 0;
+        while polls < max_polls {
+            match self.select_next_task() {
+                Some(task_id) => {
+                    if self.poll_task(task_id) {
+                        polls += 1;
                     }
                 }
-                None => {
-                    // Nothing to do
-                    continue;
-                }
-            };
+                None => break,
+            }
         }
+        polls
+    }
+
+    /// Polls the task once and clears its ready bit.
+    ///
+    /// Returns false if there is no such task.
+    unsafe fn poll_task(&self, task_id: u32) -> bool {
+        let task_mask = M::bit(task_id);
+        M::remove(&self.ready_mask, task_mask);
+
+        let mtask = match self.tasks.as_slice().get(task_id as usize) {
+            Some(slot) => &mut *slot.get(),
+            // No such slot
+            None => return false,
+        };
+        let res = match *mtask {
+            Some(ref mut task) => {
+                let waker = new_task_waker(&self.ready_mask, task_id);
+                let mut cx = Context::from_waker(&waker);
+
+                M::store(&self.current_task_mask, task_mask);
+                let res = task.future.as_mut().poll(&mut cx);
+                // No task is running between polls.
+                M::store(&self.current_task_mask, M::EMPTY);
+                res
+            }
+            None => {
+                // Nothing to do
+                return false;
+            }
+        };
+
+        if let Poll::Ready(()) = res {
+            // Remove task if has finished
+            *mtask = None;
+        }
+        true
     }
 
     /// Returns true if task was successfully added.
-    /// Returns false if task_id is too high or already occupied.
+    /// Returns false if task_id is too high or already occupied, or
+    /// the reactor is quiescing.
     ///
     /// The caller must ensure it has unique write access to the
     /// reactor.
     pub unsafe fn add_task(&self, task_id: u32, f: Pin<&'a mut dyn Future<Output = ()>>) -> bool {
-        if task_id >= 32 {
-            false
-        } else {
-            let ptr = self.tasks[task_id as usize].get();
-            if (*ptr).is_none() {
-                *self.tasks[task_id as usize].get() = Some(f);
-                self.set_task_ready(TaskId::unsafe_new(task_id));
-                true
+        self.add_task_named(task_id, "<unnamed>", f)
+    }
+
+    /// Same as `add_task`, but gives the task a name, so it can be
+    /// told apart when debugging. See `task_name`.
+    pub unsafe fn add_task_named(
+        &self,
+        task_id: u32,
+        name: &'static str,
+        f: Pin<&'a mut dyn Future<Output = ()>>,
+    ) -> bool {
+        if self.quiescing.load(Ordering::SeqCst) {
+            return false;
+        }
+
+        match self.task_id(task_id) {
+            None => false,
+            Some(id) => {
+                let ptr = self.tasks.as_slice()[task_id as usize].get();
+                if (*ptr).is_none() {
+                    *ptr = Some(Task { name, future: f });
+                    self.set_task_ready(id);
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    /// Removes a task that has not completed yet, dropping its
+    /// future. The task is not polled anymore, even if it was ready.
+    ///
+    /// Returns true if there was a task in the slot.
+    ///
+    /// A task must not remove itself, as its future is being polled
+    /// meanwhile. This is checked in debug builds.
+    ///
+    /// The caller must ensure it has unique write access to the
+    /// reactor.
+    pub unsafe fn remove_task(&self, task_id: u32) -> bool {
+        match self.task_id(task_id) {
+            None => false,
+            Some(id) => {
+                debug_assert!(
+                    id.get_mask::<M>() != self.get_current_task_mask(),
+                    "a task can't remove itself"
+                );
+
+                M::remove(&self.ready_mask, id.get_mask());
+                (*self.tasks.as_slice()[task_id as usize].get())
+                    .take()
+                    .is_some()
+            }
+        }
+    }
+
+    /// Returns true if the slot holds a task that has not completed
+    /// yet.
+    ///
+    /// The slot is read without synchronization, so this must only be
+    /// called from the reactor thread and not concurrently with
+    /// `add_task` or `remove_task`. Otherwise, the answer may be stale
+    /// by the time it is used.
+    pub fn is_task_present(&self, task_id: u32) -> bool {
+        match self.tasks.as_slice().get(task_id as usize) {
+            Some(slot) => unsafe { (*slot.get()).is_some() },
+            None => false,
+        }
+    }
+
+    /// Returns the name of the task, or `None` if there is no such
+    /// task.
+    ///
+    /// Must only be called from the reactor thread.
+    pub fn task_name(&self, task_id: u32) -> Option<&'static str> {
+        let slot = self.tasks.as_slice().get(task_id as usize)?;
+        unsafe { (*slot.get()).as_ref().map(|task| task.name) }
+    }
+
+    /// Returns the name of the task that is being polled.
+    ///
+    /// Intended for the panic handler.
+    pub fn current_task_name(&self) -> Option<&'static str> {
+        self.task_name(self.get_current_task_mask().highest()?)
+    }
+
+    /// Removes all tasks and clears all state.
+    ///
+    /// The caller must ensure the reactor is not running and no other
+    /// thread uses it.
+    #[cfg(test)]
+    pub(crate) unsafe fn reset(&self) {
+        for slot in self.tasks.as_slice() {
+            *slot.get() = None;
+        }
+        M::store(&self.ready_mask, M::EMPTY);
+        M::store(&self.current_task_mask, M::EMPTY);
+        self.quiescing.store(false, Ordering::SeqCst);
+    }
+
+    /// Waits for all other tasks to complete, but no longer than
+    /// `deadline`. Resolves to `true` if they have completed.
+    ///
+    /// The returned future must be run as the lowest-priority task of
+    /// this reactor. Once it is polled, new tasks are rejected, while
+    /// the existing ones keep running, so in-flight transfers can
+    /// finish before the system is reset.
+    ///
+    /// The future stays ready until it resolves, so the reactor
+    /// doesn't sleep meanwhile.
+    pub fn quiesce<'r>(&'r self, deadline: time::Duration) -> quiesce::Quiesce<'r, 'a, A, M> {
+        quiesce::Quiesce::new(self, deadline)
+    }
+
+    /// Returns the number of tasks that have not completed yet, except
+    /// the ones in `except_mask`.
+    ///
+    /// Must only be called from the reactor thread.
+    fn pending_tasks(&self, except_mask: M) -> usize {
+        self.tasks
+            .as_slice()
+            .iter()
+            .enumerate()
+            .filter(|&(id, slot)| {
+                // Slot of the current task is borrowed mutably.
+                !except_mask.contains(id as u32) && unsafe { (*slot.get()).is_some() }
+            })
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use core::sync::atomic::{AtomicBool, AtomicU32};
+    use std::cell::RefCell;
+
+    /// Expands to an array with an empty task slot per token.
+    macro_rules! empty_slots {
+        (@slot $x:tt) => { UnsafeCell::new(None) };
+        ($($x:tt)*) => { [$(empty_slots!(@slot $x)),*] };
+    }
+
+    #[test]
+    fn test_small_reactor() {
+        let reactor: Reactor<[TaskSlot; 4]> = Reactor::from_array([
+            UnsafeCell::new(None),
+            UnsafeCell::new(None),
+            UnsafeCell::new(None),
+            UnsafeCell::new(None),
+        ]);
+        assert_eq!(4, reactor.task_count());
+        assert!(reactor.task_id(3).is_some());
+        assert_eq!(None, reactor.task_id(4));
+
+        let done = AtomicBool::new(false);
+        let mut task = futures::future::lazy(|_| done.store(true, Ordering::SeqCst));
+        let mut rejected = futures::future::ready(());
+        unsafe {
+            assert!(!reactor.add_task(4, Pin::new_unchecked(&mut rejected)));
+            assert!(reactor.add_task(3, Pin::new_unchecked(&mut task)));
+
+            // Ready bits of missing slots are skipped.
+            reactor.run();
+        }
+
+        assert!(done.load(Ordering::SeqCst));
+        assert!(!reactor.is_ready());
+    }
+
+    /// Checks the global reactor is clean, then leaves a pending task
+    /// and ready bits behind.
+    fn use_global_reactor() {
+        let _guard = crate::ReactorGuard::acquire();
+
+        assert_eq!(0, REACTOR.pending_tasks(0));
+        assert!(!REACTOR.is_ready());
+        assert_eq!(0, REACTOR.get_current_task_mask());
+
+        let task: &'static mut _ =
+            Box::leak(Box::new(futures::future::poll_fn(|_cx| Poll::Pending)));
+        unsafe {
+            assert!(REACTOR.add_task(7, Pin::new_unchecked(task)));
+            REACTOR.run();
+        }
+        REACTOR.set_ready_task_mask(0x8000_0100);
+    }
+
+    #[test]
+    fn test_guard_resets_reactor_1() {
+        use_global_reactor();
+    }
+
+    #[test]
+    fn test_guard_resets_reactor_2() {
+        use_global_reactor();
+    }
+
+    #[test]
+    fn test_task_names() {
+        let reactor: Reactor<[TaskSlot; 3]> = Reactor::from_array([
+            UnsafeCell::new(None),
+            UnsafeCell::new(None),
+            UnsafeCell::new(None),
+        ]);
+
+        let current = RefCell::new(None);
+        let mut named = futures::future::poll_fn(|_cx| {
+            *current.borrow_mut() = reactor.current_task_name();
+            Poll::<()>::Pending
+        });
+        let mut unnamed = futures::future::poll_fn(|_cx| Poll::<()>::Pending);
+        unsafe {
+            assert!(reactor.add_task_named(2, "terminal", Pin::new_unchecked(&mut named)));
+            assert!(reactor.add_task(0, Pin::new_unchecked(&mut unnamed)));
+            reactor.run();
+        }
+
+        assert_eq!(Some("terminal"), *current.borrow());
+        assert_eq!(None, reactor.current_task_name());
+        assert_eq!(Some("terminal"), reactor.task_name(2));
+        assert_eq!(Some("<unnamed>"), reactor.task_name(0));
+        assert_eq!(None, reactor.task_name(1));
+        assert_eq!(None, reactor.task_name(3));
+    }
+
+    #[test]
+    fn test_is_task_present() {
+        let reactor: Reactor<[TaskSlot; 2]> =
+            Reactor::from_array([UnsafeCell::new(None), UnsafeCell::new(None)]);
+
+        let mut pending = futures::future::poll_fn(|_cx| Poll::<()>::Pending);
+        let mut done = futures::future::ready(());
+        unsafe {
+            assert!(reactor.add_task(0, Pin::new_unchecked(&mut pending)));
+            assert!(reactor.add_task(1, Pin::new_unchecked(&mut done)));
+            assert!(reactor.is_task_present(1));
+            reactor.run();
+        }
+
+        assert!(reactor.is_task_present(0));
+        // Completed tasks are removed.
+        assert!(!reactor.is_task_present(1));
+        assert!(!reactor.is_task_present(2));
+
+        unsafe { reactor.remove_task(0) };
+        assert!(!reactor.is_task_present(0));
+    }
+
+    #[test]
+    fn test_remove_task() {
+        let reactor: Reactor<[TaskSlot; 2]> =
+            Reactor::from_array([UnsafeCell::new(None), UnsafeCell::new(None)]);
+        reactor.ready_mask.store(0, Ordering::SeqCst);
+
+        let polls = AtomicU32::new(0);
+        let mut task = futures::future::poll_fn(|_cx| {
+            polls.fetch_add(1, Ordering::SeqCst);
+            Poll::<()>::Pending
+        });
+        let mut other = futures::future::ready(());
+        unsafe {
+            assert!(reactor.add_task(1, Pin::new_unchecked(&mut task)));
+            reactor.run();
+            assert_eq!(1, polls.load(Ordering::SeqCst));
+
+            reactor.set_task_ready(TaskId::new(1).unwrap());
+            assert!(reactor.remove_task(1));
+
+            // The ready bit is cleared and the slot is free.
+            assert!(!reactor.is_ready());
+            assert!(!reactor.remove_task(1));
+            assert!(reactor.add_task(1, Pin::new_unchecked(&mut other)));
+            reactor.run();
+        }
+        assert_eq!(1, polls.load(Ordering::SeqCst));
+
+        unsafe {
+            assert!(!reactor.remove_task(0));
+            assert!(!reactor.remove_task(2));
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "a task can't remove itself")]
+    fn test_remove_current_task() {
+        let reactor: Reactor<[TaskSlot; 1]> = Reactor::from_array([UnsafeCell::new(None)]);
+
+        let mut task = futures::future::poll_fn(|_cx| {
+            unsafe { reactor.remove_task(0) };
+            Poll::<()>::Pending
+        });
+        unsafe {
+            assert!(reactor.add_task(0, Pin::new_unchecked(&mut task)));
+            reactor.run();
+        }
+    }
+
+    #[test]
+    fn test_reactor64() {
+        let reactor: Reactor64<[TaskSlot; 40]> = Reactor::from_array(empty_slots!(
+            _ _ _ _ _ _ _ _ _ _
+            _ _ _ _ _ _ _ _ _ _
+            _ _ _ _ _ _ _ _ _ _
+            _ _ _ _ _ _ _ _ _ _
+        ));
+        assert_eq!(40, reactor.task_count());
+        assert!(reactor.task_id(39).is_some());
+        assert_eq!(None, reactor.task_id(40));
+        // Clear ready bits of the empty slots.
+        unsafe { reactor.run() };
+
+        let order = RefCell::new(Vec::new());
+        let mut low = futures::future::lazy(|_| order.borrow_mut().push(3));
+        let mut high = futures::future::lazy(|_| order.borrow_mut().push(39));
+        let mut polled = false;
+        let mut woken = futures::future::poll_fn(|cx| {
+            order.borrow_mut().push(33);
+            if polled {
+                Poll::Ready(())
+            } else {
+                polled = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        });
+        unsafe {
+            assert!(reactor.add_task(3, Pin::new_unchecked(&mut low)));
+            assert!(reactor.add_task(39, Pin::new_unchecked(&mut high)));
+            assert!(reactor.add_task(33, Pin::new_unchecked(&mut woken)));
+            reactor.run();
+        }
+
+        // The waker marks the task ready in its own reactor.
+        assert_eq!(vec![39, 33, 33, 3], *order.borrow());
+        assert!(!reactor.is_ready());
+    }
+
+    #[test]
+    fn test_task_id_fits_mask() {
+        let reactor: Reactor<[TaskSlot; 33]> = Reactor::from_array(empty_slots!(
+            _ _ _ _ _ _ _ _ _ _ _
+            _ _ _ _ _ _ _ _ _ _ _
+            _ _ _ _ _ _ _ _ _ _ _
+        ));
+        assert!(reactor.task_id(31).is_some());
+        assert_eq!(None, reactor.task_id(32));
+    }
+
+    #[test]
+    fn test_run_counts_polls() {
+        let reactor: Reactor<[TaskSlot; 3]> = Reactor::from_array([
+            UnsafeCell::new(None),
+            UnsafeCell::new(None),
+            UnsafeCell::new(None),
+        ]);
+
+        // Ready bits of empty slots are not counted.
+        assert_eq!(0, unsafe { reactor.run() });
+
+        let polls = AtomicU32::new(0);
+        // Marks itself ready twice before completing.
+        let mut task = futures::future::poll_fn(|_cx| {
+            if polls.fetch_add(1, Ordering::SeqCst) == 2 {
+                Poll::Ready(())
             } else {
-                false
+                reactor.set_task_ready(TaskId::new(2).unwrap());
+                Poll::Pending
             }
+        });
+        let mut other = futures::future::ready(());
+        unsafe {
+            assert!(reactor.add_task(2, Pin::new_unchecked(&mut task)));
+            assert!(reactor.add_task(0, Pin::new_unchecked(&mut other)));
+            assert_eq!(4, reactor.run());
+            assert_eq!(0, reactor.run());
+        }
+    }
+
+    #[test]
+    fn test_run_budget() {
+        let reactor: Reactor<[TaskSlot; 2]> =
+            Reactor::from_array([UnsafeCell::new(None), UnsafeCell::new(None)]);
+
+        let polls = AtomicU32::new(0);
+        // Never blocks.
+        let mut task = futures::future::poll_fn(|_cx| {
+            polls.fetch_add(1, Ordering::SeqCst);
+            reactor.set_task_ready(TaskId::new(1).unwrap());
+            Poll::<()>::Pending
+        });
+        unsafe {
+            assert!(reactor.add_task(1, Pin::new_unchecked(&mut task)));
         }
+
+        assert_eq!(10, unsafe { reactor.run_budget(10) });
+        assert_eq!(10, polls.load(Ordering::SeqCst));
+        assert!(reactor.is_ready());
+
+        assert_eq!(0, unsafe { reactor.run_budget(0) });
+        assert_eq!(10, polls.load(Ordering::SeqCst));
     }
 }
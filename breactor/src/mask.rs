@@ -0,0 +1,200 @@
+//! Task masks.
+//!
+//! The reactor keeps one bit per task, so the width of the mask limits
+//! the number of tasks. See `Reactor64`.
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// A set of task ids, one bit per task.
+///
+/// Implemented for `u32` and `u64`.
+pub trait Mask: Copy + Eq {
+    /// Storage for the mask that can be shared with interrupts.
+    type Atomic: Sync;
+
+    /// Number of tasks the mask can hold.
+    const BITS: u32;
+
+    const EMPTY: Self;
+
+    /// Atomic storage with no bits set.
+    const ATOMIC_EMPTY: Self::Atomic;
+
+    /// Atomic storage with all bits set.
+    const ATOMIC_FULL: Self::Atomic;
+
+    /// Returns the mask of a single task.
+    ///
+    /// The mask is empty if the id doesn't fit.
+    fn bit(id: u32) -> Self;
+
+    fn contains(self, id: u32) -> bool;
+
+    /// Returns the highest task id in the mask.
+    fn highest(self) -> Option<u32>;
+
+    fn load(atomic: &Self::Atomic) -> Self;
+
+    fn store(atomic: &Self::Atomic, mask: Self);
+
+    /// Atomically adds the tasks to the mask.
+    fn insert(atomic: &Self::Atomic, mask: Self);
+
+    /// Atomically removes the tasks from the mask.
+    fn remove(atomic: &Self::Atomic, mask: Self);
+}
+
+impl Mask for u32 {
+    type Atomic = AtomicU32;
+
+    const BITS: u32 = 32;
+    const EMPTY: u32 = 0;
+
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ATOMIC_EMPTY: AtomicU32 = AtomicU32::new(0);
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ATOMIC_FULL: AtomicU32 = AtomicU32::new(u32::max_value());
+
+    fn bit(id: u32) -> u32 {
+        1_u32.checked_shl(id).unwrap_or(0)
+    }
+
+    fn contains(self, id: u32) -> bool {
+        self & Self::bit(id) != 0
+    }
+
+    fn highest(self) -> Option<u32> {
+        match self.leading_zeros() {
+            32 => None,
+            zeros => Some(31 - zeros),
+        }
+    }
+
+    fn load(atomic: &AtomicU32) -> u32 {
+        atomic.load(Ordering::SeqCst)
+    }
+
+    fn store(atomic: &AtomicU32, mask: u32) {
+        atomic.store(mask, Ordering::SeqCst);
+    }
+
+    fn insert(atomic: &AtomicU32, mask: u32) {
+        atomic.fetch_or(mask, Ordering::SeqCst);
+    }
+
+    fn remove(atomic: &AtomicU32, mask: u32) {
+        atomic.fetch_and(!mask, Ordering::SeqCst);
+    }
+}
+
+/// Cortex-M4 has no 64-bit atomics, so the mask is stored as two
+/// words, the low one first.
+///
+/// Each bit is still set and cleared atomically, which is all the
+/// reactor needs. Loading the whole mask is not atomic, but a bit
+/// changing meanwhile is no different from it changing right after
+/// the load.
+impl Mask for u64 {
+    type Atomic = [AtomicU32; 2];
+
+    const BITS: u32 = 64;
+    const EMPTY: u64 = 0;
+
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ATOMIC_EMPTY: [AtomicU32; 2] = [AtomicU32::new(0), AtomicU32::new(0)];
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ATOMIC_FULL: [AtomicU32; 2] = [
+        AtomicU32::new(u32::max_value()),
+        AtomicU32::new(u32::max_value()),
+    ];
+
+    fn bit(id: u32) -> u64 {
+        1_u64.checked_shl(id).unwrap_or(0)
+    }
+
+    fn contains(self, id: u32) -> bool {
+        self & Self::bit(id) != 0
+    }
+
+    fn highest(self) -> Option<u32> {
+        match self.leading_zeros() {
+            64 => None,
+            zeros => Some(63 - zeros),
+        }
+    }
+
+    fn load(atomic: &[AtomicU32; 2]) -> u64 {
+        u64::from(atomic[1].load(Ordering::SeqCst)) << 32
+            | u64::from(atomic[0].load(Ordering::SeqCst))
+    }
+
+    fn store(atomic: &[AtomicU32; 2], mask: u64) {
+        for (word, &bits) in atomic.iter().zip(&split(mask)) {
+            word.store(bits, Ordering::SeqCst);
+        }
+    }
+
+    fn insert(atomic: &[AtomicU32; 2], mask: u64) {
+        for (word, &bits) in atomic.iter().zip(&split(mask)) {
+            if bits != 0 {
+                word.fetch_or(bits, Ordering::SeqCst);
+            }
+        }
+    }
+
+    fn remove(atomic: &[AtomicU32; 2], mask: u64) {
+        for (word, &bits) in atomic.iter().zip(&split(mask)) {
+            if bits != 0 {
+                word.fetch_and(!bits, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// Splits the mask into the low and high words.
+#[allow(clippy::cast_possible_truncation)]
+fn split(mask: u64) -> [u32; 2] {
+    [mask as u32, (mask >> 32) as u32]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bit() {
+        assert_eq!(0x8000_0000, u32::bit(31));
+        assert_eq!(0, u32::bit(32));
+        assert_eq!(0x1_0000_0000, u64::bit(32));
+        assert_eq!(0, u64::bit(64));
+    }
+
+    #[test]
+    fn test_highest() {
+        assert_eq!(None, 0_u32.highest());
+        assert_eq!(Some(0), 1_u32.highest());
+        assert_eq!(Some(31), 0x8000_0001_u32.highest());
+
+        assert_eq!(None, 0_u64.highest());
+        assert_eq!(Some(5), 0x21_u64.highest());
+        assert_eq!(Some(32), 0x1_0000_0021_u64.highest());
+        assert_eq!(Some(63), u64::max_value().highest());
+    }
+
+    #[test]
+    fn test_atomic_u64() {
+        let mask = u64::ATOMIC_EMPTY;
+
+        u64::insert(&mask, 0x8000_0000_0000_0001);
+        u64::insert(&mask, 0x1_0000_0000);
+        assert_eq!(0x8000_0001_0000_0001, u64::load(&mask));
+
+        u64::remove(&mask, 0x8000_0000_0000_0000);
+        assert_eq!(0x1_0000_0001, u64::load(&mask));
+        assert!(u64::load(&mask).contains(32));
+        assert!(!u64::load(&mask).contains(63));
+
+        u64::store(&mask, 0x2_0000_0000);
+        assert_eq!(Some(33), u64::load(&mask).highest());
+        assert_eq!(u64::max_value(), u64::load(&u64::ATOMIC_FULL));
+    }
+}
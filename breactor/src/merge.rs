@@ -0,0 +1,202 @@
+//! A stream that merges two streams into one.
+use core::pin::Pin;
+use futures::stream::Fuse;
+use futures::stream::StreamExt;
+use futures::task::Context;
+use futures::{Poll, Stream};
+
+/// Yields items from whichever stream is ready.
+///
+/// When both streams are ready, they are polled in turns, so a busy
+/// stream can't starve the other one. The merged stream ends when
+/// both streams end.
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct Merge<A, B> {
+    a: Fuse<A>,
+    b: Fuse<B>,
+    /// Poll `b` first on the next call.
+    b_first: bool,
+}
+
+impl<A: Unpin, B: Unpin> Unpin for Merge<A, B> {}
+
+pub fn merge<A, B>(a: A, b: B) -> Merge<A, B>
+where
+    A: Stream,
+    B: Stream<Item = A::Item>,
+{
+    Merge {
+        a: a.fuse(),
+        b: b.fuse(),
+        b_first: false,
+    }
+}
+
+impl<A, B> Merge<A, B> {
+    pub fn into_inner(self) -> (A, B) {
+        (self.a.into_inner(), self.b.into_inner())
+    }
+}
+
+impl<A, B> Stream for Merge<A, B>
+where
+    A: Stream + Unpin,
+    B: Stream<Item = A::Item> + Unpin,
+{
+    type Item = A::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<A::Item>> {
+        let this = &mut *self;
+
+        // If the first stream is pending, the second one is polled as
+        // well, so both of them register the waker before the merged
+        // stream returns pending.
+        for &poll_b in &[this.b_first, !this.b_first] {
+            let res = if poll_b {
+                Pin::new(&mut this.b).poll_next(cx)
+            } else {
+                Pin::new(&mut this.a).poll_next(cx)
+            };
+
+            if let Poll::Ready(Some(x)) = res {
+                this.b_first = !poll_b;
+                return Poll::Ready(Some(x));
+            }
+        }
+
+        if this.a.is_done() && this.b.is_done() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    use futures::task::noop_waker;
+
+    /// Behaves like a USART: pending when there is no input. Counts
+    /// polls, so it's possible to check the waker was registered.
+    #[derive(Clone, Default)]
+    struct MockInput(Rc<RefCell<MockState>>);
+
+    #[derive(Default)]
+    struct MockState {
+        data: VecDeque<u8>,
+        closed: bool,
+        polls: usize,
+    }
+
+    impl MockInput {
+        fn push(&self, data: &[u8]) {
+            self.0.borrow_mut().data.extend(data);
+        }
+
+        fn close(&self) {
+            self.0.borrow_mut().closed = true;
+        }
+
+        fn polls(&self) -> usize {
+            self.0.borrow().polls
+        }
+    }
+
+    impl Stream for MockInput {
+        type Item = u8;
+
+        fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<u8>> {
+            let mut state = self.0.borrow_mut();
+            state.polls += 1;
+            match state.data.pop_front() {
+                Some(x) => Poll::Ready(Some(x)),
+                None if state.closed => Poll::Ready(None),
+                None => Poll::Pending,
+            }
+        }
+    }
+
+    fn poll_item<S: Stream<Item = u8> + Unpin>(stream: &mut S) -> Poll<Option<u8>> {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        Pin::new(stream).poll_next(&mut cx)
+    }
+
+    fn drain<S: Stream<Item = u8> + Unpin>(stream: &mut S) -> Vec<u8> {
+        let mut res = Vec::new();
+        while let Poll::Ready(Some(x)) = poll_item(stream) {
+            res.push(x);
+        }
+        res
+    }
+
+    #[test]
+    fn test_items_from_each_source() {
+        let a = MockInput::default();
+        let b = MockInput::default();
+        let mut merged = merge(a.clone(), b.clone());
+
+        assert_eq!(Poll::Pending, poll_item(&mut merged));
+
+        a.push(b"ab");
+        assert_eq!(b"ab", &drain(&mut merged)[..]);
+
+        b.push(b"cd");
+        assert_eq!(b"cd", &drain(&mut merged)[..]);
+
+        a.push(b"e");
+        assert_eq!(b"e", &drain(&mut merged)[..]);
+    }
+
+    #[test]
+    fn test_fair_interleaving() {
+        let a = MockInput::default();
+        let b = MockInput::default();
+        let mut merged = merge(a.clone(), b.clone());
+
+        a.push(b"aaaa");
+        b.push(b"bb");
+        assert_eq!(b"ababaa", &drain(&mut merged)[..]);
+
+        // The next turn is b's, even though a is also ready.
+        a.push(b"a");
+        b.push(b"b");
+        assert_eq!(b"ba", &drain(&mut merged)[..]);
+    }
+
+    #[test]
+    fn test_pending_polls_both() {
+        let a = MockInput::default();
+        let b = MockInput::default();
+        let mut merged = merge(a.clone(), b.clone());
+
+        assert_eq!(Poll::Pending, poll_item(&mut merged));
+        assert_eq!(1, a.polls());
+        assert_eq!(1, b.polls());
+    }
+
+    #[test]
+    fn test_ends_when_both_end() {
+        let a = MockInput::default();
+        let b = MockInput::default();
+        let mut merged = merge(a.clone(), b.clone());
+
+        a.push(b"a");
+        a.close();
+        b.push(b"b");
+        assert_eq!(b"ab", &drain(&mut merged)[..]);
+        assert_eq!(Poll::Pending, poll_item(&mut merged));
+
+        b.push(b"c");
+        b.close();
+        assert_eq!(Poll::Ready(Some(b'c')), poll_item(&mut merged));
+        assert_eq!(Poll::Ready(None), poll_item(&mut merged));
+    }
+}
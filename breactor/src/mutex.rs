@@ -1,11 +1,16 @@
 //! Mutual exclusion for futures.
 
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
 use core::pin::Pin;
 use core::sync::atomic::{AtomicU32, Ordering};
-use futures::task::Context;
+use futures::task::{Context, Waker};
 use futures::{Future, Poll};
 
+use stm32f4::SpinLock;
+
 use super::REACTOR;
+use crate::Mask;
 
 /// Mutex guarantees exclusive access for a task.
 ///
@@ -53,20 +58,133 @@ use super::REACTOR;
 /// # fn main() {
 /// # }
 /// ```
+///
+/// ## Fairness
+/// By default, releasing the mutex wakes all waiting tasks, and the
+/// one with the highest priority gets the lock. A low-priority task
+/// may starve if higher-priority ones keep locking the mutex. A mutex
+/// created with `new_fifo` hands the lock to the waiting tasks in
+/// arrival order instead.
+///
+/// ## Guarding data
+/// To protect a value rather than a resource, use `MutexCell`. Its
+/// guard dereferences to the value and releases the lock on drop.
 #[allow(missing_debug_implementations)]
 pub struct Mutex {
-    /// The tasks, that are currently waiting on the mutex.
+    /// Wakers of the tasks, that are currently waiting on the mutex.
     ///
     /// When the mutex is released, all those tasks are woken up. This
     /// usually results in the highest priority task acquiring a lock.
-    wait_task_mask: AtomicU32,
+    wakers: SpinLock<Wakers>,
 
     /// The current owner of the mutex lock.
     ///
     /// When 0, the mutex is empty.
     owner: AtomicU32,
+
+    /// Grant the lock in arrival order.
+    fifo: bool,
+
+    /// Ids of the waiting tasks, the oldest first. Only used in FIFO
+    /// mode.
+    queue: SpinLock<WaitQueue>,
+}
+
+/// A ring of task ids.
+///
+/// The global reactor has 32 tasks and each of them waits for at most
+/// one lock, so the queue never overflows.
+struct WaitQueue {
+    ids: [u8; 32],
+    head: usize,
+    len: usize,
+}
+
+impl WaitQueue {
+    const fn new() -> WaitQueue {
+        WaitQueue {
+            ids: [0; 32],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn position(&self, id: u8) -> Option<usize> {
+        (0..self.len).find(|&i| self.ids[(self.head + i) % self.ids.len()] == id)
+    }
+
+    /// Adds the task to the end of the queue, unless it's already
+    /// there.
+    fn push(&mut self, id: u8) {
+        if self.position(id).is_none() {
+            debug_assert!(self.len < self.ids.len());
+            let tail = (self.head + self.len) % self.ids.len();
+            self.ids[tail] = id;
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let id = self.ids[self.head];
+        self.head = (self.head + 1) % self.ids.len();
+        self.len -= 1;
+        Some(id)
+    }
+
+    fn remove(&mut self, id: u8) {
+        if let Some(pos) = self.position(id) {
+            // Shift the younger tasks towards the head.
+            for i in pos..self.len - 1 {
+                self.ids[(self.head + i) % self.ids.len()] =
+                    self.ids[(self.head + i + 1) % self.ids.len()];
+            }
+            self.len -= 1;
+        }
+    }
 }
 
+/// Wakers of the waiting tasks, indexed by task id.
+struct Wakers([Option<Waker>; 32]);
+
+impl Wakers {
+    #[rustfmt::skip]
+    const fn new() -> Wakers {
+        Wakers([
+            None, None, None, None, None, None, None, None,
+            None, None, None, None, None, None, None, None,
+            None, None, None, None, None, None, None, None,
+            None, None, None, None, None, None, None, None,
+        ])
+    }
+
+    fn register(&mut self, id: u32, waker: &Waker) {
+        let stored = &mut self.0[id as usize];
+        match *stored {
+            Some(ref current) if current.will_wake(waker) => {}
+            _ => *stored = Some(waker.clone()),
+        }
+    }
+
+    fn take(&mut self, id: u32) -> Option<Waker> {
+        self.0[id as usize].take()
+    }
+
+    fn wake_all(self) {
+        for waker in self.0.iter().flatten() {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+/// The owner recorded by `try_lock` when it is called outside of a
+/// task (including interrupt handlers preempting a task), so the mutex
+/// doesn't look empty.
+const NOT_A_TASK: u32 = u32::max_value();
+
 /// If you have this lock, you have locked the underlying mutex.
 #[allow(missing_debug_implementations)]
 pub struct MutexLock<'a> {
@@ -76,6 +194,8 @@ pub struct MutexLock<'a> {
 #[allow(missing_debug_implementations)]
 pub struct LockFuture<'a> {
     mutex: &'a Mutex,
+    /// Mask of the task queued in FIFO mode, or 0.
+    queued: u32,
 }
 
 impl<'a> Drop for MutexLock<'a> {
@@ -88,39 +208,508 @@ impl Mutex {
     /// Creates new empty mutex.
     pub const fn new() -> Mutex {
         Mutex {
-            wait_task_mask: AtomicU32::new(0),
+            wakers: SpinLock::new(Wakers::new()),
+            owner: AtomicU32::new(0),
+            fifo: false,
+            queue: SpinLock::new(WaitQueue::new()),
+        }
+    }
+
+    /// Creates new empty mutex that grants the lock in arrival order.
+    ///
+    /// Must only be locked from the tasks of the global reactor.
+    pub const fn new_fifo() -> Mutex {
+        Mutex {
+            wakers: SpinLock::new(Wakers::new()),
             owner: AtomicU32::new(0),
+            fifo: true,
+            queue: SpinLock::new(WaitQueue::new()),
         }
     }
 
     /// Return a future that will eventually lock the given mutex.
     pub const fn lock(&self) -> LockFuture {
-        LockFuture { mutex: self }
+        LockFuture {
+            mutex: self,
+            queued: 0,
+        }
+    }
+
+    /// Attempts to lock the mutex without waiting.
+    ///
+    /// Returns `None` if the mutex is already locked. Unlike `lock`,
+    /// this doesn't register the task for a wake up, so it can be used
+    /// outside of tasks (e.g., in interrupt handlers).
+    pub fn try_lock(&self) -> Option<MutexLock> {
+        // An interrupt handler may preempt a task that is being
+        // polled, so the current task is not necessarily the caller.
+        let in_isr = unsafe { stm32f4::__get_ipsr() } != 0;
+        let task = match REACTOR.get_current_task_mask() {
+            task if task != 0 && !in_isr => task,
+            _ => NOT_A_TASK,
+        };
+
+        if self.owner.compare_and_swap(0, task, Ordering::SeqCst) == 0 {
+            Some(MutexLock { mutex: self })
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if the mutex is locked.
+    ///
+    /// The result is a snapshot and may be stale by the time it is
+    /// returned.
+    pub fn is_locked(&self) -> bool {
+        self.owner_task() != 0
+    }
+
+    /// Returns the task mask of the owner, or 0 if the mutex is not
+    /// locked. All bits are set if it was locked with `try_lock`
+    /// outside of a task or from an interrupt handler.
+    ///
+    /// The result is a snapshot and may be stale by the time it is
+    /// returned.
+    pub fn owner_task(&self) -> u32 {
+        self.owner.load(Ordering::SeqCst)
     }
 
     /// Release the mutex, notifying all waiting tasks.
+    ///
+    /// In FIFO mode, the lock is handed to the oldest waiting task
+    /// instead.
     fn release(&self) {
+        if self.fifo {
+            let next = {
+                let mut queue = self.queue.lock();
+                let next = queue.pop();
+                let owner = next.map_or(0, |id| u32::bit(u32::from(id)));
+                self.owner.store(owner, Ordering::SeqCst);
+                next
+            };
+            let waker = next.and_then(|id| self.wakers.lock().take(u32::from(id)));
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+            return;
+        }
+
         self.owner.store(0, Ordering::SeqCst);
-        let tasks = self.wait_task_mask.swap(0, Ordering::SeqCst);
-        REACTOR.set_ready_task_mask(tasks);
+        // Woken after the lock is released, so the wakers run with
+        // interrupts enabled.
+        let wakers = ::core::mem::replace(&mut *self.wakers.lock(), Wakers::new());
+        wakers.wake_all();
+    }
+
+    /// Stores the waker of the task to wake it when the mutex is
+    /// released.
+    fn register(&self, task: u32, waker: &Waker) {
+        if let Some(id) = task.highest() {
+            self.wakers.lock().register(id, waker);
+        }
+    }
+
+    fn unregister(&self, task: u32) {
+        if let Some(id) = task.highest() {
+            self.wakers.lock().take(id);
+        }
+    }
+
+    /// Returns true if the task owns the lock now. Otherwise, queues
+    /// the task.
+    fn lock_fifo(&self, task: u32) -> bool {
+        let id = match task.highest() {
+            Some(id) => id as u8,
+            None => panic!("Mutex: a FIFO mutex is locked outside of a task"),
+        };
+
+        let mut queue = self.queue.lock();
+        let owner = self.owner.load(Ordering::SeqCst);
+        if owner == task {
+            // Handed over by `release`.
+            true
+        } else if owner == 0 && queue.len == 0 {
+            self.owner.store(task, Ordering::SeqCst);
+            true
+        } else {
+            queue.push(id);
+            false
+        }
+    }
+
+    /// Takes the task out of the queue, passing the lock on if it has
+    /// already been handed over.
+    fn cancel_fifo(&self, task: u32) {
+        let id = task.highest().unwrap() as u8;
+
+        let handed_over = {
+            let mut queue = self.queue.lock();
+            queue.remove(id);
+            self.owner.load(Ordering::SeqCst) == task
+        };
+        self.unregister(task);
+        if handed_over {
+            self.release();
+        }
     }
 }
 
 impl<'a> Future for LockFuture<'a> {
     type Output = MutexLock<'a>;
 
-    fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Self::Output> {
-        // TODO(rasen): use waker
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // The task mask identifies the owner; the waker is used to
+        // wake it.
         let task = REACTOR.get_current_task_mask();
+        // Registered first, so a release right after a failed attempt
+        // wakes the task.
+        self.mutex.register(task, cx.waker());
 
-        self.mutex.wait_task_mask.fetch_or(task, Ordering::SeqCst);
+        let locked = if self.mutex.fifo {
+            let locked = self.mutex.lock_fifo(task);
+            self.queued = if locked { 0 } else { task };
+            locked
+        } else {
+            self.mutex.owner.compare_and_swap(0, task, Ordering::SeqCst) == 0
+        };
 
-        let prev = self.mutex.owner.compare_and_swap(0, task, Ordering::SeqCst);
-        if prev == 0 {
-            // Mutex locked
+        if locked {
+            self.mutex.unregister(task);
             Poll::Ready(MutexLock { mutex: self.mutex })
         } else {
             Poll::Pending
         }
     }
 }
+
+impl<'a> Drop for LockFuture<'a> {
+    fn drop(&mut self) {
+        if self.queued != 0 {
+            self.mutex.cancel_fifo(self.queued);
+        }
+    }
+}
+
+/// A value protected by a `Mutex`.
+///
+/// The lock is held as long as the guard is alive, so a task can
+/// keep exclusive access across yields. Other tasks trying to lock
+/// the cell are blocked until the guard is dropped.
+#[allow(missing_debug_implementations)]
+pub struct MutexCell<T> {
+    mutex: Mutex,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for MutexCell<T> {}
+
+/// Provides exclusive access to the value of a `MutexCell`.
+#[allow(missing_debug_implementations)]
+pub struct MutexCellGuard<'a, T> {
+    _lock: MutexLock<'a>,
+    value: &'a mut T,
+}
+
+#[allow(missing_debug_implementations)]
+pub struct MutexCellLockFuture<'a, T> {
+    lock: LockFuture<'a>,
+    value: &'a UnsafeCell<T>,
+}
+
+impl<T> MutexCell<T> {
+    pub const fn new(value: T) -> MutexCell<T> {
+        MutexCell {
+            mutex: Mutex::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Return a future that will eventually lock the cell.
+    pub const fn lock(&self) -> MutexCellLockFuture<T> {
+        MutexCellLockFuture {
+            lock: self.mutex.lock(),
+            value: &self.value,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<'a, T> Future for MutexCellLockFuture<'a, T> {
+    type Output = MutexCellGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let lock = ready!(Pin::new(&mut self.lock).poll(cx));
+        Poll::Ready(MutexCellGuard {
+            _lock: lock,
+            // The mutex is locked, so this is the only reference.
+            value: unsafe { &mut *self.value.get() },
+        })
+    }
+}
+
+impl<'a, T> Deref for MutexCellGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<'a, T> DerefMut for MutexCellGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use core::cell::{Cell, RefCell};
+
+    use futures::FutureExt;
+
+    use crate::waker::task_waker;
+    use crate::{ReactorGuard, TaskId};
+
+    fn waker_of(task_id: u32) -> Waker {
+        task_waker(&REACTOR, TaskId::new(task_id).unwrap())
+    }
+
+    #[test]
+    fn test_try_lock() {
+        let mutex = Mutex::new();
+
+        let lock = mutex.try_lock();
+        assert!(lock.is_some());
+        assert!(mutex.try_lock().is_none());
+
+        drop(lock);
+        assert!(mutex.try_lock().is_some());
+    }
+
+    #[test]
+    fn test_owner_task() {
+        let _guard = ReactorGuard::acquire();
+        let mutex = Mutex::new();
+        assert!(!mutex.is_locked());
+        assert_eq!(0, mutex.owner_task());
+
+        REACTOR.current_task_mask.store(1 << 4, Ordering::SeqCst);
+        let lock = mutex.try_lock();
+        REACTOR.current_task_mask.store(0, Ordering::SeqCst);
+        assert!(mutex.is_locked());
+        assert_eq!(1 << 4, mutex.owner_task());
+
+        drop(lock);
+        assert!(!mutex.is_locked());
+
+        let _lock = mutex.try_lock();
+        assert_eq!(NOT_A_TASK, mutex.owner_task());
+    }
+
+    #[test]
+    fn test_owner_task_in_isr() {
+        let _guard = ReactorGuard::acquire();
+        let mutex = Mutex::new();
+
+        // An interrupt handler preempts the task that is being polled.
+        REACTOR.current_task_mask.store(1 << 4, Ordering::SeqCst);
+        unsafe { stm32f4::__set_ipsr(16) };
+        let lock = mutex.try_lock();
+        unsafe { stm32f4::__set_ipsr(0) };
+        REACTOR.current_task_mask.store(0, Ordering::SeqCst);
+
+        assert!(lock.is_some());
+        assert_eq!(NOT_A_TASK, mutex.owner_task());
+    }
+
+    #[test]
+    fn test_try_lock_blocks_lock() {
+        let _guard = ReactorGuard::acquire();
+        let mutex = Mutex::new();
+        let waker = waker_of(3);
+        let mut cx = Context::from_waker(&waker);
+
+        let lock = mutex.try_lock();
+        REACTOR.current_task_mask.store(1 << 3, Ordering::SeqCst);
+        let mut future = mutex.lock();
+        assert!(Pin::new(&mut future).poll(&mut cx).is_pending());
+        REACTOR.current_task_mask.store(0, Ordering::SeqCst);
+
+        // Releasing the lock wakes the waiting task.
+        drop(lock);
+        assert_eq!(1 << 3, REACTOR.ready_mask.swap(0, Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_release_wakes_waker() {
+        let _guard = ReactorGuard::acquire();
+        let mutex = Mutex::new_fifo();
+
+        let lock = mutex.try_lock();
+        // A combinator in the task 3 polls it with a waker of its own.
+        let waker = waker_of(6);
+        REACTOR.current_task_mask.store(1 << 3, Ordering::SeqCst);
+        let mut future = mutex.lock();
+        assert!(Pin::new(&mut future)
+            .poll(&mut Context::from_waker(&waker))
+            .is_pending());
+        REACTOR.current_task_mask.store(0, Ordering::SeqCst);
+
+        drop(lock);
+        assert_eq!(1 << 3, mutex.owner_task());
+        assert_eq!(1 << 6, REACTOR.ready_mask.swap(0, Ordering::SeqCst));
+    }
+
+    const LOW: u32 = 1;
+    const HIGH: u32 = 3;
+
+    /// Runs a high-priority task that locks `mutex` three times in a
+    /// row, and a low-priority one that locks it once. Returns the
+    /// order the tasks get the lock in.
+    fn contend(mutex: Mutex) -> Vec<&'static str> {
+        let _guard = ReactorGuard::acquire();
+        let mutex: &'static Mutex = Box::leak(Box::new(mutex));
+        let log: &'static RefCell<Vec<&str>> = Box::leak(Box::new(RefCell::new(Vec::new())));
+        let transfer_done: &'static Cell<bool> = Box::leak(Box::new(Cell::new(false)));
+
+        // Holds the lock until the transfer is done, then locks the
+        // mutex again right away.
+        let mut rounds = 3;
+        let mut lock = None;
+        let mut guard = None;
+        let high = futures::future::poll_fn(move |cx| loop {
+            if guard.is_some() {
+                if !transfer_done.replace(false) {
+                    return Poll::Pending;
+                }
+                guard = None;
+                rounds -= 1;
+                if rounds == 0 {
+                    return Poll::Ready(());
+                }
+            }
+
+            let future = lock.get_or_insert_with(|| mutex.lock());
+            guard = Some(ready!(Pin::new(future).poll(cx)));
+            lock = None;
+            log.borrow_mut().push("high");
+        });
+        let low = mutex.lock().map(move |_lock| log.borrow_mut().push("low"));
+
+        unsafe {
+            assert!(REACTOR.add_task(HIGH, Pin::new_unchecked(Box::leak(Box::new(high)))));
+            assert!(REACTOR.add_task(LOW, Pin::new_unchecked(Box::leak(Box::new(low)))));
+            REACTOR.run();
+
+            for _ in 0..3 {
+                // As the interrupt handler would.
+                transfer_done.set(true);
+                REACTOR.set_task_ready(TaskId::new(HIGH).unwrap());
+                REACTOR.run();
+            }
+        }
+
+        assert!(!REACTOR.is_task_present(HIGH));
+        assert!(!REACTOR.is_task_present(LOW));
+        assert!(!mutex.is_locked());
+        log.borrow().clone()
+    }
+
+    #[test]
+    fn test_priority_starves_low_task() {
+        assert_eq!(vec!["high", "high", "high", "low"], contend(Mutex::new()));
+    }
+
+    #[test]
+    fn test_fifo_grants_in_arrival_order() {
+        assert_eq!(
+            vec!["high", "low", "high", "high"],
+            contend(Mutex::new_fifo())
+        );
+    }
+
+    /// Polls the future as if it was called from the task `task_id`.
+    fn poll_in_task<'a>(task_id: u32, future: &mut LockFuture<'a>) -> Poll<MutexLock<'a>> {
+        let waker = futures::task::noop_waker();
+        REACTOR
+            .current_task_mask
+            .store(1 << task_id, Ordering::SeqCst);
+        let res = Pin::new(future).poll(&mut Context::from_waker(&waker));
+        REACTOR.current_task_mask.store(0, Ordering::SeqCst);
+        res
+    }
+
+    #[test]
+    fn test_fifo_cancelled_waiter() {
+        let _guard = ReactorGuard::acquire();
+        let mutex = Mutex::new_fifo();
+
+        let mut first = mutex.lock();
+        let lock = match poll_in_task(1, &mut first) {
+            Poll::Ready(lock) => lock,
+            Poll::Pending => panic!("the mutex is not locked"),
+        };
+        let mut second = mutex.lock();
+        let mut third = mutex.lock();
+        assert!(poll_in_task(2, &mut second).is_pending());
+        assert!(poll_in_task(3, &mut third).is_pending());
+
+        // The lock skips the cancelled waiter.
+        drop(second);
+        drop(lock);
+        assert_eq!(1 << 3, mutex.owner_task());
+        assert!(poll_in_task(3, &mut third).is_ready());
+    }
+
+    #[test]
+    fn test_fifo_passes_lock_on_cancel() {
+        let _guard = ReactorGuard::acquire();
+        let mutex = Mutex::new_fifo();
+
+        let lock = mutex.try_lock();
+        let mut waiting = mutex.lock();
+        assert!(poll_in_task(2, &mut waiting).is_pending());
+
+        // The lock is handed over to the waiting task, which doesn't
+        // want it anymore.
+        drop(lock);
+        assert_eq!(1 << 2, mutex.owner_task());
+        drop(waiting);
+        assert!(!mutex.is_locked());
+    }
+
+    #[test]
+    fn test_mutex_cell_guard_blocks_lock() {
+        let _guard = ReactorGuard::acquire();
+        let cell = MutexCell::new(0);
+        let first_waker = waker_of(2);
+        let waker = waker_of(3);
+        let mut cx = Context::from_waker(&waker);
+
+        REACTOR.current_task_mask.store(1 << 2, Ordering::SeqCst);
+        let mut first = cell.lock();
+        let mut guard = match Pin::new(&mut first).poll(&mut Context::from_waker(&first_waker)) {
+            Poll::Ready(guard) => guard,
+            Poll::Pending => panic!("the cell is not locked"),
+        };
+        *guard += 1;
+
+        REACTOR.current_task_mask.store(1 << 3, Ordering::SeqCst);
+        let mut second = cell.lock();
+        assert!(Pin::new(&mut second).poll(&mut cx).is_pending());
+
+        // Releasing the guard wakes the waiting task.
+        drop(guard);
+        assert_eq!(1 << 3, REACTOR.ready_mask.swap(0, Ordering::SeqCst));
+        match Pin::new(&mut second).poll(&mut cx) {
+            Poll::Ready(guard) => assert_eq!(1, *guard),
+            Poll::Pending => panic!("the guard is dropped"),
+        };
+        REACTOR.current_task_mask.store(0, Ordering::SeqCst);
+    }
+}
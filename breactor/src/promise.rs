@@ -4,7 +4,7 @@ use core::cell::UnsafeCell;
 use core::pin::Pin;
 use core::sync::atomic::{AtomicU32, Ordering};
 
-use futures::task::Context;
+use futures::task::{Context, Waker};
 use futures::{Future, Poll};
 
 use super::REACTOR;
@@ -15,14 +15,29 @@ use super::REACTOR;
 /// The promise can be shared between one producer and one consumer.
 ///
 /// The consumer is assumed to hold the object and should not drop it
-/// until it is resolved.
+/// until it is resolved. A consumer that is not interested in the
+/// result anymore should `cancel()` the promise instead. The producer
+/// then only updates `task` and never writes the result, so the
+/// result storage is the consumer's again right after `cancel()`.
+///
+/// The producer may preempt the consumer (e.g., resolve from an
+/// interrupt handler), but not the other way around.
 #[allow(missing_debug_implementations)]
 pub struct Promise<T> {
     /// Stores the mask of the owning task.
     ///
-    /// If `task` is `0`, the Promise have been resolved.
+    /// If `task` is `0`, the Promise have been resolved. If `task` is
+    /// `CANCELLED`, the consumer has abandoned it. `RESOLVING` is only
+    /// seen while the producer is writing the result, and
+    /// `REGISTERING` while the consumer is writing the waker.
     task: AtomicU32,
 
+    /// The waker of the consumer, set when it polls the promise.
+    ///
+    /// Only written by the consumer while `task` is `REGISTERING`, and
+    /// only taken by the producer while `task` is `RESOLVING`.
+    waker: UnsafeCell<Option<Waker>>,
+
     /// Stores the result of Promise.
     ///
     /// When `task` is non-zero, result stores `None`, and should only
@@ -35,6 +50,17 @@ pub struct Promise<T> {
 
 unsafe impl<T> Sync for Promise<T> {}
 
+/// The `task` of a promise the consumer has abandoned.
+///
+/// No single task has such a mask.
+const CANCELLED: u32 = u32::max_value();
+
+/// The `task` of a promise the producer is writing the result of.
+const RESOLVING: u32 = u32::max_value() - 1;
+
+/// The `task` of a promise the consumer is storing the waker of.
+const REGISTERING: u32 = u32::max_value() - 2;
+
 impl<T> Promise<T> {
     /// Creates an empty Promise.
     ///
@@ -43,6 +69,7 @@ impl<T> Promise<T> {
     pub const unsafe fn empty() -> Promise<T> {
         Promise {
             task: AtomicU32::new(0),
+            waker: UnsafeCell::new(None),
             result: UnsafeCell::new(None),
         }
     }
@@ -53,6 +80,7 @@ impl<T> Promise<T> {
     pub fn new() -> Promise<T> {
         Promise {
             task: AtomicU32::new(REACTOR.get_current_task_mask()),
+            waker: UnsafeCell::new(None),
             result: UnsafeCell::new(None),
         }
     }
@@ -60,6 +88,7 @@ impl<T> Promise<T> {
     pub const fn new_task(task_mask: u32) -> Promise<T> {
         Promise {
             task: AtomicU32::new(task_mask),
+            waker: UnsafeCell::new(None),
             result: UnsafeCell::new(None),
         }
     }
@@ -72,7 +101,7 @@ impl<T> Promise<T> {
         self.task.store(task, Ordering::Relaxed);
     }
 
-    /// Resolves the Promise notifying the waiting task.
+    /// Resolves the Promise waking the consumer.
     ///
     /// This should be called by the producer. The producer is not
     /// allowed to use the object after calling `resolve()`.
@@ -85,34 +114,300 @@ impl<T> Promise<T> {
     // Also, I should consider making Promise be owned by the
     // producer and tracking consumer's future-part.
     pub fn resolve(&self, result: T) {
+        // Take the promise over before touching the result, so a
+        // cancelled promise is never written.
+        let task = self.task.swap(RESOLVING, Ordering::Acquire);
+        debug_assert!(task != 0 && task != RESOLVING);
+        if task == CANCELLED {
+            // Nobody is going to take the result.
+            self.task.store(0, Ordering::Release);
+            return;
+        }
+
         unsafe {
             *self.result.get() = Some(result);
         }
+        let waker = if task == REGISTERING {
+            // The consumer checks `task` after storing the waker.
+            None
+        } else {
+            unsafe { (*self.waker.get()).take() }
+        };
+        self.task.store(0, Ordering::Release);
 
-        let task = self.task.swap(0, Ordering::Release);
-        debug_assert_ne!(task, 0);
-        REACTOR.set_ready_task_mask(task);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Abandons the promise, so the following `resolve()` drops the
+    /// result without storing it and wakes nobody.
+    ///
+    /// This should be called by the consumer that is not going to poll
+    /// the promise anymore, e.g., from `Drop` of the future that
+    /// awaits it. If the promise is already resolved, the result is
+    /// dropped right away.
+    pub fn cancel(&self) {
+        let task = self.task.load(Ordering::Acquire);
+        debug_assert_ne!(task, RESOLVING, "cancel() preempted resolve()");
+        let cancelled = task != 0
+            && self
+                .task
+                .compare_and_swap(task, CANCELLED, Ordering::AcqRel)
+                == task;
+        unsafe {
+            if cancelled {
+                // The producer doesn't touch the waker anymore.
+                *self.waker.get() = None;
+            } else {
+                // Resolved, so the producer is done with the result.
+                *self.result.get() = None;
+            }
+        }
+    }
+
+    /// Re-arms a resolved promise for the currently executed task, so
+    /// the same object can be reused for the next value.
+    ///
+    /// The result of the previous value is dropped if it has not been
+    /// consumed, so it can't be observed by the next consumer.
+    ///
+    /// Should only be called from within a task. Must not be called
+    /// while the promise is pending.
+    pub fn reset(&self) {
+        debug_assert!(self.is_resolved(), "resetting a pending promise");
+        unsafe {
+            *self.result.get() = None;
+            *self.waker.get() = None;
+        }
+        self.claim();
     }
 
     /// Returns true, if the promise is already resolved or not
     /// initialized.
     ///
+    /// A cancelled promise is not resolved until the producer calls
+    /// `resolve()`.
+    ///
     /// This method is not thread-safe with respect to `resolve()`.
     pub fn is_resolved(&self) -> bool {
         self.task.load(Ordering::Acquire) == 0
     }
+
+    /// Stores the waker of the consumer.
+    ///
+    /// Returns false if the promise has been resolved meanwhile.
+    fn register(&self, task: u32, waker: &Waker) -> bool {
+        if self
+            .task
+            .compare_and_swap(task, REGISTERING, Ordering::Acquire)
+            != task
+        {
+            return false;
+        }
+
+        unsafe {
+            let stored = &mut *self.waker.get();
+            match *stored {
+                Some(ref current) if current.will_wake(waker) => {}
+                _ => *stored = Some(waker.clone()),
+            }
+        }
+
+        self.task
+            .compare_and_swap(REGISTERING, task, Ordering::AcqRel)
+            == REGISTERING
+    }
+}
+
+/// A promise the producer can resolve with an error.
+pub type TryPromise<T, E> = Promise<Result<T, E>>;
+
+impl<T, E> TryPromise<T, E> {
+    /// Resolves the promise successfully. See `resolve`.
+    pub fn resolve_ok(&self, value: T) {
+        self.resolve(Ok(value));
+    }
+
+    /// Resolves the promise with an error. See `resolve`.
+    pub fn resolve_err(&self, error: E) {
+        self.resolve(Err(error));
+    }
 }
 
 impl<T> Future for Promise<T> {
     type Output = T;
 
-    fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<T> {
-        // TODO(rasen): use waker
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
         let task = self.task.load(Ordering::Acquire);
-        if task == 0 {
-            Poll::Ready(unsafe { ::core::ptr::replace(self.result.get(), None) }.unwrap())
-        } else {
-            Poll::Pending
+        if task != 0 && self.register(task, cx.waker()) {
+            return Poll::Pending;
         }
+
+        debug_assert!(self.is_resolved());
+        Poll::Ready(unsafe { ::core::ptr::replace(self.result.get(), None) }.unwrap())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::rc::Rc;
+
+    use crate::waker::task_waker;
+    use crate::{ReactorGuard, TaskId};
+
+    const TASK: u32 = 1 << 3;
+
+    /// Polls the promise with the waker of the task `task_id`.
+    fn poll_with<T: Unpin>(promise: &mut Promise<T>, task_id: u32) -> Poll<T> {
+        let waker = task_waker(&REACTOR, TaskId::new(task_id).unwrap());
+        Pin::new(promise).poll(&mut Context::from_waker(&waker))
+    }
+
+    fn poll<T: Unpin>(promise: &mut Promise<T>) -> Poll<T> {
+        poll_with(promise, 3)
+    }
+
+    #[test]
+    fn test_resolve_ok() {
+        let _guard = ReactorGuard::acquire();
+        let mut promise: TryPromise<u32, ()> = Promise::new_task(TASK);
+
+        assert_eq!(Poll::Pending, poll(&mut promise));
+        assert!(!REACTOR.is_ready());
+
+        promise.resolve_ok(42);
+        assert_eq!(TASK, REACTOR.ready_mask.swap(0, Ordering::SeqCst));
+        assert_eq!(Poll::Ready(Ok(42)), poll(&mut promise));
+    }
+
+    #[test]
+    fn test_resolve_err() {
+        let _guard = ReactorGuard::acquire();
+        let mut promise: TryPromise<(), &str> = Promise::new_task(TASK);
+
+        assert_eq!(Poll::Pending, poll(&mut promise));
+
+        promise.resolve_err("bus error");
+        assert_eq!(TASK, REACTOR.ready_mask.swap(0, Ordering::SeqCst));
+        assert_eq!(Poll::Ready(Err("bus error")), poll(&mut promise));
+    }
+
+    #[test]
+    fn test_resolve_wakes_waker() {
+        let _guard = ReactorGuard::acquire();
+        let mut promise = Promise::new_task(TASK);
+
+        // Not polled yet, so there is no one to wake.
+        promise.resolve(1);
+        assert!(!REACTOR.is_ready());
+        assert_eq!(Poll::Ready(1), poll(&mut promise));
+
+        // A combinator may poll it with a waker of its own.
+        reset_in_task(&promise, TASK);
+        assert_eq!(Poll::Pending, poll_with(&mut promise, 6));
+        promise.resolve(2);
+        assert_eq!(1 << 6, REACTOR.ready_mask.swap(0, Ordering::SeqCst));
+        assert_eq!(Poll::Ready(2), poll(&mut promise));
+    }
+
+    fn reset_in_task<T>(promise: &Promise<T>, task_mask: u32) {
+        REACTOR.current_task_mask.store(task_mask, Ordering::SeqCst);
+        promise.reset();
+        REACTOR.current_task_mask.store(0, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_reset() {
+        let _guard = ReactorGuard::acquire();
+        let mut promise = Promise::new_task(TASK);
+
+        assert_eq!(Poll::Pending, poll(&mut promise));
+        promise.resolve(1);
+        assert_eq!(TASK, REACTOR.ready_mask.swap(0, Ordering::SeqCst));
+        assert_eq!(Poll::Ready(1), poll(&mut promise));
+
+        reset_in_task(&promise, 1 << 5);
+        assert!(!promise.is_resolved());
+        assert_eq!(Poll::Pending, poll_with(&mut promise, 5));
+
+        promise.resolve(2);
+        assert_eq!(1 << 5, REACTOR.ready_mask.swap(0, Ordering::SeqCst));
+        assert_eq!(Poll::Ready(2), poll(&mut promise));
+    }
+
+    #[test]
+    fn test_reset_drops_unconsumed_result() {
+        let _guard = ReactorGuard::acquire();
+        let mut promise = Promise::new_task(TASK);
+        let result = Rc::new(42);
+
+        promise.resolve(result.clone());
+        reset_in_task(&promise, TASK);
+        assert_eq!(1, Rc::strong_count(&result));
+        assert_eq!(Poll::Pending, poll(&mut promise));
+    }
+
+    /// A future that awaits a promise it doesn't own, as drivers do.
+    struct Consumer<'a>(&'a mut Promise<Rc<u32>>);
+
+    impl<'a> Drop for Consumer<'a> {
+        fn drop(&mut self) {
+            self.0.cancel();
+        }
+    }
+
+    #[test]
+    fn test_drop_before_resolve() {
+        let _guard = ReactorGuard::acquire();
+        let mut promise = Promise::new_task(TASK);
+        let result = Rc::new(42);
+
+        let mut consumer = Consumer(&mut promise);
+        assert_eq!(Poll::Pending, poll(consumer.0));
+        drop(consumer);
+        assert!(!promise.is_resolved());
+
+        promise.resolve(result.clone());
+        assert!(promise.is_resolved());
+        // The task is not woken and the result is dropped.
+        assert!(!REACTOR.is_ready());
+        assert_eq!(1, Rc::strong_count(&result));
+    }
+
+    #[test]
+    fn test_resolve_does_not_write_cancelled() {
+        let _guard = ReactorGuard::acquire();
+        let promise = Promise::new_task(TASK);
+        promise.cancel();
+
+        // The consumer reuses the storage after cancelling.
+        let reused = Rc::new(1);
+        unsafe {
+            *promise.result.get() = Some(reused.clone());
+        }
+
+        let result = Rc::new(2);
+        promise.resolve(result.clone());
+        assert!(promise.is_resolved());
+        assert_eq!(1, Rc::strong_count(&result));
+        let stored = unsafe { (*promise.result.get()).take() };
+        assert!(Rc::ptr_eq(&reused, &stored.unwrap()));
+    }
+
+    #[test]
+    fn test_drop_after_resolve() {
+        let _guard = ReactorGuard::acquire();
+        let mut promise = Promise::new_task(TASK);
+        let result = Rc::new(42);
+
+        promise.resolve(result.clone());
+        REACTOR.ready_mask.swap(0, Ordering::SeqCst);
+
+        drop(Consumer(&mut promise));
+        assert!(promise.is_resolved());
+        assert_eq!(1, Rc::strong_count(&result));
     }
 }
@@ -0,0 +1,144 @@
+//! Graceful reactor shutdown.
+//!
+//! See `Reactor::quiesce`.
+use core::array::FixedSizeArray;
+use core::pin::Pin;
+use core::sync::atomic::Ordering;
+use core::task::Context;
+
+use futures::{Future, Poll};
+
+use crate::time::{Delay, Duration};
+use crate::{Mask, Reactor, TaskSlot};
+
+#[allow(missing_debug_implementations)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Quiesce<'r, 'a, A, M: Mask> {
+    reactor: &'r Reactor<'a, A, M>,
+    deadline: Delay,
+}
+
+impl<'r, 'a, A, M: Mask> Quiesce<'r, 'a, A, M> {
+    pub(crate) fn new(reactor: &'r Reactor<'a, A, M>, deadline: Duration) -> Self {
+        Quiesce {
+            reactor,
+            deadline: Delay::new(deadline),
+        }
+    }
+}
+
+impl<'r, 'a, A: FixedSizeArray<TaskSlot<'a>>, M: Mask> Future for Quiesce<'r, 'a, A, M> {
+    type Output = bool;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<bool> {
+        self.reactor.quiescing.store(true, Ordering::SeqCst);
+
+        let current_task = self.reactor.get_current_task_mask();
+
+        if self.reactor.pending_tasks(current_task) == 0 {
+            return Poll::Ready(true);
+        }
+        if self.deadline.is_elapsed() {
+            return Poll::Ready(false);
+        }
+
+        // Tasks don't notify us when they complete, so check again
+        // once the others have been polled.
+        self.reactor.set_ready_task_mask(current_task);
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use core::cell::UnsafeCell;
+    use core::sync::atomic::AtomicU32;
+
+    use futures::FutureExt;
+
+    use crate::time::tick;
+    use crate::{ReactorGuard, TaskId};
+
+    const NOT_RESOLVED: u32 = 2;
+    const WORKER: u32 = 2;
+
+    fn small_reactor<'a>() -> Reactor<'a, [TaskSlot<'a>; 4]> {
+        let reactor = Reactor::from_array([
+            UnsafeCell::new(None),
+            UnsafeCell::new(None),
+            UnsafeCell::new(None),
+            UnsafeCell::new(None),
+        ]);
+        // Clear ready bits of the empty slots.
+        unsafe { reactor.run() };
+        reactor
+    }
+
+    #[test]
+    fn test_task_completes_before_deadline() {
+        let _guard = ReactorGuard::acquire();
+        let reactor = small_reactor();
+
+        let result = AtomicU32::new(NOT_RESOLVED);
+        let polls = AtomicU32::new(0);
+
+        // Completes on the third poll.
+        let mut worker = futures::future::poll_fn(|_cx| {
+            if polls.fetch_add(1, Ordering::SeqCst) == 2 {
+                Poll::Ready(())
+            } else {
+                reactor.set_task_ready(TaskId::new(WORKER).unwrap());
+                Poll::Pending
+            }
+        });
+        let mut quiesce = reactor
+            .quiesce(Duration::from_millis(5))
+            .map(|done| result.store(u32::from(done), Ordering::SeqCst));
+        let mut rejected = futures::future::ready(());
+
+        unsafe {
+            assert!(reactor.add_task(WORKER, Pin::new_unchecked(&mut worker)));
+            assert!(reactor.add_task(0, Pin::new_unchecked(&mut quiesce)));
+            reactor.run();
+
+            assert!(!reactor.add_task(3, Pin::new_unchecked(&mut rejected)));
+        }
+
+        assert_eq!(3, polls.load(Ordering::SeqCst));
+        assert_eq!(1, result.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_deadline_passes() {
+        let _guard = ReactorGuard::acquire();
+        let reactor = small_reactor();
+
+        let result = AtomicU32::new(NOT_RESOLVED);
+
+        // Waits for an event that never comes.
+        let mut worker = futures::future::poll_fn(|_cx| Poll::<()>::Pending);
+        let mut quiesce = reactor
+            .quiesce(Duration::from_millis(5))
+            .map(|done| result.store(u32::from(done), Ordering::SeqCst));
+
+        unsafe {
+            assert!(reactor.add_task(WORKER, Pin::new_unchecked(&mut worker)));
+            assert!(reactor.add_task(0, Pin::new_unchecked(&mut quiesce)));
+
+            // 5 ms and one more tick
+            for _ in 0..5 {
+                tick();
+                reactor.run_budget(10);
+                assert_eq!(NOT_RESOLVED, result.load(Ordering::SeqCst));
+            }
+
+            tick();
+            reactor.run_budget(10);
+        }
+
+        assert_eq!(0, result.load(Ordering::SeqCst));
+        assert!(!reactor.is_ready());
+    }
+}
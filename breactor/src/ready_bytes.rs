@@ -0,0 +1,196 @@
+//! Batching of bytes that are immediately available.
+use core::ops::Deref;
+use core::pin::Pin;
+use futures::stream::Fuse;
+use futures::stream::StreamExt;
+use futures::task::Context;
+use futures::{Poll, Stream};
+
+/// Maximum number of bytes in a single chunk.
+pub const CHUNK_SIZE: usize = 16;
+
+/// A chunk of bytes produced by `ReadyBytes`.
+#[derive(Debug, Clone, Copy)]
+pub struct Chunk {
+    buf: [u8; CHUNK_SIZE],
+    len: usize,
+}
+
+impl Deref for Chunk {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+/// Groups all bytes the underlying stream has ready into chunks, so
+/// they can be processed at once (e.g., pasted terminal input is
+/// echoed by one `StartSendBytes`, which hands the chunk to the sink
+/// in a single operation when there is room).
+///
+/// A chunk is yielded as soon as the stream gets pending, or the
+/// chunk is full.
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct ReadyBytes<St> {
+    stream: Fuse<St>,
+}
+
+impl<St: Unpin> Unpin for ReadyBytes<St> {}
+
+pub fn ready_bytes<St>(stream: St) -> ReadyBytes<St>
+where
+    St: Stream<Item = u8>,
+{
+    ReadyBytes {
+        stream: stream.fuse(),
+    }
+}
+
+impl<St> Stream for ReadyBytes<St>
+where
+    St: Stream<Item = u8> + Unpin,
+{
+    type Item = Chunk;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Chunk>> {
+        let mut chunk = Chunk {
+            buf: [0; CHUNK_SIZE],
+            len: 0,
+        };
+
+        while chunk.len < CHUNK_SIZE {
+            match Pin::new(&mut self.stream).poll_next(cx) {
+                Poll::Ready(Some(x)) => {
+                    chunk.buf[chunk.len] = x;
+                    chunk.len += 1;
+                }
+                Poll::Ready(None) | Poll::Pending => break,
+            }
+        }
+
+        if chunk.len != 0 {
+            Poll::Ready(Some(chunk))
+        } else if self.stream.is_done() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::VecDeque;
+
+    use futures::{Future, Sink};
+
+    use crate::start_send_bytes::{SinkBytes, StartSendBytes};
+    use futures::task::noop_waker;
+
+    /// Behaves like a USART: pending when there is no input.
+    struct MockInput(VecDeque<u8>);
+
+    impl Stream for MockInput {
+        type Item = u8;
+
+        fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<u8>> {
+            match self.0.pop_front() {
+                Some(x) => Poll::Ready(Some(x)),
+                None => Poll::Pending,
+            }
+        }
+    }
+
+    /// Counts the operations.
+    #[derive(Default)]
+    struct MockSink {
+        sent: Vec<u8>,
+        operations: usize,
+    }
+
+    impl Sink<u8> for MockSink {
+        type SinkError = ();
+
+        fn poll_ready(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            self.operations += 1;
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: u8) -> Result<(), ()> {
+            self.operations += 1;
+            self.sent.push(item);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl SinkBytes for MockSink {
+        fn poll_send_bytes(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            bytes: &[u8],
+        ) -> Poll<Result<usize, ()>> {
+            self.operations += 1;
+            self.sent.extend_from_slice(bytes);
+            Poll::Ready(Ok(bytes.len()))
+        }
+    }
+
+    fn poll_chunk(stream: &mut ReadyBytes<MockInput>) -> Poll<Option<Chunk>> {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        Pin::new(stream).poll_next(&mut cx)
+    }
+
+    #[test]
+    fn test_pasted_line_is_echoed_in_one_operation() {
+        let mut input = ready_bytes(MockInput(b"hello\r".iter().cloned().collect()));
+
+        let mut echoes = 0;
+        let mut sink = MockSink::default();
+        while let Poll::Ready(Some(chunk)) = poll_chunk(&mut input) {
+            echoes += 1;
+
+            let waker = noop_waker();
+            let mut cx = Context::from_waker(&waker);
+            let mut echo = StartSendBytes::new(sink, &chunk);
+            sink = match Pin::new(&mut echo).poll(&mut cx) {
+                Poll::Ready(Ok(sink)) => sink,
+                _ => panic!("echo has not finished in a single poll"),
+            };
+        }
+
+        // One echo future and one sink operation, instead of a future
+        // and two operations per byte.
+        assert_eq!(1, echoes);
+        assert_eq!(1, sink.operations);
+        assert_eq!(b"hello\r", &sink.sent[..]);
+    }
+
+    #[test]
+    fn test_chunk_size_is_limited() {
+        let input = (0..CHUNK_SIZE as u8 + 3).collect();
+        let mut input = ready_bytes(MockInput(input));
+
+        match poll_chunk(&mut input) {
+            Poll::Ready(Some(chunk)) => assert_eq!(CHUNK_SIZE, chunk.len()),
+            _ => panic!("no chunk"),
+        }
+        match poll_chunk(&mut input) {
+            Poll::Ready(Some(chunk)) => assert_eq!(&[16, 17, 18], &chunk[..]),
+            _ => panic!("no chunk"),
+        }
+        assert_eq!(Poll::Pending, poll_chunk(&mut input).map(|x| x.is_some()));
+    }
+}
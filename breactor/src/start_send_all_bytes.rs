@@ -0,0 +1,151 @@
+use core::pin::Pin;
+use futures::task::Context;
+use futures::{Future, Poll, Sink};
+
+/// Sends all bytes of a slice to the sink, without flushing it.
+///
+/// This is a byte counterpart of `StartSendAllString`.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct StartSendAllBytes<'a, T> {
+    sink: Option<T>,
+    bytes: &'a [u8],
+    cur: usize,
+}
+
+impl<'a, T> Unpin for StartSendAllBytes<'a, T> where T: Sink<u8> + Unpin {}
+
+impl<'a, T> StartSendAllBytes<'a, T>
+where
+    T: Sink<u8> + Unpin,
+{
+    pub fn new(sink: T, bytes: &'a [u8]) -> StartSendAllBytes<'a, T> {
+        StartSendAllBytes {
+            sink: Some(sink),
+            bytes,
+            cur: 0,
+        }
+    }
+}
+
+impl<'a, T> StartSendAllBytes<'a, T>
+where
+    T: Sink<u8>,
+{
+    fn sink_mut(&mut self) -> &mut T {
+        self.sink.as_mut().take().expect("")
+    }
+
+    fn take_result(&mut self) -> T {
+        self.sink.take().expect("")
+    }
+}
+
+impl<'a, T> Future for StartSendAllBytes<'a, T>
+where
+    T: Sink<u8> + Unpin,
+{
+    type Output = Result<T, T::SinkError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+
+        while this.cur < this.bytes.len() {
+            try_ready!(Pin::new(this.sink_mut()).poll_ready(cx));
+
+            let item = this.bytes[this.cur];
+            Pin::new(this.sink_mut()).start_send(item)?;
+
+            this.cur += 1;
+        }
+
+        Poll::Ready(Ok(self.take_result()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::task::noop_waker;
+
+    use crate::start_send_all_string::StartSendAllString;
+
+    /// Accepts `room` items, then is pending.
+    #[derive(Debug, Default)]
+    struct MockSink {
+        data: Vec<u8>,
+        room: usize,
+    }
+
+    impl Sink<u8> for MockSink {
+        type SinkError = ();
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            if self.room == 0 {
+                Poll::Pending
+            } else {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: u8) -> Result<(), ()> {
+            assert_ne!(0, self.room, "start_send on a full sink");
+            self.room -= 1;
+            self.data.push(item);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn poll<F: Future + Unpin>(f: &mut F) -> Poll<F::Output> {
+        let waker = noop_waker();
+        Pin::new(f).poll(&mut Context::from_waker(&waker))
+    }
+
+    #[test]
+    fn test_sends_all_bytes() {
+        let mut sink = MockSink::default();
+
+        let mut send = StartSendAllBytes::new(&mut sink, b"\x00\xffab");
+        send.sink_mut().room = 3;
+        assert!(poll(&mut send).is_pending());
+
+        // Continues from where the sink was full.
+        send.sink_mut().room = 3;
+        match poll(&mut send) {
+            Poll::Ready(Ok(sink)) => assert_eq!(2, sink.room),
+            _ => panic!("not all bytes are sent"),
+        }
+        assert_eq!(b"\x00\xffab", &sink.data[..]);
+    }
+
+    #[test]
+    fn test_empty_slice() {
+        let mut send = StartSendAllBytes::new(MockSink::default(), &[]);
+        match poll(&mut send) {
+            Poll::Ready(Ok(sink)) => assert!(sink.data.is_empty()),
+            _ => panic!("sending nothing is not finished"),
+        }
+    }
+
+    #[test]
+    fn test_string() {
+        let sink = MockSink {
+            data: Vec::new(),
+            room: 16,
+        };
+
+        match poll(&mut StartSendAllString::new(sink, "AT\r\n")) {
+            Poll::Ready(Ok(sink)) => assert_eq!(b"AT\r\n", &sink.data[..]),
+            _ => panic!("not all bytes are sent"),
+        }
+    }
+}
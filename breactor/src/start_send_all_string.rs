@@ -2,39 +2,21 @@ use core::pin::Pin;
 use futures::task::Context;
 use futures::{Future, Poll, Sink};
 
+use crate::start_send_all_bytes::StartSendAllBytes;
+
+/// Sends all bytes of a string to the sink, without flushing it.
+///
+/// See `StartSendAllBytes`.
 #[derive(Debug)]
 #[must_use = "futures do nothing unless polled"]
-pub struct StartSendAllString<'a, T> {
-    sink: Option<T>,
-    string: &'a str,
-    cur: usize,
-}
-
-impl<'a, T> Unpin for StartSendAllString<'a, T> where T: Sink<u8> + Unpin {}
+pub struct StartSendAllString<'a, T>(StartSendAllBytes<'a, T>);
 
 impl<'a, T> StartSendAllString<'a, T>
 where
     T: Sink<u8> + Unpin,
 {
     pub fn new(sink: T, string: &'a str) -> StartSendAllString<'a, T> {
-        StartSendAllString {
-            sink: Some(sink),
-            string,
-            cur: 0,
-        }
-    }
-}
-
-impl<'a, T> StartSendAllString<'a, T>
-where
-    T: Sink<u8>,
-{
-    fn sink_mut(&mut self) -> &mut T {
-        self.sink.as_mut().take().expect("")
-    }
-
-    fn take_result(&mut self) -> T {
-        self.sink.take().expect("")
+        StartSendAllString(StartSendAllBytes::new(sink, string.as_bytes()))
     }
 }
 
@@ -45,17 +27,6 @@ where
     type Output = Result<T, T::SinkError>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let this = &mut *self;
-
-        while this.cur < this.string.as_bytes().len() {
-            try_ready!(Pin::new(this.sink_mut()).poll_ready(cx));
-
-            let item = this.string.as_bytes()[this.cur];
-            Pin::new(this.sink_mut()).start_send(item)?;
-
-            this.cur += 1;
-        }
-
-        Poll::Ready(Ok(self.take_result()))
+        Pin::new(&mut self.0).poll(cx)
     }
 }
@@ -0,0 +1,178 @@
+use core::pin::Pin;
+use futures::task::Context;
+use futures::{Future, Poll, Sink};
+
+/// A sink that can take several bytes in a single operation.
+pub trait SinkBytes: Sink<u8> {
+    /// Starts sending as many bytes from the start of `bytes` as the
+    /// sink has room for, and returns their number.
+    ///
+    /// Returns `Poll::Pending` if there is no room for a single byte.
+    /// The task is woken when there is. `bytes` must not be empty.
+    fn poll_send_bytes(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bytes: &[u8],
+    ) -> Poll<Result<usize, Self::SinkError>>;
+}
+
+impl<'a, S> SinkBytes for &'a mut S
+where
+    S: SinkBytes + Unpin + ?Sized,
+{
+    fn poll_send_bytes(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bytes: &[u8],
+    ) -> Poll<Result<usize, Self::SinkError>> {
+        Pin::new(&mut **self).poll_send_bytes(cx, bytes)
+    }
+}
+
+/// Sends all bytes of a slice to the sink, without flushing it.
+///
+/// Unlike `StartSendAllBytes`, the sink takes the bytes in as few
+/// operations as it has room for, rather than one at a time.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct StartSendBytes<'a, T> {
+    sink: Option<T>,
+    bytes: &'a [u8],
+}
+
+impl<'a, T> Unpin for StartSendBytes<'a, T> where T: SinkBytes + Unpin {}
+
+impl<'a, T> StartSendBytes<'a, T>
+where
+    T: SinkBytes + Unpin,
+{
+    pub fn new(sink: T, bytes: &'a [u8]) -> StartSendBytes<'a, T> {
+        StartSendBytes {
+            sink: Some(sink),
+            bytes,
+        }
+    }
+}
+
+impl<'a, T> Future for StartSendBytes<'a, T>
+where
+    T: SinkBytes + Unpin,
+{
+    type Output = Result<T, T::SinkError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+
+        while !this.bytes.is_empty() {
+            let sink = this.sink.as_mut().expect("polled after completion");
+            let sent = try_ready!(Pin::new(sink).poll_send_bytes(cx, this.bytes));
+            this.bytes = &this.bytes[sent..];
+        }
+
+        Poll::Ready(Ok(this.sink.take().expect("polled after completion")))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::task::noop_waker;
+
+    /// Takes up to `room` bytes, and counts the operations.
+    #[derive(Debug, Default)]
+    struct MockSink {
+        data: Vec<u8>,
+        room: usize,
+        operations: usize,
+    }
+
+    impl Sink<u8> for MockSink {
+        type SinkError = ();
+
+        fn poll_ready(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            self.operations += 1;
+            if self.room == 0 {
+                Poll::Pending
+            } else {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: u8) -> Result<(), ()> {
+            assert_ne!(0, self.room, "start_send on a full sink");
+            self.operations += 1;
+            self.room -= 1;
+            self.data.push(item);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl SinkBytes for MockSink {
+        fn poll_send_bytes(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            bytes: &[u8],
+        ) -> Poll<Result<usize, ()>> {
+            self.operations += 1;
+            let sent = ::core::cmp::min(self.room, bytes.len());
+            if sent == 0 {
+                return Poll::Pending;
+            }
+
+            self.room -= sent;
+            self.data.extend_from_slice(&bytes[..sent]);
+            Poll::Ready(Ok(sent))
+        }
+    }
+
+    fn poll<F: Future + Unpin>(f: &mut F) -> Poll<F::Output> {
+        let waker = noop_waker();
+        Pin::new(f).poll(&mut Context::from_waker(&waker))
+    }
+
+    #[test]
+    fn test_sends_in_one_operation() {
+        let sink = MockSink {
+            room: 16,
+            ..MockSink::default()
+        };
+
+        match poll(&mut StartSendBytes::new(sink, b"hello\r")) {
+            Poll::Ready(Ok(sink)) => {
+                assert_eq!(b"hello\r", &sink.data[..]);
+                assert_eq!(1, sink.operations);
+            }
+            _ => panic!("not all bytes are sent"),
+        }
+    }
+
+    #[test]
+    fn test_continues_when_full() {
+        let mut sink = MockSink {
+            room: 2,
+            ..MockSink::default()
+        };
+
+        let mut send = StartSendBytes::new(&mut sink, b"hello");
+        assert!(poll(&mut send).is_pending());
+
+        // Continues from where the sink was full.
+        send.sink.as_mut().unwrap().room = 3;
+        match poll(&mut send) {
+            Poll::Ready(Ok(sink)) => assert_eq!(0, sink.room),
+            _ => panic!("not all bytes are sent"),
+        }
+        assert_eq!(b"hello", &sink.data[..]);
+        // Sent, pending, sent.
+        assert_eq!(3, sink.operations);
+    }
+}
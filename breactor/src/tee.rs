@@ -0,0 +1,164 @@
+//! A sink that duplicates every item into two sinks.
+use core::pin::Pin;
+use futures::task::Context;
+use futures::{Poll, Sink};
+
+/// Forwards each item to both inner sinks.
+///
+/// The tee is ready only when both sinks are ready, so backpressure
+/// on either of them stalls the whole tee. This guarantees both sinks
+/// receive exactly the same items.
+#[derive(Debug)]
+#[must_use = "sinks do nothing unless polled"]
+pub struct Tee<A, B> {
+    a: A,
+    b: B,
+}
+
+impl<A, B> Tee<A, B> {
+    pub fn new(a: A, b: B) -> Tee<A, B> {
+        Tee { a, b }
+    }
+
+    pub fn get_ref(&self) -> (&A, &B) {
+        (&self.a, &self.b)
+    }
+
+    pub fn into_inner(self) -> (A, B) {
+        (self.a, self.b)
+    }
+}
+
+/// Combines results of polling both sinks. Errors take precedence
+/// over pending, pending takes precedence over ready.
+fn join<E>(a: Poll<Result<(), E>>, b: Poll<Result<(), E>>) -> Poll<Result<(), E>> {
+    match (a, b) {
+        (Poll::Ready(Err(e)), _) | (_, Poll::Ready(Err(e))) => Poll::Ready(Err(e)),
+        (Poll::Ready(Ok(())), Poll::Ready(Ok(()))) => Poll::Ready(Ok(())),
+        _ => Poll::Pending,
+    }
+}
+
+impl<T, A, B> Sink<T> for Tee<A, B>
+where
+    T: Clone,
+    A: Sink<T> + Unpin,
+    B: Sink<T, SinkError = A::SinkError> + Unpin,
+{
+    type SinkError = A::SinkError;
+
+    fn poll_ready(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::SinkError>> {
+        // Both sinks are always polled, so both register the waker.
+        let a = Pin::new(&mut self.a).poll_ready(cx);
+        let b = Pin::new(&mut self.b).poll_ready(cx);
+        join(a, b)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: T) -> Result<(), Self::SinkError> {
+        Pin::new(&mut self.a).start_send(item.clone())?;
+        Pin::new(&mut self.b).start_send(item)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::SinkError>> {
+        let a = Pin::new(&mut self.a).poll_flush(cx);
+        let b = Pin::new(&mut self.b).poll_flush(cx);
+        join(a, b)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::SinkError>> {
+        let a = Pin::new(&mut self.a).poll_close(cx);
+        let b = Pin::new(&mut self.b).poll_close(cx);
+        join(a, b)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::task::noop_waker;
+
+    #[derive(Debug, Default)]
+    struct MockSink {
+        data: Vec<u8>,
+        busy: bool,
+    }
+
+    impl Sink<u8> for MockSink {
+        type SinkError = ();
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            if self.busy {
+                Poll::Pending
+            } else {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: u8) -> Result<(), ()> {
+            assert!(!self.busy, "start_send on a busy sink");
+            self.data.push(item);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            self.poll_ready(cx)
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            self.poll_ready(cx)
+        }
+    }
+
+    fn send_all(tee: &mut Tee<MockSink, MockSink>, bytes: &[u8]) -> usize {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut sent = 0;
+        for &b in bytes {
+            match Pin::new(&mut *tee).poll_ready(&mut cx) {
+                Poll::Ready(Ok(())) => Pin::new(&mut *tee).start_send(b).unwrap(),
+                _ => break,
+            }
+            sent += 1;
+        }
+        sent
+    }
+
+    #[test]
+    fn test_bytes_reach_both_sinks() {
+        let mut tee = Tee::new(MockSink::default(), MockSink::default());
+
+        assert_eq!(5, send_all(&mut tee, b"hello"));
+
+        let (a, b) = tee.into_inner();
+        assert_eq!(b"hello", &a.data[..]);
+        assert_eq!(b"hello", &b.data[..]);
+    }
+
+    #[test]
+    fn test_backpressure_stalls_both() {
+        let mut tee = Tee::new(MockSink::default(), MockSink::default());
+        assert_eq!(2, send_all(&mut tee, b"ab"));
+
+        tee.b.busy = true;
+        assert_eq!(0, send_all(&mut tee, b"cd"));
+        assert_eq!(b"ab", &tee.get_ref().0.data[..]);
+        assert_eq!(b"ab", &tee.get_ref().1.data[..]);
+
+        tee.b.busy = false;
+        assert_eq!(2, send_all(&mut tee, b"cd"));
+
+        let (a, b) = tee.into_inner();
+        assert_eq!(b"abcd", &a.data[..]);
+        assert_eq!(b"abcd", &b.data[..]);
+    }
+}
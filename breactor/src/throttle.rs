@@ -0,0 +1,101 @@
+//! Rate limiting for streams.
+use core::pin::Pin;
+use futures::task::Context;
+use futures::{Future, Poll, Stream};
+
+use crate::time::{Delay, Duration};
+
+/// Stream for the `throttle` function.
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct Throttle<S> {
+    stream: S,
+    interval: Duration,
+    delay: Option<Delay>,
+}
+
+impl<S: Unpin> Unpin for Throttle<S> {}
+
+/// Limits the stream to at most one item per `min_interval`.
+///
+/// Items are not dropped: the inner stream is not polled until the
+/// interval since the previous item has passed, so a fast producer is
+/// slowed down.
+pub fn throttle<S: Stream>(min_interval: Duration, stream: S) -> Throttle<S> {
+    Throttle {
+        stream,
+        interval: min_interval,
+        delay: None,
+    }
+}
+
+impl<S> Throttle<S> {
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S> Stream for Throttle<S>
+where
+    S: Stream + Unpin,
+{
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        if let Some(ref mut delay) = this.delay {
+            ready!(Pin::new(delay).poll(cx));
+        }
+
+        let item = ready!(Pin::new(&mut this.stream).poll_next(cx));
+        if item.is_some() {
+            this.delay = Some(Delay::new(this.interval));
+        }
+
+        Poll::Ready(item)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::time::{now, tick};
+    use crate::ReactorGuard;
+    use futures::task::noop_waker;
+
+    #[test]
+    fn test_items_are_spaced_by_interval() {
+        let _guard = ReactorGuard::acquire();
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let mut stream = throttle(Duration::from_millis(3), futures::stream::iter(1..=3));
+
+        let mut received = Vec::new();
+        while received.len() < 3 {
+            match Pin::new(&mut stream).poll_next(&mut cx) {
+                Poll::Ready(Some(x)) => received.push((x, now())),
+                Poll::Ready(None) => panic!("stream has finished early"),
+                Poll::Pending => tick(),
+            }
+        }
+
+        assert_eq!(
+            vec![1, 2, 3],
+            received.iter().map(|x| x.0).collect::<Vec<_>>()
+        );
+        for pair in received.windows(2) {
+            // 3 ms and one more tick
+            assert_eq!(4, pair[1].1.wrapping_sub(pair[0].1));
+        }
+
+        assert_eq!(Poll::Pending, Pin::new(&mut stream).poll_next(&mut cx));
+        tick();
+        tick();
+        tick();
+        tick();
+        assert_eq!(Poll::Ready(None), Pin::new(&mut stream).poll_next(&mut cx));
+    }
+}
@@ -0,0 +1,371 @@
+//! Tick-based timekeeping.
+//!
+//! Time is measured in ticks of a periodic timer. The application is
+//! responsible for calling `tick()` from the timer interrupt and
+//! setting the timer frequency with `set_tick_frequency()`.
+//!
+//! Timing APIs take a `Duration`, which is converted to ticks at the
+//! configured frequency.
+use core::cmp;
+use core::ops::Add;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU32, Ordering};
+use futures::task::Context;
+use futures::{Future, Poll, Stream};
+
+use crate::REACTOR;
+
+static TICKS: AtomicU32 = AtomicU32::new(0);
+
+/// Tick frequency in Hz.
+static TICK_FREQUENCY: AtomicU32 = AtomicU32::new(DEFAULT_TICK_FREQUENCY);
+
+/// Tick frequency used until `set_tick_frequency()` is called. A tick
+/// is exactly one millisecond.
+pub const DEFAULT_TICK_FREQUENCY: u32 = 1000;
+
+/// Sets the frequency `tick()` is called at, in Hz.
+///
+/// Should be called once, when the timer is initialized, before any
+/// `Duration` is converted to ticks.
+pub fn set_tick_frequency(hz: u32) {
+    assert_ne!(hz, 0);
+    TICK_FREQUENCY.store(hz, Ordering::SeqCst);
+}
+
+pub fn tick_frequency() -> u32 {
+    TICK_FREQUENCY.load(Ordering::SeqCst)
+}
+
+/// A span of time with millisecond resolution.
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
+pub struct Duration {
+    millis: u32,
+}
+
+impl Duration {
+    pub const fn from_millis(millis: u32) -> Duration {
+        Duration { millis }
+    }
+
+    /// Saturates at `u32::MAX` milliseconds (about 49 days).
+    pub const fn from_secs(secs: u32) -> Duration {
+        // `saturating_mul` and `if` aren't allowed in const fns, so
+        // the product is replaced with all ones on overflow.
+        let overflow = (secs > ::core::u32::MAX / 1000) as u32;
+        Duration {
+            millis: secs.wrapping_mul(1000) | 0_u32.wrapping_sub(overflow),
+        }
+    }
+
+    /// Rounds up to whole milliseconds.
+    pub const fn from_micros(micros: u32) -> Duration {
+        Duration {
+            millis: micros / 1000 + (micros % 1000 != 0) as u32,
+        }
+    }
+
+    /// Returns the time `ticks` ticks take at the current tick
+    /// frequency, rounded down to whole milliseconds.
+    pub fn from_ticks(ticks: u32) -> Duration {
+        Duration::from_ticks_at(ticks, tick_frequency())
+    }
+
+    #[allow(clippy::cast_possible_truncation)] // saturated
+    pub fn from_ticks_at(ticks: u32, hz: u32) -> Duration {
+        let millis = u64::from(ticks) * 1000 / u64::from(hz);
+        Duration::from_millis(::core::cmp::min(millis, u64::from(::core::u32::MAX)) as u32)
+    }
+
+    pub const fn as_millis(self) -> u32 {
+        self.millis
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.millis == 0
+    }
+
+    /// Converts to ticks at the current tick frequency, rounding up.
+    pub fn to_ticks(self) -> u32 {
+        self.to_ticks_at(tick_frequency())
+    }
+
+    /// Converts to ticks at `hz`, rounding up, so the result is never
+    /// shorter than the duration.
+    #[allow(clippy::cast_possible_truncation)] // saturated
+    pub fn to_ticks_at(self, hz: u32) -> u32 {
+        let ticks = (u64::from(self.millis) * u64::from(hz) + 999) / 1000;
+        ::core::cmp::min(ticks, u64::from(::core::u32::MAX)) as u32
+    }
+
+    pub fn saturating_mul(self, rhs: u32) -> Duration {
+        Duration::from_millis(self.millis.saturating_mul(rhs))
+    }
+}
+
+impl Add for Duration {
+    type Output = Duration;
+
+    fn add(self, rhs: Duration) -> Duration {
+        Duration::from_millis(self.millis.saturating_add(rhs.millis))
+    }
+}
+
+/// Tasks that wait for the next tick.
+static WAITING_TASK_MASK: AtomicU32 = AtomicU32::new(0);
+
+/// Returns the number of ticks elapsed since startup.
+///
+/// The counter wraps around on overflow.
+pub fn now() -> u32 {
+    TICKS.load(Ordering::SeqCst)
+}
+
+/// Advances time by one tick and wakes all tasks waiting on a
+/// `Delay`.
+///
+/// Should be called from a periodic timer interrupt.
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::SeqCst);
+    REACTOR.set_ready_task_mask(WAITING_TASK_MASK.swap(0, Ordering::SeqCst));
+}
+
+/// A future that resolves once the given duration has passed.
+///
+/// The duration is rounded up to whole ticks. As the current tick
+/// might be about to end, one more tick is waited for, so the delay
+/// is never shorter than requested.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Delay {
+    deadline: u32,
+}
+
+fn deadline(duration: Duration) -> u32 {
+    let ticks = if duration.is_zero() {
+        0
+    } else {
+        duration.to_ticks().saturating_add(1)
+    };
+    now().wrapping_add(ticks)
+}
+
+impl Delay {
+    pub fn new(duration: Duration) -> Delay {
+        Delay {
+            deadline: deadline(duration),
+        }
+    }
+
+    /// Creates a delay that expires once `now()` reaches `deadline`.
+    pub fn until(deadline: u32) -> Delay {
+        Delay { deadline }
+    }
+
+    /// Restarts the delay, so it expires `duration` from now.
+    pub fn reset(&mut self, duration: Duration) {
+        self.deadline = deadline(duration);
+    }
+
+    #[allow(clippy::cast_possible_wrap)] // wrapping is intended
+    pub fn is_elapsed(&self) -> bool {
+        now().wrapping_sub(self.deadline) as i32 >= 0
+    }
+}
+
+impl Future for Delay {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.is_elapsed() {
+            return Poll::Ready(());
+        }
+
+        WAITING_TASK_MASK.fetch_or(REACTOR.get_current_task_mask(), Ordering::SeqCst);
+
+        // The tick might have happened before the task was registered.
+        if self.is_elapsed() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// A stream that yields every `period`. See `interval`.
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct Interval {
+    delay: Delay,
+    period: u32,
+}
+
+/// Returns a stream that yields once per `period`, starting one period
+/// from now.
+///
+/// Deadlines are computed from the previous deadline rather than from
+/// the time the item is taken, so the interval doesn't drift. If the
+/// task falls behind, the missed items are yielded right away. The
+/// period is at least one tick.
+pub fn interval(period: Duration) -> Interval {
+    let period = cmp::max(period.to_ticks(), 1);
+    Interval {
+        delay: Delay::until(now().wrapping_add(period)),
+        period,
+    }
+}
+
+impl Stream for Interval {
+    type Item = ();
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<()>> {
+        ready!(Pin::new(&mut self.delay).poll(cx));
+
+        let next = self.delay.deadline.wrapping_add(self.period);
+        self.delay = Delay::until(next);
+        Poll::Ready(Some(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::task::noop_waker;
+
+    #[test]
+    fn test_delay() {
+        let _guard = crate::ReactorGuard::acquire();
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        // A tick is a millisecond, and one more tick is waited for.
+        let mut delay = Delay::new(Duration::from_millis(2));
+        assert_eq!(Poll::Pending, Pin::new(&mut delay).poll(&mut cx));
+        tick();
+        assert_eq!(Poll::Pending, Pin::new(&mut delay).poll(&mut cx));
+        tick();
+        assert_eq!(Poll::Pending, Pin::new(&mut delay).poll(&mut cx));
+        tick();
+        assert_eq!(Poll::Ready(()), Pin::new(&mut delay).poll(&mut cx));
+    }
+
+    #[test]
+    fn test_zero_delay() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let mut delay = Delay::new(Duration::default());
+        assert_eq!(Poll::Ready(()), Pin::new(&mut delay).poll(&mut cx));
+    }
+
+    #[test]
+    fn test_duration_constructors() {
+        assert_eq!(2000, Duration::from_secs(2).as_millis());
+        assert_eq!(4_294_967_000, Duration::from_secs(4_294_967).as_millis());
+        assert_eq!(::core::u32::MAX, Duration::from_secs(4_294_968).as_millis());
+        assert_eq!(
+            ::core::u32::MAX,
+            Duration::from_secs(::core::u32::MAX).as_millis()
+        );
+        assert_eq!(0, Duration::from_micros(0).as_millis());
+        assert_eq!(1, Duration::from_micros(1).as_millis());
+        assert_eq!(2, Duration::from_micros(1001).as_millis());
+        assert_eq!(
+            Duration::from_millis(1500),
+            Duration::from_secs(1) + Duration::from_millis(500)
+        );
+        assert_eq!(
+            Duration::from_millis(::core::u32::MAX),
+            Duration::from_millis(10).saturating_mul(::core::u32::MAX)
+        );
+    }
+
+    #[test]
+    fn test_duration_to_ticks() {
+        // 1 kHz: a tick per millisecond
+        assert_eq!(0, Duration::from_millis(0).to_ticks_at(1000));
+        assert_eq!(50, Duration::from_millis(50).to_ticks_at(1000));
+        assert_eq!(1, Duration::from_micros(20).to_ticks_at(1000));
+
+        // 4 Hz: 250 ms per tick, rounded up
+        assert_eq!(1, Duration::from_millis(1).to_ticks_at(4));
+        assert_eq!(1, Duration::from_millis(250).to_ticks_at(4));
+        assert_eq!(2, Duration::from_millis(251).to_ticks_at(4));
+        assert_eq!(20, Duration::from_secs(5).to_ticks_at(4));
+
+        // no overflow
+        assert_eq!(
+            ::core::u32::MAX,
+            Duration::from_millis(::core::u32::MAX).to_ticks_at(1_000_000)
+        );
+    }
+
+    #[test]
+    fn test_ticks_to_duration() {
+        assert_eq!(Duration::from_millis(7), Duration::from_ticks_at(7, 1000));
+        assert_eq!(Duration::from_millis(750), Duration::from_ticks_at(3, 4));
+        // rounded down
+        assert_eq!(Duration::from_millis(333), Duration::from_ticks_at(1, 3));
+        assert_eq!(
+            Duration::from_millis(::core::u32::MAX),
+            Duration::from_ticks_at(::core::u32::MAX, 1)
+        );
+
+        for &hz in &[4, 1000] {
+            let duration = Duration::from_secs(3);
+            assert_eq!(
+                duration,
+                Duration::from_ticks_at(duration.to_ticks_at(hz), hz)
+            );
+        }
+    }
+
+    #[test]
+    fn test_delay_wraps_around() {
+        let delay = Delay {
+            deadline: now().wrapping_sub(1),
+        };
+        assert!(delay.is_elapsed());
+
+        let delay = Delay {
+            deadline: now().wrapping_add(::core::u32::MAX / 2),
+        };
+        assert!(!delay.is_elapsed());
+    }
+
+    #[test]
+    fn test_interval_fires_once_per_period() {
+        let _guard = crate::ReactorGuard::acquire();
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let mut interval = interval(Duration::from_ticks(3));
+        let mut fired = Vec::new();
+        for t in 0..10 {
+            while let Poll::Ready(Some(())) = Pin::new(&mut interval).poll_next(&mut cx) {
+                fired.push(t);
+            }
+            tick();
+        }
+        assert_eq!(vec![3, 6, 9], fired);
+    }
+
+    #[test]
+    fn test_interval_catches_up() {
+        let _guard = crate::ReactorGuard::acquire();
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let mut interval = interval(Duration::from_ticks(2));
+        for _ in 0..5 {
+            tick();
+        }
+
+        let mut fired = 0;
+        while let Poll::Ready(Some(())) = Pin::new(&mut interval).poll_next(&mut cx) {
+            fired += 1;
+        }
+        assert_eq!(2, fired);
+    }
+}
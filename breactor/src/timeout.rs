@@ -0,0 +1,113 @@
+//! Time limits for futures.
+use core::pin::Pin;
+use futures::task::Context;
+use futures::{Future, Poll};
+
+use crate::time::{Delay, Duration};
+
+/// The future has not resolved before its deadline.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct TimedOut;
+
+/// Resolves to the output of the inner future, or to `TimedOut` if
+/// the deadline passes first.
+///
+/// Time is measured with `time::now()`, so it only passes while
+/// `time::tick()` is called. A future that resolves on the deadline
+/// tick still wins.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Timeout<F> {
+    future: F,
+    delay: Delay,
+}
+
+impl<F: Unpin> Unpin for Timeout<F> {}
+
+impl<F: Future> Timeout<F> {
+    /// Limits `future` to `duration` from now. See `Delay` for how the
+    /// duration is rounded.
+    pub fn new(future: F, duration: Duration) -> Timeout<F> {
+        Timeout {
+            future,
+            delay: Delay::new(duration),
+        }
+    }
+
+    /// Limits `future` until `time::now()` reaches `deadline`.
+    pub fn until(future: F, deadline: u32) -> Timeout<F> {
+        Timeout {
+            future,
+            delay: Delay::until(deadline),
+        }
+    }
+
+    pub fn into_inner(self) -> F {
+        self.future
+    }
+}
+
+impl<F> Future for Timeout<F>
+where
+    F: Future + Unpin,
+{
+    type Output = Result<F::Output, TimedOut>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+
+        if let Poll::Ready(x) = Pin::new(&mut this.future).poll(cx) {
+            return Poll::Ready(Ok(x));
+        }
+
+        ready!(Pin::new(&mut this.delay).poll(cx));
+        Poll::Ready(Err(TimedOut))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::time::{now, tick};
+    use crate::ReactorGuard;
+    use futures::task::noop_waker;
+
+    fn poll<F: Future + Unpin>(f: &mut F) -> Poll<F::Output> {
+        let waker = noop_waker();
+        Pin::new(f).poll(&mut Context::from_waker(&waker))
+    }
+
+    #[test]
+    fn test_hanging_future_times_out() {
+        let _guard = ReactorGuard::acquire();
+
+        let mut timeout = Timeout::new(futures::future::empty::<()>(), Duration::from_millis(2));
+        for _ in 0..3 {
+            assert_eq!(Poll::Pending, poll(&mut timeout));
+            tick();
+        }
+        assert_eq!(Poll::Ready(Err(TimedOut)), poll(&mut timeout));
+    }
+
+    #[test]
+    fn test_ready_future_wins() {
+        let _guard = ReactorGuard::acquire();
+
+        let mut timeout = Timeout::until(futures::future::ready(7), now());
+        assert_eq!(Poll::Ready(Ok(7)), poll(&mut timeout));
+    }
+
+    #[test]
+    fn test_future_resolves_before_deadline() {
+        let _guard = ReactorGuard::acquire();
+
+        let deadline = now().wrapping_add(5);
+        let mut timeout = Timeout::until(Delay::until(now().wrapping_add(2)), deadline);
+        assert_eq!(Poll::Pending, poll(&mut timeout));
+        tick();
+        assert_eq!(Poll::Pending, poll(&mut timeout));
+        tick();
+        assert_eq!(Poll::Ready(Ok(())), poll(&mut timeout));
+    }
+}
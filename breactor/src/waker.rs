@@ -1,26 +1,131 @@
+//! Task wakers.
+//!
+//! The reactor passes each task a waker that marks the task ready,
+//! the same way `Reactor::set_task_ready` does. Thus, futures that
+//! clone and store wakers (e.g., `futures` combinators and channels)
+//! work with the reactor as is.
+//!
+//! `Promise` and `Mutex` wake the waker they are polled with, so they
+//! can be awaited through such combinators. Other drivers still keep
+//! the task mask (`Reactor::get_current_task_mask`) and must be polled
+//! by the task itself.
+use core::marker::PhantomData;
+use core::ops::Deref;
 use core::task::{RawWaker, RawWakerVTable, Waker};
 
-use super::REACTOR;
+use crate::mask::Mask;
+use crate::{Reactor, TaskId};
 
-pub const WAKER_VTABLE: RawWakerVTable =
-    RawWakerVTable::new(waker_clone, waker_wake, waker_wake_by_ref, waker_drop);
+/// Ready mask of a reactor.
+///
+/// It is aligned, so the low bits of its address are zero and a waker
+/// can keep the task id there. Thus, a waker is a single pointer that
+/// needs no allocation, whatever the width of the mask is.
+#[repr(align(64))]
+pub(crate) struct ReadyMask<M: Mask>(pub M::Atomic);
 
-unsafe fn waker_clone(data: *const ()) -> RawWaker {
-    RawWaker::new(data, &WAKER_VTABLE)
+/// Low bits of a waker that hold the task id.
+const ID_BITS: usize = 64 - 1;
+
+impl<M: Mask> ReadyMask<M> {
+    pub fn set_ready(&self, mask: M) {
+        if mask != M::EMPTY {
+            M::insert(self, mask);
+            unsafe { stm32f4::__set_event() };
+        }
+    }
+}
+
+impl<M: Mask> Deref for ReadyMask<M> {
+    type Target = M::Atomic;
+
+    fn deref(&self) -> &M::Atomic {
+        &self.0
+    }
 }
 
-unsafe fn waker_wake(data: *const ()) {
-    let task_mask = data as u32;
-    REACTOR.set_ready_task_mask(task_mask)
+struct TaskWaker<M>(PhantomData<M>);
+
+impl<M: Mask> TaskWaker<M> {
+    const VTABLE: RawWakerVTable = RawWakerVTable::new(
+        waker_clone::<M>,
+        waker_wake::<M>,
+        waker_wake::<M>,
+        waker_drop,
+    );
 }
 
-unsafe fn waker_wake_by_ref(data: *const ()) {
-    let task_mask = data as u32;
-    REACTOR.set_ready_task_mask(task_mask)
+unsafe fn waker_clone<M: Mask>(data: *const ()) -> RawWaker {
+    RawWaker::new(data, &TaskWaker::<M>::VTABLE)
+}
+
+unsafe fn waker_wake<M: Mask>(data: *const ()) {
+    let data = data as usize;
+    let ready_mask = &*((data & !ID_BITS) as *const ReadyMask<M>);
+    ready_mask.set_ready(M::bit((data & ID_BITS) as u32));
 }
 
 unsafe fn waker_drop(_data: *const ()) {}
 
-pub fn new_task_waker(task_mask: u32) -> Waker {
-    unsafe { Waker::from_raw(RawWaker::new(task_mask as *const (), &WAKER_VTABLE)) }
+/// Returns a waker that marks the task ready in the reactor.
+///
+/// Waking a task the reactor has no slot for does nothing.
+pub fn task_waker<A, M: Mask>(reactor: &'static Reactor<'_, A, M>, id: TaskId) -> Waker {
+    new_task_waker(&reactor.ready_mask, id.0)
+}
+
+/// Returns a waker that marks the task ready in `ready_mask`.
+///
+/// The waker points to the mask, so it must not outlive the reactor.
+pub(crate) fn new_task_waker<M: Mask>(ready_mask: &ReadyMask<M>, task_id: u32) -> Waker {
+    debug_assert!(task_id as usize <= ID_BITS);
+    let data = ready_mask as *const ReadyMask<M> as usize | task_id as usize;
+    unsafe { Waker::from_raw(RawWaker::new(data as *const (), &TaskWaker::<M>::VTABLE)) }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use core::cell::UnsafeCell;
+    use core::sync::atomic::Ordering;
+
+    use crate::{Reactor64, ReactorGuard, TaskSlot, REACTOR};
+
+    #[test]
+    fn test_wake() {
+        let _guard = ReactorGuard::acquire();
+
+        let waker = task_waker(&REACTOR, TaskId::new(5).unwrap());
+        assert!(!REACTOR.is_ready());
+
+        waker.wake_by_ref();
+        assert_eq!(1 << 5, REACTOR.ready_mask.swap(0, Ordering::SeqCst));
+
+        // Clones wake the same task.
+        let clone = waker.clone();
+        drop(waker);
+        clone.wake();
+        assert_eq!(1 << 5, REACTOR.ready_mask.swap(0, Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_wake_task_that_does_not_fit() {
+        let _guard = ReactorGuard::acquire();
+
+        task_waker(&REACTOR, TaskId::new(40).unwrap()).wake();
+        assert!(!REACTOR.is_ready());
+    }
+
+    #[test]
+    fn test_wake_reactor64() {
+        let reactor: &'static Reactor64<[TaskSlot; 1]> =
+            Box::leak(Box::new(Reactor64::from_array([UnsafeCell::new(None)])));
+        unsafe { reactor.run() };
+
+        for &id in &[0, 32, 63] {
+            task_waker(reactor, TaskId::new(id).unwrap()).wake();
+        }
+        assert_eq!(0x8000_0001_0000_0001, u64::load(&reactor.ready_mask));
+    }
 }
@@ -0,0 +1,89 @@
+//! Cooperative rescheduling.
+use core::pin::Pin;
+
+use futures::task::Context;
+use futures::{Future, Poll};
+
+use crate::REACTOR;
+
+/// Returns a future that lets other tasks run before the current one
+/// continues.
+///
+/// On the first poll, the future marks the current task ready and
+/// returns pending, so the reactor gets a chance to select another
+/// task. It resolves on the next poll.
+///
+/// The reactor always selects the ready task with the highest
+/// priority, so only higher-priority tasks run meanwhile. Tasks with
+/// lower priority still wait until the current task blocks.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+
+        self.yielded = true;
+        REACTOR.set_ready_task_mask(REACTOR.get_current_task_mask());
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::cell::RefCell;
+
+    use futures::{FutureExt, StreamExt};
+
+    use crate::{ReactorGuard, TaskId};
+
+    const LOW: u32 = 1;
+    const HIGH: u32 = 2;
+
+    #[test]
+    fn test_tasks_interleave() {
+        let _guard = ReactorGuard::acquire();
+
+        let log: &'static RefCell<Vec<&str>> = Box::leak(Box::new(RefCell::new(Vec::new())));
+
+        // Waits for the low-priority task to wake it.
+        let high = futures::future::poll_fn(move |_cx| {
+            log.borrow_mut().push("high");
+            Poll::<()>::Pending
+        });
+        // Wakes the high-priority task on every step, as an interrupt
+        // would.
+        let low = futures::stream::iter(0..3)
+            .then(move |_| {
+                log.borrow_mut().push("low");
+                REACTOR.set_task_ready(TaskId::new(HIGH).unwrap());
+                yield_now()
+            })
+            .for_each(|()| futures::future::ready(()))
+            .map(move |()| log.borrow_mut().push("done"));
+
+        unsafe {
+            assert!(REACTOR.add_task(HIGH, Pin::new_unchecked(Box::leak(Box::new(high)))));
+            assert!(REACTOR.add_task(LOW, Pin::new_unchecked(Box::leak(Box::new(low)))));
+            REACTOR.run();
+        }
+
+        assert_eq!(
+            vec!["high", "low", "high", "low", "high", "low", "high", "done"],
+            *log.borrow()
+        );
+    }
+}
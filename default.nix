@@ -15,6 +15,7 @@ in with pkgs; {
       minicom
       openocd
       expect
+      python3
     ];
   };
 }
@@ -0,0 +1,200 @@
+//! Allocator benchmark.
+//!
+//! Runs a randomized workload of allocations and deallocations and
+//! measures how many cycles each of them takes. The allocator and the
+//! cycle source are passed in, so the bookkeeping doesn't depend on
+//! the hardware.
+
+/// Number of allocations that can be live at the same time.
+const SLOTS: usize = 16;
+
+/// Maximum size of a single allocation.
+const MAX_SIZE: usize = 256;
+
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub struct Stats {
+    pub allocs: u32,
+    pub alloc_cycles: u64,
+    pub frees: u32,
+    pub free_cycles: u64,
+    /// Allocations that returned null.
+    pub failed: u32,
+}
+
+impl Stats {
+    pub fn record_alloc(&mut self, cycles: u32) {
+        self.allocs += 1;
+        self.alloc_cycles += u64::from(cycles);
+    }
+
+    pub fn record_free(&mut self, cycles: u32) {
+        self.frees += 1;
+        self.free_cycles += u64::from(cycles);
+    }
+
+    /// Average cycles per allocation. `None` if nothing was recorded.
+    pub fn avg_alloc(&self) -> Option<u64> {
+        average(self.alloc_cycles, self.allocs)
+    }
+
+    /// Average cycles per deallocation. `None` if nothing was
+    /// recorded.
+    pub fn avg_free(&self) -> Option<u64> {
+        average(self.free_cycles, self.frees)
+    }
+}
+
+fn average(total: u64, count: u32) -> Option<u64> {
+    if count == 0 {
+        None
+    } else {
+        Some(total / u64::from(count))
+    }
+}
+
+/// xorshift32. Good enough to shuffle the workload and doesn't need
+/// the hardware RNG, so runs are reproducible.
+struct XorShift(u32);
+
+impl XorShift {
+    fn next(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+}
+
+/// Calls `f` and returns its result along with the number of cycles
+/// it took.
+fn measure<C, R>(cycles: &mut C, f: impl FnOnce() -> R) -> (R, u32)
+where
+    C: FnMut() -> u32,
+{
+    let start = cycles();
+    let res = f();
+    let end = cycles();
+    (res, end.wrapping_sub(start))
+}
+
+/// Runs `iterations` randomized steps and frees everything that is
+/// still allocated at the end.
+///
+/// Each step picks a random slot: if it holds an allocation, it is
+/// freed; otherwise, a new block of random size (1 to 256 bytes) is
+/// allocated. `cycles` is sampled right before and after every
+/// `alloc`/`free` call. It may wrap around.
+///
+/// `free` receives the size the block was allocated with.
+pub fn run<C, A, F>(mut cycles: C, mut alloc: A, mut free: F, iterations: u32, seed: u32) -> Stats
+where
+    C: FnMut() -> u32,
+    A: FnMut(usize) -> *mut u8,
+    F: FnMut(*mut u8, usize),
+{
+    // xorshift gets stuck at zero.
+    let mut rng = XorShift(if seed == 0 { 1 } else { seed });
+    let mut slots: [(*mut u8, usize); SLOTS] = [(::core::ptr::null_mut(), 0); SLOTS];
+    let mut stats = Stats::default();
+
+    for _ in 0..iterations {
+        let r = rng.next() as usize;
+        let slot = &mut slots[r % SLOTS];
+
+        if slot.0.is_null() {
+            let size = (r >> 8) % MAX_SIZE + 1;
+            let (ptr, took) = measure(&mut cycles, || alloc(size));
+
+            if ptr.is_null() {
+                stats.failed += 1;
+            } else {
+                stats.record_alloc(took);
+                *slot = (ptr, size);
+            }
+        } else {
+            let (ptr, size) = *slot;
+            stats.record_free(measure(&mut cycles, || free(ptr, size)).1);
+            slot.0 = ::core::ptr::null_mut();
+        }
+    }
+
+    for &(ptr, size) in slots.iter().filter(|slot| !slot.0.is_null()) {
+        stats.record_free(measure(&mut cycles, || free(ptr, size)).1);
+    }
+
+    stats
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::cell::Cell;
+
+    #[test]
+    fn test_average() {
+        let mut stats = Stats::default();
+        assert_eq!(None, stats.avg_alloc());
+        assert_eq!(None, stats.avg_free());
+
+        stats.record_alloc(10);
+        stats.record_alloc(21);
+        stats.record_free(u32::max_value());
+        stats.record_free(u32::max_value());
+
+        assert_eq!(Some(15), stats.avg_alloc());
+        assert_eq!(Some(u64::from(u32::max_value())), stats.avg_free());
+    }
+
+    #[test]
+    fn test_mock_cycles() {
+        // Start close to the overflow to check wrapping.
+        let now = Cell::new(u32::max_value() - 1000);
+        let live = Cell::new(0usize);
+        let mut buf = [0u8; 1];
+
+        let stats = run(
+            || now.get(),
+            |size| {
+                assert!(size >= 1 && size <= MAX_SIZE);
+                now.set(now.get().wrapping_add(100));
+                live.set(live.get() + 1);
+                buf.as_mut_ptr()
+            },
+            |_, _| {
+                now.set(now.get().wrapping_add(40));
+                live.set(live.get() - 1);
+            },
+            1000,
+            42,
+        );
+
+        assert_eq!(0, live.get());
+        assert_eq!(0, stats.failed);
+        assert!(stats.allocs > 0);
+        assert_eq!(stats.allocs, stats.frees);
+        assert_eq!(Some(100), stats.avg_alloc());
+        assert_eq!(Some(40), stats.avg_free());
+    }
+
+    #[test]
+    fn test_failed_allocs_are_not_counted() {
+        let now = Cell::new(0);
+        let stats = run(
+            || {
+                now.set(now.get() + 1);
+                now.get()
+            },
+            |_| ::core::ptr::null_mut(),
+            |_, _| panic!("nothing to free"),
+            100,
+            7,
+        );
+
+        assert_eq!(0, stats.allocs);
+        assert_eq!(0, stats.frees);
+        assert_eq!(100, stats.failed);
+        assert_eq!(None, stats.avg_alloc());
+    }
+}
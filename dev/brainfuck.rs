@@ -0,0 +1,183 @@
+//! Brainfuck interpreter.
+//!
+//! Runs on a fixed-size tape and with a step limit, so a faulty
+//! program can't hang the kernel.
+
+/// Number of tape cells.
+pub const TAPE_SIZE: usize = 256;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Error {
+    /// `[` or `]` without a pair.
+    UnmatchedBracket,
+    /// The pointer has moved outside the tape.
+    TapeOverflow,
+    /// The program has not finished within the step limit.
+    StepLimit,
+}
+
+/// Interprets `program`.
+///
+/// `,` reads from `input` (0 when exhausted); `.` passes the current
+/// cell to `output`. Characters other than the eight commands are
+/// ignored. Each executed command counts as a step.
+pub fn interpret<I, O>(
+    program: &[u8],
+    mut input: I,
+    mut output: O,
+    max_steps: usize,
+) -> Result<(), Error>
+where
+    I: Iterator<Item = u8>,
+    O: FnMut(u8),
+{
+    check_brackets(program)?;
+
+    let mut tape = [0_u8; TAPE_SIZE];
+    let mut ptr = 0_usize;
+    let mut pc = 0_usize;
+    let mut steps = 0_usize;
+
+    while pc < program.len() {
+        if steps == max_steps {
+            return Err(Error::StepLimit);
+        }
+        steps += 1;
+
+        match program[pc] {
+            b'>' => {
+                ptr += 1;
+                if ptr == TAPE_SIZE {
+                    return Err(Error::TapeOverflow);
+                }
+            }
+            b'<' => {
+                if ptr == 0 {
+                    return Err(Error::TapeOverflow);
+                }
+                ptr -= 1;
+            }
+            b'+' => tape[ptr] = tape[ptr].wrapping_add(1),
+            b'-' => tape[ptr] = tape[ptr].wrapping_sub(1),
+            b'.' => output(tape[ptr]),
+            b',' => tape[ptr] = input.next().unwrap_or(0),
+            b'[' if tape[ptr] == 0 => pc = matching_close(program, pc),
+            b']' if tape[ptr] != 0 => pc = matching_open(program, pc),
+            _ => {}
+        }
+
+        pc += 1;
+    }
+
+    Ok(())
+}
+
+/// Splits `program!input` into the program and its input. Without
+/// `!`, the input is empty.
+pub fn split_input(args: &[u8]) -> (&[u8], &[u8]) {
+    match args.iter().position(|&c| c == b'!') {
+        Some(pos) => (&args[..pos], &args[pos + 1..]),
+        None => (args, &[]),
+    }
+}
+
+fn check_brackets(program: &[u8]) -> Result<(), Error> {
+    let mut depth = 0_usize;
+    for &c in program {
+        match c {
+            b'[' => depth += 1,
+            b']' => depth = depth.checked_sub(1).ok_or(Error::UnmatchedBracket)?,
+            _ => {}
+        }
+    }
+
+    if depth == 0 {
+        Ok(())
+    } else {
+        Err(Error::UnmatchedBracket)
+    }
+}
+
+/// Returns position of `]` matching `[` at `pc`.
+fn matching_close(program: &[u8], mut pc: usize) -> usize {
+    let mut depth = 0;
+    loop {
+        match program[pc] {
+            b'[' => depth += 1,
+            b']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return pc;
+                }
+            }
+            _ => {}
+        }
+        pc += 1;
+    }
+}
+
+/// Returns position of `[` matching `]` at `pc`.
+fn matching_open(program: &[u8], mut pc: usize) -> usize {
+    let mut depth = 0;
+    loop {
+        match program[pc] {
+            b']' => depth += 1,
+            b'[' => {
+                depth -= 1;
+                if depth == 0 {
+                    return pc;
+                }
+            }
+            _ => {}
+        }
+        pc -= 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(program: &[u8], input: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut output = Vec::new();
+        interpret(program, input.iter().cloned(), |c| output.push(c), 100_000)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn test_hello_world() {
+        let program = b"++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        assert_eq!(Ok(b"Hello World!\n".to_vec()), run(program, b""));
+    }
+
+    #[test]
+    fn test_input() {
+        // Echoes input until 0.
+        assert_eq!(Ok(b"abc".to_vec()), run(b",[.,]", b"abc"));
+        // Exhausted input reads as 0.
+        assert_eq!(Ok(vec![0]), run(b",.", b""));
+    }
+
+    #[test]
+    fn test_split_input() {
+        assert_eq!((&b",[.,]"[..], &b"abc"[..]), split_input(b",[.,]!abc"));
+        assert_eq!((&b",[.,]"[..], &b""[..]), split_input(b",[.,]!"));
+        assert_eq!((&b"+."[..], &b""[..]), split_input(b"+."));
+        // Only the first `!` separates the input.
+        assert_eq!((&b","[..], &b"a!b"[..]), split_input(b",!a!b"));
+    }
+
+    #[test]
+    fn test_command_with_input() {
+        let (program, input) = split_input(b",[.,]!hi");
+        assert_eq!(Ok(b"hi".to_vec()), run(program, input));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(Err(Error::UnmatchedBracket), run(b"[", b""));
+        assert_eq!(Err(Error::UnmatchedBracket), run(b"]+[", b""));
+        assert_eq!(Err(Error::TapeOverflow), run(b"<", b""));
+        assert_eq!(Err(Error::StepLimit), run(b"+[]", b""));
+    }
+}
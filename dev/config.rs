@@ -0,0 +1,427 @@
+//! Persistent system configuration.
+//!
+//! The configuration is stored in a dedicated flash sector as a
+//! fixed-size record guarded by a magic number and a CRC-32, so an
+//! erased or corrupted sector is detected and defaults are used
+//! instead.
+//!
+//! Layout (little-endian):
+//!
+//! | offset | size | field             |
+//! |--------|------|-------------------|
+//! | 0      | 4    | magic (`BKCF`)    |
+//! | 4      | 1    | SSID length       |
+//! | 5      | 32   | SSID              |
+//! | 37     | 1    | password length   |
+//! | 38     | 64   | password          |
+//! | 102    | 4    | ESP8266 baud rate |
+//! | 106    | 4    | CRC-32 of 0..106  |
+//!
+//! On the device, the record is kept at the start of a flash sector,
+//! see `FlashStorage`.
+
+use stm32f4::flash::{self, Flash};
+
+/// Non-volatile storage the configuration is saved to.
+///
+/// Follows flash semantics: `erase` sets the whole region to `0xff`,
+/// and `write` can only clear bits.
+pub trait Storage {
+    fn read(&self, offset: usize, buf: &mut [u8]);
+    fn erase(&mut self) -> Result<(), Error>;
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Error>;
+}
+
+/// A flash sector dedicated to the configuration.
+///
+/// Erasing the sector blocks until it is done, which takes up to
+/// seconds. Code running from flash stalls meanwhile.
+#[allow(missing_debug_implementations)]
+pub struct FlashStorage {
+    flash: &'static Flash,
+    sector: u32,
+    start: *mut u8,
+}
+
+impl FlashStorage {
+    /// # Safety
+    /// `start` must be the start of the flash sector `sector`, and
+    /// the sector must not be used for anything else.
+    pub const unsafe fn new(flash: &'static Flash, sector: u32, start: *mut u8) -> FlashStorage {
+        FlashStorage {
+            flash,
+            sector,
+            start,
+        }
+    }
+}
+
+impl Storage for FlashStorage {
+    fn read(&self, offset: usize, buf: &mut [u8]) {
+        for (i, x) in buf.iter_mut().enumerate() {
+            *x = unsafe { ::core::ptr::read_volatile(self.start.add(offset + i)) };
+        }
+    }
+
+    fn erase(&mut self) -> Result<(), Error> {
+        self.flash.unlock();
+        let res = self.flash.erase_sector(self.sector);
+        self.flash.lock();
+        res.map_err(Error::Flash)
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Error> {
+        self.flash.unlock();
+        let res = unsafe { self.flash.program(self.start.add(offset), data) };
+        self.flash.lock();
+        res.map_err(Error::Flash)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Error {
+    /// Storage doesn't contain a configuration record.
+    BadMagic,
+    /// Record is corrupted.
+    BadCrc,
+    /// Record has a valid CRC but contains invalid values.
+    Invalid,
+    /// Flash could not be erased or programmed.
+    Flash(flash::Error),
+}
+
+pub const MAX_SSID_LEN: usize = 32;
+pub const MAX_PASS_LEN: usize = 64;
+
+const MAGIC: u32 = 0x4643_4b42; // "BKCF"
+
+const SSID_OFFSET: usize = 4;
+const PASS_OFFSET: usize = SSID_OFFSET + 1 + MAX_SSID_LEN;
+const BAUD_OFFSET: usize = PASS_OFFSET + 1 + MAX_PASS_LEN;
+const CRC_OFFSET: usize = BAUD_OFFSET + 4;
+
+/// Size of the serialized configuration in bytes.
+pub const SIZE: usize = CRC_OFFSET + 4;
+
+const DEFAULT_SSID: &str = "Rotem Indiana_Guest";
+const DEFAULT_PASS: &str = "snickershock";
+const DEFAULT_BAUD: u32 = 115_200;
+
+#[derive(Copy, Clone)]
+pub struct Config {
+    ssid: [u8; MAX_SSID_LEN],
+    ssid_len: u8,
+    pass: [u8; MAX_PASS_LEN],
+    pass_len: u8,
+    /// ESP8266 USART baud rate.
+    pub baud: u32,
+}
+
+impl Config {
+    /// Returns `None` if SSID or password is too long.
+    #[allow(clippy::cast_possible_truncation)] // lengths are checked
+    pub fn new(ssid: &str, pass: &str, baud: u32) -> Option<Config> {
+        if ssid.len() > MAX_SSID_LEN || pass.len() > MAX_PASS_LEN {
+            return None;
+        }
+
+        let mut config = Config {
+            ssid: [0; MAX_SSID_LEN],
+            ssid_len: ssid.len() as u8,
+            pass: [0; MAX_PASS_LEN],
+            pass_len: pass.len() as u8,
+            baud,
+        };
+        config.ssid[..ssid.len()].copy_from_slice(ssid.as_bytes());
+        config.pass[..pass.len()].copy_from_slice(pass.as_bytes());
+        Some(config)
+    }
+
+    pub fn ssid(&self) -> &str {
+        // Validated on construction and deserialization.
+        unsafe { ::core::str::from_utf8_unchecked(&self.ssid[..self.ssid_len as usize]) }
+    }
+
+    pub fn pass(&self) -> &str {
+        unsafe { ::core::str::from_utf8_unchecked(&self.pass[..self.pass_len as usize]) }
+    }
+
+    /// Sets Wi-Fi credentials. Returns `false` and leaves the
+    /// configuration unchanged if either of them is too long.
+    pub fn set_wifi(&mut self, ssid: &str, pass: &str) -> bool {
+        match Config::new(ssid, pass, self.baud) {
+            Some(config) => {
+                *self = config;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn serialize(&self, buf: &mut [u8; SIZE]) {
+        *buf = [0; SIZE];
+        buf[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        buf[SSID_OFFSET] = self.ssid_len;
+        buf[SSID_OFFSET + 1..PASS_OFFSET].copy_from_slice(&self.ssid);
+        buf[PASS_OFFSET] = self.pass_len;
+        buf[PASS_OFFSET + 1..BAUD_OFFSET].copy_from_slice(&self.pass);
+        buf[BAUD_OFFSET..CRC_OFFSET].copy_from_slice(&self.baud.to_le_bytes());
+        let crc = crc32(&buf[..CRC_OFFSET]);
+        buf[CRC_OFFSET..].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    pub fn deserialize(buf: &[u8; SIZE]) -> Result<Config, Error> {
+        if read_u32(&buf[0..4]) != MAGIC {
+            return Err(Error::BadMagic);
+        }
+        if read_u32(&buf[CRC_OFFSET..]) != crc32(&buf[..CRC_OFFSET]) {
+            return Err(Error::BadCrc);
+        }
+
+        let ssid_len = buf[SSID_OFFSET] as usize;
+        let pass_len = buf[PASS_OFFSET] as usize;
+        if ssid_len > MAX_SSID_LEN || pass_len > MAX_PASS_LEN {
+            return Err(Error::Invalid);
+        }
+
+        let ssid = ::core::str::from_utf8(&buf[SSID_OFFSET + 1..SSID_OFFSET + 1 + ssid_len])
+            .map_err(|_| Error::Invalid)?;
+        let pass = ::core::str::from_utf8(&buf[PASS_OFFSET + 1..PASS_OFFSET + 1 + pass_len])
+            .map_err(|_| Error::Invalid)?;
+
+        Config::new(ssid, pass, read_u32(&buf[BAUD_OFFSET..CRC_OFFSET])).ok_or(Error::Invalid)
+    }
+
+    pub fn load<S: Storage>(storage: &S) -> Result<Config, Error> {
+        let mut buf = [0; SIZE];
+        storage.read(0, &mut buf);
+        Config::deserialize(&buf)
+    }
+
+    /// Loads configuration, falling back to defaults if the storage
+    /// doesn't contain a valid one.
+    pub fn load_or_default<S: Storage>(storage: &S) -> Config {
+        Config::load(storage).unwrap_or_default()
+    }
+
+    /// Replaces the configuration in the storage.
+    pub fn save<S: Storage>(&self, storage: &mut S) -> Result<(), Error> {
+        let mut buf = [0; SIZE];
+        self.serialize(&mut buf);
+        storage.erase()?;
+        storage.write(0, &buf)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new(DEFAULT_SSID, DEFAULT_PASS, DEFAULT_BAUD).unwrap()
+    }
+}
+
+impl PartialEq for Config {
+    fn eq(&self, other: &Config) -> bool {
+        self.ssid() == other.ssid() && self.pass() == other.pass() && self.baud == other.baud
+    }
+}
+
+impl ::core::fmt::Debug for Config {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        // Don't leak the password to logs.
+        f.debug_struct("Config")
+            .field("ssid", &self.ssid())
+            .field("baud", &self.baud)
+            .finish()
+    }
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from(buf[0]) | u32::from(buf[1]) << 8 | u32::from(buf[2]) << 16 | u32::from(buf[3]) << 24
+}
+
+/// CRC-32 (IEEE 802.3), bitwise to avoid a lookup table.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffff_u32;
+    for &b in data {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// In-memory flash sector.
+    struct MemFlash {
+        data: [u8; 128],
+        erases: usize,
+    }
+
+    impl MemFlash {
+        fn new() -> MemFlash {
+            MemFlash {
+                data: [0xff; 128],
+                erases: 0,
+            }
+        }
+    }
+
+    impl Storage for MemFlash {
+        fn read(&self, offset: usize, buf: &mut [u8]) {
+            buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+        }
+
+        fn erase(&mut self) -> Result<(), Error> {
+            self.data = [0xff; 128];
+            self.erases += 1;
+            Ok(())
+        }
+
+        fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Error> {
+            for (cell, &x) in self.data[offset..].iter_mut().zip(data) {
+                // Programming can only clear bits.
+                *cell &= x;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(0xcbf4_3926, crc32(b"123456789"));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let config = Config::new("my network", "secret", 9600).unwrap();
+        let mut flash = MemFlash::new();
+
+        config.save(&mut flash).unwrap();
+
+        let loaded = Config::load(&flash).unwrap();
+        assert_eq!(config, loaded);
+        assert_eq!("my network", loaded.ssid());
+        assert_eq!("secret", loaded.pass());
+        assert_eq!(9600, loaded.baud);
+    }
+
+    #[test]
+    fn test_save_overwrites_previous() {
+        let mut flash = MemFlash::new();
+        Config::new("first", "password1", 9600)
+            .unwrap()
+            .save(&mut flash)
+            .unwrap();
+        Config::new("second", "pw", 115_200)
+            .unwrap()
+            .save(&mut flash)
+            .unwrap();
+
+        assert_eq!(2, flash.erases);
+        assert_eq!(
+            Config::new("second", "pw", 115_200).unwrap(),
+            Config::load(&flash).unwrap()
+        );
+    }
+
+    /// Returns a storage over `sector`, and the mock flash registers.
+    fn flash_storage(sector: &mut [u8]) -> (FlashStorage, &'static Flash) {
+        let flash: &'static Flash = Box::leak(Box::new(unsafe { ::core::mem::zeroed() }));
+        (
+            unsafe { FlashStorage::new(flash, 11, sector.as_mut_ptr()) },
+            flash,
+        )
+    }
+
+    #[test]
+    fn test_flash_storage_round_trip() {
+        let mut sector = [0xff; SIZE];
+        let (mut storage, flash) = flash_storage(&mut sector);
+        let config = Config::new("my network", "secret", 9600).unwrap();
+
+        config.save(&mut storage).unwrap();
+        assert_eq!(config, Config::load(&storage).unwrap());
+
+        // The control register is locked again. CR is at 0x10.
+        let cr = unsafe { *(flash as *const Flash as *const u32).add(4) };
+        assert_ne!(0, cr & 1 << 31);
+    }
+
+    #[test]
+    fn test_flash_storage_error() {
+        let mut sector = [0xff; SIZE];
+        let (mut storage, flash) = flash_storage(&mut sector);
+        // WRPERR in SR, at 0x0C.
+        unsafe { *(flash as *const Flash as *mut u32).add(3) = 1 << 4 };
+
+        assert_eq!(
+            Err(Error::Flash(flash::Error::WriteProtection)),
+            Config::default().save(&mut storage)
+        );
+    }
+
+    #[test]
+    fn test_erased_flash_falls_back_to_default() {
+        let flash = MemFlash::new();
+        assert_eq!(Err(Error::BadMagic), Config::load(&flash));
+        assert_eq!(Config::default(), Config::load_or_default(&flash));
+    }
+
+    #[test]
+    fn test_corruption_is_detected() {
+        let mut flash = MemFlash::new();
+        Config::new("network", "password", 9600)
+            .unwrap()
+            .save(&mut flash)
+            .unwrap();
+
+        for &offset in &[
+            SSID_OFFSET,
+            SSID_OFFSET + 1,
+            PASS_OFFSET + 3,
+            BAUD_OFFSET,
+            CRC_OFFSET,
+        ] {
+            let mut corrupted = MemFlash::new();
+            corrupted.data = flash.data;
+            corrupted.data[offset] ^= 0x10;
+
+            assert_eq!(Err(Error::BadCrc), Config::load(&corrupted));
+            assert_eq!(Config::default(), Config::load_or_default(&corrupted));
+        }
+    }
+
+    #[test]
+    fn test_invalid_record() {
+        let mut buf = [0; SIZE];
+        Config::new("network", "password", 9600)
+            .unwrap()
+            .serialize(&mut buf);
+
+        // Valid CRC, but the length is out of range.
+        buf[SSID_OFFSET] = (MAX_SSID_LEN + 1) as u8;
+        let crc = crc32(&buf[..CRC_OFFSET]);
+        buf[CRC_OFFSET..].copy_from_slice(&crc.to_le_bytes());
+
+        assert_eq!(Err(Error::Invalid), Config::deserialize(&buf));
+    }
+
+    #[test]
+    fn test_too_long_credentials() {
+        let long = "x".repeat(MAX_PASS_LEN + 1);
+        assert!(Config::new(&long[..MAX_SSID_LEN + 1], "pass", 9600).is_none());
+        assert!(Config::new("ssid", &long, 9600).is_none());
+        assert!(Config::new(&long[..MAX_SSID_LEN], &long[..MAX_PASS_LEN], 9600).is_some());
+
+        let mut config = Config::default();
+        assert!(!config.set_wifi("ssid", &long));
+        assert_eq!(Config::default(), config);
+        assert!(config.set_wifi("ssid", "pass"));
+        assert_eq!("ssid", config.ssid());
+    }
+}
@@ -1,7 +1,10 @@
 //! CS43L22 Low Power, Stereo DAC with Headphone and Speaker Amplifiers.
 use crate::i2c;
 
-use futures::{Future, FutureExt, TryFutureExt};
+use core::pin::Pin;
+use core::task::Context;
+
+use futures::{Future, Poll, TryFutureExt};
 
 #[allow(missing_debug_implementations)]
 pub struct Cs43l22 {
@@ -72,6 +75,24 @@ enum Register {
     ChargePumpFrequency = 0x34,
 }
 
+/// Register writes of the power-down sequence, in order.
+///
+/// The datasheet ("Recommended Power-Down Sequence") requires the
+/// outputs to be muted and volume transitions disabled before the
+/// chip is powered down. Skipping any of these steps results in an
+/// audible pop.
+const POWER_DOWN_SEQUENCE: [[u8; 2]; 5] = [
+    // Mute headphone and speaker channels.
+    [Register::PlaybackCtl2 as u8, 0xF0],
+    // Mute PCM inputs.
+    [Register::PCMAVol as u8, 0x80],
+    [Register::PCMBVol as u8, 0x80],
+    // Disable soft ramp and zero cross volume transitions.
+    [Register::AnalogZCAndSRSettings as u8, 0x00],
+    // Set PDN bits.
+    [Register::PowerCtl1 as u8, 0x9F],
+];
+
 impl Cs43l22 {
     /// Create new Cs43l22 instance.
     ///
@@ -90,19 +111,104 @@ impl Cs43l22 {
     }
 
     pub fn get_chip_id(&'static mut self) -> impl Future<Output = Result<u8, Error>> + 'static {
-        let addr = self.i2c_addr;
-
-        self.buffer[0] = 0x01; // ID register
-        let buffer = self.buffer.as_mut_ptr();
-
+        // 0x01 is the ID register
         self.i2c
-            .start_transfer()
-            .then(move |i2c| i2c.master_transmitter_raw(addr, buffer, 1))
-            .and_then(move |(i2c, _buffer)| i2c.master_receiver_raw(addr, buffer, 1))
-            .map_ok(|(mut i2c, buffer)| {
-                i2c.stop();
-                buffer[0]
-            })
+            .read_register(self.i2c_addr, 0x01, &mut self.buffer[..1])
+            .map_ok(|data| data[0])
             .map_err(Error::I2cError)
     }
+
+    /// Powers the chip down without an audible pop.
+    ///
+    /// The master clock must be kept running for at least 100 us
+    /// after the future resolves.
+    pub fn power_down(&self) -> PowerDown {
+        PowerDown {
+            i2c_addr: self.i2c_addr,
+            state: PowerDownState::StartTransfer(self.i2c.start_transfer()),
+        }
+    }
+}
+
+#[allow(missing_debug_implementations)]
+pub struct PowerDown {
+    i2c_addr: u16,
+    state: PowerDownState,
+}
+
+enum PowerDownState {
+    StartTransfer(i2c::StartTransferFuture),
+    /// Writing `POWER_DOWN_SEQUENCE[n]`.
+    Write(usize, i2c::Transmission<'static>),
+    Done,
+}
+
+impl Unpin for PowerDown {}
+
+impl Future for PowerDown {
+    type Output = Result<(), Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        use self::PowerDownState::*;
+
+        let this = &mut *self;
+        let addr = this.i2c_addr;
+
+        loop {
+            this.state = match this.state {
+                StartTransfer(ref mut start_transfer) => {
+                    let i2c = ready!(Pin::new(start_transfer).poll(cx));
+                    Write(0, i2c.master_transmitter(addr, &POWER_DOWN_SEQUENCE[0]))
+                }
+                Write(n, ref mut transmission) => {
+                    let (mut i2c, _buf) = try_ready!(Pin::new(transmission).poll(cx));
+                    match POWER_DOWN_SEQUENCE.get(n + 1) {
+                        // Repeated start.
+                        Some(write) => Write(n + 1, i2c.master_transmitter(addr, write)),
+                        None => {
+                            i2c.stop();
+                            Done
+                        }
+                    }
+                }
+                Done => {
+                    return Poll::Ready(Ok(()));
+                }
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::debug::{mock_bus, poll_in_task, ReactorGuard};
+
+    const TASK: u32 = 31;
+
+    #[test]
+    fn test_power_down_sequence() {
+        let _guard = ReactorGuard::acquire();
+        let bus = mock_bus();
+        let cs43l22 = Cs43l22::new(bus, true);
+
+        let mut power_down = cs43l22.power_down();
+        let mut writes = Vec::new();
+        while poll_in_task(TASK, &mut power_down).is_pending() {
+            assert!(writes.len() < 10, "power down has not finished");
+            writes.push(bus.complete_transmission());
+        }
+
+        assert_eq!(
+            vec![
+                (0b1001_0110, vec![0x0F, 0xF0]),
+                (0b1001_0110, vec![0x1A, 0x80]),
+                (0b1001_0110, vec![0x1B, 0x80]),
+                (0b1001_0110, vec![0x0A, 0x00]),
+                (0b1001_0110, vec![0x02, 0x9F]),
+            ],
+            writes
+        );
+    }
 }
@@ -1,13 +1,86 @@
-use futures::prelude::*;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use futures::task::Context;
+use futures::{Future, Poll, Sink, Stream};
 use std::collections::VecDeque;
 
+use breactor::REACTOR;
+
+use crate::i2c::I2cBus;
 use crate::resettable_stream::ResettableStream;
+use crate::usart::Usart;
+
+static REACTOR_BUSY: AtomicBool = AtomicBool::new(false);
+
+/// Serializes tests that use the global reactor or `breactor::time`.
+pub struct ReactorGuard(());
+
+impl ReactorGuard {
+    pub fn acquire() -> ReactorGuard {
+        while REACTOR_BUSY.compare_and_swap(false, true, Ordering::SeqCst) {
+            std::thread::yield_now();
+        }
+
+        ReactorGuard(())
+    }
+}
+
+impl Drop for ReactorGuard {
+    fn drop(&mut self) {
+        REACTOR_BUSY.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Polls the future once from within the reactor task `task_id`, so
+/// the code under test sees a real task mask.
+///
+/// The caller must hold a `ReactorGuard`.
+pub fn poll_in_task<F: Future + Unpin>(task_id: u32, f: &mut F) -> Poll<F::Output> {
+    let mut result = Poll::Pending;
+    {
+        let mut task = futures::future::poll_fn(|cx| {
+            result = Pin::new(&mut *f).poll(cx);
+            Poll::Ready(())
+        });
+
+        unsafe {
+            // The task finishes within `run()`, so the reactor doesn't
+            // keep the reference.
+            let task: &mut dyn Future<Output = ()> = &mut task;
+            let task: &'static mut dyn Future<Output = ()> = ::core::mem::transmute(task);
+            assert!(REACTOR.add_task(task_id, Pin::new_unchecked(task)));
+            REACTOR.run();
+        }
+    }
+    result
+}
+
+/// Returns a USART whose buffers hold 3 bytes each.
+pub fn mock_usart() -> Usart<[u8; 4], [u8; 4]> {
+    // Zeroed memory stands in for the USART registers.
+    let regs: &'static stm32f4::usart::Usart =
+        Box::leak(Box::new(unsafe { ::core::mem::zeroed() }));
+    Usart::new(regs, [0; 4], [0; 4])
+}
+
+/// Returns an I2C bus that lives for the rest of the test run.
+pub fn mock_bus() -> &'static I2cBus {
+    // Zeroed memory stands in for the I2C registers.
+    let regs: &'static stm32f4::i2c::I2c = Box::leak(Box::new(unsafe { ::core::mem::zeroed() }));
+    Box::leak(Box::new(I2cBus::new(regs)))
+}
 
 /// A `Sink + Stream` implementation backed by `Vec` and `VecDeque`. Should only be used for
 /// testing.
+///
+/// Responses queued with `respond_on_flush` only appear in the stream after the sink is flushed
+/// (one response per flush), which mimics a device that answers after receiving a complete
+/// command.
 pub struct TestChannel<T> {
     sink: Vec<T>,
     stream: VecDeque<T>,
+    responses: VecDeque<Vec<T>>,
+    flushes: Vec<usize>,
 }
 
 impl<T> TestChannel<T> {
@@ -15,6 +88,8 @@ impl<T> TestChannel<T> {
         TestChannel {
             sink: Vec::new(),
             stream: VecDeque::new(),
+            responses: VecDeque::new(),
+            flushes: Vec::new(),
         }
     }
 
@@ -25,31 +100,63 @@ impl<T> TestChannel<T> {
     pub fn stream(&mut self) -> &mut VecDeque<T> {
         &mut self.stream
     }
+
+    /// Queues a response that is moved to the stream on a flush.
+    ///
+    /// Each flush releases a single response.
+    pub fn respond_on_flush<I: IntoIterator<Item = T>>(&mut self, items: I) {
+        self.responses.push_back(items.into_iter().collect());
+    }
+
+    /// Returns the sink length at the time of each flush.
+    pub fn flushes(&self) -> &Vec<usize> {
+        &self.flushes
+    }
 }
 
+impl<T> Unpin for TestChannel<T> {}
+
 impl<T> Sink<T> for TestChannel<T> {
     type SinkError = ();
 
-    fn start_send(&mut self, item: T) -> StartSend<T, Self::SinkError> {
+    fn poll_ready(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::SinkError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: T) -> Result<(), Self::SinkError> {
         self.sink.push(item);
-        Ok(AsyncSink::Ready)
+        Ok(())
     }
 
-    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-        Ok(Async::Ready(()))
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::SinkError>> {
+        let this = &mut *self;
+        this.flushes.push(this.sink.len());
+        if let Some(response) = this.responses.pop_front() {
+            this.stream.extend(response);
+        }
+        Poll::Ready(Ok(()))
     }
 
-    fn close(&mut self) -> Poll<(), Self::SinkError> {
-        self.poll_complete()
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::SinkError>> {
+        self.poll_flush(cx)
     }
 }
 
 impl<T> Stream for TestChannel<T> {
     type Item = T;
-    type Error = ();
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        Ok(self.stream.pop_front().into())
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // Behave like a USART: an empty stream is pending rather than finished.
+        match self.stream.pop_front() {
+            Some(x) => Poll::Ready(Some(x)),
+            None => Poll::Pending,
+        }
     }
 }
 
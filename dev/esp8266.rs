@@ -7,7 +7,12 @@ use core::task::Context;
 
 use futures::{Future, Poll, Sink, Stream, TryFutureExt};
 
+use breactor::circular_buffer::CircularBuffer;
+use breactor::flush::Flush;
+use breactor::start_send_all;
+use breactor::start_send_all_bytes::StartSendAllBytes;
 use breactor::start_send_all_string::StartSendAllString;
+use breactor::time::{Delay, Duration};
 
 #[allow(unused)]
 macro_rules! debug_log {
@@ -21,9 +26,33 @@ macro_rules! debug_log {
     };
 }
 
+/// Inter-byte timeout for responses to short commands.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum number of simultaneous connections in `AT+CIPMUX=1` mode.
+pub const MAX_CONNECTIONS: usize = 5;
+
+const LINK_IDS: [&str; MAX_CONNECTIONS] = ["0", "1", "2", "3", "4"];
+
+/// Size of the per-connection receive buffer.
+const LINK_BUFFER_SIZE: usize = 64;
+
 #[allow(missing_debug_implementations)]
 pub struct Esp8266<Channel: Stream<Item = u8> + Sink<u8>> {
     usart: Channel,
+    demux: IpdDemux,
+}
+
+/// A TCP connection, identified by its link id.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Connection {
+    id: u8,
+}
+
+impl Connection {
+    pub fn id(self) -> u8 {
+        self.id
+    }
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -38,6 +67,10 @@ pub enum Error {
     UsartError,
     /// Internal buffer is too small to contain all ESP8266 output.
     BufferOverflow,
+    /// ESP8266 has stopped responding mid-response.
+    Timeout,
+    /// Link id is not lower than `MAX_CONNECTIONS`.
+    InvalidLinkId,
 }
 
 impl<S, E> From<TakeUntilError<S, E>> for Error {
@@ -46,6 +79,7 @@ impl<S, E> From<TakeUntilError<S, E>> for Error {
             TakeUntilError::Finished(_) => Error::UsartFinished,
             TakeUntilError::StreamError(_, _) => Error::UsartError,
             TakeUntilError::BufferOverflow(_) => Error::BufferOverflow,
+            TakeUntilError::Timeout(_) => Error::Timeout,
         }
     }
 }
@@ -68,7 +102,10 @@ impl<Channel: Stream<Item = u8> + Sink<u8> + Unpin> Esp8266<Channel> {
     /// # }
     /// ```
     pub const fn new(usart: Channel) -> Esp8266<Channel> {
-        Esp8266 { usart }
+        Esp8266 {
+            usart,
+            demux: IpdDemux::new(),
+        }
     }
 
     /// Check if the USART is connected to ESP8266 (actually, anything
@@ -98,17 +135,18 @@ impl<Channel: Stream<Item = u8> + Sink<u8> + Unpin> Esp8266<Channel> {
     /// ```
     pub fn check_at<'a>(&'a mut self) -> impl Future<Output = Result<bool, Error>> + 'a {
         StartSendAllString::new(&mut self.usart, "AT\r\n")
+            .and_then(Flush::new)
             .map_err(|_err| Error::Generic)
             .and_then(|usart| {
                 TakeUntil::new([0; 32], usart, [b"OK\r\n" as &[u8], b"ERROR\r\n" as &[u8]])
-                    .map_err(|_err| Error::Generic)
+                    .with_timeout(RESPONSE_TIMEOUT)
+                    .map_err(From::from)
             })
             .map_ok(|(_buffer, _size, _m, _usart)| {
                 // If any pattern matched, the other side understands
                 // AT commands.
                 true
             })
-            .map_err(|_err| Error::Generic)
     }
 
     /// List available access points.
@@ -150,6 +188,7 @@ impl<Channel: Stream<Item = u8> + Sink<u8> + Unpin> Esp8266<Channel> {
         R: FixedSizeArray<AccessPoint> + 'a,
     {
         StartSendAllString::new(&mut self.usart, "AT+CWLAP\r\n")
+            .and_then(Flush::new)
             .map_err(|_| Error::Generic)
             .and_then(|usart| {
                 TakeUntil::new([0; 32], usart, [b"\r\r\n" as &[u8]]).map_err(From::from)
@@ -176,6 +215,7 @@ impl<Channel: Stream<Item = u8> + Sink<u8> + Unpin> Esp8266<Channel> {
             .and_then(|usart| StartSendAllString::new(usart, "\",\""))
             .and_then(move |usart| StartSendAllString::new(usart, pass))
             .and_then(|usart| StartSendAllString::new(usart, "\"\r\n"))
+            .and_then(Flush::new)
             .map_err(|_err| Error::Generic)
             .and_then(|usart| {
                 TakeUntil::new([0; 128], usart, [b"OK\r\n" as &[u8], b"ERROR\r\n" as &[u8]])
@@ -188,6 +228,348 @@ impl<Channel: Stream<Item = u8> + Sink<u8> + Unpin> Esp8266<Channel> {
             })
             .map_err(|_err| Error::Generic)
     }
+
+    /// Enables multiple connections mode (`AT+CIPMUX=1`). This must
+    /// be done before `connect_tcp`.
+    pub fn enable_multiple_connections<'a>(
+        &'a mut self,
+    ) -> impl Future<Output = Result<bool, Error>> + 'a {
+        StartSendAllString::new(&mut self.usart, "AT+CIPMUX=1\r\n")
+            .and_then(Flush::new)
+            .map_err(|_err| Error::Generic)
+            .and_then(|usart| {
+                TakeUntil::new([0; 32], usart, [b"OK\r\n" as &[u8], b"ERROR\r\n" as &[u8]])
+                    .with_timeout(RESPONSE_TIMEOUT)
+                    .map_err(From::from)
+            })
+            .map_ok(|(_buffer, _size, m, _usart)| match m {
+                b"OK\r\n" => true,
+                b"ERROR\r\n" => false,
+                _ => unreachable!(),
+            })
+    }
+
+    /// Opens a TCP connection to `host:port` using the given link id.
+    pub fn connect_tcp<'a>(
+        &'a mut self,
+        id: u8,
+        host: &'a str,
+        port: u16,
+    ) -> impl Future<Output = Result<Connection, Error>> + 'a {
+        let link_id = LINK_IDS.get(usize::from(id)).cloned();
+        futures::future::ready(link_id.ok_or(Error::InvalidLinkId))
+            .and_then(move |link_id| {
+                StartSendAllString::new(&mut self.usart, "AT+CIPSTART=")
+                    .and_then(move |usart| StartSendAllString::new(usart, link_id))
+                    .and_then(|usart| StartSendAllString::new(usart, ",\"TCP\",\""))
+                    .and_then(move |usart| StartSendAllString::new(usart, host))
+                    .and_then(|usart| StartSendAllString::new(usart, "\","))
+                    .and_then(move |usart| send_decimal(usart, usize::from(port)))
+                    .and_then(|usart| StartSendAllString::new(usart, "\r\n"))
+                    .and_then(Flush::new)
+                    .map_err(|_err| Error::Generic)
+            })
+            .and_then(|usart| {
+                TakeUntil::new([0; 128], usart, [b"OK\r\n" as &[u8], b"ERROR\r\n" as &[u8]])
+                    .map_err(From::from)
+            })
+            .and_then(move |(_buffer, _size, m, _usart)| {
+                futures::future::ready(match m {
+                    b"OK\r\n" => Ok(Connection { id }),
+                    _ => Err(Error::Generic),
+                })
+            })
+    }
+
+    /// Sends `data` over the connection (`AT+CIPSEND`).
+    ///
+    /// ESP8266 accepts at most 2048 bytes at a time.
+    pub fn send<'a>(
+        &'a mut self,
+        conn: Connection,
+        data: &'a [u8],
+    ) -> impl Future<Output = Result<(), Error>> + 'a {
+        let link_id = LINK_IDS[usize::from(conn.id)];
+        futures::future::lazy(move |_| Ok(&mut self.usart))
+            .and_then(|usart| StartSendAllString::new(usart, "AT+CIPSEND="))
+            .and_then(move |usart| StartSendAllString::new(usart, link_id))
+            .and_then(|usart| StartSendAllString::new(usart, ","))
+            .and_then(move |usart| send_decimal(usart, data.len()))
+            .and_then(|usart| StartSendAllString::new(usart, "\r\n"))
+            .and_then(Flush::new)
+            .map_err(|_err| Error::Generic)
+            .and_then(|usart| {
+                TakeUntil::new([0; 64], usart, [b">" as &[u8], b"ERROR\r\n" as &[u8]])
+                    .with_timeout(RESPONSE_TIMEOUT)
+                    .map_err(From::from)
+            })
+            .and_then(|(_buffer, _size, m, usart)| {
+                futures::future::ready(match m {
+                    b">" => Ok(usart),
+                    _ => Err(Error::Generic),
+                })
+            })
+            .and_then(move |usart| {
+                StartSendAllBytes::new(usart, data)
+                    .and_then(Flush::new)
+                    .map_err(|_err| Error::Generic)
+            })
+            .and_then(|usart| {
+                TakeUntil::new(
+                    [0; 64],
+                    usart,
+                    [b"SEND OK\r\n" as &[u8], b"SEND FAIL\r\n" as &[u8]],
+                )
+                .map_err(From::from)
+            })
+            .and_then(|(_buffer, _size, m, _usart)| {
+                futures::future::ready(match m {
+                    b"SEND OK\r\n" => Ok(()),
+                    _ => Err(Error::Generic),
+                })
+            })
+    }
+
+    /// Returns a stream of bytes received over the connection.
+    ///
+    /// Data that arrives for other connections is buffered until
+    /// they are read. All other ESP8266 output is discarded, so no
+    /// commands should be issued while the stream is polled.
+    pub fn incoming<'a>(&'a mut self, conn: Connection) -> impl Stream<Item = u8> + 'a {
+        futures::stream::poll_fn(move |cx| loop {
+            if let Some(c) = self.demux.pop(conn.id) {
+                return Poll::Ready(Some(c));
+            }
+
+            match ready!(Pin::new(&mut self.usart).poll_next(cx)) {
+                Some(c) => self.demux.push(c),
+                None => return Poll::Ready(None),
+            }
+        })
+    }
+}
+
+/// Exponential backoff delays.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub const fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+
+    /// Returns the next delay. Each call doubles the delay, up to
+    /// `max`.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = ::core::cmp::min(self.current.saturating_mul(2), self.max);
+        delay
+    }
+
+    /// Restarts the sequence from the initial delay.
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
+
+/// Default backoff for reconnecting to an access point.
+pub const RECONNECT_BACKOFF: Backoff =
+    Backoff::new(Duration::from_secs(1), Duration::from_secs(64));
+
+/// Calls `join` until it reports a successful connection, waiting
+/// with `backoff` between attempts.
+///
+/// The backoff is reset on success, so it can be reused on the next
+/// connection drop.
+pub fn join_with_backoff<'a, F, Fut>(
+    backoff: &'a mut Backoff,
+    join: F,
+) -> JoinWithBackoff<'a, F, Fut>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<bool, Error>> + Unpin,
+{
+    JoinWithBackoff {
+        backoff,
+        join,
+        state: JoinState::Idle,
+    }
+}
+
+enum JoinState<Fut> {
+    Idle,
+    Joining(Fut),
+    Waiting(Delay),
+}
+
+#[allow(missing_debug_implementations)]
+#[must_use = "futures do nothing unless polled"]
+pub struct JoinWithBackoff<'a, F, Fut> {
+    backoff: &'a mut Backoff,
+    join: F,
+    state: JoinState<Fut>,
+}
+
+impl<'a, F, Fut: Unpin> Unpin for JoinWithBackoff<'a, F, Fut> {}
+
+impl<'a, F, Fut> Future for JoinWithBackoff<'a, F, Fut>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<bool, Error>> + Unpin,
+{
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        loop {
+            this.state = match this.state {
+                JoinState::Idle => JoinState::Joining((this.join)()),
+                JoinState::Joining(ref mut f) => match ready!(Pin::new(f).poll(cx)) {
+                    Ok(true) => {
+                        this.backoff.reset();
+                        this.state = JoinState::Idle;
+                        return Poll::Ready(());
+                    }
+                    Ok(false) | Err(_) => JoinState::Waiting(Delay::new(this.backoff.next_delay())),
+                },
+                JoinState::Waiting(ref mut delay) => {
+                    ready!(Pin::new(delay).poll(cx));
+                    JoinState::Idle
+                }
+            };
+        }
+    }
+}
+
+/// Sends decimal representation of `value`.
+fn send_decimal<S>(sink: S, value: usize) -> impl Future<Output = Result<S, S::SinkError>>
+where
+    S: Sink<u8> + Unpin,
+{
+    start_send_all::new(sink, futures::stream::iter(Decimal::new(value)))
+        .map_ok(|(sink, _digits)| sink)
+}
+
+/// Iterator over ASCII digits of a number.
+struct Decimal {
+    value: usize,
+    divisor: usize,
+}
+
+impl Decimal {
+    fn new(value: usize) -> Decimal {
+        let mut divisor = 1;
+        while value / divisor >= 10 {
+            divisor *= 10;
+        }
+
+        Decimal { value, divisor }
+    }
+}
+
+impl Iterator for Decimal {
+    type Item = u8;
+
+    #[allow(clippy::cast_possible_truncation)] // digit is always lower than 10
+    fn next(&mut self) -> Option<u8> {
+        if self.divisor == 0 {
+            None
+        } else {
+            let digit = self.value / self.divisor % 10;
+            self.divisor /= 10;
+            Some(b'0' + digit as u8)
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum IpdState {
+    /// Matching `+IPD,`. Holds the number of bytes matched so far.
+    Prefix(usize),
+    /// Expecting link id.
+    Id,
+    /// Expecting comma after link id.
+    Comma(u8),
+    /// Parsing data length.
+    Length(u8, usize),
+    /// Receiving data. Holds the number of bytes left.
+    Data(u8, usize),
+}
+
+/// Demultiplexes `+IPD,<id>,<len>:<data>` frames into per-connection
+/// buffers.
+struct IpdDemux {
+    state: IpdState,
+    links: [CircularBuffer<u8, [u8; LINK_BUFFER_SIZE]>; MAX_CONNECTIONS],
+}
+
+impl IpdDemux {
+    const PREFIX: &'static [u8] = b"+IPD,";
+
+    const fn new() -> IpdDemux {
+        IpdDemux {
+            state: IpdState::Prefix(0),
+            links: [
+                CircularBuffer::new([0; LINK_BUFFER_SIZE]),
+                CircularBuffer::new([0; LINK_BUFFER_SIZE]),
+                CircularBuffer::new([0; LINK_BUFFER_SIZE]),
+                CircularBuffer::new([0; LINK_BUFFER_SIZE]),
+                CircularBuffer::new([0; LINK_BUFFER_SIZE]),
+            ],
+        }
+    }
+
+    /// Processes a single byte received from ESP8266.
+    ///
+    /// Data is dropped if the connection buffer is full.
+    fn push(&mut self, c: u8) {
+        self.state = match self.state {
+            IpdState::Prefix(n) if c == Self::PREFIX[n] => {
+                if n + 1 == Self::PREFIX.len() {
+                    IpdState::Id
+                } else {
+                    IpdState::Prefix(n + 1)
+                }
+            }
+            IpdState::Prefix(_) if c == Self::PREFIX[0] => IpdState::Prefix(1),
+            IpdState::Prefix(_) => IpdState::Prefix(0),
+            IpdState::Id => match c {
+                b'0'..=b'9' if usize::from(c - b'0') < MAX_CONNECTIONS => IpdState::Comma(c - b'0'),
+                _ => IpdState::Prefix(0),
+            },
+            IpdState::Comma(id) if c == b',' => IpdState::Length(id, 0),
+            IpdState::Comma(_) => IpdState::Prefix(0),
+            IpdState::Length(id, len) => match c {
+                b'0'..=b'9' => IpdState::Length(
+                    id,
+                    len.saturating_mul(10).saturating_add(usize::from(c - b'0')),
+                ),
+                b':' if len != 0 => IpdState::Data(id, len),
+                _ => IpdState::Prefix(0),
+            },
+            IpdState::Data(id, left) => {
+                let _ = self.links[usize::from(id)].push(c);
+                if left == 1 {
+                    IpdState::Prefix(0)
+                } else {
+                    IpdState::Data(id, left - 1)
+                }
+            }
+        };
+    }
+
+    /// Pops a received byte for the given connection.
+    fn pop(&mut self, id: u8) -> Option<u8> {
+        self.links[usize::from(id)].pop()
+    }
 }
 
 fn parse_ap_list<A>(b: &[u8]) -> (A, usize)
@@ -327,6 +709,8 @@ struct TakeUntil<'a, A, S, M> {
     stream: Option<S>,
     matches: M,
     cur: usize,
+    /// Inter-byte timeout and the delay tracking it.
+    timeout: Option<(Duration, Delay)>,
     __phantom: PhantomData<&'a u8>,
 }
 
@@ -342,9 +726,17 @@ where
             stream: Some(stream),
             matches,
             cur: 0,
+            timeout: None,
             __phantom: PhantomData,
         }
     }
+
+    /// Fails with `TakeUntilError::Timeout` if no byte arrives
+    /// within `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> TakeUntil<'a, A, S, M> {
+        self.timeout = Some((timeout, Delay::new(timeout)));
+        self
+    }
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -357,6 +749,9 @@ enum TakeUntilError<S, E> {
 
     /// Provided buffer is too small.
     BufferOverflow(S),
+
+    /// No byte has arrived within the timeout.
+    Timeout(S),
 }
 
 impl<'a, A, S, M> Unpin for TakeUntil<'a, A, S, M>
@@ -389,6 +784,10 @@ where
                     self.buffer.as_mut_slice()[cur] = c;
                     self.cur += 1;
 
+                    if let Some((timeout, ref mut delay)) = self.timeout {
+                        delay.reset(timeout);
+                    }
+
                     for m in self.matches.as_slice() {
                         if self.buffer.as_slice()[..self.cur].ends_with(m) {
                             let mut b: A = unsafe { ::core::mem::uninitialized() };
@@ -405,9 +804,213 @@ where
                 }
 
                 Poll::Pending => {
+                    if let Some((_, ref mut delay)) = self.timeout {
+                        if Pin::new(delay).poll(cx).is_ready() {
+                            return Poll::Ready(Err(TakeUntilError::Timeout(
+                                self.stream.take().unwrap(),
+                            )));
+                        }
+                    }
+
                     return Poll::Pending;
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::debug::{ReactorGuard, TestChannel};
+    use breactor::time::{now, tick};
+    use futures::task::noop_waker;
+
+    fn poll_once<F: Future>(f: F) -> F::Output {
+        let mut f = Box::pin(f);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        match f.as_mut().poll(&mut cx) {
+            Poll::Ready(x) => x,
+            Poll::Pending => panic!("future is not ready"),
+        }
+    }
+
+    #[test]
+    fn test_check_at_flushes_before_reading() {
+        let mut esp = Esp8266::new(TestChannel::new());
+        esp.usart
+            .respond_on_flush(b"AT\r\r\n\r\nOK\r\n".iter().cloned());
+
+        assert_eq!(Ok(true), poll_once(esp.check_at()));
+        assert_eq!(b"AT\r\n", &esp.usart.sink()[..]);
+        assert_eq!(&vec![4], esp.usart.flushes());
+    }
+
+    #[test]
+    fn test_join_ap_flushes_whole_command() {
+        let mut esp = Esp8266::new(TestChannel::new());
+        esp.usart.respond_on_flush(b"OK\r\n".iter().cloned());
+
+        assert_eq!(Ok(true), poll_once(esp.join_ap("ssid", "pass")));
+
+        let command = b"AT+CWJAP=\"ssid\",\"pass\"\r\n";
+        assert_eq!(&command[..], &esp.usart.sink()[..]);
+        assert_eq!(&vec![command.len()], esp.usart.flushes());
+    }
+
+    #[test]
+    fn test_take_until_timeout() {
+        let _guard = ReactorGuard::acquire();
+
+        let mut channel = TestChannel::new();
+        channel.stream().extend(b"+CWLAP:".iter().cloned());
+        let mut take = TakeUntil::new([0; 32], channel, [b"OK\r\n" as &[u8]])
+            .with_timeout(Duration::from_millis(2));
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut take).poll(&mut cx).is_pending());
+
+        // 2 ms is 2 ticks at the default frequency, and the delay
+        // waits one more.
+        for _ in 0..2 {
+            tick();
+            assert!(Pin::new(&mut take).poll(&mut cx).is_pending());
+        }
+
+        tick();
+        match Pin::new(&mut take).poll(&mut cx) {
+            Poll::Ready(Err(TakeUntilError::Timeout(_))) => {}
+            _ => panic!("timeout has not fired"),
+        }
+    }
+
+    fn collect_ready<S: Stream<Item = u8> + Unpin>(mut stream: S) -> Vec<u8> {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut result = Vec::new();
+        while let Poll::Ready(Some(c)) = Pin::new(&mut stream).poll_next(&mut cx) {
+            result.push(c);
+        }
+        result
+    }
+
+    #[test]
+    fn test_backoff_sequence() {
+        let mut backoff = Backoff::new(Duration::from_millis(1), Duration::from_millis(8));
+        let delays: Vec<u32> = (0..6).map(|_| backoff.next_delay().as_millis()).collect();
+        assert_eq!(vec![1, 2, 4, 8, 8, 8], delays);
+
+        backoff.reset();
+        assert_eq!(Duration::from_millis(1), backoff.next_delay());
+        assert_eq!(Duration::from_millis(2), backoff.next_delay());
+    }
+
+    #[test]
+    fn test_join_with_backoff() {
+        let _guard = ReactorGuard::acquire();
+
+        // Mock ESP8266 that fails three times and succeeds afterwards.
+        let mut attempts = Vec::new();
+        let mut backoff = Backoff::new(Duration::from_millis(1), Duration::from_millis(2));
+        {
+            let mut join = join_with_backoff(&mut backoff, || {
+                attempts.push(now());
+                let result = match attempts.len() {
+                    1 => Err(Error::Timeout),
+                    2 | 3 => Ok(false),
+                    _ => Ok(true),
+                };
+                futures::future::ready(result)
+            });
+
+            let waker = noop_waker();
+            let mut cx = Context::from_waker(&waker);
+            while Pin::new(&mut join).poll(&mut cx).is_pending() {
+                tick();
+            }
+        }
+
+        let intervals: Vec<u32> = attempts
+            .windows(2)
+            .map(|x| x[1].wrapping_sub(x[0]))
+            .collect();
+        // Delays of 1, 2 and 2 ms, each one tick longer.
+        assert_eq!(vec![2, 3, 3], intervals);
+
+        // Backoff is reset after the successful join.
+        assert_eq!(
+            Backoff::new(Duration::from_millis(1), Duration::from_millis(2)),
+            backoff
+        );
+    }
+
+    #[test]
+    fn test_connect_tcp() {
+        let mut esp = Esp8266::new(TestChannel::new());
+        esp.usart
+            .respond_on_flush(b"1,CONNECT\r\n\r\nOK\r\n".iter().cloned());
+
+        let conn = poll_once(esp.connect_tcp(1, "example.com", 8080)).unwrap();
+        assert_eq!(1, conn.id());
+        assert_eq!(
+            &b"AT+CIPSTART=1,\"TCP\",\"example.com\",8080\r\n"[..],
+            &esp.usart.sink()[..]
+        );
+    }
+
+    #[test]
+    fn test_connect_tcp_invalid_link_id() {
+        let mut esp = Esp8266::new(TestChannel::new());
+
+        assert_eq!(
+            Err(Error::InvalidLinkId),
+            poll_once(esp.connect_tcp(5, "example.com", 80))
+        );
+        assert!(esp.usart.sink().is_empty());
+    }
+
+    #[test]
+    fn test_send() {
+        let mut esp = Esp8266::new(TestChannel::new());
+        esp.usart.respond_on_flush(b"\r\nOK\r\n> ".iter().cloned());
+        esp.usart
+            .respond_on_flush(b"\r\nRecv 5 bytes\r\n\r\nSEND OK\r\n".iter().cloned());
+
+        assert_eq!(Ok(()), poll_once(esp.send(Connection { id: 2 }, b"hello")));
+        assert_eq!(&b"AT+CIPSEND=2,5\r\nhello"[..], &esp.usart.sink()[..]);
+        // The data is only sent after the prompt.
+        assert_eq!(&vec![16, 21], esp.usart.flushes());
+    }
+
+    #[test]
+    fn test_ipd_demux_interleaved() {
+        let mut demux = IpdDemux::new();
+        for &c in b"\r\n+IPD,0,3:abc\r\n+IPD,1,2:xy\r\nOK\r\n+IPD,0,2:de".iter() {
+            demux.push(c);
+        }
+
+        let link0: Vec<u8> = ::std::iter::from_fn(|| demux.pop(0)).collect();
+        let link1: Vec<u8> = ::std::iter::from_fn(|| demux.pop(1)).collect();
+        assert_eq!(b"abcde", &link0[..]);
+        assert_eq!(b"xy", &link1[..]);
+        assert_eq!(None, demux.pop(2));
+    }
+
+    #[test]
+    fn test_incoming_demultiplexes_links() {
+        let mut esp = Esp8266::new(TestChannel::new());
+        esp.usart.stream().extend(
+            b"+IPD,0,4:ping\r\n+IPD,1,5:hello\r\n+IPD,0,4:pong\r\n+IPD,1,1:!"
+                .iter()
+                .cloned(),
+        );
+
+        let conn0 = Connection { id: 0 };
+        let conn1 = Connection { id: 1 };
+        assert_eq!(b"pingpong", &collect_ready(esp.incoming(conn0))[..]);
+        assert_eq!(b"hello!", &collect_ready(esp.incoming(conn1))[..]);
+    }
+}
@@ -0,0 +1,192 @@
+//! Q16.16 fixed-point arithmetic.
+//!
+//! Allows doing sensor conversions without floating point.
+use core::ops::{Add, Div, Mul, Neg, Sub};
+
+/// A signed Q16.16 fixed-point number.
+///
+/// All operations wrap on overflow.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
+pub struct Fixed(i32);
+
+impl Fixed {
+    /// Number of fractional bits.
+    pub const FRAC_BITS: u32 = 16;
+
+    pub const ZERO: Fixed = Fixed(0);
+    pub const ONE: Fixed = Fixed(1 << Fixed::FRAC_BITS);
+
+    /// Creates a number from its raw Q16.16 representation.
+    pub const fn from_bits(bits: i32) -> Fixed {
+        Fixed(bits)
+    }
+
+    /// Returns raw Q16.16 representation.
+    pub const fn to_bits(self) -> i32 {
+        self.0
+    }
+
+    pub const fn from_int(x: i16) -> Fixed {
+        Fixed((x as i32) << Fixed::FRAC_BITS)
+    }
+
+    /// Creates a number from thousandths, e.g., `from_milli(-46_850)`
+    /// is `-46.85`.
+    #[allow(clippy::cast_possible_truncation)] // result fits if x/1000 fits i16
+    pub const fn from_milli(x: i32) -> Fixed {
+        Fixed((((x as i64) << Fixed::FRAC_BITS) / 1000) as i32)
+    }
+
+    /// Returns the value in thousandths, rounded towards negative
+    /// infinity.
+    #[allow(clippy::cast_possible_truncation)] // |result| < 2^15 * 1000
+    pub const fn to_milli(self) -> i32 {
+        ((self.0 as i64 * 1000) >> Fixed::FRAC_BITS) as i32
+    }
+
+    /// Returns the integer part, rounded towards negative infinity.
+    pub const fn floor(self) -> i32 {
+        self.0 >> Fixed::FRAC_BITS
+    }
+
+    /// Returns square root, or `None` if the number is negative.
+    #[allow(clippy::cast_sign_loss)] // checked to be non-negative
+    #[allow(clippy::cast_possible_truncation)] // sqrt(2^47) < 2^31
+    pub fn sqrt(self) -> Option<Fixed> {
+        if self.0 < 0 {
+            return None;
+        }
+
+        // sqrt(x / 2^16) * 2^16 == sqrt(x * 2^16)
+        let n = (self.0 as u64) << Fixed::FRAC_BITS;
+
+        // Bit-by-bit integer square root.
+        let mut rem = n;
+        let mut root = 0_u64;
+        let mut bit = 1_u64 << 62;
+        while bit > n {
+            bit >>= 2;
+        }
+        while bit != 0 {
+            if rem >= root + bit {
+                rem -= root + bit;
+                root = (root >> 1) + bit;
+            } else {
+                root >>= 1;
+            }
+            bit >>= 2;
+        }
+
+        Some(Fixed(root as i32))
+    }
+}
+
+impl Add for Fixed {
+    type Output = Fixed;
+
+    fn add(self, other: Fixed) -> Fixed {
+        Fixed(self.0.wrapping_add(other.0))
+    }
+}
+
+impl Sub for Fixed {
+    type Output = Fixed;
+
+    fn sub(self, other: Fixed) -> Fixed {
+        Fixed(self.0.wrapping_sub(other.0))
+    }
+}
+
+impl Neg for Fixed {
+    type Output = Fixed;
+
+    fn neg(self) -> Fixed {
+        Fixed(self.0.wrapping_neg())
+    }
+}
+
+impl Mul for Fixed {
+    type Output = Fixed;
+
+    #[allow(clippy::cast_possible_truncation)] // wraps on overflow
+    fn mul(self, other: Fixed) -> Fixed {
+        Fixed(((i64::from(self.0) * i64::from(other.0)) >> Fixed::FRAC_BITS) as i32)
+    }
+}
+
+impl Div for Fixed {
+    type Output = Fixed;
+
+    /// # Panics
+    /// Panics if `other` is zero.
+    #[allow(clippy::cast_possible_truncation)] // wraps on overflow
+    fn div(self, other: Fixed) -> Fixed {
+        Fixed(((i64::from(self.0) << Fixed::FRAC_BITS) / i64::from(other.0)) as i32)
+    }
+}
+
+impl ::core::fmt::Display for Fixed {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> Result<(), ::core::fmt::Error> {
+        let m = self.to_milli();
+        let sign = if m < 0 { "-" } else { "" };
+        write!(f, "{}{}.{:03}", sign, (m / 1000).abs(), (m % 1000).abs())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn to_f64(x: Fixed) -> f64 {
+        f64::from(x.to_bits()) / 65536.0
+    }
+
+    fn from_f64(x: f64) -> Fixed {
+        Fixed::from_bits((x * 65536.0).round() as i32)
+    }
+
+    const EPSILON: f64 = 2.0 / 65536.0;
+
+    const VALUES: [f64; 9] = [0.0, 1.0, -1.0, 0.5, 3.25, -7.125, 100.01, -46.85, 175.72];
+
+    #[test]
+    fn test_conversions() {
+        assert_eq!(Fixed::ONE, Fixed::from_int(1));
+        assert_eq!(-46_850, Fixed::from_milli(-46_850).to_milli());
+        assert_eq!(3, Fixed::from_milli(3_999).floor());
+        assert_eq!(-4, Fixed::from_milli(-3_001).floor());
+    }
+
+    #[test]
+    fn test_mul_div() {
+        for &a in VALUES.iter() {
+            for &b in VALUES.iter() {
+                let (x, y) = (from_f64(a), from_f64(b));
+                assert!((to_f64(x * y) - a * b).abs() < EPSILON * (1.0 + a.abs() + b.abs()));
+                if b != 0.0 {
+                    assert!((to_f64(x / y) - a / b).abs() < EPSILON * (1.0 + (a / b).abs()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_sqrt() {
+        for &a in VALUES.iter() {
+            let x = from_f64(a);
+            if a < 0.0 {
+                assert_eq!(None, x.sqrt());
+            } else {
+                assert!((to_f64(x.sqrt().unwrap()) - a.sqrt()).abs() < EPSILON);
+            }
+        }
+        assert_eq!(Some(Fixed::from_int(12)), Fixed::from_int(144).sqrt());
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!("-46.850", format!("{}", Fixed::from_milli(-46_850)));
+        assert_eq!("-0.500", format!("{}", Fixed::from_milli(-500)));
+        assert_eq!("3.250", format!("{}", Fixed::from_milli(3_250)));
+    }
+}
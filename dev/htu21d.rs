@@ -3,6 +3,7 @@
 //! This module provides a driver for
 //! [HTU21D](https://cdn-shop.adafruit.com/datasheets/1899_HTU21D.pdf)
 //! sensor.
+use super::fixed::Fixed;
 use super::i2c;
 
 use core::marker::PhantomData;
@@ -11,6 +12,8 @@ use core::task::Context;
 
 use futures::{Future, Poll};
 
+use breactor::time::{Delay, Duration};
+
 #[allow(missing_debug_implementations)]
 pub struct Htu21d {
     i2c: &'static i2c::I2cBus,
@@ -35,6 +38,26 @@ impl Htu21d {
     pub fn read_humidity_hold_master(&'static self) -> Htu21dCommand<HoldMaster, Humidity> {
         Htu21dCommand::StartTransfer(self.i2c.start_transfer(), READ_HUM_HOLD_MASTER_CMD.as_ptr())
     }
+
+    /// Reads temperature without holding the bus during the
+    /// conversion, so other devices can use it meanwhile.
+    pub fn read_temperature_no_hold_master(
+        &'static self,
+    ) -> Htu21dCommand<NoHoldMaster, Temperature> {
+        Htu21dCommand::StartTransfer(
+            self.i2c.start_transfer(),
+            READ_TEMP_NO_HOLD_MASTER_CMD.as_ptr(),
+        )
+    }
+
+    /// Reads humidity without holding the bus during the conversion,
+    /// so other devices can use it meanwhile.
+    pub fn read_humidity_no_hold_master(&'static self) -> Htu21dCommand<NoHoldMaster, Humidity> {
+        Htu21dCommand::StartTransfer(
+            self.i2c.start_transfer(),
+            READ_HUM_NO_HOLD_MASTER_CMD.as_ptr(),
+        )
+    }
 }
 
 /// A marker for a measurement that holds master.
@@ -61,11 +84,11 @@ impl Temperature {
     }
 
     /// Return temperature in degrees celsius.
-    #[allow(clippy::float_arithmetic)]
-    // f32::from is not constant
-    #[allow(clippy::cast_lossless)]
-    pub const fn celsius(self) -> f32 {
-        -46.85 + 175.72 * ((self.0 & !0x3) as f32) / ((1 << 16) as f32)
+    pub fn celsius(self) -> Fixed {
+        // Raw bits of a fixed-point number are the sample divided by
+        // 2^16.
+        let sample = Fixed::from_bits(i32::from(self.0 & !0x3));
+        Fixed::from_milli(-46_850) + Fixed::from_milli(175_720) * sample
     }
 
     /// Temperature in milliseconds.
@@ -97,11 +120,9 @@ impl Humidity {
         self.0
     }
 
-    #[allow(clippy::float_arithmetic)]
-    // f32::from is not constant
-    #[allow(clippy::cast_lossless)]
-    pub const fn percents(self) -> f32 {
-        -6.0 + 125.0 * ((self.0 & !0x3) as f32) / ((1 << 16) as f32)
+    pub fn percents(self) -> Fixed {
+        let sample = Fixed::from_bits(i32::from(self.0 & !0x3));
+        Fixed::from_int(-6) + Fixed::from_int(125) * sample
     }
 
     // i64::from is not constant
@@ -146,9 +167,7 @@ const HTU21D_ADDRESS: u16 = 0x80;
 
 const READ_TEMP_HOLD_MASTER_CMD: [u8; 1] = [0xE3];
 const READ_HUM_HOLD_MASTER_CMD: [u8; 1] = [0xE5];
-#[allow(dead_code)]
 const READ_TEMP_NO_HOLD_MASTER_CMD: [u8; 1] = [0xF3];
-#[allow(dead_code)]
 const READ_HUM_NO_HOLD_MASTER_CMD: [u8; 1] = [0xF5];
 #[allow(dead_code)]
 const WRITE_USER_CMD: [u8; 1] = [0xE6];
@@ -156,12 +175,22 @@ const WRITE_USER_CMD: [u8; 1] = [0xE6];
 const READ_USER_CMD: [u8; 1] = [0xE7];
 const SOFT_RESET_CMD: [u8; 1] = [0xFE];
 
+/// Time to wait for a no-hold master conversion. The longest one (14-bit
+/// temperature) takes 50 ms.
+const CONVERSION_TIME: Duration = Duration::from_millis(50);
+
+/// Time the sensor takes to restart after a soft reset.
+const RESET_TIME: Duration = Duration::from_millis(15);
+
 static mut __READ_BUFFER: [u8; 3] = [0; 3];
 
 #[allow(missing_debug_implementations)]
 pub enum Htu21dCommand<H, R> {
     StartTransfer(i2c::StartTransferFuture, *const u8),
     CmdTransmission(i2c::Transmission<'static>),
+    /// Waiting for the measurement or the restart with the bus released.
+    Conversion(Delay, &'static i2c::I2cBus),
+    StartResultTransfer(i2c::StartTransferFuture),
     ResultTransmission(i2c::Transmission<'static>),
     Done(u16, PhantomData<(H, R)>),
 }
@@ -201,11 +230,76 @@ where
                 Done(sample, _) => {
                     return Poll::Ready(Ok(<T>::from(*sample)));
                 }
+                _ => unsafe {
+                    ::core::intrinsics::unreachable();
+                },
             };
         }
     }
 }
 
+impl<T> Htu21dCommand<NoHoldMaster, T>
+where
+    T: From<u16> + Copy,
+{
+    fn poll_no_hold_master(&mut self, cx: &mut Context) -> Poll<Result<T, Htu21dError>> {
+        use self::Htu21dCommand::*;
+
+        loop {
+            *self = match self {
+                StartTransfer(ref mut start_transfer, ref cmd) => {
+                    let i2c = ready!(Pin::new(start_transfer).poll(cx));
+                    CmdTransmission(i2c.master_transmitter_raw(HTU21D_ADDRESS, *cmd, 1))
+                }
+                CmdTransmission(ref mut transmission) => {
+                    let (mut i2c, _buf) = try_ready!(Pin::new(transmission).poll(cx));
+                    i2c.stop();
+                    // The transfer is dropped here, so the bus is
+                    // released for the conversion time.
+                    Conversion(Delay::new(CONVERSION_TIME), i2c.bus())
+                }
+                Conversion(ref mut delay, bus) => {
+                    ready!(Pin::new(delay).poll(cx));
+                    let bus: &'static i2c::I2cBus = *bus;
+                    StartResultTransfer(bus.start_transfer())
+                }
+                StartResultTransfer(ref mut start_transfer) => {
+                    let i2c = ready!(Pin::new(start_transfer).poll(cx));
+                    ResultTransmission(i2c.master_receiver_raw(
+                        HTU21D_ADDRESS,
+                        unsafe { &mut __READ_BUFFER }.as_mut_ptr(),
+                        unsafe { &__READ_BUFFER }.len(),
+                    ))
+                }
+                ResultTransmission(ref mut transmission) => {
+                    let (mut i2c, buf) = try_ready!(Pin::new(transmission).poll(cx));
+                    i2c.stop();
+                    Done((u16::from(buf[0]) << 8) | u16::from(buf[1]), PhantomData)
+                }
+                Done(sample, _) => {
+                    return Poll::Ready(Ok(<T>::from(*sample)));
+                }
+            };
+        }
+    }
+}
+
+impl Future for Htu21dCommand<NoHoldMaster, Temperature> {
+    type Output = Result<Temperature, Htu21dError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.poll_no_hold_master(cx)
+    }
+}
+
+impl Future for Htu21dCommand<NoHoldMaster, Humidity> {
+    type Output = Result<Humidity, Htu21dError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.poll_no_hold_master(cx)
+    }
+}
+
 impl Future for Htu21dCommand<NoHoldMaster, Reset> {
     type Output = Result<Reset, Htu21dError>;
 
@@ -223,6 +317,11 @@ impl Future for Htu21dCommand<NoHoldMaster, Reset> {
                 CmdTransmission(ref mut transmission) => {
                     let (mut i2c, _buf) = try_ready!(Pin::new(transmission).poll(cx));
                     i2c.stop();
+                    // The bus is released while the sensor restarts.
+                    Conversion(Delay::new(RESET_TIME), i2c.bus())
+                }
+                Conversion(ref mut delay, _) => {
+                    ready!(Pin::new(delay).poll(cx));
                     Done(0, PhantomData)
                 }
                 Done(_, _) => {
@@ -235,3 +334,91 @@ impl Future for Htu21dCommand<NoHoldMaster, Reset> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::debug::{mock_bus, poll_in_task, ReactorGuard};
+    use breactor::time::tick;
+
+    const TASK: u32 = 31;
+    const OTHER_TASK: u32 = 30;
+
+    /// Returns true if another task can acquire the bus.
+    fn bus_is_free(bus: &'static i2c::I2cBus) -> bool {
+        poll_in_task(OTHER_TASK, &mut bus.start_transfer()).is_ready()
+    }
+
+    #[test]
+    fn test_fixed_point_conversions() {
+        for sample in (0..=0xffff_u16).step_by(0x3ff) {
+            let temp = Temperature(sample);
+            let reference = -46.85 + 175.72 * f64::from(sample & !0x3) / 65536.0;
+            let celsius = f64::from(temp.celsius().to_bits()) / 65536.0;
+            assert!((celsius - reference).abs() < 0.001, "{:x}", sample);
+            assert!((i64::from(temp.celsius().to_milli()) - temp.millicelsius()).abs() <= 1);
+
+            let hum = Humidity(sample);
+            let reference = -6.0 + 125.0 * f64::from(sample & !0x3) / 65536.0;
+            let percents = f64::from(hum.percents().to_bits()) / 65536.0;
+            assert!((percents - reference).abs() < 0.001, "{:x}", sample);
+            assert!((i64::from(hum.percents().to_milli()) - hum.millipercents()).abs() <= 1);
+        }
+    }
+
+    #[test]
+    fn test_soft_reset_waits_for_restart() {
+        let _guard = ReactorGuard::acquire();
+        let bus = mock_bus();
+        let htu21d: &'static Htu21d = Box::leak(Box::new(Htu21d::new(bus)));
+
+        let mut reset = htu21d.soft_reset();
+        assert!(poll_in_task(TASK, &mut reset).is_pending());
+
+        bus.complete_transfer(&[]);
+        assert!(poll_in_task(TASK, &mut reset).is_pending());
+        assert!(bus_is_free(bus));
+
+        for _ in 0..RESET_TIME.to_ticks() {
+            tick();
+        }
+        assert!(poll_in_task(TASK, &mut reset).is_pending());
+
+        tick();
+        match poll_in_task(TASK, &mut reset) {
+            Poll::Ready(Ok(Reset)) => {}
+            _ => panic!("reset has not finished"),
+        }
+    }
+
+    #[test]
+    fn test_no_hold_master_releases_bus_during_conversion() {
+        let _guard = ReactorGuard::acquire();
+        let bus = mock_bus();
+        let htu21d: &'static Htu21d = Box::leak(Box::new(Htu21d::new(bus)));
+
+        let mut read = htu21d.read_temperature_no_hold_master();
+        assert!(poll_in_task(TASK, &mut read).is_pending());
+        assert!(!bus_is_free(bus));
+
+        // The command is sent, so the conversion starts.
+        bus.complete_transfer(&[]);
+        assert!(poll_in_task(TASK, &mut read).is_pending());
+        assert!(bus_is_free(bus));
+
+        // The delay waits one tick more than the conversion takes.
+        for _ in 0..=CONVERSION_TIME.to_ticks() {
+            tick();
+        }
+        assert!(poll_in_task(TASK, &mut read).is_pending());
+        assert!(!bus_is_free(bus));
+
+        bus.complete_transfer(&[0x66, 0x4c, 0x00]);
+        match poll_in_task(TASK, &mut read) {
+            Poll::Ready(Ok(temp)) => assert_eq!(0x664c, temp.raw()),
+            _ => panic!("temperature has not been read"),
+        }
+        assert!(bus_is_free(bus));
+    }
+}
@@ -5,12 +5,13 @@ use core::marker::PhantomData;
 use core::pin::Pin;
 use core::task::Context;
 
+use stm32f4::gpio::{self, Gpio};
 use stm32f4::i2c::{self, I2c};
 
-use futures::{Future, FutureExt, Poll};
+use futures::{Future, FutureExt, Poll, TryFutureExt};
 
 use breactor::mutex::{Mutex, MutexLock};
-use breactor::promise::Promise;
+use breactor::promise::{Promise, TryPromise};
 
 pub static I2C1_BUS: I2cBus = I2cBus::new(unsafe { &i2c::I2C1 });
 pub static I2C2_BUS: I2cBus = I2cBus::new(unsafe { &i2c::I2C2 });
@@ -21,10 +22,33 @@ pub struct I2cBus {
     i2c: &'static I2c,
     mutex: Mutex,
     slave_address: UnsafeCell<u16>,
+    address_mode: UnsafeCell<AddressMode>,
     buffer: UnsafeCell<*mut u8>,
     buf_left: UnsafeCell<usize>,
+    /// Decides the number of remaining bytes from the first received
+    /// byte. Only used by dynamic-length receives.
+    length_fn: UnsafeCell<Option<fn(u8) -> usize>>,
+    /// Register address sent before the data. Only used by the
+    /// register helpers.
+    register: UnsafeCell<Option<u8>>,
+    /// Buffer and size of the read that follows the current write
+    /// after a repeated start. Only used by `write_read`.
+    read_after_write: UnsafeCell<Option<(*mut u8, usize)>>,
 
-    result: UnsafeCell<Promise<Result<(), Error>>>,
+    result: UnsafeCell<TryPromise<(), Error>>,
+}
+
+/// How `I2cBus::slave_address` is sent.
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+enum AddressMode {
+    /// 7-bit address, shifted left and with the direction bit.
+    Bit7,
+    Bit10Transmitter,
+    /// The address is sent for writing first, then the start
+    /// condition is repeated to send the header for reading.
+    Bit10Receiver,
+    /// The start condition has been repeated.
+    Bit10Restarted,
 }
 
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
@@ -43,12 +67,150 @@ pub enum Error {
 
     BusError,
 
+    /// The transfer was aborted by `I2cBus::recover`.
+    BusReset,
+
     /// Unknown I2C error.
     ///
     /// The internal value is I2C event.
     Unknown(u32),
 }
 
+/// SCL and SDA pins of a bus, for `I2cBus::recover`.
+///
+/// On STM32F4DISCOVERY, I2C1 is on PB6 (SCL) and PB9 (SDA), AF4.
+#[allow(missing_debug_implementations)]
+pub struct I2cPins {
+    pub gpio: &'static Gpio,
+    pub scl: u32,
+    pub sda: u32,
+    /// Alternate function that connects the pins to the I2C.
+    pub af: gpio::GpioAF,
+}
+
+unsafe impl Sync for I2cPins {}
+
+/// Pin action of the bus recovery sequence.
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+enum Recovery {
+    SclLow,
+    SclHigh,
+    SdaLow,
+    SdaHigh,
+}
+
+/// Clocks SCL until the slave releases SDA, at most 9 times, then
+/// generates a stop condition.
+///
+/// A slave that missed the master reset holds SDA low until it has
+/// shifted out the rest of its byte and got a NACK, which takes at
+/// most 9 clocks.
+fn recovery_sequence<S, A>(mut sda_released: S, mut act: A)
+where
+    S: FnMut() -> bool,
+    A: FnMut(Recovery),
+{
+    for _ in 0..9 {
+        if sda_released() {
+            break;
+        }
+        act(Recovery::SclLow);
+        act(Recovery::SclHigh);
+    }
+
+    // SDA rises while SCL is high.
+    act(Recovery::SclLow);
+    act(Recovery::SdaLow);
+    act(Recovery::SclHigh);
+    act(Recovery::SdaHigh);
+}
+
+/// Addresses below and above are reserved.
+const SCAN_FIRST: u8 = 0x08;
+const SCAN_LAST: u8 = 0x77;
+
+#[allow(missing_debug_implementations)]
+pub struct Scan<'a> {
+    bus: &'static I2cBus,
+    found: &'a mut [u8],
+    count: usize,
+    /// The address being probed.
+    address: u8,
+    state: ScanState,
+}
+
+enum ScanState {
+    StartTransfer(StartTransferFuture),
+    Probe(Transmission<'static>),
+}
+
+impl<'a> Unpin for Scan<'a> {}
+
+impl<'a> Future for Scan<'a> {
+    type Output = usize;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+
+        loop {
+            this.state = match this.state {
+                ScanState::StartTransfer(ref mut start_transfer) => {
+                    let transfer = ready!(Pin::new(start_transfer).poll(cx));
+                    let addr = u16::from(this.address) << 1;
+                    ScanState::Probe(transfer.master_transmitter(addr, &[]))
+                }
+                ScanState::Probe(ref mut transmission) => {
+                    // On NACK, the error handler has released the bus
+                    // already.
+                    if let Ok((mut transfer, _)) = ready!(Pin::new(transmission).poll(cx)) {
+                        transfer.stop();
+                        if let Some(slot) = this.found.get_mut(this.count) {
+                            *slot = this.address;
+                            this.count += 1;
+                        }
+                    }
+
+                    if this.address == SCAN_LAST || this.count == this.found.len() {
+                        return Poll::Ready(this.count);
+                    }
+                    this.address += 1;
+                    ScanState::StartTransfer(this.bus.start_transfer())
+                }
+            };
+        }
+    }
+}
+
+/// Future returned by `I2cBus::recover`.
+#[allow(missing_debug_implementations)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Recover {
+    bus: &'static I2cBus,
+    pins: &'static I2cPins,
+    init: &'static i2c::I2cInit,
+    /// Set once the transfer in progress has been aborted.
+    lock: Option<StartTransferFuture>,
+}
+
+impl Future for Recover {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = &mut *self;
+        let bus = this.bus;
+        let lock = this.lock.get_or_insert_with(|| {
+            bus.abort();
+            bus.start_transfer()
+        });
+
+        let _transfer = ready!(Pin::new(lock).poll(cx));
+        unsafe {
+            bus.reset(this.pins, this.init);
+        }
+        Poll::Ready(())
+    }
+}
+
 #[allow(missing_debug_implementations)]
 pub struct I2cTransfer {
     #[allow(dead_code)]
@@ -62,13 +224,17 @@ unsafe impl Sync for I2cBus {}
 pub existential type StartTransferFuture: Future<Output = I2cTransfer>;
 
 impl I2cBus {
-    const fn new(i2c: &'static I2c) -> Self {
+    pub(crate) const fn new(i2c: &'static I2c) -> Self {
         I2cBus {
             i2c,
             mutex: Mutex::new(),
             slave_address: UnsafeCell::new(0),
+            address_mode: UnsafeCell::new(AddressMode::Bit7),
             buffer: UnsafeCell::new(::core::ptr::null_mut()),
             buf_left: UnsafeCell::new(0),
+            length_fn: UnsafeCell::new(None),
+            register: UnsafeCell::new(None),
+            read_after_write: UnsafeCell::new(None),
             result: UnsafeCell::new(unsafe { Promise::empty() }),
         }
     }
@@ -78,6 +244,303 @@ impl I2cBus {
             .lock()
             .map(move |lock| I2cTransfer { lock, bus: self })
     }
+
+    /// Reads `buf.len()` bytes starting at register `reg` of the
+    /// slave, and releases the bus.
+    ///
+    /// The register address is written, then the data is read after a
+    /// repeated start.
+    pub fn read_register<'a>(
+        &'static self,
+        addr: u16,
+        reg: u8,
+        buf: &'a mut [u8],
+    ) -> impl Future<Output = Result<&'a [u8], Error>> + 'a {
+        self.start_transfer()
+            .then(move |transfer| transfer.start_write_read(addr, Some(reg), &[], buf))
+            .map_ok(|(mut transfer, data)| {
+                transfer.stop();
+                data
+            })
+    }
+
+    /// Writes `data` to the slave starting at register `reg`, and
+    /// releases the bus.
+    pub fn write_register<'a>(
+        &'static self,
+        addr: u16,
+        reg: u8,
+        data: &'a [u8],
+    ) -> impl Future<Output = Result<(), Error>> + 'a {
+        self.start_transfer()
+            .then(move |transfer| {
+                transfer.start_transmitter(
+                    addr,
+                    AddressMode::Bit7,
+                    Some(reg),
+                    data.as_ptr(),
+                    data.len(),
+                    None,
+                )
+            })
+            .map_ok(|(mut transfer, _)| transfer.stop())
+    }
+
+    /// Probes all 7-bit addresses from 0x08 to 0x77 with an empty
+    /// write, and stores the addresses that acknowledge into `found`.
+    ///
+    /// Resolves to the number of addresses stored. Scanning stops
+    /// once `found` is full.
+    pub fn scan<'a>(&'static self, found: &'a mut [u8]) -> Scan<'a> {
+        Scan {
+            bus: self,
+            found,
+            count: 0,
+            address: SCAN_FIRST,
+            state: ScanState::StartTransfer(self.start_transfer()),
+        }
+    }
+
+    /// Frees the bus after a slave got stuck holding SDA low, e.g.,
+    /// because the MCU was reset in the middle of a transfer.
+    ///
+    /// A transfer in progress fails with `Error::BusReset`. Once its
+    /// owner releases the bus, the pins are switched to GPIO to clock
+    /// the slave out and generate a stop condition. Then they are
+    /// switched back, and the peripheral is reset and initialized with
+    /// `init`. The bus stays locked meanwhile.
+    ///
+    /// # Safety
+    ///
+    /// `pins` must be the pins of this bus, and nothing else must use
+    /// them.
+    pub unsafe fn recover(
+        &'static self,
+        pins: &'static I2cPins,
+        init: &'static i2c::I2cInit,
+    ) -> Recover {
+        Recover {
+            bus: self,
+            pins,
+            init,
+            lock: None,
+        }
+    }
+
+    /// Fails the transfer in progress, if any, with
+    /// `Error::BusReset`.
+    fn abort(&self) {
+        unsafe {
+            self.i2c.it_disable(i2c::Interrupt::Evt);
+            self.i2c.it_disable(i2c::Interrupt::Buf);
+            self.i2c.it_disable(i2c::Interrupt::Err);
+
+            // The interrupts are disabled, so the handlers can't
+            // resolve it meanwhile.
+            let result = &*self.result.get();
+            if !result.is_resolved() {
+                result.resolve_err(Error::BusReset);
+            }
+        }
+    }
+
+    /// Runs the recovery sequence on `pins`, then resets the
+    /// peripheral. See `recover`.
+    unsafe fn reset(&self, pins: &I2cPins, init: &i2c::I2cInit) {
+        let pin_config = |mode, af| gpio::GpioConfig {
+            mode,
+            ospeed: gpio::GpioOSpeed::FAST_SPEED,
+            otype: gpio::GpioOType::OPEN_DRAIN,
+            pupd: gpio::GpioPuPd::NO,
+            af,
+        };
+
+        // Open-drain outputs are released when set.
+        pins.gpio.set_bit(pins.scl);
+        pins.gpio.set_bit(pins.sda);
+        let output = || pin_config(gpio::GpioMode::OUTPUT, gpio::GpioAF::AF0);
+        pins.gpio.enable(pins.scl, output());
+        pins.gpio.enable(pins.sda, output());
+
+        recovery_sequence(
+            || pins.gpio.read_bit(pins.sda),
+            |action| {
+                match action {
+                    Recovery::SclLow => pins.gpio.clear_bit(pins.scl),
+                    Recovery::SclHigh => pins.gpio.set_bit(pins.scl),
+                    Recovery::SdaLow => pins.gpio.clear_bit(pins.sda),
+                    Recovery::SdaHigh => pins.gpio.set_bit(pins.sda),
+                }
+                // Half of a 100 kHz clock period
+                stm32f4::delay::delay_us(5);
+            },
+        );
+
+        pins.gpio
+            .enable(pins.scl, pin_config(gpio::GpioMode::AF, pins.af));
+        pins.gpio
+            .enable(pins.sda, pin_config(gpio::GpioMode::AF, pins.af));
+
+        self.i2c.software_reset();
+        self.i2c.init(init);
+    }
+
+    /// Stores a byte received in master receiver mode.
+    ///
+    /// Must only be called from the event interrupt handler.
+    unsafe fn receive_byte(&self, byte: u8) {
+        let buffer = self.buffer.get();
+        let buf_left = self.buf_left.get();
+
+        debug_assert!(*buf_left > 0);
+
+        **buffer = byte;
+
+        *buf_left -= 1;
+        (*buffer) = (*buffer).offset(1);
+
+        if let Some(length_fn) = (*self.length_fn.get()).take() {
+            // The first byte determines the number of bytes left.
+            // Never overflow the buffer.
+            *buf_left = ::core::cmp::min(length_fn(byte), *buf_left);
+        }
+
+        if *buf_left == 1 {
+            // NACK the last byte.
+            self.i2c.set_acknowledge(false);
+        } else if *buf_left == 0 {
+            self.finish();
+        }
+    }
+
+    /// Advances the master state machine.
+    ///
+    /// Must only be called from the event interrupt handler.
+    unsafe fn handle_event(&self, status: i2c::Status) {
+        use stm32f4::i2c::{Direction, Sr1Masks, Sr2Masks};
+
+        if !status.sr2(Sr2Masks::MSL) {
+            // Slave mode is not supported.
+            return;
+        }
+
+        let buf_left = self.buf_left.get();
+        let address = *self.slave_address.get();
+        let address_mode = self.address_mode.get();
+
+        if status.sr1(Sr1Masks::SB) {
+            // EV5
+            match *address_mode {
+                // not really data, but who cares
+                AddressMode::Bit7 => {
+                    self.i2c.send_data(address as u8);
+                    // Might have been disabled for a repeated start.
+                    self.i2c.it_enable(i2c::Interrupt::Buf);
+                }
+                AddressMode::Bit10Transmitter | AddressMode::Bit10Receiver => {
+                    self.i2c.send_10bit_header(address, Direction::Transmitter)
+                }
+                AddressMode::Bit10Restarted => {
+                    self.i2c.send_10bit_header(address, Direction::Receiver);
+                    self.i2c.it_enable(i2c::Interrupt::Buf);
+                }
+            }
+        } else if status.sr1(Sr1Masks::ADD10) {
+            // EV9
+            self.i2c.send_10bit_address(address);
+        } else if status.sr1(Sr1Masks::ADDR) {
+            // EV6
+            if *address_mode == AddressMode::Bit10Receiver {
+                // The slave is addressed for writing, repeat the start
+                // to switch to reading. TXE would fire until then.
+                self.i2c.it_disable(i2c::Interrupt::Buf);
+                self.i2c.generate_start();
+                *address_mode = AddressMode::Bit10Restarted;
+            } else if status.sr2(Sr2Masks::TRA)
+                && *buf_left == 0
+                && (*self.register.get()).is_none()
+            {
+                // Nothing to send, so BTF is never set.
+                self.end_write();
+            } else if *buf_left == 1 {
+                self.i2c.set_acknowledge(false);
+            }
+        } else if status.sr2(Sr2Masks::TRA) {
+            if !status.sr1(Sr1Masks::TxE) {
+                return;
+            }
+
+            if let Some(register) = (*self.register.get()).take() {
+                // EV8, the register address precedes the data
+                self.i2c.send_data(register);
+            } else if *buf_left > 0 {
+                // EV8
+                let buffer = self.buffer.get();
+                self.i2c.send_data(**buffer);
+
+                *buf_left -= 1;
+                (*buffer) = (*buffer).offset(1);
+            } else if status.sr1(Sr1Masks::BTF) {
+                self.end_write();
+            }
+        } else if status.sr1(Sr1Masks::RxNE) {
+            // EV7
+            self.receive_byte(self.i2c.receive_data());
+        }
+    }
+
+    /// Called after the last byte of a master transmitter has been
+    /// sent.
+    unsafe fn end_write(&self) {
+        if let Some((buffer, size)) = (*self.read_after_write.get()).take() {
+            // Repeat the start instead of EV8_2 to read from the same
+            // slave. TXE would fire until then.
+            *self.buffer.get() = buffer;
+            *self.buf_left.get() = size;
+            *self.slave_address.get() |= 0x01;
+            self.i2c.set_acknowledge(true);
+            self.i2c.it_disable(i2c::Interrupt::Buf);
+            self.i2c.generate_start();
+        } else {
+            // EV8_2
+            self.finish();
+        }
+    }
+
+    unsafe fn finish(&self) {
+        self.i2c.it_disable(i2c::Interrupt::Evt);
+        self.i2c.it_disable(i2c::Interrupt::Buf);
+        self.i2c.it_disable(i2c::Interrupt::Err);
+
+        let result = self.result.get();
+        (*result).resolve_ok(());
+    }
+}
+
+#[cfg(test)]
+impl I2cBus {
+    /// Completes the current transmission as the interrupt handler
+    /// would, storing `data` into the receive buffer.
+    pub(crate) fn complete_transfer(&self, data: &[u8]) {
+        unsafe {
+            let buffer = *self.buffer.get();
+            ::core::ptr::copy_nonoverlapping(data.as_ptr(), buffer, data.len());
+            (*self.result.get()).resolve_ok(());
+        }
+    }
+
+    /// Completes the current master transmitter transmission as the
+    /// interrupt handler would. Returns the slave address and the
+    /// bytes sent.
+    pub(crate) fn complete_transmission(&self) -> (u16, Vec<u8>) {
+        unsafe {
+            let data = ::core::slice::from_raw_parts(*self.buffer.get(), *self.buf_left.get());
+            let result = (*self.slave_address.get(), data.to_vec());
+            *self.buf_left.get() = 0;
+            (*self.result.get()).resolve_ok(());
+            result
+        }
+    }
 }
 
 #[allow(missing_debug_implementations)]
@@ -86,6 +549,8 @@ pub struct Transmission<'a> {
 
     data: *mut u8,
     size: usize,
+    /// The size is only known after the transmission completes.
+    dynamic: bool,
 
     __phantom: PhantomData<&'a u8>,
 }
@@ -97,9 +562,15 @@ impl<'a> Future for Transmission<'a> {
         let result = self.transfer.as_ref().unwrap().bus.result.get();
         unsafe {
             try_ready!(Pin::new(&mut *result).poll(cx));
+            let transfer = self.transfer.take().unwrap();
+            let size = if self.dynamic {
+                *transfer.bus.buffer.get() as usize - self.data as usize
+            } else {
+                self.size
+            };
             Poll::Ready(Ok((
-                self.transfer.take().unwrap(),
-                ::core::slice::from_raw_parts(self.data, self.size),
+                transfer,
+                ::core::slice::from_raw_parts(self.data, size),
             )))
         }
     }
@@ -115,12 +586,75 @@ impl I2cTransfer {
         addr: u16,
         data_ptr: *const u8,
         data_size: usize,
+    ) -> Transmission<'a> {
+        self.start_transmitter(addr, AddressMode::Bit7, None, data_ptr, data_size, None)
+    }
+
+    /// Same as `master_transmitter`, but addresses the slave with a
+    /// 10-bit address.
+    pub fn master_transmitter_10bit(self, addr: u16, data: &[u8]) -> Transmission {
+        debug_assert!(addr <= 0x3ff);
+        self.start_transmitter(
+            addr,
+            AddressMode::Bit10Transmitter,
+            None,
+            data.as_ptr(),
+            data.len(),
+            None,
+        )
+    }
+
+    /// Writes `tx` to the slave, then reads `rx` from it after a
+    /// repeated start, without releasing the bus in between.
+    ///
+    /// Some devices forget the register written to on stop, so the
+    /// register must be read this way.
+    pub fn write_read<'a>(self, addr: u16, tx: &'a [u8], rx: &'a mut [u8]) -> Transmission<'a> {
+        self.start_write_read(addr, None, tx, rx)
+    }
+
+    fn start_write_read<'a>(
+        self,
+        addr: u16,
+        register: Option<u8>,
+        tx: &'a [u8],
+        rx: &'a mut [u8],
+    ) -> Transmission<'a> {
+        debug_assert!(!rx.is_empty());
+        let transmission = self.start_transmitter(
+            addr,
+            AddressMode::Bit7,
+            register,
+            tx.as_ptr(),
+            tx.len(),
+            Some((rx.as_mut_ptr(), rx.len())),
+        );
+        Transmission {
+            data: rx.as_mut_ptr(),
+            size: rx.len(),
+            ..transmission
+        }
+    }
+
+    fn start_transmitter<'a>(
+        self,
+        addr: u16,
+        address_mode: AddressMode,
+        register: Option<u8>,
+        data_ptr: *const u8,
+        data_size: usize,
+        read_after_write: Option<(*mut u8, usize)>,
     ) -> Transmission<'a> {
         unsafe {
             *self.bus.slave_address.get() = addr;
+            *self.bus.address_mode.get() = address_mode;
+            *self.bus.register.get() = register;
             *self.bus.buffer.get() = data_ptr as *mut u8;
             *self.bus.buf_left.get() = data_size;
-            *self.bus.result.get() = Promise::new();
+            *self.bus.read_after_write.get() = read_after_write;
+            // The previous transfer might have been dropped after it
+            // was resolved, but before its result was read.
+            (*self.bus.result.get()).reset();
 
             self.bus.i2c.generate_start();
 
@@ -133,6 +667,7 @@ impl I2cTransfer {
             transfer: Some(self),
             data: data_ptr as *mut _,
             size: data_size,
+            dynamic: false,
             __phantom: PhantomData,
         }
     }
@@ -146,12 +681,42 @@ impl I2cTransfer {
         addr: u16,
         data_ptr: *mut u8,
         data_size: usize,
+    ) -> Transmission<'a> {
+        self.start_receiver(addr | 0x01, AddressMode::Bit7, data_ptr, data_size, None)
+    }
+
+    /// Same as `master_receiver`, but addresses the slave with a
+    /// 10-bit address.
+    pub fn master_receiver_10bit(self, addr: u16, data: &mut [u8]) -> Transmission {
+        debug_assert!(addr <= 0x3ff);
+        self.start_receiver(
+            addr,
+            AddressMode::Bit10Receiver,
+            data.as_mut_ptr(),
+            data.len(),
+            None,
+        )
+    }
+
+    fn start_receiver<'a>(
+        self,
+        addr: u16,
+        address_mode: AddressMode,
+        data_ptr: *mut u8,
+        data_size: usize,
+        length_fn: Option<fn(u8) -> usize>,
     ) -> Transmission<'a> {
         unsafe {
-            *self.bus.slave_address.get() = addr | 0x01;
+            *self.bus.slave_address.get() = addr;
+            *self.bus.address_mode.get() = address_mode;
+            *self.bus.length_fn.get() = length_fn;
+            *self.bus.register.get() = None;
+            *self.bus.read_after_write.get() = None;
             *self.bus.buffer.get() = data_ptr;
             *self.bus.buf_left.get() = data_size;
-            *self.bus.result.get() = Promise::new();
+            // The previous transfer might have been dropped after it
+            // was resolved, but before its result was read.
+            (*self.bus.result.get()).reset();
 
             self.bus.i2c.generate_start();
             self.bus.i2c.set_acknowledge(true);
@@ -165,10 +730,39 @@ impl I2cTransfer {
             transfer: Some(self),
             data: data_ptr,
             size: data_size,
+            dynamic: length_fn.is_some(),
             __phantom: PhantomData,
         }
     }
 
+    /// Receives a block whose length is decided by its first byte
+    /// (e.g., a count byte followed by data).
+    ///
+    /// `remaining` is called from the interrupt handler with the first
+    /// byte and returns the number of bytes that follow it. The
+    /// transfer is truncated if they don't fit into `data`.
+    ///
+    /// The resulting slice includes the first byte.
+    pub fn master_receiver_dynamic(
+        self,
+        addr: u16,
+        data: &mut [u8],
+        remaining: fn(u8) -> usize,
+    ) -> Transmission {
+        self.start_receiver(
+            addr | 0x01,
+            AddressMode::Bit7,
+            data.as_mut_ptr(),
+            data.len(),
+            Some(remaining),
+        )
+    }
+
+    /// Returns the bus this transfer is performed on.
+    pub fn bus(&self) -> &'static I2cBus {
+        self.bus
+    }
+
     pub fn stop(&mut self) {
         // TODO: check START has been generated before?
         unsafe {
@@ -177,122 +771,661 @@ impl I2cTransfer {
     }
 }
 
+/// Handles an event interrupt of the bus.
+unsafe fn handle_ev(bus: &I2cBus) {
+    bus.handle_event(bus.i2c.get_last_status());
+}
+
+/// Handles an error interrupt of the bus. The transfer fails and the
+/// interrupts stay disabled until the next one starts.
+unsafe fn handle_er(bus: &I2cBus) {
+    let event = bus.i2c.get_last_event();
+
+    bus.i2c.it_disable(i2c::Interrupt::Evt);
+    bus.i2c.it_disable(i2c::Interrupt::Buf);
+    bus.i2c.it_disable(i2c::Interrupt::Err);
+
+    let error = if event & (i2c::Sr1Masks::AF as u32) != 0 {
+        bus.i2c.it_clear_pending(i2c::Sr1Masks::AF as u32);
+        if i2c::Status(event).sr2(i2c::Sr2Masks::MSL) {
+            // The master keeps the bus after a NACK, release it.
+            bus.i2c.generate_stop();
+        }
+        Error::AcknowledgementFailure
+    } else if event & (i2c::Sr1Masks::ARLO as u32) != 0 {
+        bus.i2c.it_clear_pending(i2c::Sr1Masks::ARLO as u32);
+        Error::ArbitrationLost
+    } else if event & (i2c::Sr1Masks::BERR as u32) != 0 {
+        bus.i2c.it_clear_pending(i2c::Sr1Masks::BERR as u32);
+        Error::BusError
+    } else {
+        Error::Unknown(event)
+    };
+
+    let result = bus.result.get();
+    (*result).resolve_err(error);
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn __isr_i2c1_ev() {
-    let bus = &I2C1_BUS;
+    handle_ev(&I2C1_BUS);
+}
 
-    let event = bus.i2c.get_last_event();
+#[no_mangle]
+pub unsafe extern "C" fn __isr_i2c1_er() {
+    handle_er(&I2C1_BUS);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __isr_i2c2_ev() {
+    handle_ev(&I2C2_BUS);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __isr_i2c2_er() {
+    handle_er(&I2C2_BUS);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __isr_i2c3_ev() {
+    handle_ev(&I2C3_BUS);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __isr_i2c3_er() {
+    handle_er(&I2C3_BUS);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use core::cell::Cell;
+
+    use crate::debug::{mock_bus, poll_in_task, ReactorGuard};
+
+    const TASK: u32 = 31;
+
+    fn count_prefix(count: u8) -> usize {
+        count as usize
+    }
+
+    /// Starts a dynamic-length receive and feeds `bytes` to it as the
+    /// interrupt handler would. Returns the received block and whether
+    /// each byte was acknowledged.
+    fn receive_dynamic(buffer_size: usize, bytes: &[u8]) -> (Vec<u8>, Vec<bool>) {
+        let bus = mock_bus();
+        let buf: &'static mut [u8] = Box::leak(vec![0; buffer_size].into_boxed_slice());
+
+        let mut f = Box::pin(
+            bus.start_transfer()
+                .then(move |transfer| transfer.master_receiver_dynamic(0x40, buf, count_prefix)),
+        );
+        assert!(poll_in_task(TASK, &mut f).is_pending());
+
+        let mut acks = Vec::new();
+        let mut result = None;
+        for &b in bytes {
+            assert!(result.is_none(), "transfer has finished early");
+            // The ACK bit applies to the byte being received.
+            acks.push(unsafe { bus.i2c.get_acknowledge() });
+            unsafe { bus.receive_byte(b) };
+
+            if let Poll::Ready(res) = poll_in_task(TASK, &mut f) {
+                result = Some(res.unwrap().1.to_vec());
+            }
+        }
+
+        (result.expect("transfer has not finished"), acks)
+    }
+
+    const MASTER: u32 = 0x0003_0000; // BUSY, MSL
+    const TRANSMITTER: u32 = MASTER | 0x0004_0000; // TRA
+
+    #[test]
+    fn test_transmitter_with_composite_status() {
+        let _guard = ReactorGuard::acquire();
+        let bus = mock_bus();
+
+        let mut f = Box::pin(
+            bus.start_transfer()
+                .then(move |transfer| transfer.master_transmitter(0x40, &[0xa, 0xb])),
+        );
+        assert!(poll_in_task(TASK, &mut f).is_pending());
+
+        unsafe {
+            // SB
+            bus.handle_event(i2c::Status(MASTER | 0x01));
+            assert_eq!(0x40, bus.i2c.receive_data());
+            // ADDR, TXE
+            bus.handle_event(i2c::Status(TRANSMITTER | 0x82));
+            assert_eq!(0x40, bus.i2c.receive_data());
+            // TXE
+            bus.handle_event(i2c::Status(TRANSMITTER | 0x80));
+            assert_eq!(0xa, bus.i2c.receive_data());
+            // TXE, BTF, and unrelated PEC bits
+            bus.handle_event(i2c::Status(TRANSMITTER | 0x84 | 0xff00_0000));
+            assert_eq!(0xb, bus.i2c.receive_data());
+            // TXE, not BTF yet
+            bus.handle_event(i2c::Status(TRANSMITTER | 0x80));
+        }
+        assert!(poll_in_task(TASK, &mut f).is_pending());
+
+        unsafe {
+            // TXE, BTF
+            bus.handle_event(i2c::Status(TRANSMITTER | 0x84));
+        }
+        match poll_in_task(TASK, &mut f) {
+            Poll::Ready(Ok((_, data))) => assert_eq!(&[0xa, 0xb], data),
+            _ => panic!("transfer has not finished"),
+        }
+    }
+
+    #[test]
+    fn test_receiver_with_composite_status() {
+        let _guard = ReactorGuard::acquire();
+        let bus = mock_bus();
+        let buf: &'static mut [u8] = Box::leak(vec![0; 2].into_boxed_slice());
+
+        let mut f = Box::pin(
+            bus.start_transfer()
+                .then(move |transfer| transfer.master_receiver(0x40, buf)),
+        );
+        assert!(poll_in_task(TASK, &mut f).is_pending());
 
-    if event == 0x30000 {
-        // MSL, BUSY
-        return;
+        unsafe {
+            // SB
+            bus.handle_event(i2c::Status(MASTER | 0x01));
+            assert_eq!(0x41, bus.i2c.receive_data());
+            // ADDR
+            bus.handle_event(i2c::Status(MASTER | 0x02));
+
+            // RXNE with BTF, as the byte was late.
+            bus.i2c.send_data(0xa);
+            bus.handle_event(i2c::Status(MASTER | 0x44));
+            bus.i2c.send_data(0xb);
+            bus.handle_event(i2c::Status(MASTER | 0x44));
+        }
+        match poll_in_task(TASK, &mut f) {
+            Poll::Ready(Ok((_, data))) => assert_eq!(&[0xa, 0xb], data),
+            _ => panic!("transfer has not finished"),
+        }
     }
-    if event == 0x0 {
-        return;
+
+    /// Returns a register of the mock I2C at `offset` bytes.
+    fn reg(bus: &I2cBus, offset: usize) -> *mut u32 {
+        unsafe { (bus.i2c as *const I2c as *mut u8).add(offset) as *mut u32 }
     }
 
-    match ::core::mem::transmute(event) {
-        i2c::Event::MasterModeSelect => {
-            let slave_address = *bus.slave_address.get();
-            // not really data, but who cares
-            // TODO(ashmalko): handle ADDR10
-            bus.i2c.send_data(slave_address as u8);
+    const CR1: usize = 0x00;
+    const CR2: usize = 0x04;
+    const SR1: usize = 0x14;
+    const SR2: usize = 0x18;
+    const CR1_START: u32 = 1 << 8;
+    const CR1_ACK: u32 = 1 << 10;
+    const CR2_ITBUFEN: u32 = 1 << 10;
+
+    #[test]
+    fn test_transmitter_10bit() {
+        let _guard = ReactorGuard::acquire();
+        let bus = mock_bus();
+
+        let mut f = Box::pin(
+            bus.start_transfer()
+                .then(move |transfer| transfer.master_transmitter_10bit(0x2a5, &[0xa])),
+        );
+        assert!(poll_in_task(TASK, &mut f).is_pending());
+
+        unsafe {
+            // SB
+            bus.handle_event(i2c::Status(MASTER | 0x01));
+            assert_eq!(0xf4, bus.i2c.receive_data());
+            // ADD10
+            bus.handle_event(i2c::Status(MASTER | 0x08));
+            assert_eq!(0xa5, bus.i2c.receive_data());
+            // ADDR, TXE
+            bus.handle_event(i2c::Status(TRANSMITTER | 0x82));
+            assert_eq!(0xa5, bus.i2c.receive_data());
+            // TXE
+            bus.handle_event(i2c::Status(TRANSMITTER | 0x80));
+            assert_eq!(0xa, bus.i2c.receive_data());
+            // TXE, BTF
+            bus.handle_event(i2c::Status(TRANSMITTER | 0x84));
+        }
+        match poll_in_task(TASK, &mut f) {
+            Poll::Ready(Ok((_, data))) => assert_eq!(&[0xa], data),
+            _ => panic!("transfer has not finished"),
         }
-        i2c::Event::MasterTransmitterModeSelected | i2c::Event::MasterReceiverModeSelected => {
-            let buf_left = bus.buf_left.get();
-            if (*buf_left) == 1 {
-                bus.i2c.set_acknowledge(false);
+    }
+
+    #[test]
+    fn test_receiver_10bit() {
+        let _guard = ReactorGuard::acquire();
+        let bus = mock_bus();
+        let buf: &'static mut [u8] = Box::leak(vec![0; 1].into_boxed_slice());
+
+        let mut f = Box::pin(
+            bus.start_transfer()
+                .then(move |transfer| transfer.master_receiver_10bit(0x2a5, buf)),
+        );
+        assert!(poll_in_task(TASK, &mut f).is_pending());
+
+        unsafe {
+            // The hardware clears START once it is generated.
+            *reg(bus, CR1) &= !CR1_START;
+
+            // SB
+            bus.handle_event(i2c::Status(MASTER | 0x01));
+            assert_eq!(0xf4, bus.i2c.receive_data());
+            // ADD10
+            bus.handle_event(i2c::Status(MASTER | 0x08));
+            assert_eq!(0xa5, bus.i2c.receive_data());
+
+            // ADDR, TXE, as the slave is addressed for writing.
+            bus.handle_event(i2c::Status(TRANSMITTER | 0x82));
+            assert_ne!(0, *reg(bus, CR1) & CR1_START);
+            // Otherwise, TXE fires until the start is generated.
+            assert_eq!(0, *reg(bus, CR2) & CR2_ITBUFEN);
+
+            // SB
+            bus.handle_event(i2c::Status(MASTER | 0x01));
+            assert_eq!(0xf5, bus.i2c.receive_data());
+            assert_ne!(0, *reg(bus, CR2) & CR2_ITBUFEN);
+            // ADDR
+            bus.handle_event(i2c::Status(MASTER | 0x02));
+            // The only byte is not acknowledged.
+            assert!(!bus.i2c.get_acknowledge());
+
+            // RXNE
+            bus.i2c.send_data(0xa);
+            bus.handle_event(i2c::Status(MASTER | 0x40));
+        }
+        match poll_in_task(TASK, &mut f) {
+            Poll::Ready(Ok((_, data))) => assert_eq!(&[0xa], data),
+            _ => panic!("transfer has not finished"),
+        }
+    }
+
+    #[test]
+    fn test_write_read() {
+        let _guard = ReactorGuard::acquire();
+        let bus = mock_bus();
+        let rx: &'static mut [u8] = Box::leak(vec![0; 2].into_boxed_slice());
+
+        let mut f = Box::pin(
+            bus.start_transfer()
+                .then(move |transfer| transfer.write_read(0x40, &[0x01], rx)),
+        );
+        assert!(poll_in_task(TASK, &mut f).is_pending());
+
+        unsafe {
+            // SB
+            bus.handle_event(i2c::Status(MASTER | 0x01));
+            assert_eq!(0x40, bus.i2c.receive_data());
+            // ADDR, TXE
+            bus.handle_event(i2c::Status(TRANSMITTER | 0x82));
+            // TXE
+            bus.handle_event(i2c::Status(TRANSMITTER | 0x80));
+            assert_eq!(0x01, bus.i2c.receive_data());
+
+            *reg(bus, CR1) = 0;
+            // TXE, BTF
+            bus.handle_event(i2c::Status(TRANSMITTER | 0x84));
+            assert_eq!(
+                CR1_START | CR1_ACK,
+                *reg(bus, CR1),
+                "no stop between phases"
+            );
+            assert_eq!(0, *reg(bus, CR2) & CR2_ITBUFEN);
+        }
+        assert!(poll_in_task(TASK, &mut f).is_pending());
+
+        unsafe {
+            // SB
+            bus.handle_event(i2c::Status(MASTER | 0x01));
+            assert_eq!(0x41, bus.i2c.receive_data());
+            assert_ne!(0, *reg(bus, CR2) & CR2_ITBUFEN);
+            // ADDR
+            bus.handle_event(i2c::Status(MASTER | 0x02));
+            // RXNE
+            bus.i2c.send_data(0xa);
+            bus.handle_event(i2c::Status(MASTER | 0x40));
+            assert!(!bus.i2c.get_acknowledge());
+            bus.i2c.send_data(0xb);
+            bus.handle_event(i2c::Status(MASTER | 0x40));
+        }
+        match poll_in_task(TASK, &mut f) {
+            Poll::Ready(Ok((_, data))) => assert_eq!(&[0xa, 0xb], data),
+            _ => panic!("transfer has not finished"),
+        }
+    }
+
+    const CR1_STOP: u32 = 1 << 9;
+
+    #[test]
+    fn test_read_register() {
+        let _guard = ReactorGuard::acquire();
+        let bus = mock_bus();
+        let buf: &'static mut [u8] = Box::leak(vec![0; 3].into_boxed_slice());
+
+        let mut f = Box::pin(bus.read_register(0x40, 0x2c, buf));
+        assert!(poll_in_task(TASK, &mut f).is_pending());
+
+        unsafe {
+            // SB
+            bus.handle_event(i2c::Status(MASTER | 0x01));
+            assert_eq!(0x40, bus.i2c.receive_data());
+            // ADDR, TXE
+            bus.handle_event(i2c::Status(TRANSMITTER | 0x82));
+            // TXE
+            bus.handle_event(i2c::Status(TRANSMITTER | 0x80));
+            assert_eq!(0x2c, bus.i2c.receive_data());
+            // TXE, not BTF yet
+            bus.handle_event(i2c::Status(TRANSMITTER | 0x80));
+            assert_eq!(0x2c, bus.i2c.receive_data());
+
+            *reg(bus, CR1) = 0;
+            // TXE, BTF
+            bus.handle_event(i2c::Status(TRANSMITTER | 0x84));
+            assert_eq!(CR1_START | CR1_ACK, *reg(bus, CR1));
+
+            // SB
+            bus.handle_event(i2c::Status(MASTER | 0x01));
+            assert_eq!(0x41, bus.i2c.receive_data());
+            // ADDR
+            bus.handle_event(i2c::Status(MASTER | 0x02));
+            for &b in &[0xa, 0xb, 0xc] {
+                // RXNE
+                bus.i2c.send_data(b);
+                bus.handle_event(i2c::Status(MASTER | 0x40));
             }
         }
-        i2c::Event::MasterByteTransmitted => {
-            let buf_left = bus.buf_left.get();
+        match poll_in_task(TASK, &mut f) {
+            Poll::Ready(Ok(data)) => assert_eq!(&[0xa, 0xb, 0xc], data),
+            _ => panic!("transfer has not finished"),
+        }
+        assert_ne!(0, unsafe { *reg(bus, CR1) } & CR1_STOP);
+    }
+
+    #[test]
+    fn test_write_register() {
+        let _guard = ReactorGuard::acquire();
+        let bus = mock_bus();
 
-            if *buf_left == 0 {
-                bus.i2c.it_disable(i2c::Interrupt::Evt);
-                bus.i2c.it_disable(i2c::Interrupt::Buf);
-                bus.i2c.it_disable(i2c::Interrupt::Err);
+        let mut f = Box::pin(bus.write_register(0x40, 0x2c, &[0xa, 0xb]));
+        assert!(poll_in_task(TASK, &mut f).is_pending());
 
-                let result = bus.result.get();
-                (*result).resolve(Ok(()));
+        let mut sent = Vec::new();
+        unsafe {
+            // SB
+            bus.handle_event(i2c::Status(MASTER | 0x01));
+            assert_eq!(0x40, bus.i2c.receive_data());
+            // ADDR, TXE
+            bus.handle_event(i2c::Status(TRANSMITTER | 0x82));
+            for _ in 0..3 {
+                // TXE
+                bus.handle_event(i2c::Status(TRANSMITTER | 0x80));
+                sent.push(bus.i2c.receive_data());
             }
+            assert_eq!(0, *reg(bus, CR1) & CR1_STOP);
+            // TXE, BTF
+            bus.handle_event(i2c::Status(TRANSMITTER | 0x84));
+        }
+        assert_eq!(vec![0x2c, 0xa, 0xb], sent);
+        match poll_in_task(TASK, &mut f) {
+            Poll::Ready(Ok(())) => {}
+            _ => panic!("transfer has not finished"),
         }
-        i2c::Event::MasterByteTransmitting => {
-            let buffer = bus.buffer.get();
-            let buf_left = bus.buf_left.get();
+        assert_ne!(0, unsafe { *reg(bus, CR1) } & CR1_STOP);
+    }
 
-            if *buf_left > 0 {
-                bus.i2c.send_data(**buffer);
+    #[test]
+    fn test_scan() {
+        let _guard = ReactorGuard::acquire();
+        let bus = mock_bus();
+        let found: &'static mut [u8] = Box::leak(vec![0; 5].into_boxed_slice());
+        let found_ptr = found.as_ptr();
+        let acking = [0x08, 0x1a, 0x40, 0x77];
 
-                *buf_left -= 1;
-                (*buffer) = (*buffer).offset(1);
+        let mut f = Box::pin(bus.scan(found));
+        for address in SCAN_FIRST..=SCAN_LAST {
+            assert!(poll_in_task(TASK, &mut f).is_pending());
+            if address != SCAN_FIRST {
+                // The previous probe has released the bus.
+                assert_ne!(0, unsafe { *reg(bus, CR1) } & CR1_STOP);
             }
+
+            unsafe {
+                *reg(bus, CR1) = 0;
+                // SB
+                bus.handle_event(i2c::Status(MASTER | 0x01));
+                assert_eq!(address << 1, bus.i2c.receive_data());
+                if acking.contains(&address) {
+                    // ADDR, TXE
+                    bus.handle_event(i2c::Status(TRANSMITTER | 0x82));
+                } else {
+                    // AF
+                    set_status(bus, MASTER | 0x0400);
+                    handle_er(bus);
+                }
+            }
+        }
+        match poll_in_task(TASK, &mut f) {
+            Poll::Ready(n) => {
+                let found = unsafe { ::core::slice::from_raw_parts(found_ptr, n) };
+                assert_eq!(&acking, found);
+            }
+            _ => panic!("scan has not finished"),
         }
-        i2c::Event::MasterByteReceived => {
-            let buffer = bus.buffer.get();
-            let buf_left = bus.buf_left.get();
+    }
 
-            debug_assert!(*buf_left > 0);
+    #[test]
+    fn test_scan_stops_when_full() {
+        let _guard = ReactorGuard::acquire();
+        let bus = mock_bus();
+        let found: &'static mut [u8] = Box::leak(vec![0; 1].into_boxed_slice());
 
-            **buffer = bus.i2c.receive_data();
+        let mut f = Box::pin(bus.scan(found));
+        assert!(poll_in_task(TASK, &mut f).is_pending());
+        unsafe {
+            // SB; ADDR, TXE
+            bus.handle_event(i2c::Status(MASTER | 0x01));
+            bus.handle_event(i2c::Status(TRANSMITTER | 0x82));
+        }
+        match poll_in_task(TASK, &mut f) {
+            Poll::Ready(1) => {}
+            _ => panic!("scan has not finished"),
+        }
+    }
 
-            *buf_left -= 1;
-            (*buffer) = (*buffer).offset(1);
+    /// Returns the recovery actions, if SDA is released after
+    /// `pulses` clocks.
+    fn recover(pulses: usize) -> Vec<Recovery> {
+        let mut actions = Vec::new();
+        let clocks = Cell::new(0);
+        recovery_sequence(
+            || clocks.get() >= pulses,
+            |action| {
+                if action == Recovery::SclHigh {
+                    clocks.set(clocks.get() + 1);
+                }
+                actions.push(action)
+            },
+        );
+        actions
+    }
 
-            if *buf_left == 1 {
-                bus.i2c.set_acknowledge(false);
-            } else if *buf_left == 0 {
-                let result = bus.result.get();
-                (*result).resolve(Ok(()));
+    const STOP: [Recovery; 4] = [
+        Recovery::SclLow,
+        Recovery::SdaLow,
+        Recovery::SclHigh,
+        Recovery::SdaHigh,
+    ];
 
-                bus.i2c.it_disable(i2c::Interrupt::Evt);
-                bus.i2c.it_disable(i2c::Interrupt::Buf);
-                bus.i2c.it_disable(i2c::Interrupt::Err);
-            }
+    #[test]
+    fn test_recovery_sequence() {
+        let mut expected: Vec<Recovery> = Vec::new();
+        for _ in 0..3 {
+            expected.extend(&[Recovery::SclLow, Recovery::SclHigh]);
         }
-        _ => {
-            // TODO(ashmalko): this function should be rewritten to
-            // check particular status flags, and not matching events
-            // as whole.
-            // panic!("__isr_i2c1_ev(): unknown event 0x{:x}", event);
+        expected.extend(&STOP);
+        assert_eq!(expected, recover(3));
+
+        // Only the stop condition if the bus is free.
+        assert_eq!(STOP.to_vec(), recover(0));
+    }
+
+    #[test]
+    fn test_recovery_sequence_gives_up() {
+        let actions = recover(100);
+        assert_eq!(9 * 2 + STOP.len(), actions.len());
+        assert_eq!(&STOP, &actions[18..]);
+    }
+
+    #[test]
+    fn test_abort_releases_the_bus() {
+        let _guard = ReactorGuard::acquire();
+        let bus = mock_bus();
+
+        let mut f = Box::pin(
+            bus.start_transfer()
+                .then(move |transfer| transfer.master_transmitter(0x40, &[0xa])),
+        );
+        assert!(poll_in_task(TASK, &mut f).is_pending());
+
+        bus.abort();
+        match poll_in_task(TASK, &mut f) {
+            Poll::Ready(Err(Error::BusReset)) => {}
+            _ => panic!("the transfer has not been aborted"),
         }
+        drop(f);
+
+        // Nothing to abort, and the bus is free.
+        bus.abort();
+        assert!(poll_in_task(TASK, &mut bus.start_transfer()).is_ready());
     }
-}
 
-#[no_mangle]
-pub unsafe extern "C" fn __isr_i2c1_er() {
-    let bus = &I2C1_BUS;
+    #[test]
+    fn test_dropped_result_is_not_observed() {
+        let _guard = ReactorGuard::acquire();
+        let bus = mock_bus();
 
-    let event = bus.i2c.get_last_event();
+        let mut f = Box::pin(
+            bus.start_transfer()
+                .then(move |transfer| transfer.master_transmitter(0x40, &[0xa])),
+        );
+        assert!(poll_in_task(TASK, &mut f).is_pending());
+        unsafe {
+            (*bus.result.get()).resolve_err(Error::AcknowledgementFailure);
+        }
+        // Resolved, but never polled.
+        drop(f);
 
-    bus.i2c.it_disable(i2c::Interrupt::Evt);
-    bus.i2c.it_disable(i2c::Interrupt::Buf);
-    bus.i2c.it_disable(i2c::Interrupt::Err);
+        let mut f = Box::pin(
+            bus.start_transfer()
+                .then(move |transfer| transfer.master_transmitter(0x40, &[0xb])),
+        );
+        assert!(poll_in_task(TASK, &mut f).is_pending());
 
-    let error = if event & (i2c::Sr1Masks::AF as u32) != 0 {
-        bus.i2c.it_clear_pending(i2c::Sr1Masks::AF as u32);
-        Error::AcknowledgementFailure
-    } else if event & (i2c::Sr1Masks::ARLO as u32) != 0 {
-        bus.i2c.it_clear_pending(i2c::Sr1Masks::ARLO as u32);
-        Error::ArbitrationLost
-    } else if event & (i2c::Sr1Masks::BERR as u32) != 0 {
-        bus.i2c.it_clear_pending(i2c::Sr1Masks::BERR as u32);
-        Error::BusError
-    } else {
-        Error::Unknown(event)
-    };
+        assert_eq!((0x40, vec![0xb]), bus.complete_transmission());
+        match poll_in_task(TASK, &mut f) {
+            Poll::Ready(Ok((_, data))) => assert_eq!(&[0xb], data),
+            _ => panic!("stale result observed"),
+        }
+    }
 
-    let result = bus.result.get();
-    (*result).resolve(Err(error));
-}
+    /// Sets the status registers of the mock I2C.
+    fn set_status(bus: &I2cBus, status: u32) {
+        unsafe {
+            *reg(bus, SR1) = status & 0xffff;
+            *reg(bus, SR2) = status >> 16;
+        }
+    }
 
-#[no_mangle]
-pub extern "C" fn __isr_i2c2_ev() {}
+    #[test]
+    fn test_shared_handlers() {
+        let _guard = ReactorGuard::acquire();
 
-#[no_mangle]
-pub extern "C" fn __isr_i2c2_er() {}
+        for _ in 0..2 {
+            let bus = mock_bus();
 
-#[no_mangle]
-pub extern "C" fn __isr_i2c3_ev() {}
+            let mut f = Box::pin(
+                bus.start_transfer()
+                    .then(move |transfer| transfer.master_transmitter(0x40, &[0xa])),
+            );
+            assert!(poll_in_task(TASK, &mut f).is_pending());
 
-#[no_mangle]
-pub extern "C" fn __isr_i2c3_er() {}
+            // SB; ADDR, TXE; TXE; TXE, BTF
+            for &status in &[
+                MASTER | 0x01,
+                TRANSMITTER | 0x82,
+                TRANSMITTER | 0x80,
+                TRANSMITTER | 0x84,
+            ] {
+                set_status(bus, status);
+                unsafe { handle_ev(bus) };
+            }
+            match poll_in_task(TASK, &mut f) {
+                Poll::Ready(Ok((_, data))) => assert_eq!(&[0xa], data),
+                _ => panic!("transfer has not finished"),
+            }
+
+            let mut f = Box::pin(
+                bus.start_transfer()
+                    .then(move |transfer| transfer.master_transmitter(0x40, &[0xb])),
+            );
+            assert!(poll_in_task(TASK, &mut f).is_pending());
+
+            // AF
+            set_status(bus, MASTER | 0x0400);
+            unsafe { handle_er(bus) };
+            match poll_in_task(TASK, &mut f) {
+                Poll::Ready(Err(Error::AcknowledgementFailure)) => {}
+                _ => panic!("transfer has not failed"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_non_master_status_is_ignored() {
+        let bus = mock_bus();
+        unsafe {
+            *bus.buf_left.get() = 1;
+            bus.i2c.send_data(0x55);
+
+            // Idle, slave address matched, master without flags.
+            for &status in &[0, 0x0002_0002, MASTER] {
+                bus.handle_event(i2c::Status(status));
+                assert_eq!(0x55, bus.i2c.receive_data());
+                assert_eq!(1, *bus.buf_left.get());
+            }
+        }
+    }
+
+    #[test]
+    fn test_receiver_dynamic() {
+        let _guard = ReactorGuard::acquire();
+
+        let (data, acks) = receive_dynamic(8, &[3, 0xa, 0xb, 0xc]);
+        assert_eq!(vec![3, 0xa, 0xb, 0xc], data);
+        assert_eq!(vec![true, true, true, false], acks);
+    }
+
+    #[test]
+    fn test_receiver_dynamic_single_byte() {
+        let _guard = ReactorGuard::acquire();
+
+        let (data, acks) = receive_dynamic(8, &[1, 0xa]);
+        assert_eq!(vec![1, 0xa], data);
+        assert_eq!(vec![true, false], acks);
+    }
+
+    #[test]
+    fn test_receiver_dynamic_truncates_to_buffer() {
+        let _guard = ReactorGuard::acquire();
+
+        let (data, acks) = receive_dynamic(3, &[10, 0xa, 0xb]);
+        assert_eq!(vec![10, 0xa, 0xb], data);
+        assert_eq!(vec![true, true, false], acks);
+    }
+}
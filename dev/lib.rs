@@ -11,14 +11,22 @@ extern crate breactor;
 extern crate futures;
 extern crate stm32f4;
 
-mod circular_buffer;
-// #[cfg(test)]
-// mod debug;
+#[cfg(test)]
+mod debug;
 mod resettable_stream;
 
+pub mod alloc_bench;
+pub mod brainfuck;
+pub mod config;
 pub mod cs43l22;
 pub mod esp8266;
+pub mod fixed;
 pub mod htu21d;
 pub mod i2c;
+pub mod log;
+pub mod poll_until;
+pub mod read_exact;
 pub mod rng;
 pub mod usart;
+
+pub use crate::read_exact::read_exact;
@@ -0,0 +1,312 @@
+//! Console logging and the runtime log level.
+//!
+//! The threshold is stored in an atomic, so it can be changed at any
+//! time (e.g., from the terminal) without reflashing.
+use core::array::FixedSizeArray;
+use core::fmt;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::task::Context;
+
+use futures::{Future, Poll, Sink};
+
+use breactor::mutex::{LockFuture, Mutex, MutexLock};
+
+use crate::usart::Usart;
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
+#[repr(u8)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+const LEVELS: [Level; 4] = [Level::Error, Level::Warn, Level::Info, Level::Debug];
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+impl Level {
+    /// Parses a level name as accepted by the `loglevel` command.
+    pub fn parse(name: &[u8]) -> Option<Level> {
+        LEVELS
+            .iter()
+            .cloned()
+            .find(|level| level.as_str().as_bytes() == name)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+impl ::core::fmt::Display for Level {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Returns the current threshold.
+pub fn level() -> Level {
+    LEVELS[LEVEL.load(Ordering::SeqCst) as usize]
+}
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::SeqCst);
+}
+
+/// Returns true if messages of the given level should be printed.
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::SeqCst)
+}
+
+/// Maximum length of a log message. Longer messages are truncated.
+pub const MESSAGE_SIZE: usize = 512;
+
+/// A message formatted into a fixed buffer.
+#[allow(missing_debug_implementations)]
+pub struct Message {
+    buf: [u8; MESSAGE_SIZE],
+    len: usize,
+}
+
+impl Message {
+    pub const fn new() -> Message {
+        Message {
+            buf: [0; MESSAGE_SIZE],
+            len: 0,
+        }
+    }
+
+    pub fn format(args: fmt::Arguments) -> Message {
+        let mut message = Message::new();
+        let _ = fmt::Write::write_fmt(&mut message, args);
+        message
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl Default for Message {
+    fn default() -> Message {
+        Message::new()
+    }
+}
+
+impl fmt::Write for Message {
+    /// Stores as much of `s` as fits.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = ::core::cmp::min(s.len(), MESSAGE_SIZE - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+
+        if len == s.len() {
+            Ok(())
+        } else {
+            Err(fmt::Error)
+        }
+    }
+}
+
+/// Serializes messages of different tasks written to a USART.
+#[allow(missing_debug_implementations)]
+pub struct Console<A: 'static, B: 'static> {
+    usart: &'static Usart<A, B>,
+    mutex: Mutex,
+}
+
+impl<A: FixedSizeArray<u8>, B: FixedSizeArray<u8>> Console<A, B> {
+    pub const fn new(usart: &'static Usart<A, B>) -> Console<A, B> {
+        Console {
+            usart,
+            mutex: Mutex::new(),
+        }
+    }
+
+    /// Formats the message right away, and returns a future that
+    /// writes it once the console is available.
+    ///
+    /// If `level` is below the current threshold, the message is
+    /// dropped and the future resolves immediately.
+    pub fn log(&self, level: Level, args: fmt::Arguments) -> Log<A, B> {
+        if !enabled(level) {
+            return Log {
+                usart: self.usart,
+                lock: None,
+                guard: None,
+                message: Message::new(),
+                pos: 0,
+            };
+        }
+
+        self.print(args)
+    }
+
+    /// Same as `log`, but regardless of the log level. Intended for
+    /// replies to user commands.
+    pub fn print(&self, args: fmt::Arguments) -> Log<A, B> {
+        self.send(Message::format(args))
+    }
+
+    /// Returns a future that writes `message` once the console is
+    /// available.
+    ///
+    /// The console stays locked until the whole message is in the
+    /// USART buffer, so messages don't interleave. If the buffer is
+    /// full, the future waits for the USART rather than dropping the
+    /// rest of the message.
+    pub fn send(&self, message: Message) -> Log<A, B> {
+        Log {
+            usart: self.usart,
+            lock: Some(self.mutex.lock()),
+            guard: None,
+            message,
+            pos: 0,
+        }
+    }
+}
+
+/// Future returned by `Console::log`.
+#[allow(missing_debug_implementations)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Log<'a, A: 'static, B: 'static> {
+    usart: &'static Usart<A, B>,
+    lock: Option<LockFuture<'a>>,
+    guard: Option<MutexLock<'a>>,
+    /// Owned by the future, so the message can be formatted before the
+    /// console is locked. Sent the same way `Usart::write_all` does.
+    message: Message,
+    pos: usize,
+}
+
+impl<'a, A: FixedSizeArray<u8>, B: FixedSizeArray<u8>> Future for Log<'a, A, B> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = &mut *self;
+
+        if let Some(ref mut lock) = this.lock {
+            this.guard = Some(ready!(Pin::new(lock).poll(cx)));
+            this.lock = None;
+        }
+
+        let mut usart = this.usart;
+        while this.pos < this.message.len {
+            // The USART never fails.
+            let _ = ready!(Pin::new(&mut usart).poll_ready(cx));
+            let _ = Pin::new(&mut usart).start_send(this.message.buf[this.pos]);
+            this.pos += 1;
+        }
+
+        this.guard = None;
+        Poll::Ready(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::FutureExt;
+
+    use breactor::REACTOR;
+
+    use crate::debug::{mock_usart, poll_in_task, ReactorGuard};
+
+    const TASK: u32 = 31;
+
+    #[test]
+    fn test_set_and_read_back() {
+        // Keeps the level stable for the console tests.
+        let _guard = ReactorGuard::acquire();
+        for &name in &["error", "warn", "info", "debug"] {
+            let level = Level::parse(name.as_bytes()).unwrap();
+            set_level(level);
+            assert_eq!(level, super::level());
+            assert_eq!(name, format!("{}", super::level()));
+        }
+
+        set_level(Level::Warn);
+        assert!(enabled(Level::Error));
+        assert!(enabled(Level::Warn));
+        assert!(!enabled(Level::Info));
+
+        set_level(Level::Info);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert_eq!(None, Level::parse(b""));
+        assert_eq!(None, Level::parse(b"DEBUG"));
+        assert_eq!(None, Level::parse(b"trace"));
+    }
+
+    #[test]
+    fn test_message_is_truncated() {
+        let line = [b'x'; MESSAGE_SIZE - 2];
+        let line = ::core::str::from_utf8(&line).unwrap();
+
+        let message = Message::format(format_args!("{}\r\n", line));
+        assert_eq!(MESSAGE_SIZE, message.as_bytes().len());
+
+        let message = Message::format(format_args!("{}abc", line));
+        assert_eq!(b"xxab", &message.as_bytes()[MESSAGE_SIZE - 4..]);
+    }
+
+    #[test]
+    fn test_console_serializes_tasks() {
+        let _guard = ReactorGuard::acquire();
+        let usart: &'static _ = Box::leak(Box::new(mock_usart()));
+        let console: &'static _ = Box::leak(Box::new(Console::new(usart)));
+
+        // Each message is longer than the writer buffer, so the tasks
+        // wait for the USART while they hold the console.
+        let task = |name: &'static str| {
+            console
+                .print(format_args!("{} 1\r\n", name))
+                .then(move |()| console.print(format_args!("{} 2\r\n", name)))
+        };
+
+        let mut sent = Vec::new();
+        unsafe {
+            assert!(REACTOR.add_task(1, Pin::new_unchecked(Box::leak(Box::new(task("first"))))));
+            assert!(REACTOR.add_task(2, Pin::new_unchecked(Box::leak(Box::new(task("second"))))));
+
+            while REACTOR.is_task_present(1) || REACTOR.is_task_present(2) {
+                REACTOR.run();
+                // As the interrupt handler would.
+                sent.extend(::core::iter::from_fn(|| usart.try_pop_writer()));
+            }
+        }
+
+        let sent = String::from_utf8(sent).unwrap();
+        let mut lines: Vec<&str> = sent.split_terminator("\r\n").collect();
+        // Tasks may take turns, but lines are never mixed.
+        lines.sort();
+        assert_eq!(vec!["first 1", "first 2", "second 1", "second 2"], lines);
+    }
+
+    #[test]
+    fn test_console_drops_disabled_levels() {
+        let _guard = ReactorGuard::acquire();
+        let usart: &'static _ = Box::leak(Box::new(mock_usart()));
+        let console = Console::new(usart);
+
+        set_level(Level::Info);
+        let mut debug = console.log(Level::Debug, format_args!("d"));
+        assert_eq!(Poll::Ready(()), poll_in_task(TASK, &mut debug));
+        assert_eq!(None, usart.try_pop_writer());
+
+        let mut error = console.log(Level::Error, format_args!("e"));
+        assert_eq!(Poll::Ready(()), poll_in_task(TASK, &mut error));
+        assert_eq!(Some(b'e'), usart.try_pop_writer());
+    }
+}
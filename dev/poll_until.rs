@@ -0,0 +1,103 @@
+//! Polling for conditions that have no interrupt.
+use core::pin::Pin;
+use core::task::Context;
+
+use futures::{Future, Poll};
+
+use breactor::time::{Delay, Duration};
+
+/// Returns a future that resolves once `check` returns `true`.
+///
+/// `check` is called on the first poll and then once every
+/// `interval`, so the task sleeps between checks instead of
+/// busy-waiting. Useful for status bits that are not wired to an
+/// interrupt (e.g., sensor data ready).
+pub fn poll_until<F: FnMut() -> bool>(check: F, interval: Duration) -> PollUntil<F> {
+    PollUntil {
+        check,
+        interval,
+        delay: None,
+    }
+}
+
+#[allow(missing_debug_implementations)]
+#[must_use = "futures do nothing unless polled"]
+pub struct PollUntil<F> {
+    check: F,
+    interval: Duration,
+    delay: Option<Delay>,
+}
+
+impl<F> Unpin for PollUntil<F> {}
+
+impl<F: FnMut() -> bool> Future for PollUntil<F> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        loop {
+            if let Some(ref mut delay) = this.delay {
+                ready!(Pin::new(delay).poll(cx));
+            }
+
+            if (this.check)() {
+                this.delay = None;
+                return Poll::Ready(());
+            }
+
+            let interval = this.interval;
+            match this.delay {
+                Some(ref mut delay) => delay.reset(interval),
+                None => this.delay = Some(Delay::new(interval)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::debug::ReactorGuard;
+    use breactor::time::{now, tick};
+    use futures::task::noop_waker;
+
+    #[test]
+    fn test_checks_once_per_interval() {
+        let _guard = ReactorGuard::acquire();
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let mut checks = Vec::new();
+        let mut f = poll_until(
+            || {
+                checks.push(now());
+                checks.len() == 4
+            },
+            Duration::from_millis(3),
+        );
+
+        let start = now();
+        let mut ticks = 0;
+        while Pin::new(&mut f).poll(&mut cx).is_pending() {
+            tick();
+            ticks += 1;
+        }
+        drop(f);
+
+        // 3 ms is 3 ticks at the default frequency, plus one tick as
+        // the delay is never shorter than requested.
+        assert_eq!(12, ticks);
+        let offsets: Vec<u32> = checks.iter().map(|t| t.wrapping_sub(start)).collect();
+        assert_eq!(vec![0, 4, 8, 12], offsets);
+    }
+
+    #[test]
+    fn test_ready_condition_resolves_immediately() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let mut f = poll_until(|| true, Duration::from_millis(3));
+        assert_eq!(Poll::Ready(()), Pin::new(&mut f).poll(&mut cx));
+    }
+}
@@ -0,0 +1,118 @@
+//! Reading a fixed number of bytes from a stream.
+use core::pin::Pin;
+use core::task::Context;
+
+use futures::{Future, Poll, Stream};
+
+/// The stream has ended before the buffer was filled.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct StreamEnded {
+    /// Number of bytes read before the end.
+    pub read: usize,
+}
+
+/// Returns a future that fills `buf` with the next `buf.len()` items
+/// of `stream`.
+///
+/// The stream is borrowed, so it can be used for further reads after
+/// the future resolves. If the stream ends early, bytes read so far
+/// are left in the beginning of `buf`.
+pub fn read_exact<'a, St>(stream: &'a mut St, buf: &'a mut [u8]) -> ReadExact<'a, St>
+where
+    St: Stream<Item = u8> + Unpin,
+{
+    ReadExact {
+        stream,
+        buf,
+        read: 0,
+    }
+}
+
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct ReadExact<'a, St> {
+    stream: &'a mut St,
+    buf: &'a mut [u8],
+    read: usize,
+}
+
+impl<'a, St> Unpin for ReadExact<'a, St> {}
+
+impl<'a, St> Future for ReadExact<'a, St>
+where
+    St: Stream<Item = u8> + Unpin,
+{
+    type Output = Result<(), StreamEnded>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        while this.read < this.buf.len() {
+            match ready!(Pin::new(&mut *this.stream).poll_next(cx)) {
+                Some(x) => {
+                    this.buf[this.read] = x;
+                    this.read += 1;
+                }
+                None => return Poll::Ready(Err(StreamEnded { read: this.read })),
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::debug::TestChannel;
+    use futures::task::noop_waker;
+
+    #[test]
+    fn test_read_across_polls() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let mut channel = TestChannel::new();
+        let mut buf = [0; 5];
+        {
+            let mut f = read_exact(&mut channel, &mut buf);
+            assert_eq!(Poll::Pending, Pin::new(&mut f).poll(&mut cx));
+
+            f.stream.stream().extend(b"ab");
+            assert_eq!(Poll::Pending, Pin::new(&mut f).poll(&mut cx));
+
+            f.stream.stream().extend(b"cdef");
+            assert_eq!(Poll::Ready(Ok(())), Pin::new(&mut f).poll(&mut cx));
+        }
+
+        assert_eq!(b"abcde", &buf);
+        // The rest is left in the stream.
+        assert_eq!(Some(&b'f'), channel.stream().front());
+    }
+
+    #[test]
+    fn test_early_end() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let mut stream = futures::stream::iter(b"xy".iter().cloned());
+        let mut buf = [0; 3];
+        let mut f = read_exact(&mut stream, &mut buf);
+
+        assert_eq!(
+            Poll::Ready(Err(StreamEnded { read: 2 })),
+            Pin::new(&mut f).poll(&mut cx)
+        );
+        assert_eq!(b"xy", &buf[..2]);
+    }
+
+    #[test]
+    fn test_empty_buffer() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let mut channel = TestChannel::<u8>::new();
+        let mut f = read_exact(&mut channel, &mut []);
+        assert_eq!(Poll::Ready(Ok(())), Pin::new(&mut f).poll(&mut cx));
+    }
+}
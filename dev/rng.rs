@@ -1,6 +1,6 @@
 //! Random number generator.
 use core::pin::Pin;
-use core::sync::atomic::{AtomicU32, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use core::task::Context;
 
 use stm32f4::rng;
@@ -13,12 +13,14 @@ use futures::{Poll, Stream};
 pub static mut RNG: Rng = Rng {
     inner: unsafe { &rng::RNG },
     task: AtomicU32::new(0),
+    poll_mode: AtomicBool::new(false),
 };
 
 #[allow(missing_debug_implementations)]
 pub struct Rng<'a> {
     inner: &'a rng::Rng,
     task: AtomicU32,
+    poll_mode: AtomicBool,
 }
 
 impl<'a> Rng<'a> {
@@ -29,6 +31,15 @@ impl<'a> Rng<'a> {
     pub fn disable(&self) {
         self.inner.disable();
     }
+
+    /// In poll mode, the stream doesn't rely on the RNG interrupt and
+    /// keeps the task ready until a number is available.
+    ///
+    /// This makes RNG work even if its IRQ is not enabled in NVIC, at
+    /// the cost of spinning the CPU.
+    pub fn set_poll_mode(&self, poll_mode: bool) {
+        self.poll_mode.store(poll_mode, Ordering::SeqCst);
+    }
 }
 
 impl<'a> Stream for Rng<'a> {
@@ -51,7 +62,12 @@ impl<'a> Stream for Rng<'a> {
                 Poll::Ready(Some(Err(err)))
             }
             Ok(None) => {
-                self.inner.it_enable();
+                if self.poll_mode.load(Ordering::SeqCst) {
+                    self.task.fetch_and(!task, Ordering::SeqCst);
+                    REACTOR.set_ready_task_mask(task);
+                } else {
+                    self.inner.it_enable();
+                }
                 Poll::Pending
             }
         }
@@ -64,3 +80,62 @@ pub unsafe extern "C" fn __isr_hash_rng() {
     REACTOR.set_ready_task_mask(task);
     RNG.inner.it_disable();
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::debug::ReactorGuard;
+    use futures::{Future, StreamExt};
+
+    const TASK: u32 = 31;
+
+    #[test]
+    fn test_poll_mode_without_interrupt() {
+        let _guard = ReactorGuard::acquire();
+
+        // Zeroed memory stands in for the RNG registers: no data is
+        // ready.
+        let regs: &'static rng::Rng = Box::leak(Box::new(unsafe { ::core::mem::zeroed() }));
+        let mut rng = Rng {
+            inner: regs,
+            task: AtomicU32::new(0),
+            poll_mode: AtomicBool::new(false),
+        };
+        rng.set_poll_mode(true);
+
+        let mut polls = 0;
+        let mut result = None;
+        {
+            let mut task = futures::future::poll_fn(|cx| {
+                polls += 1;
+                if polls == 3 {
+                    // Hardware has generated a number.
+                    let words = regs as *const rng::Rng as *mut u32;
+                    unsafe {
+                        *words.offset(1) = 0x1; // SR.DRDY
+                        *words.offset(2) = 0xdead_beef; // DR
+                    }
+                }
+
+                let res = ready!(rng.poll_next_unpin(cx));
+                result = res;
+                Poll::Ready(())
+            });
+
+            unsafe {
+                // The task finishes within `run()`, so the reactor
+                // doesn't keep the reference.
+                let task: &mut dyn Future<Output = ()> = &mut task;
+                let task: &'static mut dyn Future<Output = ()> = ::core::mem::transmute(task);
+                assert!(REACTOR.add_task(TASK, Pin::new_unchecked(task)));
+                REACTOR.run();
+            }
+        }
+
+        assert_eq!(3, polls);
+        assert_eq!(Some(Ok(0xdead_beef)), result);
+        // The interrupt has not been enabled.
+        assert_eq!(0, unsafe { *(regs as *const rng::Rng as *const u32) } & 0x8);
+    }
+}
@@ -4,14 +4,21 @@ use core::pin::Pin;
 use core::task::Context;
 use stm32f4::usart;
 
-use crate::circular_buffer::CircularBuffer;
 use crate::resettable_stream::ResettableStream;
 
-use futures::{Poll, Sink, Stream};
+use futures::{Future, Poll, Sink, Stream, TryFutureExt};
 
 use core::array::FixedSizeArray;
+#[cfg(feature = "usart-dma")]
+use core::sync::atomic::AtomicUsize;
 use core::sync::atomic::{AtomicU32, Ordering};
 
+#[cfg(feature = "usart-dma")]
+use stm32f4::dma;
+
+use breactor::circular_buffer::CircularBuffer;
+use breactor::start_send_all_bytes::StartSendAllBytes;
+use breactor::start_send_bytes::SinkBytes;
 use breactor::REACTOR;
 
 #[allow(missing_debug_implementations)]
@@ -21,6 +28,60 @@ pub struct Usart<A, B> {
     reader_task_mask: AtomicU32,
     writer_buffer: CircularBuffer<u8, A>,
     reader_buffer: CircularBuffer<u8, B>,
+    rx_total: AtomicU32,
+    rx_dropped: AtomicU32,
+    tx_total: AtomicU32,
+    overruns: AtomicU32,
+    framing_errors: AtomicU32,
+    noise_errors: AtomicU32,
+    parity_errors: AtomicU32,
+    #[cfg(feature = "usart-dma")]
+    tx_dma: Option<TxDma>,
+    /// Length of the running DMA transfer, 0 if the stream is idle,
+    /// or `TX_DMA_CLAIMED` while a transfer is being started.
+    #[cfg(feature = "usart-dma")]
+    tx_dma_len: AtomicUsize,
+}
+
+/// DMA stream that transmits for a USART.
+///
+/// The stream and channel must be the ones mapped to the USART TX
+/// request, e.g., DMA1 stream 6 channel 4 for USART2 (see the
+/// reference manual, "DMA1 request mapping").
+#[cfg(feature = "usart-dma")]
+#[allow(missing_debug_implementations)]
+pub struct TxDma {
+    pub dma: &'static dma::Dma,
+    pub stream: usize,
+    pub channel: u32,
+}
+
+#[cfg(feature = "usart-dma")]
+const TX_DMA_CLAIMED: usize = usize::max_value();
+
+/// Traffic counters of a USART. All counters wrap around on overflow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsartStats {
+    /// Bytes received, including dropped ones.
+    pub rx_total: u32,
+    /// Bytes dropped because the reader buffer was full.
+    pub rx_dropped: u32,
+    /// Bytes transmitted.
+    pub tx_total: u32,
+}
+
+/// Receive error counters of a USART. All counters wrap around on
+/// overflow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct UsartErrors {
+    /// A byte arrived before the previous one was read, so it is lost.
+    pub overrun: u32,
+    /// A byte had no valid stop bit.
+    pub framing: u32,
+    /// Noise was detected while receiving a byte.
+    pub noise: u32,
+    /// A byte had a wrong parity bit.
+    pub parity: u32,
 }
 
 impl<A: FixedSizeArray<u8>, B: FixedSizeArray<u8>> Usart<A, B> {
@@ -35,6 +96,99 @@ impl<A: FixedSizeArray<u8>, B: FixedSizeArray<u8>> Usart<A, B> {
             reader_task_mask: AtomicU32::new(0),
             writer_buffer: CircularBuffer::new(writer_buffer),
             reader_buffer: CircularBuffer::new(reader_buffer),
+            rx_total: AtomicU32::new(0),
+            rx_dropped: AtomicU32::new(0),
+            tx_total: AtomicU32::new(0),
+            overruns: AtomicU32::new(0),
+            framing_errors: AtomicU32::new(0),
+            noise_errors: AtomicU32::new(0),
+            parity_errors: AtomicU32::new(0),
+            #[cfg(feature = "usart-dma")]
+            tx_dma: None,
+            #[cfg(feature = "usart-dma")]
+            tx_dma_len: AtomicUsize::new(0),
+        }
+    }
+
+    /// Creates a USART that transmits with DMA instead of the TXE
+    /// interrupt.
+    ///
+    /// `dma_tx_isr` must be called from the interrupt of the DMA
+    /// stream. The DMA clock must be enabled, and the buffers must be
+    /// in SRAM.
+    #[cfg(feature = "usart-dma")]
+    pub const fn with_tx_dma(
+        usart: &'static usart::Usart,
+        tx_dma: TxDma,
+        writer_buffer: A,
+        reader_buffer: B,
+    ) -> Usart<A, B> {
+        Usart {
+            usart,
+            writer_task_mask: AtomicU32::new(0),
+            reader_task_mask: AtomicU32::new(0),
+            writer_buffer: CircularBuffer::new(writer_buffer),
+            reader_buffer: CircularBuffer::new(reader_buffer),
+            rx_total: AtomicU32::new(0),
+            rx_dropped: AtomicU32::new(0),
+            tx_total: AtomicU32::new(0),
+            overruns: AtomicU32::new(0),
+            framing_errors: AtomicU32::new(0),
+            noise_errors: AtomicU32::new(0),
+            parity_errors: AtomicU32::new(0),
+            tx_dma: Some(tx_dma),
+            tx_dma_len: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> UsartStats {
+        UsartStats {
+            rx_total: self.rx_total.load(Ordering::SeqCst),
+            rx_dropped: self.rx_dropped.load(Ordering::SeqCst),
+            tx_total: self.tx_total.load(Ordering::SeqCst),
+        }
+    }
+
+    pub fn error_counts(&self) -> UsartErrors {
+        UsartErrors {
+            overrun: self.overruns.load(Ordering::SeqCst),
+            framing: self.framing_errors.load(Ordering::SeqCst),
+            noise: self.noise_errors.load(Ordering::SeqCst),
+            parity: self.parity_errors.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Sends all bytes of `data`. Resolves when the last one is in the
+    /// writer buffer, so it doesn't wait for the transmission.
+    ///
+    /// `data` may be larger than the buffer: the rest is sent as the
+    /// USART makes room.
+    pub fn write_all<'a>(&'a self, data: &'a [u8]) -> impl Future<Output = Result<(), ()>> + 'a {
+        StartSendAllBytes::new(self, data).map_ok(|_| ())
+    }
+
+    /// Counts the receive errors the USART reports.
+    ///
+    /// Error flags are set along with RXNE and are cleared by reading
+    /// the received byte, so this must be called before. Without a
+    /// received byte, the flags are cleared here. Bytes with framing,
+    /// noise, or parity errors are still delivered.
+    unsafe fn count_errors(&self) {
+        let errors = [
+            (usart::InterruptFlag::ORE, &self.overruns),
+            (usart::InterruptFlag::FE, &self.framing_errors),
+            (usart::InterruptFlag::NE, &self.noise_errors),
+            (usart::InterruptFlag::PE, &self.parity_errors),
+        ];
+
+        for &(flag, counter) in &errors {
+            if self.usart.it_flag_status(flag) {
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                if !self.usart.it_flag_status(usart::InterruptFlag::RXNE) {
+                    self.usart.it_clear_flag(flag);
+                }
+            }
         }
     }
 
@@ -42,17 +196,125 @@ impl<A: FixedSizeArray<u8>, B: FixedSizeArray<u8>> Usart<A, B> {
         let res = self.writer_buffer.push(item);
         if res {
             self.writer_task_mask.store(0, Ordering::SeqCst);
-
-            // This triggers TXE interrupt if transmitter is already
-            // empty, so the USART catches up with new data.
-            self.usart.it_enable(usart::Interrupt::TXE);
+            self.start_transmit();
         }
         res
     }
 
+    /// Pushes as many bytes of `bytes` into the writer buffer as fit,
+    /// and starts the transmission once for all of them.
+    ///
+    /// Returns the number of bytes pushed.
+    pub fn try_push_writer_bytes(&self, bytes: &[u8]) -> usize {
+        let pushed = self.writer_buffer.push_slice(bytes);
+        if pushed != 0 {
+            self.writer_task_mask.store(0, Ordering::SeqCst);
+            self.start_transmit();
+        }
+        pushed
+    }
+
+    /// Makes the USART catch up with new data in the writer buffer.
+    fn start_transmit(&self) {
+        #[cfg(feature = "usart-dma")]
+        {
+            if self.tx_dma.is_some() {
+                self.start_tx_dma();
+                return;
+            }
+        }
+
+        // This triggers TXE interrupt if transmitter is already
+        // empty.
+        self.usart.it_enable(usart::Interrupt::TXE);
+    }
+
+    /// Starts a DMA transfer of the bytes at the head of the writer
+    /// buffer, unless one is running already.
+    ///
+    /// Only the contiguous part of the buffer is transferred. The rest
+    /// goes with the next transfer, started from `dma_tx_isr`.
+    #[cfg(feature = "usart-dma")]
+    fn start_tx_dma(&self) {
+        let tx_dma = match self.tx_dma {
+            Some(ref tx_dma) => tx_dma,
+            None => return,
+        };
+
+        loop {
+            // The claim makes this the only consumer of the writer
+            // buffer until the transfer completes.
+            if self
+                .tx_dma_len
+                .compare_and_swap(0, TX_DMA_CLAIMED, Ordering::SeqCst)
+                != 0
+            {
+                return;
+            }
+
+            let data = self.writer_buffer.peek_contiguous();
+            if !data.is_empty() {
+                let len = ::core::cmp::min(data.len(), 0xffff);
+                // The length must be in place before the transfer
+                // complete interrupt can fire.
+                self.tx_dma_len.store(len, Ordering::SeqCst);
+
+                self.usart.set_dma_transmit(true);
+                #[allow(clippy::cast_possible_truncation)]
+                unsafe {
+                    tx_dma.dma.start_mem_to_periph(
+                        tx_dma.stream,
+                        tx_dma.channel,
+                        self.usart.dr_address(),
+                        data.as_ptr(),
+                        len as u16,
+                    );
+                }
+                return;
+            }
+
+            self.tx_dma_len.store(0, Ordering::SeqCst);
+
+            // A byte pushed while the buffer was claimed is not
+            // picked up by the producer, so check again.
+            if self.writer_buffer.was_empty() {
+                return;
+            }
+        }
+    }
+
+    /// DMA interrupt service routine.
+    ///
+    /// It should be called for the interrupt of the DMA stream passed
+    /// to `with_tx_dma`.
+    #[cfg(feature = "usart-dma")]
+    pub unsafe fn dma_tx_isr(&self) {
+        let tx_dma = match self.tx_dma {
+            Some(ref tx_dma) => tx_dma,
+            None => return,
+        };
+
+        if !tx_dma.dma.is_transfer_complete(tx_dma.stream) {
+            return;
+        }
+        tx_dma.dma.clear_transfer_complete(tx_dma.stream);
+
+        let len = self.tx_dma_len.load(Ordering::SeqCst);
+        self.writer_buffer.consume(len);
+        #[allow(clippy::cast_possible_truncation)]
+        self.tx_total.fetch_add(len as u32, Ordering::SeqCst);
+        self.tx_dma_len.store(0, Ordering::SeqCst);
+
+        let task_mask = self.writer_task_mask.swap(0, Ordering::SeqCst);
+        REACTOR.set_ready_task_mask(task_mask);
+
+        self.start_tx_dma();
+    }
+
     pub fn try_pop_writer(&self) -> Option<u8> {
         let res = self.writer_buffer.pop();
         if res.is_some() {
+            self.tx_total.fetch_add(1, Ordering::SeqCst);
             let task_mask = self.writer_task_mask.swap(0, Ordering::SeqCst);
             REACTOR.set_ready_task_mask(task_mask);
         }
@@ -60,10 +322,13 @@ impl<A: FixedSizeArray<u8>, B: FixedSizeArray<u8>> Usart<A, B> {
     }
 
     pub fn try_push_reader(&self, item: u8) -> bool {
+        self.rx_total.fetch_add(1, Ordering::SeqCst);
         let res = self.reader_buffer.push(item);
         if res {
             let task_mask = self.reader_task_mask.swap(0, Ordering::SeqCst);
             REACTOR.set_ready_task_mask(task_mask);
+        } else {
+            self.rx_dropped.fetch_add(1, Ordering::SeqCst);
         }
         res
     }
@@ -91,10 +356,13 @@ impl<A: FixedSizeArray<u8>, B: FixedSizeArray<u8>> Usart<A, B> {
     /// # }
     /// ```
     pub unsafe fn isr(&self) {
+        self.count_errors();
+
         if self.usart.it_status(usart::Interrupt::RXNE) {
             let c = self.usart.get_unsafe();
             // If the buffer is full, we discard _new_ input.
-            // That's not ideal :(
+            // That's not ideal :( At least, it is counted in
+            // `rx_dropped`.
             let _ = self.try_push_reader(c);
         }
 
@@ -129,10 +397,6 @@ impl<'a, A: FixedSizeArray<u8>, B: FixedSizeArray<u8>> Sink<u8> for &'a Usart<A,
 
     fn start_send(self: Pin<&mut Self>, item: u8) -> Result<(), Self::SinkError> {
         if self.try_push_writer(item) {
-            // This triggers TXE interrupt if transmitter was
-            // empty, so the USART catches up with new data.
-            self.usart.it_enable(usart::Interrupt::TXE);
-
             Ok(())
         } else {
             panic!("Usart: start_send was called, but the queue is not ready");
@@ -156,6 +420,22 @@ impl<'a, A: FixedSizeArray<u8>, B: FixedSizeArray<u8>> Sink<u8> for &'a Usart<A,
     }
 }
 
+impl<'a, A: FixedSizeArray<u8>, B: FixedSizeArray<u8>> SinkBytes for &'a Usart<A, B> {
+    fn poll_send_bytes(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        bytes: &[u8],
+    ) -> Poll<Result<usize, Self::SinkError>> {
+        self.writer_task_mask
+            .store(REACTOR.get_current_task_mask(), Ordering::SeqCst);
+
+        match self.try_push_writer_bytes(bytes) {
+            0 => Poll::Pending,
+            pushed => Poll::Ready(Ok(pushed)),
+        }
+    }
+}
+
 impl<'a, A: FixedSizeArray<u8>, B: FixedSizeArray<u8>> Stream for &'a Usart<A, B> {
     type Item = u8;
 
@@ -178,3 +458,215 @@ impl<'a, A: FixedSizeArray<u8>, B: FixedSizeArray<u8>> ResettableStream for &'a
         while let Some(_) = self.try_pop_reader() {}
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use breactor::start_send_bytes::StartSendBytes;
+
+    use crate::debug::{mock_usart, poll_in_task, ReactorGuard};
+
+    #[test]
+    fn test_rx_drops_are_counted() {
+        let usart = mock_usart();
+
+        // The buffer holds 3 bytes.
+        for c in b"abcde" {
+            usart.try_push_reader(*c);
+        }
+        assert_eq!(
+            UsartStats {
+                rx_total: 5,
+                rx_dropped: 2,
+                tx_total: 0,
+            },
+            usart.stats()
+        );
+
+        assert_eq!(Some(b'a'), usart.try_pop_reader());
+        assert!(usart.try_push_reader(b'f'));
+        assert!(!usart.try_push_reader(b'g'));
+        assert_eq!(7, usart.stats().rx_total);
+        assert_eq!(3, usart.stats().rx_dropped);
+    }
+
+    /// Writes a register of the mock USART at `offset` bytes.
+    fn set_reg(mock: &Usart<[u8; 4], [u8; 4]>, offset: usize, value: u32) {
+        unsafe {
+            let base = mock.usart as *const usart::Usart as *mut u8;
+            *(base.add(offset) as *mut u32) = value;
+        }
+    }
+
+    const SR: usize = 0x00;
+    const DR: usize = 0x04;
+    const CR1: usize = 0x0C;
+
+    const SR_ORE: u32 = 1 << 3;
+    const SR_RXNE: u32 = 1 << 5;
+    const CR1_RXNEIE: u32 = 1 << 5;
+
+    #[test]
+    fn test_overrun_is_counted() {
+        let usart = mock_usart();
+        set_reg(&usart, SR, SR_ORE);
+
+        unsafe { usart.isr() };
+        assert_eq!(
+            UsartErrors {
+                overrun: 1,
+                ..UsartErrors::default()
+            },
+            usart.error_counts()
+        );
+    }
+
+    #[test]
+    fn test_byte_before_overrun_is_received() {
+        let usart = mock_usart();
+        set_reg(&usart, SR, SR_ORE | SR_RXNE);
+        set_reg(&usart, DR, u32::from(b'a'));
+        set_reg(&usart, CR1, CR1_RXNEIE);
+
+        unsafe { usart.isr() };
+        assert_eq!(1, usart.error_counts().overrun);
+        assert_eq!(Some(b'a'), usart.try_pop_reader());
+    }
+
+    #[test]
+    fn test_tx_is_counted_when_transmitted() {
+        let usart = mock_usart();
+
+        assert!(usart.try_push_writer(b'a'));
+        assert!(usart.try_push_writer(b'b'));
+        assert_eq!(0, usart.stats().tx_total);
+
+        assert_eq!(Some(b'a'), usart.try_pop_writer());
+        assert_eq!(Some(b'b'), usart.try_pop_writer());
+        assert_eq!(None, usart.try_pop_writer());
+        assert_eq!(2, usart.stats().tx_total);
+    }
+
+    #[test]
+    fn test_write_all_larger_than_buffer() {
+        let _guard = ReactorGuard::acquire();
+        let usart = mock_usart();
+        let mut write = Box::pin(usart.write_all(b"hello"));
+
+        // The buffer holds 3 bytes.
+        assert_eq!(Poll::Pending, poll_in_task(1, &mut write));
+        assert_eq!(Some(b'h'), usart.try_pop_writer());
+        assert_eq!(Some(b'e'), usart.try_pop_writer());
+        assert_eq!(Poll::Ready(Ok(())), poll_in_task(1, &mut write));
+
+        let sent: Vec<u8> = ::core::iter::from_fn(|| usart.try_pop_writer()).collect();
+        assert_eq!(b"llo", &sent[..]);
+    }
+
+    #[test]
+    fn test_send_bytes() {
+        let _guard = ReactorGuard::acquire();
+        let usart = mock_usart();
+        let mut send = StartSendBytes::new(&usart, b"hello");
+
+        // The buffer holds 3 bytes.
+        assert_eq!(Poll::Pending, poll_in_task(1, &mut send).map(|_| ()));
+        assert_eq!(Some(b'h'), usart.try_pop_writer());
+        assert_eq!(Some(b'e'), usart.try_pop_writer());
+        assert!(poll_in_task(1, &mut send).is_ready());
+
+        let sent: Vec<u8> = ::core::iter::from_fn(|| usart.try_pop_writer()).collect();
+        assert_eq!(b"llo", &sent[..]);
+    }
+
+    #[cfg(feature = "usart-dma")]
+    mod tx_dma {
+        use super::*;
+
+        const STREAM: usize = 6;
+        const HISR: usize = 0x04;
+        const S6CR: usize = 0x10 + 0x18 * STREAM;
+        const S6NDTR: usize = S6CR + 0x04;
+        const S6M0AR: usize = S6CR + 0x0C;
+
+        const HISR_TCIF6: u32 = 1 << 21;
+        const CR_EN: u32 = 1 << 0;
+
+        /// Returns the USART, its DMA registers, and the address of
+        /// the writer buffer as DMA sees it.
+        fn mock_usart() -> (&'static Usart<[u8; 4], [u8; 4]>, *mut u8, u32) {
+            let regs: &'static usart::Usart = Box::leak(Box::new(unsafe { ::core::mem::zeroed() }));
+            let dma: &'static dma::Dma = Box::leak(Box::new(unsafe { ::core::mem::zeroed() }));
+            let usart = Box::leak(Box::new(Usart::with_tx_dma(
+                regs,
+                TxDma {
+                    dma,
+                    stream: STREAM,
+                    channel: 4,
+                },
+                [0; 4],
+                [0; 4],
+            )));
+            // The buffer is empty, so its head is the array start.
+            #[allow(clippy::cast_possible_truncation)]
+            let base = usart.writer_buffer.peek_contiguous().as_ptr() as usize as u32;
+            (usart, dma as *const dma::Dma as *mut u8, base)
+        }
+
+        fn reg(dma: *mut u8, offset: usize) -> *mut u32 {
+            unsafe { dma.add(offset) as *mut u32 }
+        }
+
+        /// Returns the length of the running transfer and the index of
+        /// its first byte in the writer buffer, and completes it.
+        fn complete(usart: &Usart<[u8; 4], [u8; 4]>, dma: *mut u8, base: u32) -> (u32, u32) {
+            unsafe {
+                assert_ne!(0, *reg(dma, S6CR) & CR_EN);
+                let transfer = (*reg(dma, S6NDTR), *reg(dma, S6M0AR) - base);
+
+                *reg(dma, S6CR) &= !CR_EN;
+                *reg(dma, HISR) = HISR_TCIF6;
+                usart.dma_tx_isr();
+                *reg(dma, HISR) = 0;
+
+                transfer
+            }
+        }
+
+        #[test]
+        fn test_transfer_starts_on_push() {
+            let (usart, dma, base) = mock_usart();
+
+            assert!(usart.try_push_writer(b'a'));
+            assert!(usart.try_push_writer(b'b'));
+            // The second byte waits for the first transfer.
+            assert_eq!((1, 0), complete(usart, dma, base));
+            assert_eq!((1, 1), complete(usart, dma, base));
+            assert_eq!(2, usart.stats().tx_total);
+
+            assert_eq!(0, unsafe { *reg(dma, S6CR) } & CR_EN);
+            assert!(usart.writer_buffer.was_empty());
+        }
+
+        #[test]
+        fn test_transfer_wraps() {
+            let (usart, dma, base) = mock_usart();
+
+            assert!(usart.try_push_writer(b'a'));
+            assert!(usart.try_push_writer(b'b'));
+            assert!(usart.try_push_writer(b'c'));
+            assert_eq!((1, 0), complete(usart, dma, base));
+            assert!(usart.try_push_writer(b'd'));
+            assert_eq!((2, 1), complete(usart, dma, base));
+
+            // "d" is the last item of the array, and "e" and "f" wrap
+            // around to its start.
+            assert!(usart.try_push_writer(b'e'));
+            assert!(usart.try_push_writer(b'f'));
+            assert_eq!((1, 3), complete(usart, dma, base));
+            assert_eq!((2, 0), complete(usart, dma, base));
+            assert_eq!(6, usart.stats().tx_total);
+        }
+    }
+}
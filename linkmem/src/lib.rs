@@ -6,18 +6,356 @@
 #![no_std]
 
 extern crate smalloc;
+extern crate stm32f4;
 
-use smalloc::Smalloc;
+use core::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use smalloc::{HeapStats, Smalloc};
+
+pub use smalloc::BlockInfo;
+use stm32f4::IrqLock;
 
 #[cfg_attr(not(test), global_allocator)]
-static mut ALLOCATOR: Smalloc = Smalloc {
-    start: 0 as *mut u8,
-    size: 0,
-};
+#[cfg_attr(test, allow(dead_code))]
+static GLOBAL: Allocator = Allocator;
+
+static mut ALLOCATOR: Smalloc = Smalloc::new(0 as *mut u8, 0);
+
+/// `fn(usize)` set with `set_oom_hook()`, or null.
+static OOM_HOOK: AtomicPtr<()> = AtomicPtr::new(0 as *mut ());
+
+/// Allocator for DMA buffers.
+///
+/// It is separate from the global allocator, so the general heap can
+/// be placed in memory that DMA can't access (e.g., CCM RAM).
+static mut DMA_ALLOCATOR: Smalloc = Smalloc::new(0 as *mut u8, 0);
+
+/// Errors returned by `init()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitError {
+    /// The global allocator is already initialized and `reinit` is
+    /// not set.
+    AlreadyInitialized,
+    /// The new allocator failed to initialize.
+    Smalloc(smalloc::InitError),
+}
+
+impl From<smalloc::InitError> for InitError {
+    fn from(err: smalloc::InitError) -> InitError {
+        InitError::Smalloc(err)
+    }
+}
+
+/// Initializes `alloc` and installs it as the global allocator.
+/// Returns the previous one.
+///
+/// Replacing an initialized allocator (e.g., moving from a boot arena
+/// to the main SRAM) requires `reinit`. Blocks allocated from the
+/// previous allocator must not be freed after that, as the new one
+/// doesn't own them. Nothing must allocate while the allocator is
+/// being replaced.
+///
+/// On failure, the previous allocator is kept.
+pub fn init(alloc: Smalloc, reinit: bool) -> Result<Smalloc, InitError> {
+    unsafe {
+        if !reinit && !ALLOCATOR.start.is_null() {
+            return Err(InitError::AlreadyInitialized);
+        }
+
+        alloc.init()?;
+        Ok(core::mem::replace(&mut ALLOCATOR, alloc))
+    }
+}
+
+/// Registers `f` to be called when the global allocator fails to
+/// serve a request. `f` receives the requested size.
+///
+/// The hook is called from inside the allocator, so it must not
+/// allocate. It can, e.g., log the size and `stats()` before the
+/// allocation error handler halts the system.
+pub fn set_oom_hook(f: fn(usize)) {
+    OOM_HOOK.store(f as *mut (), Ordering::SeqCst);
+}
+
+/// Calls the OOM hook if `ptr` is null.
+fn check_oom(ptr: *mut u8, size: usize) -> *mut u8 {
+    if ptr.is_null() {
+        let hook = OOM_HOOK.load(Ordering::SeqCst);
+        if !hook.is_null() {
+            let hook: fn(usize) = unsafe { core::mem::transmute(hook) };
+            hook(size);
+        }
+    }
+    ptr
+}
+
+/// Runs `f` on `ALLOCATOR` with interrupts disabled.
+///
+/// Memory can be allocated and freed from interrupt handlers as well
+/// as from tasks, so an interrupt must not preempt the allocator
+/// halfway through updating the free lists.
+///
+/// On non-ARM targets there are no interrupts, so the lock is a
+/// no-op (it only updates the emulated PRIMASK).
+fn with_allocator<R>(f: impl FnOnce(&Smalloc) -> R) -> R {
+    unsafe {
+        let _lock = IrqLock::new();
+        f(&ALLOCATOR)
+    }
+}
+
+/// Runs `f` on `DMA_ALLOCATOR` with interrupts disabled, see
+/// `with_allocator()`.
+fn with_dma_allocator<R>(f: impl FnOnce(&Smalloc) -> R) -> R {
+    unsafe {
+        let _lock = IrqLock::new();
+        f(&DMA_ALLOCATOR)
+    }
+}
+
+/// Forwards to `ALLOCATOR`, reporting failures to the OOM hook.
+///
+/// The OOM hook is called after the lock is released.
+#[cfg_attr(test, allow(dead_code))]
+struct Allocator;
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = with_allocator(|a| GlobalAlloc::alloc(a, layout));
+        check_oom(ptr, layout.size())
+    }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = with_allocator(|a| GlobalAlloc::alloc_zeroed(a, layout));
+        check_oom(ptr, layout.size())
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        with_allocator(|a| GlobalAlloc::dealloc(a, ptr, layout))
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let ptr = with_allocator(|a| GlobalAlloc::realloc(a, ptr, layout, new_size));
+        check_oom(ptr, new_size)
+    }
+}
+
+/// Adds another memory region to the global heap.
+///
+/// `start` must be pointer-aligned, and the region must not be used
+/// for anything else.
+pub unsafe fn add_region(start: *mut u8, size: usize) -> Result<(), smalloc::InitError> {
+    with_allocator(|a| a.add_region(start, size))
+}
+
+/// Returns usage of the global heap.
+pub fn stats() -> HeapStats {
+    with_allocator(|a| unsafe { a.stats() })
+}
+
+/// Copies the first blocks of the global heap into `out`. Returns
+/// the number of blocks copied.
+///
+/// The heap is walked with interrupts disabled, so the result is a
+/// consistent snapshot, and the caller is free to allocate while
+/// going through it.
+pub fn blocks(out: &mut [BlockInfo]) -> usize {
+    with_allocator(|a| {
+        let blocks = unsafe { a.blocks() };
+        out.iter_mut().zip(blocks).map(|(o, b)| *o = b).count()
+    })
+}
+
+/// Returns the peak number of bytes allocated from the global heap.
+///
+/// Useful for tuning the heap size.
+pub fn high_water_mark() -> usize {
+    with_allocator(|a| a.high_water_mark())
+}
 
-pub fn init(alloc: Smalloc) {
+/// Installs `alloc` as the DMA buffer allocator and initializes it.
+///
+/// `alloc` must serve memory that is reachable by DMA (i.e., main
+/// SRAM, not CCM).
+pub fn init_dma(alloc: Smalloc) -> Result<(), smalloc::InitError> {
     unsafe {
-        ALLOCATOR = alloc;
-        ALLOCATOR.init();
+        DMA_ALLOCATOR = alloc;
+        DMA_ALLOCATOR.init()
+    }
+}
+
+/// Allocates a buffer that can be used for DMA transfers.
+///
+/// Returns null if the allocation fails or `init_dma()` has not been
+/// called. The buffer must be released with `dma_free()`.
+pub unsafe fn dma_alloc(layout: Layout) -> *mut u8 {
+    with_dma_allocator(|a| {
+        if a.size == 0 {
+            core::ptr::null_mut()
+        } else if layout.align() > core::mem::size_of::<*mut u8>() {
+            a.alloc_aligned(layout.size(), layout.align())
+        } else {
+            a.alloc(layout.size())
+        }
+    })
+}
+
+/// Releases a buffer allocated with `dma_alloc()`.
+pub unsafe fn dma_free(ptr: *mut u8) {
+    with_dma_allocator(|a| a.free(ptr))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use core::sync::atomic::AtomicBool;
+
+    static ALLOCATOR_BUSY: AtomicBool = AtomicBool::new(false);
+
+    /// Serializes tests that replace the global allocator.
+    struct AllocatorGuard(());
+
+    impl AllocatorGuard {
+        fn acquire() -> AllocatorGuard {
+            while ALLOCATOR_BUSY.compare_and_swap(false, true, Ordering::SeqCst) {}
+            AllocatorGuard(())
+        }
+    }
+
+    impl Drop for AllocatorGuard {
+        fn drop(&mut self) {
+            ALLOCATOR_BUSY.store(false, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_dma_alloc_uses_dma_region() {
+        const SIZE: usize = 1024;
+        const WORDS: usize = SIZE / core::mem::size_of::<usize>();
+        static mut DMA_REGION: [usize; WORDS] = [0; WORDS];
+        static mut HEAP: [usize; WORDS] = [0; WORDS];
+
+        let _guard = AllocatorGuard::acquire();
+        unsafe {
+            let layout = Layout::from_size_align_unchecked(64, 4);
+            assert!(dma_alloc(layout).is_null());
+
+            init(Smalloc::new(HEAP.as_mut_ptr() as *mut u8, SIZE), true).unwrap();
+            init_dma(Smalloc::new(DMA_REGION.as_mut_ptr() as *mut u8, SIZE)).unwrap();
+
+            let start = DMA_REGION.as_ptr() as usize;
+            for &align in &[1, 4, 16] {
+                let layout = Layout::from_size_align_unchecked(64, align);
+                let ptr = dma_alloc(layout) as usize;
+                assert!(ptr >= start && ptr + 64 <= start + SIZE);
+                assert_eq!(0, ptr % align);
+
+                // ...and not from the general heap
+                let heap = HEAP.as_ptr() as usize;
+                assert!(ptr < heap || ptr >= heap + SIZE);
+            }
+
+            let ptr = dma_alloc(layout);
+            dma_free(ptr);
+            assert_eq!(ptr, dma_alloc(layout));
+        }
+    }
+
+    #[test]
+    fn test_init_returns_previous() {
+        const SIZE: usize = 1024;
+        const WORDS: usize = SIZE / core::mem::size_of::<usize>();
+        static mut BOOT_HEAP: [usize; WORDS] = [0; WORDS];
+        static mut HEAP: [usize; WORDS] = [0; WORDS];
+
+        let _guard = AllocatorGuard::acquire();
+        unsafe {
+            let boot = BOOT_HEAP.as_mut_ptr() as *mut u8;
+            let heap = HEAP.as_mut_ptr() as *mut u8;
+
+            // Start from an uninitialized allocator.
+            ALLOCATOR = Smalloc::new(0 as *mut u8, 0);
+
+            let prev = init(Smalloc::new(boot, SIZE), false).unwrap();
+            assert!(prev.start.is_null());
+
+            assert_eq!(
+                Err(InitError::AlreadyInitialized),
+                init(Smalloc::new(heap, SIZE), false).map(|_| ())
+            );
+            assert_eq!(
+                Err(InitError::Smalloc(smalloc::InitError::RegionTooSmall)),
+                init(Smalloc::new(heap, 0), true).map(|_| ())
+            );
+            // Failures keep the current allocator.
+            assert_eq!(boot, ALLOCATOR.start);
+
+            let prev = init(Smalloc::new(heap, SIZE), true).unwrap();
+            assert_eq!(boot, prev.start);
+            assert_eq!(heap, ALLOCATOR.start);
+        }
+    }
+
+    #[test]
+    fn test_blocks_snapshot() {
+        const SIZE: usize = 1024;
+        const WORDS: usize = SIZE / core::mem::size_of::<usize>();
+        static mut HEAP: [usize; WORDS] = [0; WORDS];
+
+        let _guard = AllocatorGuard::acquire();
+        unsafe {
+            init(Smalloc::new(HEAP.as_mut_ptr() as *mut u8, SIZE), true).unwrap();
+            let ptr = with_allocator(|a| a.alloc(16));
+
+            let empty = BlockInfo {
+                addr: core::ptr::null(),
+                size: 0,
+                free: false,
+            };
+            let mut out = [empty; 4];
+            assert_eq!(2, blocks(&mut out));
+            assert_eq!(ptr as *const u8, out[0].addr);
+            assert!(!out[0].free);
+            assert!(out[1].free);
+            assert_eq!(empty, out[2]);
+
+            // Blocks that don't fit are left out.
+            assert_eq!(1, blocks(&mut out[..1]));
+        }
+    }
+
+    #[test]
+    fn test_allocator_is_locked() {
+        // The emulated PRIMASK is shared by the tests that use the
+        // allocator.
+        let _guard = AllocatorGuard::acquire();
+        assert_eq!(0, unsafe { stm32f4::__get_primask() });
+
+        // Interrupts are disabled while the allocator runs...
+        with_allocator(|_| assert_eq!(1, unsafe { stm32f4::__get_primask() }));
+
+        // ...and restored afterwards.
+        assert_eq!(0, unsafe { stm32f4::__get_primask() });
+    }
+
+    #[test]
+    fn test_oom_hook() {
+        use core::sync::atomic::AtomicUsize;
+
+        static REQUESTED: AtomicUsize = AtomicUsize::new(0);
+        fn hook(size: usize) {
+            REQUESTED.store(size, Ordering::SeqCst);
+        }
+
+        // No hook is registered yet.
+        assert!(check_oom(core::ptr::null_mut(), 16).is_null());
+
+        set_oom_hook(hook);
+
+        let mut x = 0u8;
+        assert_eq!(&mut x as *mut u8, check_oom(&mut x, 16));
+        assert_eq!(0, REQUESTED.load(Ordering::SeqCst));
+
+        assert!(check_oom(core::ptr::null_mut(), 4096).is_null());
+        assert_eq!(4096, REQUESTED.load(Ordering::SeqCst));
     }
 }
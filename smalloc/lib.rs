@@ -10,10 +10,11 @@
 //! - Wide range of error detection
 //!
 //! # Design details
-//! This is essentially a SLOB allocator where all free blocks are
-//! linked together sorted by size. Sorting speedups best-fit
-//! search. All blocks are also doubly-linked in list by their order
-//! in memory. This makes coalescing possible.
+//! This is essentially a SLOB allocator with segregated free
+//! lists. Free blocks are split into size classes by powers of two,
+//! and blocks of each class are linked together sorted by size. This
+//! speedups best-fit search. All blocks are also doubly-linked in
+//! list by their order in memory. This makes coalescing possible.
 //!
 //! The tag for blocks is:
 //!
@@ -29,8 +30,8 @@
 //! - Pointer to next free block (for free blocks only. Doesn't count
 //! to total memory overhead)
 //!
-//!    This links all free blocks in increasing size order. Sorting
-//!    speedups best-fit search.
+//!    This links free blocks of the same size class in increasing
+//!    size order.
 //!
 //! Embedded devices don't usually have allocations larger than 64
 //! kbytes, so it's perfectly safe to have 2-byte long sizes. Thus,
@@ -42,17 +43,32 @@
 //! Note: it's possible to allocate whole 65536 bytes (full 64 kbytes
 //! if treat size 0 as 64 kbytes)
 //!
+//! ## Regions
+//! Besides the region given to `Smalloc::new()`, more regions can be
+//! added with `Smalloc::add_region()`. Free blocks of all regions are
+//! kept in the same free lists. A block never spans multiple regions,
+//! and blocks from different regions are never coalesced, even if
+//! the regions are adjacent.
+//!
+//! ## Free lists
+//! The start of the memory holds heads of `BUCKETS` free lists. List
+//! `i` holds free blocks of `2^i` to `2^(i+1) - 1` bytes, so the last
+//! one ends at the maximum block size.
+//!
 //! ## Allocation
-//! The allocation is done by traversing the list of free blocks and
-//! choosing the first one that fits. This is essentially a best-fit
-//! algorithm as the list is sorted.
+//! The allocation is done by traversing the list of the size class
+//! the requested size belongs to and choosing the first block that
+//! fits. If there is none, the first block of the next non-empty
+//! class is taken: any block there fits and it is the smallest
+//! one. This is still a best-fit algorithm, but only one list has to
+//! be walked.
 //!
 //! ## Deallocation
 //! Deallocation is as simple as mark current block as free and try to
 //! coalesce it with neighbors. Aware not to coalesce blocks if total
-//! size exceeds limit. Then add new free block to a list of free
-//! blocks with respect to the size (Don't forget to remove coalesced
-//! blocks).
+//! size exceeds limit. Then add new free block to the list of its
+//! size class with respect to the size (Don't forget to remove
+//! coalesced blocks).
 //!
 //! ## Error detection
 //! Error detaction is primarily a checking for block list invariant:
@@ -72,10 +88,14 @@
 //! If list invariant is not preserved for current block, there is
 //! chance it's not start of block at all.
 //!
+//! ### Use after free
+//! If enabled with `Smalloc::with_poison()`, freed blocks are filled
+//! with `POISON_BYTE`, so stale reads return a recognizable pattern.
+//!
 //! ### Force check
 //! It's possible to force check all memory. It's as easy as
 //! traversing the whole list of blocks, checking list invariant for
-//! every entry.
+//! every entry. This is done by `Smalloc::check()`.
 #![crate_name = "smalloc"]
 #![crate_type = "rlib"]
 #![cfg_attr(not(test), no_std)]
@@ -89,6 +109,8 @@ extern crate rand;
 #[cfg(test)]
 extern crate rand_isaac;
 
+use ::core::cell::Cell;
+use ::core::marker::PhantomData;
 use ::core::ptr;
 use core::alloc::GlobalAlloc;
 use core::alloc::Layout;
@@ -97,9 +119,9 @@ fn psize() -> usize {
     ::core::mem::size_of::<*mut u8>()
 }
 
-#[allow(clippy::cast_possible_wrap)]
-fn ipsize() -> isize {
-    ::core::mem::size_of::<*mut u8>() as isize
+/// Size of the free list heads at the start of the memory.
+fn hsize() -> usize {
+    BUCKETS * psize()
 }
 
 fn bbsize() -> usize {
@@ -111,7 +133,6 @@ fn ibbsize() -> isize {
     ::core::mem::size_of::<BusyBlock>() as isize
 }
 
-#[allow(dead_code)]
 fn fbsize() -> usize {
     ::core::mem::size_of::<FreeBlock>()
 }
@@ -127,15 +148,67 @@ pub struct Smalloc {
     pub start: *mut u8,
     /// Size of the memory served by Smalloc
     pub size: usize,
+    /// Sum of sizes of all busy blocks
+    used: Cell<usize>,
+    /// The highest value `used` has reached
+    high_water_mark: Cell<usize>,
+    /// Fill freed blocks with `POISON_BYTE`
+    poison: bool,
+    /// Regions added with `add_region()`, the most recent first
+    regions: Cell<*mut Region>,
+    /// Free list entries visited while searching for a block
+    #[cfg(test)]
+    visited: Cell<usize>,
+}
+
+/// Header of a region added with `Smalloc::add_region()`. Blocks of
+/// the region follow it.
+#[repr(C)]
+struct Region {
+    next: *mut Region,
+    end: *mut u8,
+}
+
+fn rsize() -> usize {
+    ::core::mem::size_of::<Region>()
 }
 
 unsafe impl GlobalAlloc for Smalloc {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.alloc(layout.size())
+        if layout.align() > psize() {
+            self.alloc_aligned(layout.size(), layout.align())
+        } else {
+            self.alloc(layout.size())
+        }
+    }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if layout.align() > psize() {
+            let ptr = self.alloc_aligned(layout.size(), layout.align());
+            if !ptr.is_null() {
+                ptr::write_bytes(ptr, 0, layout.size());
+            }
+            ptr
+        } else {
+            self.alloc_zeroed(layout.size())
+        }
     }
     unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
         self.free(ptr)
     }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if layout.align() > psize() {
+            // `Smalloc::realloc` might move the block to an unaligned
+            // address.
+            let new_ptr = self.alloc_aligned(new_size, layout.align());
+            if !new_ptr.is_null() {
+                ptr::copy_nonoverlapping(ptr, new_ptr, ::core::cmp::min(layout.size(), new_size));
+                self.free(ptr);
+            }
+            new_ptr
+        } else {
+            self.realloc(ptr, new_size)
+        }
+    }
 }
 
 #[cfg_attr(test, derive(Clone, Copy, Debug, PartialEq))]
@@ -194,43 +267,364 @@ impl ::core::fmt::Display for BusyBlock {
 
 const MAX_ALLOC: usize = 64 * 1024 - 4;
 
+/// Number of free list size classes. Block sizes are `u16`, so 16
+/// classes cover them all.
+const BUCKETS: usize = 16;
+
+/// Returns the size class of a free block.
+fn bucket(size: u16) -> usize {
+    (15 - (size | 1).leading_zeros()) as usize
+}
+
+/// Errors returned by `Smalloc::init()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitError {
+    /// The region is smaller than `Smalloc::MIN_SIZE`.
+    RegionTooSmall,
+}
+
+/// Heap usage returned by `Smalloc::stats()`.
+///
+/// All sizes are usable sizes of blocks, so they don't include block
+/// tags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HeapStats {
+    /// Memory available for allocations: `used + free`.
+    pub total: usize,
+    pub free: usize,
+    pub used: usize,
+    /// The biggest block that can be allocated at once.
+    pub largest_free: usize,
+    pub free_block_count: usize,
+}
+
+/// A block of the heap, as yielded by `Smalloc::blocks()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockInfo {
+    /// Start of the block's usable memory (i.e., the pointer `alloc`
+    /// returns for a busy block).
+    pub addr: *const u8,
+    /// Usable size, excluding the tag.
+    pub size: u16,
+    pub free: bool,
+}
+
+/// Iterator over heap blocks.
+#[derive(Debug)]
+pub struct BlockIter<'a> {
+    block: *const FreeBlock,
+    end: *const FreeBlock,
+    /// The region to continue with after `end`
+    region: *const Region,
+    __phantom: PhantomData<&'a Smalloc>,
+}
+
+impl<'a> Iterator for BlockIter<'a> {
+    type Item = BlockInfo;
+
+    fn next(&mut self) -> Option<BlockInfo> {
+        unsafe {
+            if self.block >= self.end {
+                if self.region.is_null() {
+                    return None;
+                }
+
+                self.block = (self.region as *const u8).add(rsize()) as *const _;
+                self.end = (*self.region).end as *const _;
+                self.region = (*self.region).next;
+            }
+
+            let info = BlockInfo {
+                addr: (self.block as *const u8).add(bbsize()),
+                size: (*self.block).size,
+                free: (*self.block).is_free(),
+            };
+            self.block = info.addr.add(info.size as usize) as *const _;
+            Some(info)
+        }
+    }
+}
+
+/// Errors returned by `Smalloc::check()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapError {
+    /// Block's `prev_size` doesn't match the size of the previous
+    /// block. `offset` is the offset of the block tag from the start
+    /// of its region.
+    BadPrevSize { offset: usize },
+    /// Block at `offset` extends past the end of the region.
+    BadSize { offset: usize },
+    /// Free list is not sorted by block size or holds a block of
+    /// another size class.
+    UnsortedFreeList,
+    /// Free list has more entries than there are free blocks.
+    CycleDetected,
+}
+
+/// Checks `prev_size` and `size` of blocks that span `first..size`
+/// bytes of a region at `start`. Returns the number of free blocks.
+unsafe fn check_blocks(start: *const u8, first: usize, size: usize) -> Result<usize, HeapError> {
+    let mut free_blocks = 0;
+    let mut prev_size = 0;
+    let mut offset = first;
+    while offset < size {
+        let block = start.add(offset) as *const FreeBlock;
+
+        if (*block).prev_size & !0x1 != prev_size {
+            return Err(HeapError::BadPrevSize { offset });
+        }
+
+        let next_offset = offset + bbsize() + (*block).size as usize;
+        if next_offset > size {
+            return Err(HeapError::BadSize { offset });
+        }
+
+        if (*block).is_free() {
+            free_blocks += 1;
+        }
+        prev_size = (*block).size;
+        offset = next_offset;
+    }
+
+    Ok(free_blocks)
+}
+
+/// Byte freed blocks are filled with if poisoning is enabled.
+pub const POISON_BYTE: u8 = 0xdd;
+
 impl Smalloc {
+    /// The smallest region `init()` accepts: the free list heads
+    /// plus a single free block tag.
+    pub const MIN_SIZE: usize =
+        BUCKETS * ::core::mem::size_of::<*mut u8>() + ::core::mem::size_of::<FreeBlock>();
+
+    /// The smallest region `add_region()` accepts: the region header
+    /// plus a single free block tag.
+    pub const MIN_REGION_SIZE: usize =
+        ::core::mem::size_of::<Region>() + ::core::mem::size_of::<FreeBlock>();
+
+    /// Creates an allocator serving `size` bytes starting at `start`.
+    ///
+    /// `init()` must be called before any allocation.
+    pub const fn new(start: *mut u8, size: usize) -> Smalloc {
+        Smalloc {
+            start,
+            size,
+            used: Cell::new(0),
+            high_water_mark: Cell::new(0),
+            poison: false,
+            regions: Cell::new(ptr::null_mut()),
+            #[cfg(test)]
+            visited: Cell::new(0),
+        }
+    }
+
+    /// Enables filling freed blocks with `POISON_BYTE`, so a
+    /// use-after-free reads a recognizable pattern.
+    ///
+    /// Block tags and the free list pointer are not poisoned, so the
+    /// first pointer-sized bytes of a freed block are overwritten.
+    pub fn with_poison(self, poison: bool) -> Smalloc {
+        Smalloc { poison, ..self }
+    }
+
+    /// Returns the number of bytes currently allocated.
+    ///
+    /// This counts usable sizes of busy blocks, so it includes
+    /// rounding, but excludes block tags.
+    pub fn used(&self) -> usize {
+        self.used.get()
+    }
+
+    /// Returns the peak number of allocated bytes since `init()` or
+    /// the last `reset_high_water_mark()`.
+    pub fn high_water_mark(&self) -> usize {
+        self.high_water_mark.get()
+    }
+
+    /// Resets the high-water mark to the current usage.
+    pub fn reset_high_water_mark(&self) {
+        self.high_water_mark.set(self.used.get());
+    }
+
+    fn account_alloc(&self, size: usize) {
+        let used = self.used.get() + size;
+        self.used.set(used);
+        if used > self.high_water_mark.get() {
+            self.high_water_mark.set(used);
+        }
+    }
+
+    fn account_free(&self, size: usize) {
+        self.used.set(self.used.get() - size);
+    }
+
+    /// Returns the head of the free list for size class `bucket`.
     #[allow(clippy::cast_ptr_alignment)]
-    fn free_list_start(&self) -> *mut *mut FreeBlock {
-        self.start as *mut _
+    unsafe fn free_list(&self, bucket: usize) -> *mut *mut FreeBlock {
+        (self.start as *mut *mut FreeBlock).add(bucket)
     }
 
     /// Initializes memory for allocator.
     ///
     /// Must be called before any allocation.
-    #[allow(clippy::cast_possible_truncation)] // cur_size is guaranteed to be less than MAX_ALLOC
-    #[allow(clippy::cast_possible_wrap)] // MAX_ALLOC should not wrap when cast to isize
-    pub unsafe fn init(&self) {
-        *self.free_list_start() = self.start.offset(ipsize()) as *mut FreeBlock;
+    ///
+    /// Returns an error (and leaves memory untouched) if the region is
+    /// smaller than `MIN_SIZE`.
+    pub unsafe fn init(&self) -> Result<(), InitError> {
+        if self.size < Self::MIN_SIZE {
+            return Err(InitError::RegionTooSmall);
+        }
+
+        self.used.set(0);
+        self.high_water_mark.set(0);
+        self.regions.set(ptr::null_mut());
 
+        for i in 0..BUCKETS {
+            *self.free_list(i) = ptr::null_mut();
+        }
+        self.add_blocks(self.start.add(hsize()), self.size - hsize());
+
+        Ok(())
+    }
+
+    /// Adds another memory region to serve allocations from (e.g.,
+    /// CCM RAM in addition to the main SRAM).
+    ///
+    /// Must be called after `init()`. `start` must be pointer-aligned
+    /// and the region must not overlap with the ones already served.
+    /// Blocks are never merged across regions.
+    ///
+    /// Returns an error (and leaves memory untouched) if the region is
+    /// smaller than `MIN_REGION_SIZE`.
+    #[allow(clippy::cast_ptr_alignment)]
+    pub unsafe fn add_region(&self, start: *mut u8, size: usize) -> Result<(), InitError> {
+        if size < Self::MIN_REGION_SIZE {
+            return Err(InitError::RegionTooSmall);
+        }
+
+        let region = start as *mut Region;
+        *region = Region {
+            next: self.regions.get(),
+            end: start.add(size),
+        };
+        self.regions.set(region);
+
+        self.add_blocks(start.add(rsize()), size - rsize());
+
+        Ok(())
+    }
+
+    /// Lays out free blocks covering `size` bytes at `start` and adds
+    /// them to the free lists.
+    #[allow(clippy::cast_possible_truncation)] // cur_size is guaranteed to be less than MAX_ALLOC
+    #[allow(clippy::cast_ptr_alignment)]
+    unsafe fn add_blocks(&self, start: *mut u8, mut size: usize) {
         let mut prev_size = 0;
-        let mut cur_offset = ipsize();
-        let mut size = self.size - psize();
+        let mut cur_offset = 0;
         while size != 0 {
             let cur_size = ::core::cmp::min(MAX_ALLOC, size - bbsize());
             size -= cur_size + bbsize();
-            *(self.start.offset(cur_offset) as *mut _) = FreeBlock {
+
+            let block = start.add(cur_offset) as *mut FreeBlock;
+            *block = FreeBlock {
                 prev_size: prev_size + 1,
                 size: cur_size as u16,
-                next: if size == 0 {
-                    ptr::null_mut()
-                } else {
-                    self.start
-                        .offset(cur_offset + ibbsize() + MAX_ALLOC as isize)
-                        as *mut _
-                },
+                next: ptr::null_mut(),
             };
+            self.install_free_block(block);
 
             prev_size = cur_size as u16;
-            cur_offset += cur_size as isize + ibbsize();
+            cur_offset += cur_size + bbsize();
+        }
+    }
+
+    /// Returns the end of the region `block` belongs to.
+    unsafe fn region_end(&self, block: *const u8) -> *const u8 {
+        let mut region = self.regions.get();
+        while !region.is_null() {
+            if block >= region as *const u8 && block < (*region).end {
+                return (*region).end;
+            }
+            region = (*region).next;
+        }
+
+        self.start.add(self.size)
+    }
+
+    /// Checks heap consistency.
+    ///
+    /// Walks all blocks, verifying that each block's `prev_size`
+    /// matches the size of the previous block, and then verifies each
+    /// free list is sorted and holds blocks of its size class
+    /// only. Catches most buffer overflows that have overwritten a
+    /// block tag.
+    pub unsafe fn check(&self) -> Result<(), HeapError> {
+        let mut free_blocks = check_blocks(self.start, hsize(), self.size)?;
+
+        let mut region = self.regions.get();
+        while !region.is_null() {
+            let size = (*region).end as usize - region as usize;
+            free_blocks += check_blocks(region as *const u8, rsize(), size)?;
+            region = (*region).next;
+        }
+
+        let mut entries = 0;
+        for i in 0..BUCKETS {
+            let mut cur = *self.free_list(i);
+            let mut prev_size = 0;
+            while !cur.is_null() {
+                entries += 1;
+                if entries > free_blocks {
+                    return Err(HeapError::CycleDetected);
+                }
+                if (*cur).size < prev_size || bucket((*cur).size) != i {
+                    return Err(HeapError::UnsortedFreeList);
+                }
+
+                prev_size = (*cur).size;
+                cur = (*cur).next;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns an iterator over all blocks. Blocks of the main region
+    /// come first, followed by added regions, the most recent first.
+    /// Within a region, blocks are in memory order.
+    ///
+    /// The heap must not be modified while iterating. Block tags are
+    /// trusted, so run `check()` first if the heap might be corrupted.
+    pub unsafe fn blocks(&self) -> BlockIter<'_> {
+        BlockIter {
+            block: self.start.add(hsize()) as *const _,
+            end: self.start.add(self.size) as *const _,
+            region: self.regions.get(),
+            __phantom: PhantomData,
         }
     }
 
+    /// Computes heap usage by traversing all blocks.
+    pub unsafe fn stats(&self) -> HeapStats {
+        let mut stats = HeapStats::default();
+
+        for block in self.blocks() {
+            let size = block.size as usize;
+
+            if block.free {
+                stats.free += size;
+                stats.free_block_count += 1;
+                stats.largest_free = ::core::cmp::max(stats.largest_free, size);
+            } else {
+                stats.used += size;
+            }
+        }
+
+        stats.total = stats.used + stats.free;
+        stats
+    }
+
     #[allow(clippy::cast_possible_truncation)] // size is checked to be u16
     #[allow(clippy::cast_possible_wrap)]
     pub unsafe fn alloc(&self, mut size: usize) -> *mut u8 {
@@ -243,13 +637,99 @@ impl Smalloc {
 
         size = (size + psize() - 1) & !(psize() - 1);
 
-        let (prev_empty, cur) = self.find_free_block(size as u16);
+        let (link, cur) = self.find_free_block(size as u16);
         if cur.is_null() {
             return ptr::null_mut();
         }
 
+        self.alloc_from(link, cur, size)
+    }
+
+    /// Same as `alloc`, but the returned pointer is aligned to `align`,
+    /// which must be a power of two.
+    ///
+    /// If the payload of a free block is not aligned, the block is
+    /// split and the padding before the aligned payload becomes a
+    /// separate free block. Blocks are only split if the padding can
+    /// hold a free block tag, so the padding might be bigger than
+    /// `align`.
+    #[allow(clippy::cast_possible_truncation)] // sizes are less than block size
+    #[allow(clippy::cast_possible_wrap)]
+    pub unsafe fn alloc_aligned(&self, mut size: usize, align: usize) -> *mut u8 {
+        if size == 0 || !align.is_power_of_two() {
+            return ptr::null_mut();
+        }
+        if size > ::core::u16::MAX as usize {
+            return ptr::null_mut();
+        }
+
+        size = (size + psize() - 1) & !(psize() - 1);
+
+        // smaller classes hold only blocks that are too small
+        let mut found = None;
+        'search: for i in bucket(size as u16)..BUCKETS {
+            let mut link = self.free_list(i);
+            while !(*link).is_null() {
+                let cur = *link;
+                let payload = (cur as *mut u8).offset(ibbsize()) as usize;
+                let mut pad = payload.wrapping_neg() & (align - 1);
+                while pad != 0 && pad < fbsize() {
+                    pad += align;
+                }
+
+                if pad + size <= (*cur).size as usize {
+                    found = Some((link, cur, pad));
+                    break 'search;
+                }
+
+                link = &mut (*cur).next as *mut _;
+            }
+        }
+
+        let (mut link, mut cur, pad) = match found {
+            Some(x) => x,
+            None => return ptr::null_mut(),
+        };
+
+        if pad != 0 {
+            *link = (*cur).next;
+
+            let aligned = (cur as *mut u8).add(pad) as *mut FreeBlock;
+            *aligned = FreeBlock {
+                prev_size: (pad - bbsize() + 1) as u16,
+                size: (*cur).size - pad as u16,
+                next: ptr::null_mut(),
+            };
+            (*cur).size = (pad - bbsize()) as u16;
+
+            let next =
+                (aligned as *mut u8).offset(ibbsize() + (*aligned).size as isize) as *mut FreeBlock;
+            if (next as *const u8) < self.region_end(aligned as *const u8) {
+                (*next).prev_size = (*aligned).size + (*next).is_free() as u16;
+            }
+
+            self.install_free_block(cur);
+            self.install_free_block(aligned);
+
+            link = self.find_link(aligned);
+            cur = aligned;
+        }
+
+        self.alloc_from(link, cur, size)
+    }
+
+    /// Turns free block `cur` (that `link` points to) into a busy
+    /// block of `size`, splitting off the tail if it's large enough.
+    #[allow(clippy::cast_possible_truncation)] // size is checked to be u16
+    #[allow(clippy::cast_possible_wrap)]
+    unsafe fn alloc_from(
+        &self,
+        link: *mut *mut FreeBlock,
+        cur: *mut FreeBlock,
+        mut size: usize,
+    ) -> *mut u8 {
         // remove block from free list
-        *self.get_next_ptr(prev_empty) = (*cur).next;
+        *link = (*cur).next;
 
         let prev_cur_size = (*cur).size;
         if (prev_cur_size as isize) - (size as isize) < ifbsize() {
@@ -265,7 +745,7 @@ impl Smalloc {
             let split_next_next = (split_next as *mut u8)
                 .offset((*split_next).size as isize + ibbsize())
                 as *mut FreeBlock;
-            if split_next_next < self.start.add(self.size) as *mut FreeBlock {
+            if (split_next_next as *const u8) < self.region_end(cur as *const u8) {
                 (*split_next_next).prev_size =
                     (*split_next).size + (*split_next_next).is_free() as u16;
             }
@@ -279,18 +759,151 @@ impl Smalloc {
             prev_size: (*cur).prev_size - 1,
             size: size as u16,
         };
+        self.account_alloc(size);
 
         (cur as *mut u8).offset(ibbsize())
     }
 
-    #[allow(clippy::cast_possible_wrap)]
-    #[allow(clippy::cast_possible_truncation)] // bbsize < u16
+    /// Same as `alloc`, but the whole usable size of the returned
+    /// block (which may be bigger than requested) is zeroed.
+    pub unsafe fn alloc_zeroed(&self, size: usize) -> *mut u8 {
+        let ptr = self.alloc(size);
+        if !ptr.is_null() {
+            ptr::write_bytes(ptr, 0, Self::usable_size(ptr));
+        }
+        ptr
+    }
+
+    /// Returns the number of bytes the allocation owns.
+    ///
+    /// It's at least the requested size, but may be bigger: sizes are
+    /// rounded up, and a block is not split if the rest is too small
+    /// to hold a free block. All of it can be used.
+    ///
+    /// `ptr` must be returned by this allocator and not freed yet.
+    pub unsafe fn usable_size(ptr: *mut u8) -> usize {
+        let block = ptr.offset(-ibbsize()) as *const BusyBlock;
+        (*block).size as usize
+    }
+
     pub unsafe fn free(&self, ptr: *mut u8) {
         if ptr.is_null() {
             return;
         }
 
-        let mut block = ptr.offset(-ibbsize()) as *mut FreeBlock;
+        let block = ptr.offset(-ibbsize()) as *mut FreeBlock;
+        self.account_free((*block).size as usize);
+        if self.poison {
+            ptr::write_bytes(ptr, POISON_BYTE, (*block).size as usize);
+        }
+        self.release(block);
+    }
+
+    /// Resizes the allocation, preserving its contents.
+    ///
+    /// Grows in place if the next block is free and large enough,
+    /// otherwise allocates a new block and copies the data. Shrinking
+    /// is always done in place.
+    ///
+    /// A null `ptr` is allocated; zero `new_size` frees `ptr` and
+    /// returns null. If the allocation fails, null is returned and the
+    /// original block is left untouched.
+    #[allow(clippy::cast_possible_truncation)] // sizes are checked against MAX_ALLOC
+    #[allow(clippy::cast_possible_wrap)]
+    pub unsafe fn realloc(&self, ptr: *mut u8, new_size: usize) -> *mut u8 {
+        if ptr.is_null() {
+            return self.alloc(new_size);
+        }
+        if new_size == 0 {
+            self.free(ptr);
+            return ptr::null_mut();
+        }
+        if new_size > ::core::u16::MAX as usize {
+            return ptr::null_mut();
+        }
+
+        let size = (new_size + psize() - 1) & !(psize() - 1);
+
+        let block = ptr.offset(-ibbsize()) as *mut BusyBlock;
+        let old_size = (*block).size as usize;
+
+        if size > old_size {
+            let end = self.region_end(ptr);
+            let next_block = ptr.add(old_size) as *mut FreeBlock;
+            let merged_size = if (next_block as *const u8) < end && (*next_block).is_free() {
+                old_size + bbsize() + (*next_block).size as usize
+            } else {
+                0
+            };
+
+            if merged_size >= size && merged_size < MAX_ALLOC {
+                // grow in place
+                *self.find_link(next_block) = (*next_block).next;
+
+                let next_next = ptr.add(merged_size) as *mut FreeBlock;
+                if (next_next as *const u8) < end {
+                    (*next_next).prev_size = merged_size as u16 + (*next_next).is_free() as u16;
+                }
+                (*block).size = merged_size as u16;
+            } else {
+                let new_ptr = self.alloc(new_size);
+                if !new_ptr.is_null() {
+                    ptr::copy_nonoverlapping(ptr, new_ptr, old_size);
+                    self.free(ptr);
+                }
+                return new_ptr;
+            }
+        }
+
+        self.split_busy_block(block, size);
+
+        let cur_size = (*block).size as usize;
+        if cur_size > old_size {
+            self.account_alloc(cur_size - old_size);
+        } else {
+            self.account_free(old_size - cur_size);
+        }
+
+        ptr
+    }
+
+    /// Shrinks the busy block to `size`, releasing the tail if it is
+    /// large enough to hold a free block.
+    ///
+    /// Doesn't update the allocation statistics.
+    #[allow(clippy::cast_possible_truncation)] // sizes are less than block size
+    #[allow(clippy::cast_possible_wrap)]
+    unsafe fn split_busy_block(&self, block: *mut BusyBlock, size: usize) {
+        let cur_size = (*block).size as usize;
+        if (cur_size as isize) - (size as isize) < ifbsize() {
+            return;
+        }
+
+        let tail = (block as *mut u8).offset(ibbsize() + size as isize) as *mut BusyBlock;
+        *tail = BusyBlock {
+            prev_size: size as u16,
+            size: (cur_size - size - bbsize()) as u16,
+        };
+        (*block).size = size as u16;
+
+        let next = (tail as *mut u8).offset(ibbsize() + (*tail).size as isize) as *mut FreeBlock;
+        if (next as *const u8) < self.region_end(tail as *const u8) {
+            (*next).prev_size = (*tail).size + (*next).is_free() as u16;
+        }
+
+        // the tail might be merged with the next block
+        self.release(tail as *mut FreeBlock);
+    }
+
+    /// Marks busy block as free, coalesces it with neighbors, and
+    /// adds it to the free list.
+    ///
+    /// Doesn't update the allocation statistics.
+    #[allow(clippy::cast_possible_wrap)]
+    #[allow(clippy::cast_possible_truncation)] // bbsize < u16
+    unsafe fn release(&self, mut block: *mut FreeBlock) {
+        // neighbors are only merged within the block's region
+        let end = self.region_end(block as *const u8);
 
         // try merge with previous
         let prev_block =
@@ -302,11 +915,10 @@ impl Smalloc {
             && (*prev_block).is_free()
             && (*block).size as usize + (*prev_block).size as usize + bbsize() < MAX_ALLOC
         {
-            let prev = self.find_previous_block(prev_block);
             // remove prev_block from list temporary
-            *self.get_next_ptr(prev) = (*prev_block).next;
+            *self.find_link(prev_block) = (*prev_block).next;
 
-            if (next_block as *mut u8) < self.start.add(self.size) {
+            if (next_block as *const u8) < end {
                 (*next_block).prev_size += (*prev_block).size + bbsize() as u16;
             }
             (*prev_block).size += (*block).size + bbsize() as u16;
@@ -317,16 +929,15 @@ impl Smalloc {
         }
 
         // try merge with next
-        if (next_block as *mut u8) < self.start.add(self.size)
+        if (next_block as *const u8) < end
             && (*next_block).is_free()
             && (*block).size as usize + (*next_block).size as usize + bbsize() < MAX_ALLOC
         {
-            let prev = self.find_previous_block(next_block);
-            *self.get_next_ptr(prev) = (*next_block).next;
+            *self.find_link(next_block) = (*next_block).next;
 
             let next_next = (next_block as *mut u8).offset(ibbsize() + (*next_block).size as isize)
                 as *mut FreeBlock;
-            if (next_next as *mut u8) < self.start.add(self.size) {
+            if (next_next as *const u8) < end {
                 (*next_next).prev_size += (*block).size + bbsize() as u16;
             }
 
@@ -336,80 +947,85 @@ impl Smalloc {
         self.install_free_block(block);
     }
 
-    unsafe fn find_free_block(&self, size: u16) -> (*mut FreeBlock, *mut FreeBlock) {
-        self.find_free_after(size, ptr::null_mut())
-    }
+    /// Finds the smallest free block of at least `size` bytes.
+    /// Returns the link that points to it and the block itself, or
+    /// nulls if there is no such block.
+    unsafe fn find_free_block(&self, size: u16) -> (*mut *mut FreeBlock, *mut FreeBlock) {
+        let first = bucket(size);
 
-    unsafe fn find_free_after(
-        &self,
-        size: u16,
-        after: *mut FreeBlock,
-    ) -> (*mut FreeBlock, *mut FreeBlock) {
-        let mut prev = after;
-        let mut cur = *self.get_next_ptr(prev);
-
-        while !cur.is_null() && (*cur).size < size {
-            prev = cur;
-            cur = (*cur).next;
+        // blocks of the first class might be too small
+        let mut link = self.free_list(first);
+        while !(*link).is_null() {
+            #[cfg(test)]
+            self.visited.set(self.visited.get() + 1);
+
+            if (**link).size >= size {
+                return (link, *link);
+            }
+            link = &mut (**link).next as *mut _;
+        }
+
+        // all blocks of the next classes fit, the first one is the
+        // smallest
+        for i in first + 1..BUCKETS {
+            let link = self.free_list(i);
+            if !(*link).is_null() {
+                #[cfg(test)]
+                self.visited.set(self.visited.get() + 1);
+
+                return (link, *link);
+            }
         }
 
-        (prev, cur)
+        (ptr::null_mut(), ptr::null_mut())
     }
 
     unsafe fn install_free_block(&self, block: *mut FreeBlock) {
-        let (mut prev, mut next) = self.find_free_block((*block).size);
+        let size = (*block).size;
+        let mut link = self.free_list(bucket(size));
 
         // sort them by memory address when the size is same.
         // That allows one neat optimization in the free.
-        while !next.is_null() && (*next).size == (*block).size && block > next {
-            prev = next;
-            next = (*next).next;
+        while !(*link).is_null() && ((**link).size < size || (**link).size == size && block > *link)
+        {
+            link = &mut (**link).next as *mut _;
         }
 
-        *self.get_next_ptr(prev) = block;
-        (*block).next = next;
+        (*block).next = *link;
+        *link = block;
     }
 
-    unsafe fn find_previous_block(&self, block: *mut FreeBlock) -> *mut FreeBlock {
-        let mut prev = ptr::null_mut();
-        let mut cur = *self.free_list_start();
+    /// Returns the link that points to `block` in its free list.
+    unsafe fn find_link(&self, block: *mut FreeBlock) -> *mut *mut FreeBlock {
+        let mut link = self.free_list(bucket((*block).size));
 
-        while cur != block {
-            prev = cur;
-            cur = (*cur).next;
+        while *link != block {
+            link = &mut (**link).next as *mut _;
         }
 
-        prev
-    }
-
-    unsafe fn get_next_ptr(&self, block: *mut FreeBlock) -> *mut *mut FreeBlock {
-        if block.is_null() {
-            self.free_list_start()
-        } else {
-            &mut (*block).next as *mut _
-        }
+        link
     }
 
     #[cfg(test)]
     unsafe fn debug_print(&self) {
-        // print free list
-        println!("Free list:");
-        let mut cur = *self.free_list_start();
-        while !cur.is_null() {
-            println!("{:p}", cur);
-            cur = (*cur).next;
+        // print free lists
+        for i in 0..BUCKETS {
+            println!("Free list {}:", i);
+            let mut cur = *self.free_list(i);
+            while !cur.is_null() {
+                println!("{:p}", cur);
+                cur = (*cur).next;
+            }
         }
 
         // print block list
-        let mut block = self.start.offset(ipsize()) as *const FreeBlock;
-        while block < self.start.offset(self.size as isize) as *const _ {
-            if (*block).is_free() {
+        for info in self.blocks() {
+            let block = info.addr.offset(-ibbsize()) as *const FreeBlock;
+            if info.free {
                 println!("{:p}: {}", block, *block);
             } else {
                 println!("{:p}: {}", block, *(block as *const BusyBlock));
             }
-
-            block = (block as *const u8).offset((*block).size as isize + ibbsize()) as *const _;
         }
     }
 }
@@ -418,12 +1034,26 @@ impl Smalloc {
 mod test {
     use super::*;
     #[allow(unused_imports)]
-    use super::{bbsize, fbsize, ibbsize, ifbsize, ipsize, psize, BusyBlock, FreeBlock};
+    use super::{bbsize, fbsize, hsize, ibbsize, ifbsize, psize, BusyBlock, FreeBlock};
 
     use ::std::alloc;
 
     use ::core::ptr;
 
+    #[allow(clippy::cast_possible_wrap)]
+    fn ihsize() -> isize {
+        hsize() as isize
+    }
+
+    /// Returns the smallest free block, i.e., the first block of the
+    /// first non-empty free list.
+    unsafe fn first_free(a: &Smalloc) -> *mut FreeBlock {
+        (0..BUCKETS)
+            .map(|i| *a.free_list(i))
+            .find(|block| !block.is_null())
+            .unwrap_or(ptr::null_mut())
+    }
+
     fn with_memory<F>(size: usize, f: F)
     where
         F: Fn(*mut u8, &Smalloc) -> (),
@@ -431,11 +1061,8 @@ mod test {
         unsafe {
             let layout = Layout::from_size_align_unchecked(size, psize());
             let memory = alloc::alloc(layout);
-            let a: Smalloc = Smalloc {
-                start: memory,
-                size,
-            };
-            a.init();
+            let a = Smalloc::new(memory, size);
+            a.init().unwrap();
 
             f(memory, &a);
 
@@ -443,55 +1070,72 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_init_too_small() {
+        let mut memory = [0xaau8; 256];
+        for size in 0..Smalloc::MIN_SIZE {
+            let a = Smalloc::new(memory.as_mut_ptr(), size);
+            assert_eq!(Err(InitError::RegionTooSmall), unsafe { a.init() });
+        }
+        assert!(memory.iter().all(|&b| b == 0xaa));
+    }
+
+    #[test]
+    fn test_init_min_size() {
+        with_memory(Smalloc::MIN_SIZE, |_, a| unsafe {
+            let p = a.alloc(1);
+            assert_ne!(ptr::null_mut(), p);
+            assert_eq!(ptr::null_mut(), a.alloc(1));
+            a.free(p);
+        });
+    }
+
     #[test]
     fn test_init_tags() {
-        with_memory(256, |memory, _| unsafe {
-            assert_eq!(
-                memory.offset(ipsize()) as *mut FreeBlock,
-                *(memory as *const *mut FreeBlock)
-            );
+        with_memory(256, |memory, a| unsafe {
+            assert_eq!(memory.offset(ihsize()) as *mut FreeBlock, first_free(a));
             assert_eq!(
                 FreeBlock {
                     prev_size: 0x1,
-                    size: (256 - psize() - bbsize()) as u16,
+                    size: (256 - hsize() - bbsize()) as u16,
                     next: 0x0 as *mut FreeBlock
                 },
-                *(memory.offset(ipsize()) as *const FreeBlock)
+                *(memory.offset(ihsize()) as *const FreeBlock)
             );
         });
     }
 
     #[test]
     fn test_init_too_big() {
-        with_memory(130 * 1024, |memory, _| unsafe {
+        with_memory(130 * 1024, |memory, a| unsafe {
+            // the two biggest blocks are in the last size class
             assert_eq!(
-                memory.offset(ipsize()) as *mut FreeBlock,
-                *(memory as *const *mut FreeBlock)
+                memory.offset(ihsize()) as *mut FreeBlock,
+                *a.free_list(BUCKETS - 1)
             );
             assert_eq!(
                 FreeBlock {
                     prev_size: 0x1,
                     size: (64 * 1024 - 4) as u16,
-                    next: memory.offset(ipsize() + ibbsize() + 64 * 1024 - 4) as *mut FreeBlock,
+                    next: memory.offset(ihsize() + ibbsize() + 64 * 1024 - 4) as *mut FreeBlock,
                 },
-                *(memory.offset(ipsize()) as *const FreeBlock)
+                *(memory.offset(ihsize()) as *const FreeBlock)
             );
             assert_eq!(
                 FreeBlock {
                     prev_size: (64 * 1024 - 4 + 1) as u16,
                     size: (64 * 1024 - 4) as u16,
-                    next: memory.offset(ipsize() + 2 * ibbsize() + 2 * (64 * 1024 - 4))
-                        as *mut FreeBlock,
+                    next: ptr::null_mut(),
                 },
-                *(memory.offset(ipsize() + ibbsize() + 64 * 1024 - 4) as *mut FreeBlock)
+                *(memory.offset(ihsize() + ibbsize() + 64 * 1024 - 4) as *mut FreeBlock)
             );
             assert_eq!(
                 FreeBlock {
                     prev_size: (64 * 1024 - 4 + 1) as u16,
-                    size: (130 * 1024 - psize() - 3 * bbsize() - 2 * (64 * 1024 - 4)) as u16,
+                    size: (130 * 1024 - hsize() - 3 * bbsize() - 2 * (64 * 1024 - 4)) as u16,
                     next: ptr::null_mut(),
                 },
-                *(memory.offset(ipsize() + 2 * ibbsize() + 2 * (64 * 1024 - 4)) as *mut FreeBlock)
+                *(memory.offset(ihsize() + 2 * ibbsize() + 2 * (64 * 1024 - 4)) as *mut FreeBlock)
             );
         });
     }
@@ -501,25 +1145,25 @@ mod test {
         with_memory(256, |memory, a| unsafe {
             let ret = a.alloc(8);
 
-            assert_eq!(memory.offset(ipsize() + ibbsize()), ret);
+            assert_eq!(memory.offset(ihsize() + ibbsize()), ret);
             assert_eq!(
-                memory.offset(ipsize() + ibbsize() + 8) as *mut FreeBlock,
-                *(memory as *const *mut FreeBlock)
+                memory.offset(ihsize() + ibbsize() + 8) as *mut FreeBlock,
+                first_free(a)
             );
             assert_eq!(
                 BusyBlock {
                     prev_size: 0,
                     size: 8,
                 },
-                *(memory.offset(ipsize()) as *const BusyBlock)
+                *(memory.offset(ihsize()) as *const BusyBlock)
             );
             assert_eq!(
                 FreeBlock {
                     prev_size: 0x9,
-                    size: (256 - psize() - bbsize() - 0x8 - bbsize()) as u16,
+                    size: (256 - hsize() - bbsize() - 0x8 - bbsize()) as u16,
                     next: 0x0 as *mut FreeBlock,
                 },
-                *(memory.offset(ipsize() + ibbsize() + 0x8) as *mut FreeBlock)
+                *(memory.offset(ihsize() + ibbsize() + 0x8) as *mut FreeBlock)
             );
         });
     }
@@ -533,8 +1177,8 @@ mod test {
             // memory layout after test:
             // - pointer to free block
             assert_eq!(
-                *(memory.offset(0) as *const *const FreeBlock),
-                memory.offset(ipsize() + ibbsize() + 32 + ibbsize() + 16) as *const _
+                first_free(a),
+                memory.offset(ihsize() + ibbsize() + 32 + ibbsize() + 16) as *mut FreeBlock
             );
             // - busy block for 32 bytes
             assert_eq!(
@@ -542,35 +1186,35 @@ mod test {
                     prev_size: 0,
                     size: 32,
                 },
-                *(memory.offset(ipsize()) as *const BusyBlock)
+                *(memory.offset(ihsize()) as *const BusyBlock)
             );
             // - 32 bytes of data
-            assert_eq!(memory.offset(ipsize() + ibbsize()), ret1);
+            assert_eq!(memory.offset(ihsize() + ibbsize()), ret1);
             // - busy block for 16 bytes
             assert_eq!(
                 BusyBlock {
                     prev_size: 32,
                     size: 16,
                 },
-                *(memory.offset(ipsize() + ibbsize() + 32) as *const BusyBlock)
+                *(memory.offset(ihsize() + ibbsize() + 32) as *const BusyBlock)
             );
             // - 16 bytes of data
-            assert_eq!(memory.offset(ipsize() + ibbsize() + 32 + ibbsize()), ret2);
+            assert_eq!(memory.offset(ihsize() + ibbsize() + 32 + ibbsize()), ret2);
             // - free block till end
             assert_eq!(
                 FreeBlock {
                     prev_size: 17,
-                    size: (256 - psize() - bbsize() - 32 - bbsize() - 16 - bbsize()) as u16,
+                    size: (256 - hsize() - bbsize() - 32 - bbsize() - 16 - bbsize()) as u16,
                     next: 0x0 as *mut _,
                 },
-                *(memory.offset(ipsize() + ibbsize() + 32 + ibbsize() + 16) as *const FreeBlock)
+                *(memory.offset(ihsize() + ibbsize() + 32 + ibbsize() + 16) as *const FreeBlock)
             );
         });
     }
 
     #[test]
     fn test_alloc_too_big() {
-        with_memory(36 + psize(), |_, a| unsafe {
+        with_memory(36 + hsize(), |_, a| unsafe {
             let ret = a.alloc(32 + 1);
 
             assert_eq!(ptr::null_mut(), ret);
@@ -579,17 +1223,17 @@ mod test {
 
     #[test]
     fn test_alloc_max() {
-        with_memory(36 + psize(), |memory, a| unsafe {
+        with_memory(36 + hsize(), |memory, a| unsafe {
             let ret = a.alloc(32);
 
-            assert_eq!(memory.offset(ipsize() + ibbsize()), ret);
-            assert_eq!(ptr::null_mut(), *(memory as *const *mut FreeBlock));
+            assert_eq!(memory.offset(ihsize() + ibbsize()), ret);
+            assert_eq!(ptr::null_mut(), first_free(a));
         });
     }
 
     #[test]
     fn test_alloc_zero() {
-        with_memory(32, |_, a| unsafe {
+        with_memory(Smalloc::MIN_SIZE, |_, a| unsafe {
             let ret = a.alloc(0);
 
             assert_eq!(ptr::null_mut(), ret);
@@ -597,22 +1241,553 @@ mod test {
     }
 
     #[test]
-    fn test_free_single_block() {
-        with_memory(256, |memory, a| unsafe {
+    fn test_alloc_zeroed() {
+        with_memory(256, |_, a| unsafe {
             let ptr = a.alloc(32);
+            ptr::write_bytes(ptr, 0xa5, 32);
             a.free(ptr);
 
-            assert_eq!(
-                memory.offset(ipsize()) as *mut FreeBlock,
-                *(memory as *const *mut FreeBlock)
-            );
-            assert_eq!(
+            // 30 is rounded up to 32, so the whole block must be cleared
+            let zeroed = a.alloc_zeroed(30);
+            assert_eq!(ptr, zeroed);
+            assert!((0..32).all(|i| *zeroed.add(i) == 0));
+            assert_eq!(32, a.used());
+        });
+    }
+
+    #[test]
+    fn test_free_poisons_payload() {
+        unsafe {
+            let layout = Layout::from_size_align_unchecked(256, psize());
+            let memory = alloc::alloc(layout);
+            let a = Smalloc::new(memory, 256).with_poison(true);
+            a.init().unwrap();
+
+            let ptr = a.alloc(32);
+            // Prevents coalescing with the tail block.
+            let guard = a.alloc(16);
+            ptr::write_bytes(ptr, 0xa5, 32);
+            ptr::write_bytes(guard, 0xa5, 16);
+
+            a.free(ptr);
+
+            // The free list pointer is stored in the payload.
+            assert!((psize()..32).all(|i| *ptr.add(i) == POISON_BYTE));
+            let block = *(ptr.offset(-ibbsize()) as *const FreeBlock);
+            assert!(block.is_free());
+            assert_eq!(32, { block.size });
+            assert_eq!(
+                BusyBlock {
+                    prev_size: 32,
+                    size: 16,
+                },
+                *(guard.offset(-ibbsize()) as *const BusyBlock)
+            );
+            assert!((0..16).all(|i| *guard.add(i) == 0xa5));
+            assert_eq!(Ok(()), a.check());
+
+            alloc::dealloc(memory, layout);
+        }
+    }
+
+    #[test]
+    fn test_free_does_not_poison_by_default() {
+        with_memory(256, |_, a| unsafe {
+            let ptr = a.alloc(32);
+            a.alloc(16);
+            ptr::write_bytes(ptr, 0xa5, 32);
+
+            a.free(ptr);
+
+            assert!((psize()..32).all(|i| *ptr.add(i) == 0xa5));
+        });
+    }
+
+    #[test]
+    fn test_alloc_zeroed_invalid_size() {
+        with_memory(256, |_, a| unsafe {
+            assert_eq!(ptr::null_mut(), a.alloc_zeroed(0));
+            assert_eq!(
+                ptr::null_mut(),
+                a.alloc_zeroed(::core::u16::MAX as usize + 1)
+            );
+            assert_eq!(ptr::null_mut(), a.alloc_zeroed(512));
+            assert_eq!(0, a.used());
+        });
+    }
+
+    #[test]
+    fn test_alloc_aligned() {
+        for &align in &[8, 16, 32] {
+            with_memory(512, |memory, a| unsafe {
+                // shift the next free block
+                let first = a.alloc(1);
+
+                let ptr = a.alloc_aligned(24, align);
+                assert!(!ptr.is_null());
+                assert_eq!(0, ptr as usize % align);
+                assert_eq!(psize() + 24, a.used());
+
+                // the allocator is consistent and the padding is reused
+                a.free(ptr);
+                a.free(first);
+                assert_eq!(0, a.used());
+                assert_eq!(
+                    FreeBlock {
+                        prev_size: 1,
+                        size: (512 - hsize() - bbsize()) as u16,
+                        next: ptr::null_mut(),
+                    },
+                    *(memory.offset(ihsize()) as *const FreeBlock)
+                );
+            });
+        }
+    }
+
+    #[test]
+    fn test_alloc_aligned_many() {
+        with_memory(1024, |_, a| unsafe {
+            let ptrs = [
+                a.alloc_aligned(8, 32),
+                a.alloc(4),
+                a.alloc_aligned(12, 16),
+                a.alloc_aligned(1, 8),
+                a.alloc_aligned(40, 32),
+            ];
+            for (&ptr, &align) in ptrs.iter().zip(&[32, 1, 16, 8, 32]) {
+                assert!(!ptr.is_null());
+                assert_eq!(0, ptr as usize % align);
+            }
+
+            for &ptr in ptrs.iter().rev() {
+                a.free(ptr);
+            }
+            assert_eq!(0, a.used());
+            assert_eq!((1024 - hsize() - bbsize()) as u16, (*first_free(a)).size);
+        });
+    }
+
+    #[test]
+    fn test_alloc_aligned_invalid() {
+        with_memory(256, |_, a| unsafe {
+            assert_eq!(ptr::null_mut(), a.alloc_aligned(0, 8));
+            assert_eq!(ptr::null_mut(), a.alloc_aligned(8, 0));
+            assert_eq!(ptr::null_mut(), a.alloc_aligned(8, 12));
+            assert_eq!(ptr::null_mut(), a.alloc_aligned(512, 8));
+            assert_eq!(0, a.used());
+        });
+    }
+
+    #[test]
+    fn test_check_ok() {
+        with_memory(256, |_, a| unsafe {
+            assert_eq!(Ok(()), a.check());
+
+            let ptr1 = a.alloc(16);
+            let ptr2 = a.alloc(8);
+            let ptr3 = a.alloc(24);
+            a.free(ptr2);
+            assert_eq!(Ok(()), a.check());
+
+            a.free(ptr1);
+            a.free(ptr3);
+            assert_eq!(Ok(()), a.check());
+        });
+    }
+
+    #[test]
+    fn test_check_overflow() {
+        with_memory(256, |_, a| unsafe {
+            let ptr = a.alloc(16);
+            a.alloc(8);
+
+            // write past the end of the first block into the next tag
+            ptr::write_bytes(ptr, 0x42, 16 + 2);
+
+            assert_eq!(
+                Err(HeapError::BadPrevSize {
+                    offset: hsize() + bbsize() + 16
+                }),
+                a.check()
+            );
+        });
+    }
+
+    #[test]
+    fn test_check_bad_size() {
+        with_memory(256, |memory, a| unsafe {
+            a.alloc(16);
+            (*(memory.offset(ihsize()) as *mut BusyBlock)).size = 1000;
+
+            assert_eq!(Err(HeapError::BadSize { offset: hsize() }), a.check());
+        });
+    }
+
+    #[test]
+    fn test_check_unsorted_free_list() {
+        with_memory(256, |_, a| unsafe {
+            let ptr1 = a.alloc(16);
+            a.alloc(8);
+            let ptr3 = a.alloc(24);
+            a.alloc(8);
+            a.free(ptr1);
+            a.free(ptr3);
+            assert_eq!(Ok(()), a.check());
+
+            // swap the two entries of the size class
+            let head = a.free_list(bucket(16));
+            let first = *head;
+            let second = (*first).next;
+            (*first).next = (*second).next;
+            (*second).next = first;
+            *head = second;
+
+            assert_eq!(Err(HeapError::UnsortedFreeList), a.check());
+        });
+    }
+
+    #[test]
+    fn test_check_wrong_size_class() {
+        with_memory(256, |_, a| unsafe {
+            let ptr = a.alloc(16);
+            a.alloc(8);
+            a.free(ptr);
+
+            // move the block to the list of bigger blocks
+            let block = *a.free_list(bucket(16));
+            *a.free_list(bucket(16)) = ptr::null_mut();
+            (*block).next = *a.free_list(bucket(32));
+            *a.free_list(bucket(32)) = block;
+
+            assert_eq!(Err(HeapError::UnsortedFreeList), a.check());
+        });
+    }
+
+    #[test]
+    fn test_check_cycle() {
+        with_memory(256, |_, a| unsafe {
+            let ptr1 = a.alloc(16);
+            a.alloc(16);
+            a.free(ptr1);
+
+            // close the free list into a loop
+            let first = *a.free_list(bucket(16));
+            (*first).next = first;
+
+            assert_eq!(Err(HeapError::CycleDetected), a.check());
+        });
+    }
+
+    #[test]
+    fn test_stats() {
+        with_memory(512, |_, a| unsafe {
+            let initial = a.stats();
+            assert_eq!(
+                HeapStats {
+                    total: 512 - hsize() - bbsize(),
+                    free: 512 - hsize() - bbsize(),
+                    used: 0,
+                    largest_free: 512 - hsize() - bbsize(),
+                    free_block_count: 1,
+                },
+                initial
+            );
+
+            let ptr = a.alloc(32);
+            a.alloc(64);
+            let stats = a.stats();
+            assert_eq!(32 + 64, stats.used);
+            assert_eq!(a.used(), stats.used);
+            // two more tags are now used
+            assert_eq!(initial.total - 2 * bbsize(), stats.total);
+            assert_eq!(stats.total, stats.used + stats.free);
+            assert!(stats.largest_free < initial.largest_free);
+
+            a.free(ptr);
+            let stats = a.stats();
+            assert_eq!(2, stats.free_block_count);
+            assert_eq!(64, stats.used);
+            assert_eq!(stats.total, stats.used + stats.free);
+        });
+    }
+
+    /// Runs `f` with an allocator serving two adjacent 256-byte
+    /// regions: the main one and an added one.
+    fn with_two_regions<F>(f: F)
+    where
+        F: Fn(*mut u8, &Smalloc),
+    {
+        unsafe {
+            let layout = Layout::from_size_align_unchecked(512, psize());
+            let memory = alloc::alloc(layout);
+            let a = Smalloc::new(memory, 256);
+            a.init().unwrap();
+            a.add_region(memory.add(256), 256).unwrap();
+
+            f(memory, &a);
+
+            alloc::dealloc(memory, layout);
+        }
+    }
+
+    fn block_sizes(a: &Smalloc) -> Vec<(u16, bool)> {
+        unsafe { a.blocks() }.map(|b| (b.size, b.free)).collect()
+    }
+
+    #[test]
+    fn test_add_region_too_small() {
+        with_memory(256, |memory, a| unsafe {
+            let mut region = [0xaau8; 64];
+            assert_eq!(
+                Err(InitError::RegionTooSmall),
+                a.add_region(region.as_mut_ptr(), Smalloc::MIN_REGION_SIZE - 1)
+            );
+            assert!(region.iter().all(|&b| b == 0xaa));
+            assert_eq!(
+                vec![((256 - hsize() - bbsize()) as u16, true)],
+                block_sizes(a)
+            );
+            assert_eq!(
+                memory.add(hsize() + bbsize()) as *const u8,
+                a.blocks().next().unwrap().addr
+            );
+        });
+    }
+
+    #[test]
+    fn test_add_region() {
+        with_two_regions(|memory, a| unsafe {
+            let main_size = (256 - hsize() - bbsize()) as u16;
+            let region_size = (256 - rsize() - bbsize()) as u16;
+
+            assert_eq!(Ok(()), a.check());
+            assert_eq!(
+                vec![
+                    memory.add(hsize() + bbsize()) as *const u8,
+                    memory.add(256 + rsize() + bbsize()) as *const u8
+                ],
+                a.blocks().map(|b| b.addr).collect::<Vec<_>>()
+            );
+            assert_eq!(vec![(main_size, true), (region_size, true)], block_sizes(a));
+            assert_eq!(
+                usize::from(main_size) + usize::from(region_size),
+                a.stats().free
+            );
+
+            // The best fit for each size is in a different region.
+            // Sizes are rounded to pointer size, which might leave a
+            // few bytes that are too small to split off.
+            let ptr1 = a.alloc(main_size as usize & !(psize() - 1));
+            let ptr2 = a.alloc(region_size as usize & !(psize() - 1));
+            assert_eq!(memory.add(hsize() + bbsize()), ptr1);
+            assert_eq!(memory.add(256 + rsize() + bbsize()), ptr2);
+            assert_eq!(ptr::null_mut(), a.alloc(8));
+            assert_eq!(Ok(()), a.check());
+
+            a.free(ptr1);
+            a.free(ptr2);
+            assert_eq!(Ok(()), a.check());
+            assert_eq!(vec![(main_size, true), (region_size, true)], block_sizes(a));
+        });
+    }
+
+    #[test]
+    fn test_no_merge_across_regions() {
+        with_two_regions(|_, a| unsafe {
+            let region_size = 256 - rsize() - bbsize();
+
+            // The last block of the main region and the first block of
+            // the added one are adjacent in memory.
+            // Best fit picks the smaller, added region first.
+            let first = a.alloc(region_size - 64 - bbsize());
+            let tail = a.alloc(64);
+            let head = a.alloc(32);
+            let last = a.alloc(256 - hsize() - 2 * bbsize() - 32);
+            assert_eq!(Ok(()), a.check());
+            assert_eq!(0, a.stats().free_block_count);
+
+            a.free(last);
+            a.free(first);
+            assert_eq!(Ok(()), a.check());
+            assert_eq!(
+                vec![
+                    (32, false),
+                    ((256 - hsize() - 2 * bbsize() - 32) as u16, true),
+                    ((region_size - 64 - bbsize()) as u16, true),
+                    (64, false)
+                ],
+                block_sizes(a)
+            );
+
+            // Blocks still coalesce within a region.
+            a.free(tail);
+            a.free(head);
+            assert_eq!(Ok(()), a.check());
+            assert_eq!(
+                vec![
+                    ((256 - hsize() - bbsize()) as u16, true),
+                    (region_size as u16, true)
+                ],
+                block_sizes(a)
+            );
+        });
+    }
+
+    #[test]
+    fn test_blocks() {
+        with_memory(256, |memory, a| unsafe {
+            let ptr1 = a.alloc(16);
+            let ptr2 = a.alloc(30);
+            let ptr3 = a.alloc(8);
+            a.free(ptr2);
+
+            let tail = 256 - hsize() - 4 * bbsize() - 16 - 32 - 8;
+            let expected = [
+                (ptr1, 16, false),
+                (ptr2, 32, true),
+                (ptr3, 8, false),
+                (ptr3.add(8 + bbsize()), tail, true),
+            ];
+            let blocks: Vec<BlockInfo> = a.blocks().collect();
+            assert_eq!(expected.len(), blocks.len());
+            for (block, &(addr, size, free)) in blocks.iter().zip(&expected) {
+                assert_eq!(
+                    BlockInfo {
+                        addr,
+                        size: size as u16,
+                        free,
+                    },
+                    *block
+                );
+            }
+
+            // The last block ends exactly at the end of the region.
+            let last = blocks[blocks.len() - 1];
+            assert_eq!(
+                memory.add(256) as *const u8,
+                last.addr.add(last.size as usize)
+            );
+        });
+    }
+
+    /// A growable byte buffer that goes through the `GlobalAlloc`
+    /// interface, the same way `Vec<u8>` does.
+    struct ByteVec<'a> {
+        alloc: &'a dyn GlobalAlloc,
+        ptr: *mut u8,
+        len: usize,
+        cap: usize,
+    }
+
+    impl<'a> ByteVec<'a> {
+        fn new(alloc: &'a dyn GlobalAlloc) -> ByteVec<'a> {
+            ByteVec {
+                alloc,
+                ptr: ptr::null_mut(),
+                len: 0,
+                cap: 0,
+            }
+        }
+
+        unsafe fn push(&mut self, x: u8) {
+            if self.len == self.cap {
+                let new_cap = ::core::cmp::max(4, self.cap * 2);
+                self.ptr = if self.ptr.is_null() {
+                    self.alloc
+                        .alloc(Layout::from_size_align_unchecked(new_cap, 1))
+                } else {
+                    self.alloc.realloc(
+                        self.ptr,
+                        Layout::from_size_align_unchecked(self.cap, 1),
+                        new_cap,
+                    )
+                };
+                assert!(!self.ptr.is_null());
+                self.cap = new_cap;
+            }
+
+            *self.ptr.add(self.len) = x;
+            self.len += 1;
+        }
+
+        unsafe fn as_slice(&self) -> &[u8] {
+            ::core::slice::from_raw_parts(self.ptr, self.len)
+        }
+    }
+
+    impl<'a> Drop for ByteVec<'a> {
+        fn drop(&mut self) {
+            if !self.ptr.is_null() {
+                unsafe {
+                    self.alloc
+                        .dealloc(self.ptr, Layout::from_size_align_unchecked(self.cap, 1));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_global_alloc_push_loop() {
+        with_memory(1024, |_, a| unsafe {
+            {
+                let mut v = ByteVec::new(a);
+                // a filler allocation, so the buffer can't always grow
+                // in place
+                let mut filler = ByteVec::new(a);
+
+                for i in 0..200 {
+                    v.push(i as u8);
+                    if i % 16 == 0 {
+                        filler.push(i as u8);
+                    }
+                }
+
+                assert_eq!(200, v.as_slice().len());
+                assert!(v.as_slice().iter().enumerate().all(|(i, &x)| x == i as u8));
+                assert!(a.used() >= 256);
+            }
+
+            assert_eq!(0, a.used());
+        });
+    }
+
+    #[test]
+    fn test_global_alloc_align() {
+        with_memory(1024, |_, a| unsafe {
+            let layout = Layout::from_size_align_unchecked(20, 16);
+
+            let ptr = GlobalAlloc::alloc(a, layout);
+            assert_eq!(0, ptr as usize % 16);
+            ptr::write_bytes(ptr, 0x5a, 20);
+
+            let zeroed = GlobalAlloc::alloc_zeroed(a, layout);
+            assert_eq!(0, zeroed as usize % 16);
+            assert!((0..20).all(|i| *zeroed.add(i) == 0));
+
+            let ptr = GlobalAlloc::realloc(a, ptr, layout, 100);
+            assert_eq!(0, ptr as usize % 16);
+            assert!((0..20).all(|i| *ptr.add(i) == 0x5a));
+
+            GlobalAlloc::dealloc(a, zeroed, layout);
+            GlobalAlloc::dealloc(a, ptr, Layout::from_size_align_unchecked(100, 16));
+            assert_eq!(0, a.used());
+        });
+    }
+
+    #[test]
+    fn test_free_single_block() {
+        with_memory(256, |memory, a| unsafe {
+            let ptr = a.alloc(32);
+            a.free(ptr);
+
+            assert_eq!(memory.offset(ihsize()) as *mut FreeBlock, first_free(a));
+            assert_eq!(
                 FreeBlock {
                     prev_size: 0x1,
-                    size: (256 - psize() - bbsize()) as u16,
+                    size: (256 - hsize() - bbsize()) as u16,
                     next: 0x0 as *mut FreeBlock
                 },
-                *(memory.offset(ipsize()) as *const FreeBlock)
+                *(memory.offset(ihsize()) as *const FreeBlock)
             );
         });
     }
@@ -627,19 +1802,17 @@ mod test {
 
             // The memory now is:
             // - pointer to new free block
-            assert_eq!(
-                memory.offset(ipsize()) as *mut FreeBlock,
-                *(memory as *const *mut FreeBlock)
-            );
+            assert_eq!(memory.offset(ihsize()) as *mut FreeBlock, first_free(a));
 
-            // - free block itself (points to next big block)
+            // - free block itself (the big block is in another size
+            //   class, so the list ends here)
             assert_eq!(
                 FreeBlock {
                     prev_size: 1,
                     size: 32,
-                    next: memory.offset(ipsize() + ibbsize() + 32 + ibbsize() + 16) as *mut _,
+                    next: ptr::null_mut(),
                 },
-                *(memory.offset(ipsize()) as *const FreeBlock)
+                *(memory.offset(ihsize()) as *const FreeBlock)
             );
 
             // - second busy block
@@ -648,17 +1821,17 @@ mod test {
                     prev_size: 32,
                     size: 16,
                 },
-                *(memory.offset(ipsize() + ibbsize() + 32) as *const BusyBlock)
+                *(memory.offset(ihsize() + ibbsize() + 32) as *const BusyBlock)
             );
 
             // - free block till the end
             assert_eq!(
                 FreeBlock {
                     prev_size: 17,
-                    size: (256 - psize() - bbsize() - 32 - bbsize() - 16 - bbsize()) as u16,
+                    size: (256 - hsize() - bbsize() - 32 - bbsize() - 16 - bbsize()) as u16,
                     next: 0x0 as *mut _,
                 },
-                *(memory.offset(ipsize() + ibbsize() + 32 + ibbsize() + 16) as *const FreeBlock)
+                *(memory.offset(ihsize() + ibbsize() + 32 + ibbsize() + 16) as *const FreeBlock)
             );
         });
     }
@@ -675,20 +1848,16 @@ mod test {
 
             // The memory now is:
             // - pointer to free block at start
-            assert_eq!(
-                memory.offset(ipsize()) as *mut FreeBlock,
-                *(memory as *const *mut FreeBlock)
-            );
+            assert_eq!(memory.offset(ihsize()) as *mut FreeBlock, first_free(a));
 
             // - merged free block
             assert_eq!(
                 FreeBlock {
                     prev_size: 1,
                     size: 32 + bbsize() as u16 + 16,
-                    next: memory.offset(ipsize() + ibbsize() + 32 + ibbsize() + 16 + ibbsize() + 16)
-                        as *mut _,
+                    next: ptr::null_mut(),
                 },
-                *(memory.offset(ipsize()) as *const FreeBlock)
+                *(memory.offset(ihsize()) as *const FreeBlock)
             );
 
             // - busy block
@@ -697,7 +1866,7 @@ mod test {
                     prev_size: 32 + bbsize() as u16 + 16,
                     size: 16,
                 },
-                *(memory.offset(ipsize() + ibbsize() + 32 + ibbsize() + 16) as *const _)
+                *(memory.offset(ihsize() + ibbsize() + 32 + ibbsize() + 16) as *const _)
             );
 
             // - free block till the end
@@ -705,11 +1874,11 @@ mod test {
                 FreeBlock {
                     prev_size: 17,
                     size: 512
-                        - (psize() + bbsize() + 32 + bbsize() + 16 + bbsize() + 16 + bbsize())
+                        - (hsize() + bbsize() + 32 + bbsize() + 16 + bbsize() + 16 + bbsize())
                             as u16,
                     next: ptr::null_mut(),
                 },
-                *(memory.offset(ipsize() + ibbsize() + 32 + ibbsize() + 16 + ibbsize() + 16)
+                *(memory.offset(ihsize() + ibbsize() + 32 + ibbsize() + 16 + ibbsize() + 16)
                     as *const _)
             );
         });
@@ -729,8 +1898,8 @@ mod test {
             // The memory now is:
             // - pointer to free block near start
             assert_eq!(
-                memory.offset(ipsize() + ibbsize() + 16) as *mut FreeBlock,
-                *(memory as *const *mut FreeBlock)
+                memory.offset(ihsize() + ibbsize() + 16) as *mut FreeBlock,
+                first_free(a)
             );
 
             // - busy block
@@ -739,7 +1908,7 @@ mod test {
                     prev_size: 0,
                     size: 16,
                 },
-                *(memory.offset(ipsize()) as *mut BusyBlock)
+                *(memory.offset(ihsize()) as *mut BusyBlock)
             );
 
             // - free block
@@ -747,9 +1916,9 @@ mod test {
                 FreeBlock {
                     prev_size: 17,
                     size: 32 + bbsize() as u16 + 16,
-                    next: memory.offset(ipsize() + 4 * ibbsize() + 16 + 32 + 16 + 32) as *mut _,
+                    next: ptr::null_mut(),
                 },
-                *(memory.offset(ipsize() + ibbsize() + 16) as *mut _)
+                *(memory.offset(ihsize() + ibbsize() + 16) as *mut _)
             );
 
             // - busy block
@@ -758,17 +1927,17 @@ mod test {
                     prev_size: 32 + bbsize() as u16 + 16,
                     size: 32,
                 },
-                *(memory.offset(ipsize() + 3 * ibbsize() + 16 + 32 + 16) as *mut _)
+                *(memory.offset(ihsize() + 3 * ibbsize() + 16 + 32 + 16) as *mut _)
             );
 
             // - free block
             assert_eq!(
                 FreeBlock {
                     prev_size: 33,
-                    size: 512 - (psize() + 5 * bbsize() + 16 + 32 + 16 + 32) as u16,
+                    size: 512 - (hsize() + 5 * bbsize() + 16 + 32 + 16 + 32) as u16,
                     next: ptr::null_mut(),
                 },
-                *(memory.offset(ipsize() + 4 * ibbsize() + 16 + 32 + 16 + 32) as *mut _)
+                *(memory.offset(ihsize() + 4 * ibbsize() + 16 + 32 + 16 + 32) as *mut _)
             );
         });
     }
@@ -789,8 +1958,8 @@ mod test {
             // The memory now is:
             // - pointer to free block near start
             assert_eq!(
-                memory.offset(ipsize() + ibbsize() + 16) as *mut FreeBlock,
-                *(memory as *const *mut FreeBlock)
+                memory.offset(ihsize() + ibbsize() + 16) as *mut FreeBlock,
+                first_free(a)
             );
 
             // - busy block (_ptr1)
@@ -799,7 +1968,7 @@ mod test {
                     prev_size: 0,
                     size: 16,
                 },
-                *(memory.offset(ipsize()) as *mut BusyBlock)
+                *(memory.offset(ihsize()) as *mut BusyBlock)
             );
 
             // - free block (ptr2, ptr3, ptr4 merged)
@@ -807,10 +1976,9 @@ mod test {
                 FreeBlock {
                     prev_size: 17,
                     size: 32 + bbsize() as u16 + 16 + bbsize() as u16 + 24,
-                    next: memory.offset(ipsize() + 5 * ibbsize() + 16 + 32 + 16 + 24 + 32)
-                        as *mut _,
+                    next: ptr::null_mut(),
                 },
-                *(memory.offset(ipsize() + ibbsize() + 16) as *mut _)
+                *(memory.offset(ihsize() + ibbsize() + 16) as *mut _)
             );
 
             // - busy block (_ptr5)
@@ -819,17 +1987,17 @@ mod test {
                     prev_size: 32 + bbsize() as u16 + 16 + bbsize() as u16 + 24,
                     size: 32,
                 },
-                *(memory.offset(ipsize() + 4 * ibbsize() + 16 + 32 + 16 + 24) as *mut _)
+                *(memory.offset(ihsize() + 4 * ibbsize() + 16 + 32 + 16 + 24) as *mut _)
             );
 
             // - free block till end
             assert_eq!(
                 FreeBlock {
                     prev_size: 33,
-                    size: 512 - (psize() + 6 * bbsize() + 16 + 32 + 16 + 24 + 32) as u16,
+                    size: 512 - (hsize() + 6 * bbsize() + 16 + 32 + 16 + 24 + 32) as u16,
                     next: ptr::null_mut(),
                 },
-                *(memory.offset(ipsize() + 5 * ibbsize() + 16 + 32 + 16 + 24 + 32) as *mut _)
+                *(memory.offset(ihsize() + 5 * ibbsize() + 16 + 32 + 16 + 24 + 32) as *mut _)
             );
         });
     }
@@ -849,24 +2017,21 @@ mod test {
             a.free(ptr3);
             a.free(ptr2);
 
-            assert_eq!(
-                memory.offset(ipsize()) as *mut FreeBlock,
-                *(memory as *const *mut FreeBlock)
-            );
+            assert_eq!(memory.offset(ihsize()) as *mut FreeBlock, first_free(a));
             assert_eq!(
                 FreeBlock {
                     prev_size: 0x1,
-                    size: (512 - psize() - bbsize()) as u16,
+                    size: (512 - hsize() - bbsize()) as u16,
                     next: 0x0 as *mut FreeBlock
                 },
-                *(memory.offset(ipsize()) as *const FreeBlock)
+                *(memory.offset(ihsize()) as *const FreeBlock)
             );
         });
     }
 
     #[test]
     fn test_free_list_is_sorted() {
-        with_memory(512, |memory, a| unsafe {
+        with_memory(512, |_, a| unsafe {
             let ptr1 = a.alloc(16);
             let _ptr2 = a.alloc(8);
             let ptr3 = a.alloc(16);
@@ -880,10 +2045,7 @@ mod test {
 
             // free list now is:
             // - start -> ptr1
-            assert_eq!(
-                ptr1.offset(-ibbsize()) as *mut FreeBlock,
-                *(memory as *const *mut FreeBlock)
-            );
+            assert_eq!(ptr1.offset(-ibbsize()) as *mut FreeBlock, first_free(a));
             // - ptr1 -> ptr3
             assert_eq!(
                 ptr3.offset(-ibbsize()) as *mut FreeBlock,
@@ -894,11 +2056,8 @@ mod test {
                 ptr5.offset(-ibbsize()) as *mut FreeBlock,
                 *(ptr3 as *const *mut FreeBlock)
             );
-            // - ptr5 -> rest
-            assert_eq!(
-                memory.offset(ipsize() + 6 * ibbsize() + 3 * 16 + 3 * 8) as *mut FreeBlock,
-                *(ptr5 as *const *mut FreeBlock)
-            );
+            // - ptr5 is the last block of its size class
+            assert_eq!(ptr::null_mut(), *(ptr5 as *const *mut FreeBlock));
         });
     }
 
@@ -930,6 +2089,26 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_usable_size() {
+        with_memory(512, |_, a| unsafe {
+            // rounded up to the pointer size, as in test_alloc_align
+            let ptr1 = a.alloc(17);
+            assert_eq!(
+                (17 + psize() - 1) & !(psize() - 1),
+                Smalloc::usable_size(ptr1)
+            );
+
+            // the block is reused whole, as in test_dont_split_too_small
+            let ptr2 = a.alloc(32);
+            let _ptr3 = a.alloc(8);
+            a.free(ptr2);
+            let ptr4 = a.alloc(32 - psize());
+            assert_eq!(ptr2, ptr4);
+            assert_eq!(32, Smalloc::usable_size(ptr4));
+        });
+    }
+
     #[test]
     fn test_dont_split_too_small() {
         with_memory(512, |memory, a| unsafe {
@@ -944,8 +2123,8 @@ mod test {
             // The memory is:
             // - pointer to only free block in memory
             assert_eq!(
-                memory.offset(ipsize() + 2 * ibbsize() + 32 + 8) as *mut FreeBlock,
-                *(memory as *mut _)
+                memory.offset(ihsize() + 2 * ibbsize() + 32 + 8) as *mut FreeBlock,
+                first_free(a)
             );
             // - BusyBlock as for ptr1
             assert_eq!(
@@ -953,7 +2132,7 @@ mod test {
                     prev_size: 0,
                     size: 32,
                 },
-                *(memory.offset(ipsize()) as *mut _)
+                *(memory.offset(ihsize()) as *mut _)
             );
             // - BusyBlock for ptr3
             assert_eq!(
@@ -961,16 +2140,16 @@ mod test {
                     prev_size: 32,
                     size: 8,
                 },
-                *(memory.offset(ipsize() + ibbsize() + 32) as *mut _)
+                *(memory.offset(ihsize() + ibbsize() + 32) as *mut _)
             );
             // - free block till end
             assert_eq!(
                 FreeBlock {
                     prev_size: 9,
-                    size: 512 - (psize() + 3 * bbsize() + 32 + 8) as u16,
+                    size: 512 - (hsize() + 3 * bbsize() + 32 + 8) as u16,
                     next: ptr::null_mut(),
                 },
-                *(memory.offset(ipsize() + 2 * ibbsize() + 32 + 8) as *mut _)
+                *(memory.offset(ihsize() + 2 * ibbsize() + 32 + 8) as *mut _)
             );
         });
     }
@@ -1010,6 +2189,250 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_high_water_mark() {
+        with_memory(512, |_, a| unsafe {
+            assert_eq!(0, a.high_water_mark());
+
+            let ptr1 = a.alloc(32);
+            let ptr2 = a.alloc(64);
+            assert_eq!(96, a.used());
+            assert_eq!(96, a.high_water_mark());
+
+            a.free(ptr2);
+            assert_eq!(32, a.used());
+            assert_eq!(96, a.high_water_mark());
+
+            let ptr3 = a.alloc(16);
+            assert_eq!(48, a.used());
+            assert_eq!(96, a.high_water_mark());
+
+            a.free(ptr1);
+            a.free(ptr3);
+            assert_eq!(0, a.used());
+            assert_eq!(96, a.high_water_mark());
+        });
+    }
+
+    #[test]
+    fn test_reset_high_water_mark() {
+        with_memory(512, |_, a| unsafe {
+            let ptr1 = a.alloc(64);
+            let ptr2 = a.alloc(8);
+            a.free(ptr1);
+
+            a.reset_high_water_mark();
+            assert_eq!(8, a.high_water_mark());
+
+            let ptr3 = a.alloc(24);
+            assert_eq!(32, a.high_water_mark());
+
+            a.free(ptr2);
+            a.free(ptr3);
+        });
+    }
+
+    #[test]
+    fn test_realloc_null() {
+        with_memory(256, |memory, a| unsafe {
+            let ptr = a.realloc(ptr::null_mut(), 8);
+
+            assert_eq!(memory.offset(ihsize() + ibbsize()), ptr);
+            assert_eq!(8, a.used());
+        });
+    }
+
+    #[test]
+    fn test_realloc_grow_in_place() {
+        with_memory(256, |memory, a| unsafe {
+            let ptr = a.alloc(16);
+            *ptr = 0x42;
+            let ret = a.realloc(ptr, 32);
+
+            assert_eq!(ptr, ret);
+            assert_eq!(0x42, *ret);
+            assert_eq!(
+                BusyBlock {
+                    prev_size: 0,
+                    size: 32,
+                },
+                *(memory.offset(ihsize()) as *const BusyBlock)
+            );
+            // - free block till end
+            assert_eq!(
+                memory.offset(ihsize() + ibbsize() + 32) as *mut FreeBlock,
+                first_free(a)
+            );
+            assert_eq!(
+                FreeBlock {
+                    prev_size: 33,
+                    size: (256 - hsize() - bbsize() - 32 - bbsize()) as u16,
+                    next: ptr::null_mut(),
+                },
+                *(memory.offset(ihsize() + ibbsize() + 32) as *const FreeBlock)
+            );
+            assert_eq!(32, a.used());
+        });
+    }
+
+    #[test]
+    fn test_realloc_grow_in_place_whole_block() {
+        with_memory(36 + hsize(), |memory, a| unsafe {
+            let ptr = a.alloc(8);
+            let ret = a.realloc(ptr, 32);
+
+            assert_eq!(ptr, ret);
+            assert_eq!(
+                BusyBlock {
+                    prev_size: 0,
+                    size: 32,
+                },
+                *(memory.offset(ihsize()) as *const BusyBlock)
+            );
+            assert_eq!(ptr::null_mut(), first_free(a));
+        });
+    }
+
+    #[test]
+    fn test_realloc_grow_with_move() {
+        with_memory(256, |memory, a| unsafe {
+            let ptr1 = a.alloc(16);
+            let ptr2 = a.alloc(16);
+            for i in 0..16 {
+                *ptr1.add(i) = i as u8;
+            }
+
+            let ret = a.realloc(ptr1, 64);
+
+            assert_eq!(ptr2.offset(16 + ibbsize()), ret);
+            for i in 0..16 {
+                assert_eq!(i as u8, *ret.add(i));
+            }
+            // - old block is free
+            assert_eq!(
+                FreeBlock {
+                    prev_size: 1,
+                    size: 16,
+                    next: ret.offset(64) as *mut _,
+                },
+                *(memory.offset(ihsize()) as *const FreeBlock)
+            );
+            assert_eq!(
+                BusyBlock {
+                    prev_size: 16,
+                    size: 64,
+                },
+                *(ret.offset(-ibbsize()) as *const BusyBlock)
+            );
+            assert_eq!(16 + 64, a.used());
+        });
+    }
+
+    #[test]
+    fn test_realloc_grow_fails() {
+        with_memory(64 + hsize(), |_, a| unsafe {
+            let ptr = a.alloc(16);
+            let _ptr2 = a.alloc(16);
+
+            assert_eq!(ptr::null_mut(), a.realloc(ptr, 32));
+            assert_eq!(
+                BusyBlock {
+                    prev_size: 0,
+                    size: 16,
+                },
+                *(ptr.offset(-ibbsize()) as *const BusyBlock)
+            );
+            assert_eq!(32, a.used());
+        });
+    }
+
+    #[test]
+    fn test_realloc_shrink() {
+        with_memory(256, |memory, a| unsafe {
+            let ptr1 = a.alloc(64);
+            let ptr2 = a.alloc(16);
+            let ret = a.realloc(ptr1, 16);
+
+            assert_eq!(ptr1, ret);
+            assert_eq!(
+                BusyBlock {
+                    prev_size: 0,
+                    size: 16,
+                },
+                *(memory.offset(ihsize()) as *const BusyBlock)
+            );
+            // - the tail is a new free block
+            assert_eq!(
+                FreeBlock {
+                    prev_size: 17,
+                    size: (64 - 16 - bbsize()) as u16,
+                    next: ptr::null_mut(),
+                },
+                *(memory.offset(ihsize() + ibbsize() + 16) as *const FreeBlock)
+            );
+            // - the next block knows the new size
+            assert_eq!(
+                BusyBlock {
+                    prev_size: (64 - 16 - bbsize()) as u16,
+                    size: 16,
+                },
+                *(ptr2.offset(-ibbsize()) as *const BusyBlock)
+            );
+            assert_eq!(32, a.used());
+        });
+    }
+
+    #[test]
+    fn test_realloc_shrink_merges_with_next() {
+        with_memory(256, |memory, a| unsafe {
+            let ptr = a.alloc(64);
+            let ret = a.realloc(ptr, 16);
+
+            assert_eq!(ptr, ret);
+            assert_eq!(
+                memory.offset(ihsize() + ibbsize() + 16) as *mut FreeBlock,
+                first_free(a)
+            );
+            assert_eq!(
+                FreeBlock {
+                    prev_size: 17,
+                    size: (256 - hsize() - bbsize() - 16 - bbsize()) as u16,
+                    next: ptr::null_mut(),
+                },
+                *(memory.offset(ihsize() + ibbsize() + 16) as *const FreeBlock)
+            );
+            assert_eq!(16, a.used());
+        });
+    }
+
+    #[test]
+    fn test_realloc_shrink_too_small_to_split() {
+        with_memory(256, |memory, a| unsafe {
+            let ptr = a.alloc(32);
+            let ret = a.realloc(ptr, 32 - psize());
+
+            assert_eq!(ptr, ret);
+            assert_eq!(
+                BusyBlock {
+                    prev_size: 0,
+                    size: 32,
+                },
+                *(memory.offset(ihsize()) as *const BusyBlock)
+            );
+            assert_eq!(32, a.used());
+        });
+    }
+
+    #[test]
+    fn test_realloc_zero() {
+        with_memory(256, |_, a| unsafe {
+            let ptr = a.alloc(32);
+
+            assert_eq!(ptr::null_mut(), a.realloc(ptr, 0));
+            assert_eq!(0, a.used());
+        });
+    }
+
     #[test]
     fn test_endurance() {
         // That's a fucking trick because standard rand doesn't export
@@ -1053,6 +2476,55 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_search_steps() {
+        #[cfg(target_pointer_width = "64")]
+        use rand_isaac::Isaac64Rng as IsaacWordRng;
+        #[cfg(target_pointer_width = "32")]
+        use rand_isaac::IsaacRng as IsaacWordRng;
+
+        use ::rand::{Rng, SeedableRng};
+
+        with_memory(16 * 1024, |_, a| unsafe {
+            let mut rng = IsaacWordRng::from_seed([42; 32]);
+            let mut allocs = Vec::new();
+
+            // A single sorted free list would be walked past every
+            // smaller free block up to the one that fits.
+            let mut sorted_list_steps = 0;
+
+            for _ in 0..2000 {
+                // allocate more often than free, so the heap fills
+                // up and fragments
+                if allocs.is_empty() || rng.gen_range(0, 3) != 0 {
+                    let size = rng.gen_range(1, 256);
+                    let rounded = (size + psize() - 1) & !(psize() - 1);
+                    sorted_list_steps += a
+                        .blocks()
+                        .filter(|b| b.free && (b.size as usize) < rounded)
+                        .count()
+                        + 1;
+
+                    let ptr = a.alloc(size);
+                    if !ptr.is_null() {
+                        allocs.push(ptr);
+                    }
+                } else {
+                    let i = rng.gen_range(0, allocs.len());
+                    a.free(allocs.remove(i));
+                }
+            }
+
+            println!(
+                "sorted list: {} steps, size classes: {} steps",
+                sorted_list_steps,
+                a.visited.get()
+            );
+            assert_eq!(Ok(()), a.check());
+            assert!(a.visited.get() * 4 < sorted_list_steps);
+        });
+    }
+
     #[test]
     fn test_update_split_next_prev() {
         const MEMORY_SIZE: usize = 64 * 1024;
@@ -43,4 +43,8 @@ impl Led {
     pub fn turn_off(&self) {
         self.gpio.clear_bit(self.pin);
     }
+
+    pub fn toggle(&self) {
+        self.gpio.toggle_bit(self.pin);
+    }
 }
@@ -23,86 +23,269 @@ extern crate breactor;
 
 mod led;
 mod led_music;
-mod log;
-mod terminal;
 
 use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use futures::future;
 use futures::FutureExt;
-use futures::Poll;
+use futures::StreamExt;
 use futures::TryFutureExt;
 
 use stm32f4::gpio::{GPIO_B, GPIO_D};
 use stm32f4::rcc::RCC;
 use stm32f4::timer::TIM2;
-use stm32f4::{gpio, nvic, rcc, timer, usart};
+use stm32f4::{gpio, i2c, nvic, rcc, timer, usart};
 
 use ::breactor::start_send_all_string::StartSendAllString;
 
 use ::breactor::REACTOR;
 
+use ::dev::log::{Console, Level};
 use ::dev::usart::Usart;
 
-use ::dev::htu21d::{Htu21d, Htu21dError};
+use ::dev::htu21d::Htu21d;
 
 use ::dev::cs43l22::Cs43l22;
 
-use ::dev::esp8266::{AccessPoint, Esp8266};
+use ::dev::esp8266::{
+    join_with_backoff, AccessPoint, Error as EspError, Esp8266, RECONNECT_BACKOFF,
+};
 
 pub static USART3: Usart<[u8; 32], [u8; 32]> =
     Usart::new(unsafe { &::stm32f4::usart::USART3 }, [0; 32], [0; 32]);
 
 pub static mut ESP8266: Esp8266<&'static Usart<[u8; 32], [u8; 32]>> = Esp8266::new(&USART3);
 
-pub static USART2: Usart<[u8; 128], [u8; 32]> =
-    Usart::new(unsafe { &::stm32f4::usart::USART2 }, [0; 128], [0; 32]);
+/// Serializes AT command sequences of the boot task and the terminal.
+pub static ESP8266_LOCK: ::breactor::mutex::Mutex = ::breactor::mutex::Mutex::new();
 
-macro_rules! debug_log {
-    ( $( $x:expr ),* ) => {
-        {
-            use ::core::fmt::Write;
-            let _lock = unsafe { ::stm32f4::IrqLock::new() };
+/// Set once any task has joined an access point.
+static ESP8266_JOINED: AtomicBool = AtomicBool::new(false);
+
+/// Joins the access point once `ESP8266_LOCK` is acquired.
+pub fn join_ap(
+    ssid: &'static str,
+    pass: &'static str,
+) -> Pin<::alloc::boxed::Box<dyn future::Future<Output = Result<bool, EspError>>>> {
+    join_ap_locked(ssid, pass, false)
+}
+
+/// Same as `join_ap`, but succeeds without rejoining if an access
+/// point has already been joined (e.g., from the terminal), so the
+/// boot task doesn't replace the newer credentials.
+fn join_ap_once(
+    ssid: &'static str,
+    pass: &'static str,
+) -> Pin<::alloc::boxed::Box<dyn future::Future<Output = Result<bool, EspError>>>> {
+    join_ap_locked(ssid, pass, true)
+}
 
-            let _ = write!(unsafe{&::stm32f4::usart::USART2}, $($x),*);
+fn join_ap_locked(
+    ssid: &'static str,
+    pass: &'static str,
+    once: bool,
+) -> Pin<::alloc::boxed::Box<dyn future::Future<Output = Result<bool, EspError>>>> {
+    ::alloc::boxed::Box::pin(ESP8266_LOCK.lock().then(move |lock| {
+        if once && ESP8266_JOINED.load(Ordering::SeqCst) {
+            return future::ready(Ok(true)).left_future();
         }
+
+        unsafe { &mut ESP8266 }
+            .join_ap(ssid, pass)
+            .map(move |res| {
+                if res == Ok(true) {
+                    ESP8266_JOINED.store(true, Ordering::SeqCst);
+                }
+                drop(lock);
+                res
+            })
+            .right_future()
+    }))
+}
+
+#[cfg(not(feature = "usart-dma"))]
+pub static USART2: Usart<[u8; 128], [u8; 32]> =
+    Usart::new(unsafe { &::stm32f4::usart::USART2 }, [0; 128], [0; 32]);
+
+/// USART2 TX is DMA1 stream 6, channel 4.
+#[cfg(feature = "usart-dma")]
+pub static USART2: Usart<[u8; 128], [u8; 32]> = Usart::with_tx_dma(
+    unsafe { &::stm32f4::usart::USART2 },
+    ::dev::usart::TxDma {
+        dma: unsafe { &::stm32f4::dma::DMA1 },
+        stream: 6,
+        channel: 4,
+    },
+    [0; 128],
+    [0; 32],
+);
+
+/// Serializes `log!` messages of different tasks.
+static CONSOLE: Console<[u8; 128], [u8; 32]> = Console::new(&USART2);
+
+/// Formats the message, and returns a future that writes it once the
+/// console is available. Messages below the current log level are
+/// dropped.
+macro_rules! log_at {
+    ( $level:expr, $( $x:expr ),* ) => {
+        $crate::CONSOLE.log($level, format_args!($($x),*))
     };
 }
 
 macro_rules! log {
     ( $( $x:expr ),* ) => {
-        {
-            use ::core::fmt::Write;
-            let _ = write!(log::Logger::new(&USART2), $($x),*);
-        }
+        log_at!(::dev::log::Level::Info, $($x),*)
     };
 }
 
+macro_rules! debug_log {
+    ( $( $x:expr ),* ) => {
+        log_at!(::dev::log::Level::Debug, $($x),*)
+    };
+}
+
+mod terminal;
+
 static HTU21D: Htu21d = Htu21d::new(&::dev::i2c::I2C1_BUS);
 
 static mut CS43L22: Cs43l22 = Cs43l22::new(&::dev::i2c::I2C1_BUS, false);
 
+static I2C1_INIT: i2c::I2cInit = i2c::I2cInit {
+    clock_speed: 10000,
+    mode: i2c::Mode::I2C,
+    duty_cycle: i2c::DutyCycle::DutyCycle_2,
+    own_address1: 0,
+    ack: i2c::Acknowledgement::Disable,
+    acknowledged_address: i2c::AcknowledgedAddress::Bit7,
+};
+
+static I2C1_PINS: ::dev::i2c::I2cPins = ::dev::i2c::I2cPins {
+    gpio: unsafe { &GPIO_B },
+    scl: 6,
+    sda: 9,
+    af: gpio::GpioAF::AF4,
+};
+
 #[cfg(target_os = "none")]
 fn init_memory() {
     const HEAP_SIZE: usize = 64 * 1024;
     static mut HEAP: [u8; HEAP_SIZE] = [0; HEAP_SIZE];
 
-    ::linkmem::init(smalloc::Smalloc {
-        start: unsafe { &mut HEAP }.as_mut_ptr(),
-        size: HEAP_SIZE,
-    });
+    // Fails to compile if HEAP_SIZE is too small for Smalloc.
+    #[allow(dead_code)]
+    const HEAP_SIZE_CHECK: [(); 0] = [(); (HEAP_SIZE < smalloc::Smalloc::MIN_SIZE) as usize];
+
+    // Poisoning freed memory makes use-after-free easier to spot, but
+    // slows down `free`.
+    ::linkmem::init(
+        smalloc::Smalloc::new(unsafe { &mut HEAP }.as_mut_ptr(), HEAP_SIZE)
+            .with_poison(cfg!(debug_assertions)),
+        false,
+    )
+    .expect("heap is too small");
+
+    // Nothing else is placed in CCM RAM. It is not reachable by DMA,
+    // but DMA buffers come from their own heap anyway.
+    const CCM_START: usize = 0x1000_0000;
+    const CCM_SIZE: usize = 64 * 1024;
+
+    unsafe {
+        RCC.ahb1_clock_enable(rcc::Ahb1Enable::CCMDATARAM);
+        ::linkmem::add_region(CCM_START as *mut u8, CCM_SIZE).expect("CCM region is too small");
+    }
+
+    // Statics are placed in main SRAM, which is reachable by DMA.
+    const DMA_HEAP_SIZE: usize = 8 * 1024;
+    static mut DMA_HEAP: [u8; DMA_HEAP_SIZE] = [0; DMA_HEAP_SIZE];
+
+    ::linkmem::init_dma(smalloc::Smalloc::new(
+        unsafe { &mut DMA_HEAP }.as_mut_ptr(),
+        DMA_HEAP_SIZE,
+    ))
+    .expect("DMA heap is too small");
+
+    ::linkmem::set_oom_hook(panicking::report_oom);
 }
 
 #[cfg(not(target_os = "none"))]
 fn init_memory() {}
 
+/// CRC of the flash image, stored into `kernel.bin` after linking by
+/// `tools/stamp_crc.py`. `stm32_flash.ld` places it right after the
+/// image.
+#[cfg(target_os = "none")]
+#[link_section = ".image_crc"]
+#[no_mangle]
+#[used]
+pub static IMAGE_CRC: u32 = UNSTAMPED_CRC;
+
+/// Erased flash. Images that haven't been stamped (e.g., loaded from
+/// the ELF by GDB) keep it and are not checked.
+#[cfg(target_os = "none")]
+const UNSTAMPED_CRC: u32 = 0xffff_ffff;
+
+/// Panics if the flash image doesn't match `IMAGE_CRC`.
+#[cfg(target_os = "none")]
+fn check_image() {
+    extern "C" {
+        static __image_start: u8;
+        static __image_end: u8;
+    }
+
+    unsafe {
+        // The compiler must not assume the placeholder value.
+        let expected = ::core::ptr::read_volatile(&IMAGE_CRC);
+        if expected == UNSTAMPED_CRC {
+            return;
+        }
+
+        let start = &__image_start as *const u8;
+        let len = (&__image_end as *const u8 as usize - start as usize) / 4;
+        if !::stm32f4::crc::check_flash(start as *const u32, len, expected) {
+            panic!("flash image is corrupted");
+        }
+    }
+}
+
+#[cfg(not(target_os = "none"))]
+fn check_image() {}
+
+/// Flash sector that holds the configuration, see `stm32_flash.ld`.
+const CONFIG_SECTOR: u32 = 11;
+
+/// The persistent configuration storage.
+pub fn config_storage() -> ::dev::config::FlashStorage {
+    extern "C" {
+        static __config_start: u8;
+    }
+
+    unsafe {
+        ::dev::config::FlashStorage::new(
+            &::stm32f4::flash::FLASH,
+            CONFIG_SECTOR,
+            &__config_start as *const u8 as *mut u8,
+        )
+    }
+}
+
 /// The main entry of the kernel.
 #[no_mangle]
 pub extern "C" fn kmain() -> ! {
     init_memory();
+
+    let mut config = ::dev::config::Config::load_or_default(&config_storage());
+    // The tasks borrow the config, and kmain never returns.
+    let config: &'static _ = unsafe { lifetime_loundary(&mut config) };
+
     unsafe {
         init_usart2();
-        init_esp8266();
+    }
+    // Right after the console is up, so the panic is reported.
+    check_image();
+
+    unsafe {
+        init_esp8266(config.baud);
         init_leds();
         init_timer();
         init_i2c();
@@ -116,7 +299,10 @@ pub extern "C" fn kmain() -> ! {
     }
 
     // unsafe { &mut ::dev::rng::RNG }.enable();
-    // let mut print_rng = unsafe { &mut ::dev::rng::RNG }
+    // let mut print_rng = ::breactor::throttle::throttle(
+    //     ::breactor::time::Duration::from_secs(1),
+    //     unsafe { &mut ::dev::rng::RNG },
+    // )
     //     .for_each(|r| {
     //         use core::fmt::Write;
     //         let _ = writeln!(unsafe { &::stm32f4::usart::USART2 }, "RNG: {:?}\r", r);
@@ -132,73 +318,69 @@ pub extern "C" fn kmain() -> ! {
     .and_then(|stdout| terminal::run_terminal(&USART2, stdout))
     .map(|_| ());
 
+    // The reset waits until the sensor is up again.
     let mut htu21d = HTU21D
         .soft_reset()
-        .and_then(|_| {
-            // This is needed because device is not instantly up after
-            // reset, so we poll it, untill it ready.
-            future::poll_fn(|cx| {
-                match ready!(HTU21D.read_temperature_hold_master().poll_unpin(cx)) {
-                    Ok(temp) => Poll::Ready(Ok(temp)),
-                    // Acknowledge failure -> device is not ready -> retry
-                    Err(Htu21dError::I2cError(dev::i2c::Error::AcknowledgementFailure)) => {
-                        Poll::Pending
-                    }
-                    Err(x) => Poll::Ready(Err(x)),
-                }
-            })
-        })
+        .and_then(|_| HTU21D.read_temperature_hold_master())
         .and_then(|temp| {
             HTU21D
                 .read_humidity_hold_master()
                 .map_ok(move |hum| (temp, hum))
         })
-        .then(|x| {
-            match x {
-                Ok((temp, hum)) => log!("Temperature: {} C      Humidity: {}%\r\n", temp, hum),
-                Err(err) => log!("HTU21D error: {:?}\r\n", err),
+        .then(|x| match x {
+            Ok((temp, hum)) => {
+                log!("Temperature: {} C      Humidity: {}%\r\n", temp, hum).left_future()
             }
-
-            future::ready(())
+            Err(err) => log_at!(Level::Error, "HTU21D error: {:?}\r\n", err).right_future(),
         });
 
-    let mut cs43l22 = unsafe { &mut CS43L22 }.get_chip_id().then(|res| {
-        match res {
-            Ok(id) => {
-                log!("CS43L22 CHIP ID: 0b{:b}\r\n", id);
-            }
-            Err(err) => {
-                log!("Error: {:?}\r\n", err);
-            }
-        }
-
-        future::ready(())
+    let mut cs43l22 = unsafe { &mut CS43L22 }.get_chip_id().then(|res| match res {
+        Ok(id) => log!("CS43L22 CHIP ID: 0b{:b}\r\n", id).left_future(),
+        Err(err) => log_at!(Level::Error, "CS43L22 error: {:?}\r\n", err).right_future(),
     });
 
-    let mut esp8266 = unsafe { &mut ESP8266 }
-        .check_at()
-        .then(|x| {
-            log!("\r\nESP CHECK AT: {:?}\r\n", x);
-            future::ready(Ok(()) as Result<(), ()>)
-        })
-        .then(|_| unsafe { &mut ESP8266 }.list_aps::<[AccessPoint; 32]>())
-        .and_then(|(aps, size)| {
-            debug_log!("\r\nAccess points:\r\n");
-            for ap in &aps[0..::core::cmp::min(size, aps.len())] {
-                debug_log!("{:?}\r\n", ap);
-            }
-
-            future::ready(Ok(()))
-        })
-        .then(|_| unsafe { &mut ESP8266 }.join_ap("Rotem Indiana_Guest", "snickershock"))
-        .and_then(|res| {
-            debug_log!("Join status: {:?}", res);
-            future::ready(Ok(()))
+    let mut esp8266_backoff = RECONNECT_BACKOFF;
+    let esp8266_backoff = unsafe { lifetime_loundary(&mut esp8266_backoff) };
+    let mut esp8266 = ESP8266_LOCK
+        .lock()
+        .then(|lock| {
+            unsafe { &mut ESP8266 }
+                .check_at()
+                .then(|x| log!("\r\nESP CHECK AT: {:?}\r\n", x).map(|()| Ok(()) as Result<(), ()>))
+                .then(|_| unsafe { &mut ESP8266 }.list_aps::<[AccessPoint; 32]>())
+                .and_then(|(aps, size)| {
+                    let count = ::core::cmp::min(size, aps.len());
+                    debug_log!("\r\nAccess points:\r\n")
+                        .then(move |()| {
+                            futures::stream::iter(0..count)
+                                .for_each(move |i| debug_log!("{:?}\r\n", aps[i]))
+                        })
+                        .map(Ok)
+                })
+                .then(move |res| {
+                    // The terminal may join while the boot task backs off.
+                    drop(lock);
+                    match res {
+                        Ok(()) => future::ready(()).left_future(),
+                        Err(err) => {
+                            log_at!(Level::Error, "\r\nESP8266 error: {:?}\r\n", err).right_future()
+                        }
+                    }
+                })
         })
-        .map_err(|err| {
-            log!("\r\nESP8266 error: {:?}\r\n", err);
+        .then(move |()| {
+            join_with_backoff(esp8266_backoff, move || {
+                join_ap_once(config.ssid(), config.pass())
+            })
         })
-        .map(|_| ());
+        .then(|()| log!("Joined access point\r\n"));
+
+    // Blinks LD3 at 2 Hz, to show the timer is running.
+    let mut blink = ::breactor::time::interval(::breactor::time::Duration::from_millis(250))
+        .for_each(|()| {
+            led::LD3.toggle();
+            future::ready(())
+        });
 
     unsafe {
         let reactor = &REACTOR;
@@ -207,11 +389,36 @@ pub extern "C" fn kmain() -> ! {
         //
         // The infinite loop below makes all values above it
         // effectively 'static.
-        reactor.add_task(5, Pin::new_unchecked(lifetime_loundary(&mut terminal)));
-        // reactor.add_task(4, Pin::new_unchecked(lifetime_loundary(&mut print_rng)));
-        reactor.add_task(6, Pin::new_unchecked(lifetime_loundary(&mut htu21d)));
-        reactor.add_task(2, Pin::new_unchecked(lifetime_loundary(&mut cs43l22)));
-        reactor.add_task(1, Pin::new_unchecked(lifetime_loundary(&mut esp8266)));
+        reactor.add_task_named(
+            5,
+            "terminal",
+            Pin::new_unchecked(lifetime_loundary(&mut terminal)),
+        );
+        // reactor.add_task_named(
+        //     4,
+        //     "print_rng",
+        //     Pin::new_unchecked(lifetime_loundary(&mut print_rng)),
+        // );
+        reactor.add_task_named(
+            6,
+            "htu21d",
+            Pin::new_unchecked(lifetime_loundary(&mut htu21d)),
+        );
+        reactor.add_task_named(
+            2,
+            "cs43l22",
+            Pin::new_unchecked(lifetime_loundary(&mut cs43l22)),
+        );
+        reactor.add_task_named(
+            1,
+            "esp8266",
+            Pin::new_unchecked(lifetime_loundary(&mut esp8266)),
+        );
+        reactor.add_task_named(
+            0,
+            "blink",
+            Pin::new_unchecked(lifetime_loundary(&mut blink)),
+        );
 
         loop {
             reactor.run();
@@ -227,19 +434,19 @@ unsafe fn lifetime_loundary<'a, 'b, T: ?Sized>(val: &'a mut T) -> &'b mut T {
     &mut *(val as *mut _)
 }
 
+/// `breactor::time` tick frequency, in Hz.
+const TICK_HZ: u32 = 100;
+
 unsafe fn init_timer() {
     RCC.apb1_clock_enable(rcc::Apb1Enable::TIM2);
 
-    TIM2.init(&timer::TimInit {
-        prescaler: 40000,
-        counter_mode: timer::CounterMode::Up,
-        period: 128,
-        clock_division: timer::ClockDivision::Div1,
-        repetition_counter: 0,
-    });
+    let clocks = RCC.clock_freqs().expect("invalid clock configuration");
+    let (init, _) = timer::TimInit::from_frequency(TICK_HZ, &clocks);
+    TIM2.init(&init);
 
     TIM2.it_enable(timer::Dier::UIE);
 
+    ::breactor::time::set_tick_frequency(TICK_HZ);
     TIM2.enable();
 
     nvic::init(&nvic::NvicInit {
@@ -264,10 +471,6 @@ unsafe fn init_leds() {
 }
 
 unsafe fn init_usart2() {
-    use ::stm32f4::usart::USART2;
-
-    RCC.apb1_clock_enable(rcc::Apb1Enable::USART2);
-
     // Enable the peripheral clock for the pins used by USART2, PD5
     // for TX and PD6 for RX
     RCC.ahb1_clock_enable(rcc::Ahb1Enable::GPIOD);
@@ -297,21 +500,29 @@ unsafe fn init_usart2() {
 
     // The RX and TX pins are now connected to their AF so that the
     // USART2 can take over control of the pins
-    USART2.enable(&usart::UsartConfig {
-        data_bits: usart::DataBits::Bits8,
-        stop_bits: usart::StopBits::Bits1,
-        flow_control: usart::FlowControl::No,
-        baud_rate: 115_200,
-    });
-
-    USART2.it_enable(usart::Interrupt::RXNE);
+    usart::UsartId::USART2.init(
+        &RCC,
+        &usart::UsartConfig {
+            data_bits: usart::DataBits::Bits8,
+            stop_bits: usart::StopBits::Bits1,
+            flow_control: usart::FlowControl::No,
+            parity: usart::Parity::None,
+            baud_rate: 115_200,
+        },
+        0,
+        1,
+    );
 
-    nvic::init(&nvic::NvicInit {
-        irq_channel: nvic::IrqChannel::USART2,
-        priority: 0,
-        subpriority: 1,
-        enable: true,
-    });
+    #[cfg(feature = "usart-dma")]
+    {
+        RCC.ahb1_clock_enable(rcc::Ahb1Enable::DMA1);
+        nvic::init(&nvic::NvicInit {
+            irq_channel: nvic::IrqChannel::DMA1_Stream6,
+            priority: 0,
+            subpriority: 1,
+            enable: true,
+        });
+    }
 }
 
 #[cfg(target_os = "none")]
@@ -338,12 +549,26 @@ pub mod panicking {
                     let _ = write!(unsafe { &USART2 }, "\r\nPANIC\r\n");
                 }
             }
+            if let Some(name) = ::breactor::REACTOR.current_task_name() {
+                let _ = write!(unsafe { &USART2 }, "\r\nin task {}", name);
+            }
         }
         loop {
             unsafe { ::stm32f4::__wait_for_interrupt() };
         }
     }
 
+    /// Logs the failing allocation before `alloc_error` halts.
+    pub fn report_oom(size: usize) {
+        let _lock = unsafe { ::stm32f4::IrqLock::new() };
+        let _ = write!(
+            unsafe { &USART2 },
+            "\r\nOUT OF MEMORY: {} bytes requested\r\n{:?}\r\n",
+            size,
+            ::linkmem::stats()
+        );
+    }
+
     #[alloc_error_handler]
     fn alloc_error(_: core::alloc::Layout) -> ! {
         {
@@ -359,23 +584,14 @@ pub mod panicking {
 
 #[no_mangle]
 pub unsafe extern "C" fn __isr_tim2() {
-    static mut LED3_VALUE: bool = false;
-
     if TIM2.it_status(timer::Dier::UIE) {
         TIM2.it_clear_pending(timer::Dier::UIE);
 
-        LED3_VALUE = !LED3_VALUE;
-        if LED3_VALUE {
-            led::LD3.turn_on();
-        } else {
-            led::LD3.turn_off();
-        }
+        ::breactor::time::tick();
     }
 }
 
 unsafe fn init_i2c() {
-    use stm32f4::i2c;
-
     rcc::RCC.ahb1_clock_enable(rcc::Ahb1Enable::GPIOD);
     GPIO_D.enable(
         4,
@@ -414,14 +630,7 @@ unsafe fn init_i2c() {
     );
 
     rcc::RCC.apb1_clock_enable(rcc::Apb1Enable::I2C1);
-    i2c::I2C1.init(&i2c::I2cInit {
-        clock_speed: 10000,
-        mode: i2c::Mode::I2C,
-        duty_cycle: i2c::DutyCycle::DutyCycle_2,
-        own_address1: 0,
-        ack: i2c::Acknowledgement::Disable,
-        acknowledged_address: i2c::AcknowledgedAddress::Bit7,
-    });
+    i2c::I2C1.init(&I2C1_INIT);
 
     nvic::init(&nvic::NvicInit {
         irq_channel: nvic::IrqChannel::I2C1_EV,
@@ -448,11 +657,7 @@ unsafe fn init_rng() {
     });
 }
 
-unsafe fn init_esp8266() {
-    use ::stm32f4::usart::USART3;
-
-    RCC.apb1_clock_enable(rcc::Apb1Enable::USART3);
-
+unsafe fn init_esp8266(baud_rate: u32) {
     // Enable the peripheral clock for the pins used by USART3, PD8
     // for TX and PD9 for RX
     RCC.ahb1_clock_enable(rcc::Ahb1Enable::GPIOD);
@@ -480,21 +685,18 @@ unsafe fn init_esp8266() {
 
     // The RX and TX pins are now connected to their AF so that the
     // USART3 can take over control of the pins
-    USART3.enable(&usart::UsartConfig {
-        data_bits: usart::DataBits::Bits8,
-        stop_bits: usart::StopBits::Bits1,
-        flow_control: usart::FlowControl::No,
-        baud_rate: 115_200,
-    });
-
-    USART3.it_enable(usart::Interrupt::RXNE);
-
-    nvic::init(&nvic::NvicInit {
-        irq_channel: nvic::IrqChannel::USART3,
-        priority: 0,
-        subpriority: 4,
-        enable: true,
-    });
+    usart::UsartId::USART3.init(
+        &RCC,
+        &usart::UsartConfig {
+            data_bits: usart::DataBits::Bits8,
+            stop_bits: usart::StopBits::Bits1,
+            flow_control: usart::FlowControl::No,
+            parity: usart::Parity::None,
+            baud_rate,
+        },
+        0,
+        4,
+    );
 }
 
 #[no_mangle]
@@ -502,6 +704,12 @@ pub unsafe extern "C" fn __isr_usart2() {
     USART2.isr()
 }
 
+#[cfg(feature = "usart-dma")]
+#[no_mangle]
+pub unsafe extern "C" fn __isr_dma1_stream6() {
+    USART2.dma_tx_isr()
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn __isr_usart3() {
     USART3.isr()
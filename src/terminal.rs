@@ -1,15 +1,49 @@
 use crate::led;
 use crate::led_music;
+use core::fmt::Write;
 use core::task::Context;
 
 use core::pin::Pin;
 use futures::future::try_join;
-use futures::{Future, Poll, Sink, Stream, StreamExt, TryFutureExt, TryStreamExt};
+use futures::{Future, Poll, Stream, StreamExt, TryFutureExt, TryStreamExt};
 
+use breactor::ready_bytes::{ready_bytes, Chunk};
+use breactor::start_send_all_bytes::StartSendAllBytes;
 use breactor::start_send_all_string::StartSendAllString;
+use breactor::start_send_bytes::{SinkBytes, StartSendBytes};
+
+use dev::log::Message;
 
 const PROMPT: &str = "> ";
 
+/// Step limit for `bf` programs, so an infinite loop doesn't hang the
+/// terminal.
+const BF_MAX_STEPS: usize = 100_000;
+
+/// Number of heap blocks `mem` shows.
+const MEM_MAX_BLOCKS: usize = 32;
+
+/// Heap blocks `mem` is printing, copied out of the allocator.
+static mut MEM_BLOCKS: [::linkmem::BlockInfo; MEM_MAX_BLOCKS] = [::linkmem::BlockInfo {
+    addr: ::core::ptr::null(),
+    size: 0,
+    free: false,
+}; MEM_MAX_BLOCKS];
+
+/// The `mem` output line being sent.
+static mut MEM_LINE: Message = Message::new();
+
+/// Output of the last `bf` program. It is sent after the program
+/// finishes; longer output is truncated.
+static mut BF_OUTPUT: Message = Message::new();
+
+/// The configuration `wifi join` is joining with. It is saved once the
+/// join succeeds; the join borrows the credentials until then.
+static mut WIFI_CONFIG: Option<::dev::config::Config> = None;
+
+/// ESP8266 join in progress, see `super::join_ap`.
+type WifiJoin = Pin<::alloc::boxed::Box<dyn Future<Output = Result<bool, ::dev::esp8266::Error>>>>;
+
 const HELP_MESSAGE: &str = "Available commands:\r
 hi      -- welcomes you\r
 pony    -- surprise!\r
@@ -19,16 +53,27 @@ pony    -- surprise!\r
 -6/+6   -- turn off/on LED6\r
 led-fun -- some fun with LEDs\r
 temp    -- read temperature from HTU21D sensor\r
+i2c-recover -- free I2C1 bus stuck by a slave\r
+scan-i2c -- list devices responding on I2C1\r
+uart-stats -- show USART traffic and error counters\r
+mem     -- show heap usage and block map\r
+bf P!I  -- run brainfuck program P with input I\r
+altfn X -- list pins usable for peripheral X (e.g., altfn usart6)\r
+loglevel [L] -- show or set log level (error, warn, info, debug)\r
+allocbench -- measure average cycles per alloc/free\r
+wifi join S P -- join access point S with password P, and save them\r
 panic   -- throw a panic\r
 help    -- print this help\r
 ";
 
-macro_rules! log {
+/// Console message being written, see `reply!`.
+type Log = ::dev::log::Log<'static, [u8; 128], [u8; 32]>;
+
+/// Like `log!`, but ignores the log level, so command output is
+/// always printed.
+macro_rules! reply {
     ( $( $x:expr ),* ) => {
-        {
-            use ::core::fmt::Write;
-            let _ = write!(super::log::Logger::new(&super::USART2), $($x),*);
-        }
+        super::CONSOLE.print(format_args!($($x),*))
     };
 }
 
@@ -65,6 +110,7 @@ const PONY: &str = "\r
                        \\_______)     \\_______)\r
 ";
 
+#[allow(clippy::large_enum_variant)] // there is a single terminal, no need to box
 pub enum CommandResult<S> {
     Sink(Option<S>),
     Temperature(
@@ -74,15 +120,27 @@ pub enum CommandResult<S> {
             ::dev::htu21d::Htu21dCommand<::dev::htu21d::HoldMaster, ::dev::htu21d::Humidity>,
         >,
     ),
+    I2cScan(Option<S>, ::dev::i2c::Scan<'static>),
+    I2cRecover(Option<S>, ::dev::i2c::Recover),
+    WifiJoin(Option<S>, WifiJoin),
+    /// Sends a line, then the `MEM_BLOCKS` from the first index up to
+    /// the second one.
+    Mem(StartSendAllBytes<'static, S>, usize, usize),
+    Log(Option<S>, Log),
     EchoChar(Option<S>, u8),
     EchoCharStr(u8, StartSendAllString<'static, S>),
+    EchoBytes(StartSendBytes<'static, S>),
+    FlushBytes(StartSendAllBytes<'static, S>),
     FlushString(StartSendAllString<'static, S>),
     FlushPrompt(StartSendAllString<'static, S>),
 }
 
+/// Addresses found by `scan-i2c`.
+static mut I2C_SCAN_FOUND: [u8; 0x78] = [0; 0x78];
+
 impl<S> CommandResult<S>
 where
-    S: Sink<u8> + Unpin,
+    S: SinkBytes + Unpin,
 {
     pub fn echo_char(sink: S, c: u8) -> CommandResult<S> {
         match c as char {
@@ -93,10 +151,20 @@ where
         }
     }
 
+    /// Echoes printable characters, as many at once as the sink has
+    /// room for.
+    pub fn echo_bytes(sink: S, bytes: &'static [u8]) -> CommandResult<S> {
+        CommandResult::EchoBytes(StartSendBytes::new(sink, bytes))
+    }
+
     pub fn flush(sink: S, string: &'static str) -> CommandResult<S> {
         CommandResult::FlushString(StartSendAllString::new(sink, string))
     }
 
+    pub fn flush_bytes(sink: S, bytes: &'static [u8]) -> CommandResult<S> {
+        CommandResult::FlushBytes(StartSendAllBytes::new(sink, bytes))
+    }
+
     pub fn flush_prompt(sink: S) -> CommandResult<S> {
         CommandResult::FlushPrompt(StartSendAllString::new(sink, PROMPT))
     }
@@ -105,6 +173,11 @@ where
         CommandResult::Sink(Some(sink))
     }
 
+    /// Waits until `log` is written, then prints the prompt.
+    pub fn log(sink: S, log: Log) -> CommandResult<S> {
+        CommandResult::Log(Some(sink), log)
+    }
+
     pub fn temperature(sink: S) -> CommandResult<S> {
         CommandResult::Temperature(
             Some(sink),
@@ -114,11 +187,46 @@ where
             ),
         )
     }
+
+    /// Prints heap usage and the blocks, one line at a time, so the
+    /// output doesn't have to fit in a single buffer.
+    pub fn mem(sink: S) -> CommandResult<S> {
+        let count = ::linkmem::blocks(unsafe { &mut MEM_BLOCKS });
+        let header = mem_line(format_args!(
+            "{:?}\r\npeak used: {}\r\n",
+            ::linkmem::stats(),
+            ::linkmem::high_water_mark()
+        ));
+        CommandResult::Mem(StartSendAllBytes::new(sink, header), 0, count)
+    }
+
+    /// Joins the access point, and saves the credentials to flash if
+    /// it succeeds. The boot task stops retrying the stored ones then.
+    pub fn wifi_join(sink: S, ssid: &str, pass: &str) -> CommandResult<S> {
+        let mut config = ::dev::config::Config::load_or_default(&super::config_storage());
+        if !config.set_wifi(ssid, pass) {
+            return CommandResult::flush(sink, "SSID or password is too long\r\n");
+        }
+
+        // The previous join, if any, has finished.
+        let config: &'static _ = unsafe {
+            WIFI_CONFIG = Some(config);
+            WIFI_CONFIG.as_ref().unwrap()
+        };
+        CommandResult::WifiJoin(Some(sink), super::join_ap(config.ssid(), config.pass()))
+    }
+
+    pub fn i2c_scan(sink: S) -> CommandResult<S> {
+        CommandResult::I2cScan(
+            Some(sink),
+            ::dev::i2c::I2C1_BUS.scan(unsafe { &mut I2C_SCAN_FOUND }),
+        )
+    }
 }
 
 impl<S> Future for CommandResult<S>
 where
-    S: Sink<u8, SinkError = ()> + Unpin + 'static,
+    S: SinkBytes<SinkError = ()> + Unpin + 'static,
 {
     type Output = Result<S, S::SinkError>;
 
@@ -147,29 +255,83 @@ where
                         return Poll::Ready(Ok(sink));
                     }
                 }
+                CommandResult::EchoBytes(ref mut f) => {
+                    let sink = try_ready!(Pin::new(f).poll(cx));
+                    return Poll::Ready(Ok(sink));
+                }
                 CommandResult::Temperature(ref mut sink, ref mut f) => {
-                    let res = ready!(Pin::new(f).poll(cx));
-                    match res {
-                        Ok((temperature, humidity)) => {
-                            // TODO: don't use log
-                            log!(
-                                "Temperature: {} C    Humidity: {}%\r\n",
-                                temperature,
-                                humidity
-                            );
-                            CommandResult::flush_prompt(sink.take().unwrap())
-                        }
-                        Err(err) => {
-                            log!("{:?}\r\n", err);
-                            CommandResult::flush(sink.take().unwrap(), "Temperature read error\r\n")
+                    let log = match ready!(Pin::new(f).poll(cx)) {
+                        Ok((temperature, humidity)) => reply!(
+                            "Temperature: {} C    Humidity: {}%\r\n",
+                            temperature,
+                            humidity
+                        ),
+                        Err(err) => reply!("Temperature read error: {:?}\r\n", err),
+                    };
+                    CommandResult::log(sink.take().unwrap(), log)
+                }
+                CommandResult::I2cScan(ref mut sink, ref mut f) => {
+                    let count = ready!(Pin::new(f).poll(cx));
+                    let mut message = Message::new();
+                    for address in unsafe { &I2C_SCAN_FOUND[..count] } {
+                        let _ = write!(message, "0x{:02x}\r\n", address);
+                    }
+                    let _ = write!(message, "{} devices found\r\n", count);
+                    CommandResult::log(sink.take().unwrap(), super::CONSOLE.send(message))
+                }
+                CommandResult::WifiJoin(ref mut sink, ref mut f) => {
+                    let log = match ready!(f.as_mut().poll(cx)) {
+                        Ok(true) => {
+                            let config = unsafe { WIFI_CONFIG.as_ref() }.unwrap();
+                            // Erasing the sector stalls the CPU for a
+                            // second or two.
+                            match config.save(&mut super::config_storage()) {
+                                Ok(()) => {
+                                    reply!("Joined {}, configuration saved\r\n", config.ssid())
+                                }
+                                Err(err) => reply!(
+                                    "Joined {}, but failed to save: {:?}\r\n",
+                                    config.ssid(),
+                                    err
+                                ),
+                            }
                         }
+                        Ok(false) => reply!("Failed to join access point\r\n"),
+                        Err(err) => reply!("ESP8266 error: {:?}\r\n", err),
+                    };
+                    CommandResult::log(sink.take().unwrap(), log)
+                }
+                CommandResult::I2cRecover(ref mut sink, ref mut f) => {
+                    ready!(Pin::new(f).poll(cx));
+                    CommandResult::flush_prompt(sink.take().unwrap())
+                }
+                CommandResult::Mem(ref mut f, next, count) => {
+                    let sink = try_ready!(Pin::new(f).poll(cx));
+                    if *next == *count {
+                        CommandResult::flush_prompt(sink)
+                    } else {
+                        let block = unsafe { MEM_BLOCKS[*next] };
+                        let state = if block.free { "free" } else { "used" };
+                        let line = mem_line(format_args!(
+                            "{:p} {:5} {}\r\n",
+                            block.addr, block.size, state
+                        ));
+                        CommandResult::Mem(StartSendAllBytes::new(sink, line), *next + 1, *count)
                     }
                 }
+                CommandResult::Log(ref mut sink, ref mut f) => {
+                    ready!(Pin::new(f).poll(cx));
+                    CommandResult::flush_prompt(sink.take().unwrap())
+                }
                 CommandResult::Sink(ref mut sink) => return Poll::Ready(Ok(sink.take().unwrap())),
                 CommandResult::FlushString(ref mut f) => {
                     let sink = try_ready!(Pin::new(f).poll(cx));
                     CommandResult::flush_prompt(sink)
                 }
+                CommandResult::FlushBytes(ref mut f) => {
+                    let sink = try_ready!(Pin::new(f).poll(cx));
+                    CommandResult::flush_prompt(sink)
+                }
                 CommandResult::FlushPrompt(ref mut f) => {
                     let sink = try_ready!(Pin::new(f).poll(cx));
                     return Poll::Ready(Ok(sink));
@@ -182,21 +344,91 @@ where
 /// Starts a terminal.
 pub fn run_terminal<St, Si>(stream: St, sink: Si) -> impl Future<Output = Result<Si, ()>> + 'static
 where
-    St: Stream<Item = u8> + 'static,
-    Si: Sink<u8, SinkError = ()> + Unpin + 'static,
+    St: Stream<Item = u8> + Unpin + 'static,
+    Si: SinkBytes<SinkError = ()> + Unpin + 'static,
 {
-    StartSendAllString::new(sink, PROMPT)
-        .and_then(|sink| stream.map(Ok).try_fold(sink, process_char))
+    StartSendAllString::new(sink, PROMPT).and_then(|sink| {
+        ready_bytes(stream)
+            .map(Ok)
+            .try_fold(sink, |sink, chunk| ProcessChunk {
+                chunk,
+                pos: 0,
+                state: CommandResult::sink(sink),
+            })
+    })
 }
 
-static mut COMMAND: [u8; 32] = [0; 32];
+static mut COMMAND: [u8; 128] = [0; 128];
 static mut CUR: usize = 0;
 
+/// Processes all input that was available at once.
+///
+/// Pasted text arrives in a single chunk, so it is echoed by one
+/// future and, if the sink has room, in a single sink operation.
+struct ProcessChunk<Si> {
+    chunk: Chunk,
+    pos: usize,
+    state: CommandResult<Si>,
+}
+
+impl<Si> Future for ProcessChunk<Si>
+where
+    Si: SinkBytes<SinkError = ()> + Unpin + 'static,
+{
+    type Output = Result<Si, ()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        loop {
+            let sink = try_ready!(Pin::new(&mut this.state).poll(cx));
+            if this.pos == this.chunk.len() {
+                return Poll::Ready(Ok(sink));
+            }
+
+            let (state, processed) = process_input(sink, &this.chunk[this.pos..]);
+            this.state = state;
+            this.pos += processed;
+        }
+    }
+}
+
+/// Processes a run of printable characters, or a single special
+/// one. Returns the number of bytes processed.
+fn process_input<Si>(sink: Si, input: &[u8]) -> (CommandResult<Si>, usize)
+where
+    Si: SinkBytes<SinkError = ()> + Unpin + 'static,
+{
+    let command = unsafe { &mut COMMAND };
+    let cur = unsafe { &mut CUR };
+
+    let start = *cur;
+    for &c in input {
+        // The character that fills the command is processed
+        // separately, as it triggers Enter.
+        if c == 0x8 || c == b'\r' || *cur + 1 == command.len() {
+            break;
+        }
+
+        command[*cur] = c;
+        *cur += 1;
+    }
+
+    if *cur == start {
+        (process_char(sink, input[0]), 1)
+    } else {
+        let command: &'static [u8] = command;
+        (
+            CommandResult::echo_bytes(sink, &command[start..*cur]),
+            *cur - start,
+        )
+    }
+}
+
 /// Processes one character at a time. Calls `process_command` when
 /// user presses Enter or command is too long.
-fn process_char<Si>(sink: Si, c: u8) -> impl Future<Output = Result<Si, ()>> + 'static
+fn process_char<Si>(sink: Si, c: u8) -> CommandResult<Si>
 where
-    Si: Sink<u8, SinkError = ()> + Unpin + 'static,
+    Si: SinkBytes<SinkError = ()> + Unpin + 'static,
 {
     let command = unsafe { &mut COMMAND };
     let cur = unsafe { &mut CUR };
@@ -222,9 +454,51 @@ where
     CommandResult::echo_char(sink, c)
 }
 
+/// Number of randomized steps `allocbench` runs.
+const ALLOC_BENCH_ITERATIONS: u32 = 1000;
+
+fn alloc_bench() -> Message {
+    use alloc::alloc::{alloc, dealloc, Layout};
+
+    ::stm32f4::dwt::enable_cycle_counter();
+    let stats = ::dev::alloc_bench::run(
+        ::stm32f4::dwt::cycle_count,
+        |size| unsafe { alloc(Layout::from_size_align_unchecked(size, 1)) },
+        |ptr, size| unsafe { dealloc(ptr, Layout::from_size_align_unchecked(size, 1)) },
+        ALLOC_BENCH_ITERATIONS,
+        0x1234_5678,
+    );
+
+    let mut message = Message::new();
+    let _ = match (stats.avg_alloc(), stats.avg_free()) {
+        (Some(alloc), Some(free)) => write!(
+            message,
+            "alloc: {} cycles ({} calls)\r\nfree: {} cycles ({} calls)\r\n",
+            alloc, stats.allocs, free, stats.frees
+        ),
+        _ => write!(message, "allocbench: no successful allocations\r\n"),
+    };
+    if stats.failed != 0 {
+        let _ = write!(
+            message,
+            "allocbench: {} allocations failed\r\n",
+            stats.failed
+        );
+    }
+    message
+}
+
+/// Formats a line of the `mem` output into `MEM_LINE`. The previous
+/// line must have been sent.
+fn mem_line(args: ::core::fmt::Arguments) -> &'static [u8] {
+    let line = unsafe { &mut MEM_LINE };
+    *line = Message::format(args);
+    line.as_bytes()
+}
+
 fn process_enter<Si>(sink: Si) -> CommandResult<Si>
 where
-    Si: Sink<u8, SinkError = ()> + Unpin + 'static,
+    Si: SinkBytes<SinkError = ()> + Unpin + 'static,
 {
     let command = unsafe { &mut COMMAND };
     let cur = unsafe { &mut CUR };
@@ -269,14 +543,87 @@ where
             CommandResult::flush_prompt(sink)
         }
         b"led-fun" => {
-            led_music::led_fun(71000);
+            led_music::led_fun(50);
             CommandResult::flush_prompt(sink)
         }
         b"temp" | b"temperature" => CommandResult::temperature(sink),
+        b"scan-i2c" => CommandResult::i2c_scan(sink),
+        b"i2c-recover" => CommandResult::I2cRecover(Some(sink), unsafe {
+            ::dev::i2c::I2C1_BUS.recover(&super::I2C1_PINS, &super::I2C1_INIT)
+        }),
+        b"uart-stats" => CommandResult::log(
+            sink,
+            reply!(
+                "USART2: {:?}\r\nUSART2: {:?}\r\nUSART3: {:?}\r\nUSART3: {:?}\r\n",
+                super::USART2.stats(),
+                super::USART2.error_counts(),
+                super::USART3.stats(),
+                super::USART3.error_counts()
+            ),
+        ),
+        b"mem" => CommandResult::mem(sink),
         b"panic" => {
             panic!();
         }
         b"" => CommandResult::flush_prompt(sink),
+        _ if command.starts_with(b"bf ") => {
+            let (program, input) = ::dev::brainfuck::split_input(&command[b"bf ".len()..]);
+            let output = unsafe { &mut BF_OUTPUT };
+            *output = Message::new();
+            let res = ::dev::brainfuck::interpret(
+                program,
+                input.iter().cloned(),
+                |c| {
+                    let _ = output.write_char(c as char);
+                },
+                BF_MAX_STEPS,
+            );
+            let _ = match res {
+                Ok(()) => write!(output, "\r\n"),
+                Err(err) => write!(output, "\r\nbf: {:?}\r\n", err),
+            };
+            CommandResult::flush_bytes(sink, output.as_bytes())
+        }
+        b"loglevel" => CommandResult::log(sink, reply!("{}\r\n", ::dev::log::level())),
+        _ if command.starts_with(b"loglevel ") => {
+            match ::dev::log::Level::parse(&command[b"loglevel ".len()..]) {
+                Some(level) => {
+                    ::dev::log::set_level(level);
+                    CommandResult::flush_prompt(sink)
+                }
+                None => CommandResult::flush(sink, "Unknown log level\r\n"),
+            }
+        }
+        b"allocbench" => CommandResult::log(sink, super::CONSOLE.send(alloc_bench())),
+        _ if command.starts_with(b"wifi join ") => {
+            let args = &command[b"wifi join ".len()..];
+            // SSIDs with spaces are not supported; the password is the
+            // rest of the line.
+            let (ssid, pass) = match args.iter().position(|&c| c == b' ') {
+                Some(i) => (&args[..i], &args[i + 1..]),
+                None => (args, &b""[..]),
+            };
+            match (::core::str::from_utf8(ssid), ::core::str::from_utf8(pass)) {
+                (Ok(ssid), Ok(pass)) if !ssid.is_empty() => {
+                    CommandResult::wifi_join(sink, ssid, pass)
+                }
+                _ => CommandResult::flush(sink, "Usage: wifi join SSID PASSWORD\r\n"),
+            }
+        }
+        _ if command.starts_with(b"altfn ") => {
+            let mut message = Message::new();
+            let mut found = false;
+            for altfn in ::stm32f4::altfn::find(&command[b"altfn ".len()..]) {
+                let _ = write!(message, "{}\r\n", altfn);
+                found = true;
+            }
+
+            if found {
+                CommandResult::log(sink, super::CONSOLE.send(message))
+            } else {
+                CommandResult::flush(sink, "Unknown peripheral\r\n")
+            }
+        }
         _ => CommandResult::flush(sink, "Unknown command\r\n"),
     }
 }
@@ -0,0 +1,321 @@
+//! Analog-to-digital converter.
+//!
+//! Only single conversions of regular channels with 12-bit resolution
+//! are supported.
+
+// allow `<< 0`
+#![allow(clippy::identity_op)]
+
+use crate::volatile::RW;
+
+extern "C" {
+    pub static ADC1: Adc;
+    pub static ADC2: Adc;
+    pub static ADC3: Adc;
+    pub static ADC_COMMON: AdcCommon;
+}
+
+/// Don't forget to enable the ADC clock before use, and to switch the
+/// pins to analog mode.
+///
+/// ```no_run
+/// # use stm32f4::rcc;
+/// unsafe {
+///   rcc::RCC.apb2_clock_enable(rcc::Apb2Enable::ADC1);
+/// }
+/// ```
+#[repr(C)]
+#[allow(missing_debug_implementations)]
+pub struct Adc {
+    sr: RW<u32>,        // 0x00
+    cr1: RW<u32>,       // 0x04
+    cr2: RW<u32>,       // 0x08
+    smpr1: RW<u32>,     // 0x0C
+    smpr2: RW<u32>,     // 0x10
+    jofr: [RW<u32>; 4], // 0x14
+    htr: RW<u32>,       // 0x24
+    ltr: RW<u32>,       // 0x28
+    sqr1: RW<u32>,      // 0x2C
+    sqr2: RW<u32>,      // 0x30
+    sqr3: RW<u32>,      // 0x34
+    jsqr: RW<u32>,      // 0x38
+    jdr: [RW<u32>; 4],  // 0x3C
+    dr: RW<u32>,        // 0x4C
+}
+
+/// Registers shared by all ADCs.
+#[repr(C)]
+#[allow(missing_debug_implementations)]
+pub struct AdcCommon {
+    csr: RW<u32>, // 0x00
+    ccr: RW<u32>, // 0x04
+    cdr: RW<u32>, // 0x08
+}
+
+#[test]
+fn test_register_size() {
+    assert_eq!(0x50, ::core::mem::size_of::<Adc>());
+    assert_eq!(0x0C, ::core::mem::size_of::<AdcCommon>());
+}
+
+#[allow(dead_code)]
+#[repr(u32)]
+enum Sr {
+    AWD = 1 << 0,
+    /// End of conversion
+    EOC = 1 << 1,
+    JEOC = 1 << 2,
+    JSTRT = 1 << 3,
+    STRT = 1 << 4,
+    OVR = 1 << 5,
+}
+
+#[allow(dead_code)]
+#[repr(u32)]
+enum Cr2 {
+    /// A/D converter on
+    ADON = 1 << 0,
+    CONT = 1 << 1,
+    DMA = 1 << 8,
+    DDS = 1 << 9,
+    EOCS = 1 << 10,
+    ALIGN = 1 << 11,
+    /// Start conversion of regular channels
+    SWSTART = 1 << 30,
+}
+
+/// Regular channel sequence length.
+const SQR1_L: u32 = 0xF << 20;
+
+/// First conversion in the regular sequence.
+const SQR3_SQ1: u32 = 0x1F << 0;
+
+/// Temperature sensor and VREFINT enable.
+const CCR_TSVREFE: u32 = 1 << 23;
+
+/// ADC prescaler.
+const CCR_ADCPRE: u32 = 0x3 << 16;
+
+/// Channel connected to the internal temperature sensor.
+pub const CHANNEL_TEMPERATURE: u8 = 16;
+
+/// Channel connected to the internal reference voltage.
+pub const CHANNEL_VREFINT: u8 = 17;
+
+/// Sampling time in ADC clock cycles.
+#[derive(Copy, Clone, Debug)]
+#[repr(u32)]
+pub enum SampleTime {
+    Cycles3 = 0x0,
+    Cycles15 = 0x1,
+    Cycles28 = 0x2,
+    Cycles56 = 0x3,
+    Cycles84 = 0x4,
+    Cycles112 = 0x5,
+    Cycles144 = 0x6,
+    Cycles480 = 0x7,
+}
+
+/// ADC clock as a fraction of PCLK2. The ADC clock must not exceed
+/// 36 MHz.
+#[derive(Copy, Clone, Debug)]
+#[repr(u32)]
+pub enum Prescaler {
+    Div2 = 0x0 << 16,
+    Div4 = 0x1 << 16,
+    Div6 = 0x2 << 16,
+    Div8 = 0x3 << 16,
+}
+
+/// Returns the SMPR register (true for SMPR1) and the offset of the
+/// 3-bit sample time field of `channel`.
+fn smpr_field(channel: u8) -> (bool, u32) {
+    debug_assert!(channel <= 18);
+    if channel >= 10 {
+        (true, u32::from(channel - 10) * 3)
+    } else {
+        (false, u32::from(channel) * 3)
+    }
+}
+
+/// Full-scale value of a 12-bit conversion.
+const FULL_SCALE: u32 = 4095;
+
+/// Typical VREFINT voltage in mV.
+const VREFINT_MV: u32 = 1210;
+
+/// Temperature sensor voltage at 25 C in uV.
+const V25_UV: i32 = 760_000;
+
+/// Temperature sensor slope in uV per C.
+const AVG_SLOPE_UV: i32 = 2500;
+
+/// Returns the supply voltage (VDDA) in mV, given a conversion of
+/// VREFINT.
+pub fn vdda_mv(vrefint: u16) -> u32 {
+    VREFINT_MV * FULL_SCALE / u32::from(vrefint).max(1)
+}
+
+/// Converts a conversion of the temperature sensor to thousandths of
+/// degree Celsius.
+///
+/// The sensor is only accurate to about 1.5 C, and its offset varies
+/// from chip to chip by up to 45 C, so it's more suitable for
+/// detecting temperature changes.
+#[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)] // fits
+pub fn temperature_millicelsius(sample: u16, vdda_mv: u32) -> i32 {
+    let uv = u64::from(sample) * u64::from(vdda_mv) * 1000 / u64::from(FULL_SCALE);
+    (uv as i32 - V25_UV) * 1000 / AVG_SLOPE_UV + 25_000
+}
+
+impl AdcCommon {
+    pub fn set_prescaler(&self, prescaler: Prescaler) {
+        unsafe {
+            self.ccr.update_with_mask(CCR_ADCPRE, prescaler as u32);
+        }
+    }
+
+    /// Connects the temperature sensor and VREFINT to ADC1.
+    ///
+    /// The sensor needs 10 us to start up.
+    pub fn enable_internal_channels(&self) {
+        unsafe {
+            self.ccr.set_flag(CCR_TSVREFE);
+        }
+    }
+
+    pub fn internal_channels_enabled(&self) -> bool {
+        unsafe { self.ccr.get() & CCR_TSVREFE != 0 }
+    }
+}
+
+impl Adc {
+    /// Powers the ADC on.
+    ///
+    /// The ADC needs 3 us to stabilize before the first conversion.
+    pub fn enable(&self) {
+        unsafe {
+            self.cr2.set_flag(Cr2::ADON as u32);
+        }
+    }
+
+    pub fn disable(&self) {
+        unsafe {
+            self.cr2.clear_flag(Cr2::ADON as u32);
+        }
+    }
+
+    pub fn set_sample_time(&self, channel: u8, sample_time: SampleTime) {
+        let (smpr1, offset) = smpr_field(channel);
+        let smpr = if smpr1 { &self.smpr1 } else { &self.smpr2 };
+        unsafe {
+            smpr.update_with_mask(0x7 << offset, (sample_time as u32) << offset);
+        }
+    }
+
+    /// Converts `channel` once and returns the result. Blocks until
+    /// the conversion completes.
+    #[allow(clippy::cast_possible_truncation)] // the result is 12-bit
+    pub fn read_channel(&self, channel: u8) -> u16 {
+        debug_assert!(channel <= 18);
+        unsafe {
+            // A single conversion in the sequence.
+            self.sqr1.update_with_mask(SQR1_L, 0);
+            self.sqr3.update_with_mask(SQR3_SQ1, u32::from(channel));
+
+            self.cr2.set_flag(Cr2::SWSTART as u32);
+            while self.sr.get() & Sr::EOC as u32 == 0 {}
+
+            // Reading DR clears EOC.
+            self.dr.get() as u16
+        }
+    }
+
+    /// Measures the chip temperature with the internal sensor, in
+    /// thousandths of degree Celsius. See `temperature_millicelsius`.
+    ///
+    /// Only ADC1 is connected to the sensor. It must be enabled.
+    pub fn read_temperature(&self, common: &AdcCommon) -> i32 {
+        if !common.internal_channels_enabled() {
+            common.enable_internal_channels();
+            crate::delay::delay_us(10);
+        }
+
+        // The sensor and VREFINT need at least 10 us of sampling.
+        self.set_sample_time(CHANNEL_VREFINT, SampleTime::Cycles480);
+        self.set_sample_time(CHANNEL_TEMPERATURE, SampleTime::Cycles480);
+
+        let vdda = vdda_mv(self.read_channel(CHANNEL_VREFINT));
+        temperature_millicelsius(self.read_channel(CHANNEL_TEMPERATURE), vdda)
+    }
+}
+
+#[cfg(test)]
+fn mock_adc() -> Adc {
+    unsafe { ::core::mem::zeroed() }
+}
+
+#[test]
+fn test_smpr_field() {
+    assert_eq!((false, 0), smpr_field(0));
+    assert_eq!((false, 27), smpr_field(9));
+    assert_eq!((true, 0), smpr_field(10));
+    assert_eq!((true, 18), smpr_field(16));
+    assert_eq!((true, 24), smpr_field(18));
+}
+
+#[test]
+fn test_set_sample_time() {
+    let adc = mock_adc();
+    unsafe { adc.smpr1.set(0x07ff_ffff) };
+
+    adc.set_sample_time(3, SampleTime::Cycles56);
+    adc.set_sample_time(16, SampleTime::Cycles28);
+    unsafe {
+        assert_eq!(0x3 << 9, adc.smpr2.get());
+        assert_eq!(0x07ff_ffff & !(0x7 << 18) | (0x2 << 18), adc.smpr1.get());
+    }
+}
+
+#[test]
+fn test_read_channel() {
+    let adc = mock_adc();
+    unsafe {
+        // A sequence of 3 conversions from another user.
+        adc.sqr1.set(0x2 << 20);
+        adc.sqr3.set(0x7fff);
+        adc.sr.set(Sr::EOC as u32);
+        adc.dr.set(0x0abc);
+    }
+
+    assert_eq!(0x0abc, adc.read_channel(CHANNEL_TEMPERATURE));
+    unsafe {
+        assert_eq!(0, adc.sqr1.get());
+        assert_eq!(0x7fe0 | 16, adc.sqr3.get());
+        assert_ne!(0, adc.cr2.get() & Cr2::SWSTART as u32);
+    }
+}
+
+#[test]
+fn test_set_prescaler() {
+    let common: AdcCommon = unsafe { ::core::mem::zeroed() };
+    common.enable_internal_channels();
+    common.set_prescaler(Prescaler::Div4);
+    assert_eq!(CCR_TSVREFE | 0x1 << 16, unsafe { common.ccr.get() });
+}
+
+#[test]
+fn test_vdda_mv() {
+    assert_eq!(3301, vdda_mv(1501));
+    assert_eq!(2999, vdda_mv(1652));
+    assert_eq!(VREFINT_MV, vdda_mv(4095));
+}
+
+#[test]
+fn test_temperature_millicelsius() {
+    // At 4.095 V, a step is 1 mV.
+    assert_eq!(25_000, temperature_millicelsius(760, 4095));
+    assert_eq!(35_000, temperature_millicelsius(785, 4095));
+    assert_eq!(1_000, temperature_millicelsius(700, 4095));
+    assert_eq!(24_883, temperature_millicelsius(1037, 3000));
+}
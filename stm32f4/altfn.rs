@@ -0,0 +1,111 @@
+//! GPIO alternate function mapping.
+//!
+//! A subset of the "Alternate function mapping" table from the
+//! STM32F405xx/STM32F407xx datasheet. It helps to find which pins and
+//! alternate functions can be used for a peripheral.
+use crate::gpio::GpioAF;
+
+/// A single pin capable of serving a peripheral signal.
+#[derive(Copy, Clone, Debug)]
+pub struct AltFn {
+    pub peripheral: &'static str,
+    pub signal: &'static str,
+    /// GPIO port letter, e.g., `'A'`.
+    pub port: char,
+    pub pin: u32,
+    pub af: GpioAF,
+}
+
+macro_rules! altfn {
+    ( $( $peripheral:ident $signal:ident $af:ident : $( $port:ident $pin:expr ),* ; )* ) => {
+        &[ $( $( AltFn {
+            peripheral: stringify!($peripheral),
+            signal: stringify!($signal),
+            port: stringify!($port).as_bytes()[0] as char,
+            pin: $pin,
+            af: GpioAF::$af,
+        }, )* )* ]
+    };
+}
+
+pub const ALT_FUNCTIONS: &[AltFn] = altfn! {
+    I2C1 SCL AF4: B 6, B 8;
+    I2C1 SDA AF4: B 7, B 9;
+    I2C2 SCL AF4: B 10, F 1, H 4;
+    I2C2 SDA AF4: B 11, F 0, H 5;
+    I2C3 SCL AF4: A 8, H 7;
+    I2C3 SDA AF4: C 9, H 8;
+    SPI1 SCK AF5: A 5, B 3;
+    SPI1 MISO AF5: A 6, B 4;
+    SPI1 MOSI AF5: A 7, B 5;
+    SPI2 SCK AF5: B 10, B 13, I 1;
+    SPI2 MISO AF5: B 14, C 2, I 2;
+    SPI2 MOSI AF5: B 15, C 3, I 3;
+    SPI3 SCK AF6: B 3, C 10;
+    SPI3 MISO AF6: B 4, C 11;
+    SPI3 MOSI AF6: B 5, C 12;
+    USART1 TX AF7: A 9, B 6;
+    USART1 RX AF7: A 10, B 7;
+    USART2 TX AF7: A 2, D 5;
+    USART2 RX AF7: A 3, D 6;
+    USART3 TX AF7: B 10, C 10, D 8;
+    USART3 RX AF7: B 11, C 11, D 9;
+    UART4 TX AF8: A 0, C 10;
+    UART4 RX AF8: A 1, C 11;
+    UART5 TX AF8: C 12;
+    UART5 RX AF8: D 2;
+    USART6 TX AF8: C 6, G 14;
+    USART6 RX AF8: C 7, G 9;
+};
+
+/// Returns all pins that can be used for the given peripheral.
+///
+/// Peripheral name is case-insensitive.
+pub fn find(peripheral: &[u8]) -> impl Iterator<Item = &'static AltFn> + '_ {
+    ALT_FUNCTIONS
+        .iter()
+        .filter(move |x| x.peripheral.as_bytes().eq_ignore_ascii_case(peripheral))
+}
+
+impl ::core::fmt::Display for AltFn {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        write!(
+            f,
+            "{}_{}: P{}{} {:?}",
+            self.peripheral, self.signal, self.port, self.pin, self.af
+        )
+    }
+}
+
+#[cfg(test)]
+fn lookup(peripheral: &[u8]) -> impl Iterator<Item = (&'static str, char, u32, u32)> + '_ {
+    find(peripheral).map(|x| (x.signal, x.port, x.pin, x.af as u32))
+}
+
+#[test]
+fn test_usart2() {
+    let expected = [
+        ("TX", 'A', 2, 7),
+        ("TX", 'D', 5, 7),
+        ("RX", 'A', 3, 7),
+        ("RX", 'D', 6, 7),
+    ];
+    assert!(lookup(b"usart2").eq(expected.iter().cloned()));
+}
+
+#[test]
+fn test_i2c1() {
+    let expected = [
+        ("SCL", 'B', 6, 4),
+        ("SCL", 'B', 8, 4),
+        ("SDA", 'B', 7, 4),
+        ("SDA", 'B', 9, 4),
+    ];
+    assert!(lookup(b"I2C1").eq(expected.iter().cloned()));
+}
+
+#[test]
+fn test_unknown_peripheral() {
+    assert_eq!(0, lookup(b"usart7").count());
+    assert_eq!(0, lookup(b"").count());
+}
@@ -63,4 +63,162 @@ impl Crc {
             self.dr.get()
         }
     }
+
+    /// Resets the unit and computes the CRC of a byte buffer. Matches
+    /// CRC-32/MPEG-2 computed byte by byte, e.g., by host tools.
+    ///
+    /// Bytes are packed into words big-endian. The unit only accepts
+    /// whole words, so up to 3 trailing bytes are processed in
+    /// software. The result doesn't stay in the unit then.
+    pub fn block_crc_bytes(&self, data: &[u8]) -> u32 {
+        self.reset();
+        crc_bytes(data, |_, word| self.calculate_crc(word))
+    }
+}
+
+/// Checks the integrity of `len` words of flash at `start`, e.g., the
+/// firmware image on boot. Enables the CRC clock and resets the unit.
+/// On host, the CRC is computed in software.
+///
+/// `expected` is computed at build time as CRC-32/MPEG-2
+/// (polynomial 0x04C11DB7, initial value 0xFFFFFFFF, no reflection,
+/// no final XOR) over the image taken as little-endian 32-bit words,
+/// most significant bit first. Byte-oriented tools give the same
+/// result if every 4 bytes of the image are reversed first. See
+/// `software_crc`.
+///
+/// # Safety
+/// The region must be readable.
+pub unsafe fn check_flash(start: *const u32, len: usize, expected: u32) -> bool {
+    let data = ::core::slice::from_raw_parts(start, len);
+    flash_crc(data) == expected
+}
+
+#[cfg(target_arch = "arm")]
+unsafe fn flash_crc(data: &[u32]) -> u32 {
+    use crate::rcc::{Ahb1Enable, RCC};
+
+    RCC.ahb1_clock_enable(Ahb1Enable::CRC);
+    CRC.reset();
+    CRC.block_crc(data)
+}
+
+/// There is no CRC unit on host. Computing the CRC in software allows
+/// checking images in host tests.
+#[cfg(not(target_arch = "arm"))]
+unsafe fn flash_crc(data: &[u32]) -> u32 {
+    software_crc(data)
+}
+
+/// CRC-32/MPEG-2 polynomial.
+const POLYNOMIAL: u32 = 0x04c1_1db7;
+
+/// Feeds whole big-endian words of `data` to `feed_word`, and
+/// processes the trailing bytes in software.
+///
+/// `feed_word` is called with the current CRC and the word, and
+/// returns the new CRC.
+fn crc_bytes<F: FnMut(u32, u32) -> u32>(data: &[u8], mut feed_word: F) -> u32 {
+    let mut crc = 0xffff_ffff_u32;
+
+    let words = data.chunks_exact(4);
+    let tail = words.remainder();
+    for word in words {
+        let word = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        crc = feed_word(crc, word);
+    }
+
+    for &byte in tail {
+        crc ^= u32::from(byte) << 24;
+        for _ in 0..8 {
+            crc = software_crc_step(crc);
+        }
+    }
+    crc
+}
+
+/// Shifts one bit into `crc`.
+fn software_crc_step(crc: u32) -> u32 {
+    if crc & 0x8000_0000 != 0 {
+        (crc << 1) ^ POLYNOMIAL
+    } else {
+        crc << 1
+    }
+}
+
+/// Software counterpart of `Crc::block_crc_bytes`.
+pub fn software_crc_bytes(data: &[u8]) -> u32 {
+    crc_bytes(data, |crc, word| {
+        let mut crc = crc ^ word;
+        for _ in 0..32 {
+            crc = software_crc_step(crc);
+        }
+        crc
+    })
+}
+
+/// Computes the same CRC as the hardware unit does after reset
+/// (CRC-32/MPEG-2 over 32-bit words).
+///
+/// Much slower than the hardware, but doesn't need the peripheral.
+pub fn software_crc(data: &[u32]) -> u32 {
+    let mut crc = 0xffff_ffff_u32;
+    for &x in data {
+        crc ^= x;
+        for _ in 0..32 {
+            crc = software_crc_step(crc);
+        }
+    }
+    crc
+}
+
+#[test]
+fn test_software_crc() {
+    // Reference value of the hardware unit.
+    assert_eq!(0xdf8a_8a2b, software_crc(&[0x1234_5678]));
+    assert_eq!(0xffff_ffff, software_crc(&[]));
+}
+
+#[test]
+fn test_software_crc_bytes() {
+    // CRC-32/MPEG-2 check value
+    assert_eq!(0x0376_e6e7, software_crc_bytes(b"123456789"));
+    assert_eq!(0xffff_ffff, software_crc_bytes(&[]));
+
+    // Words are packed big-endian.
+    assert_eq!(
+        software_crc(&[0x1234_5678]),
+        software_crc_bytes(&[0x12, 0x34, 0x56, 0x78])
+    );
+}
+
+#[test]
+fn test_block_crc_bytes_packing() {
+    let crc: Crc = unsafe { ::core::mem::zeroed() };
+
+    // DR of the mock reads back the last word written.
+    assert_eq!(0x0102_0304, crc.block_crc_bytes(&[1, 2, 3, 4]));
+    // CR is write-only.
+    let cr = unsafe { *(&crc as *const Crc as *const u32).add(2) };
+    assert_eq!(1, cr, "not reset");
+}
+
+#[test]
+fn test_check_flash() {
+    // Vector table head, stamped by `tools/stamp_crc.py`.
+    let mut image = [0x2002_0000, 0x0800_01c1, 0x0800_0201, 0xd0f6_8f11];
+    let expected = image[3];
+
+    assert!(unsafe { check_flash(image.as_ptr(), 3, expected) });
+    assert!(!unsafe { check_flash(image.as_ptr(), 3, expected ^ 1) });
+
+    image[1] ^= 0x100;
+    assert!(!unsafe { check_flash(image.as_ptr(), 3, expected) });
+
+    // The image is stored as 00 00 02 20 c1 01 00 08 ..., byte-oriented
+    // tools need every 4 bytes reversed.
+    let reversed = [
+        0x20, 0x02, 0x00, 0x00, 0x08, 0x00, 0x01, 0xc1, 0x08, 0x00, 0x02, 0x01,
+    ];
+    assert_eq!(expected, software_crc_bytes(&reversed));
 }
@@ -0,0 +1,108 @@
+//! Busy-wait delays.
+//!
+//! The delays count core clock cycles with the DWT cycle counter, so
+//! they don't depend on the code the compiler generates for the loop.
+//! Interrupts that fire meanwhile only make the delay longer.
+
+use crate::dwt;
+use crate::rcc::RCC;
+
+/// Longest wait that is measured with a single counter reading.
+///
+/// The counter wraps around every 2^32 cycles (25 s at 168 MHz), so
+/// longer delays are split. Half of the range leaves enough margin
+/// for the loop to notice the deadline before the counter wraps past
+/// it again.
+const MAX_CHUNK: u32 = 1 << 31;
+
+/// Waits for at least `us` microseconds.
+pub fn delay_us(us: u32) {
+    wait_cycles(cycles(hclk(), u64::from(us), 1_000_000), dwt::cycle_count);
+}
+
+/// Waits for at least `ms` milliseconds.
+pub fn delay_ms(ms: u32) {
+    wait_cycles(cycles(hclk(), u64::from(ms), 1_000), dwt::cycle_count);
+}
+
+/// Returns the core clock frequency and makes sure the cycle counter
+/// runs.
+fn hclk() -> u32 {
+    if !dwt::is_cycle_counter_enabled() {
+        dwt::enable_cycle_counter();
+    }
+
+    unsafe { &RCC }
+        .clock_freqs()
+        .expect("invalid clock configuration")
+        .hclk
+}
+
+/// Returns the number of cycles of `hclk` in `time / units_per_second`
+/// seconds, rounded up.
+fn cycles(hclk: u32, time: u64, units_per_second: u64) -> u64 {
+    (u64::from(hclk) * time + units_per_second - 1) / units_per_second
+}
+
+/// Waits until `count` advances by `cycles`.
+fn wait_cycles<F: FnMut() -> u32>(mut cycles: u64, mut count: F) {
+    while cycles > 0 {
+        #[allow(clippy::cast_possible_truncation)] // checked by min
+        let chunk = ::core::cmp::min(cycles, u64::from(MAX_CHUNK)) as u32;
+
+        let start = count();
+        while count().wrapping_sub(start) < chunk {}
+
+        cycles -= u64::from(chunk);
+    }
+}
+
+#[test]
+fn test_cycles() {
+    assert_eq!(168, cycles(168_000_000, 1, 1_000_000));
+    assert_eq!(16_000, cycles(16_000_000, 1, 1_000));
+    assert_eq!(168_000_000 * 60, cycles(168_000_000, 60_000, 1_000));
+    assert_eq!(
+        168_000 * u64::from(u32::max_value()),
+        cycles(168_000_000, u64::from(u32::max_value()), 1_000)
+    );
+
+    // 1 us at 16.5 MHz is 16.5 cycles.
+    assert_eq!(17, cycles(16_500_000, 1, 1_000_000));
+    assert_eq!(0, cycles(168_000_000, 0, 1_000));
+}
+
+#[test]
+fn test_wait_cycles_across_wraparound() {
+    use core::cell::Cell;
+
+    let counter = Cell::new(u32::max_value() - 10);
+    let count = || {
+        let now = counter.get();
+        counter.set(now.wrapping_add(7));
+        now
+    };
+
+    wait_cycles(100, count);
+    let elapsed = counter.get().wrapping_sub(u32::max_value() - 10);
+    assert!(elapsed >= 100);
+    assert!(elapsed < 100 + 3 * 7);
+}
+
+#[test]
+fn test_wait_cycles_longer_than_counter_period() {
+    use core::cell::Cell;
+
+    // Advances by 2^30 cycles on every reading.
+    let readings = Cell::new(0_u64);
+    let count = || {
+        let n = readings.get();
+        readings.set(n + 1);
+        (n << 30) as u32
+    };
+
+    // 2.5 counter periods
+    wait_cycles(5 << 31, count);
+    assert!(readings.get() << 30 >= 5 << 31);
+    assert!(readings.get() << 30 < (5 << 31) + (3 << 31));
+}
@@ -0,0 +1,422 @@
+//! Direct memory access controller.
+
+// allow `<< 0`
+#![allow(clippy::identity_op)]
+
+use crate::volatile::RW;
+
+extern "C" {
+    pub static DMA1: Dma;
+    pub static DMA2: Dma;
+}
+
+/// Don't forget to enable the DMA clock before use.
+///
+/// ```no_run
+/// # use stm32f4::rcc;
+/// unsafe {
+///   rcc::RCC.ahb1_clock_enable(rcc::Ahb1Enable::DMA1);
+/// }
+/// ```
+///
+/// DMA can't access the core-coupled memory, so buffers must be
+/// placed in SRAM.
+#[repr(C)]
+#[allow(missing_debug_implementations)]
+pub struct Dma {
+    lisr: RW<u32>,  // 0x00
+    hisr: RW<u32>,  // 0x04
+    lifcr: RW<u32>, // 0x08
+    hifcr: RW<u32>, // 0x0C
+    streams: [Stream; 8],
+}
+
+#[repr(C)]
+#[allow(missing_debug_implementations)]
+pub struct Stream {
+    cr: RW<u32>,   // 0x00
+    ndtr: RW<u32>, // 0x04
+    par: RW<u32>,  // 0x08
+    m0ar: RW<u32>, // 0x0C
+    m1ar: RW<u32>, // 0x10
+    fcr: RW<u32>,  // 0x14
+}
+
+#[allow(dead_code)]
+#[repr(u32)]
+enum Cr {
+    EN = 1 << 0,
+    DMEIE = 1 << 1,
+    TEIE = 1 << 2,
+    HTIE = 1 << 3,
+    TCIE = 1 << 4,
+    PFCTRL = 1 << 5,
+    DIR = 0x3 << 6,
+    CIRC = 1 << 8,
+    PINC = 1 << 9,
+    MINC = 1 << 10,
+    PSIZE = 0x3 << 11,
+    MSIZE = 0x3 << 13,
+    PINCOS = 1 << 15,
+    PL = 0x3 << 16,
+    DBM = 1 << 18,
+    CT = 1 << 19,
+    PBURST = 0x3 << 21,
+    MBURST = 0x3 << 23,
+    CHSEL = 0x7 << 25,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Direction {
+    PeripheralToMemory = 0x0 << 6,
+    MemoryToPeripheral = 0x1 << 6,
+    /// Only DMA2 supports memory-to-memory transfers. `peripheral` is
+    /// the source address.
+    MemoryToMemory = 0x2 << 6,
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(u32)]
+pub enum Priority {
+    Low = 0x0 << 16,
+    Medium = 0x1 << 16,
+    High = 0x2 << 16,
+    VeryHigh = 0x3 << 16,
+}
+
+/// Size of a single transfer. Both the peripheral and the memory
+/// use the same size.
+#[derive(Copy, Clone, Debug)]
+#[repr(u32)]
+pub enum DataSize {
+    Byte = 0x0,
+    HalfWord = 0x1,
+    Word = 0x2,
+}
+
+/// Stream configuration for `Dma::configure_transfer`.
+///
+/// The FIFO is not used (direct mode).
+#[derive(Copy, Clone, Debug)]
+pub struct DmaConfig {
+    /// Request channel (0..7) of the peripheral, see the DMA request
+    /// mapping table in the reference manual.
+    pub channel: u32,
+    pub direction: Direction,
+    pub priority: Priority,
+    /// Address of the peripheral register.
+    pub peripheral: usize,
+    pub memory: usize,
+    /// Number of transfers (not bytes).
+    pub len: u16,
+    pub data_size: DataSize,
+    pub peripheral_increment: bool,
+    pub memory_increment: bool,
+    /// Restart from the beginning after the last transfer.
+    pub circular: bool,
+    /// Enables the transfer complete interrupt.
+    pub transfer_complete_interrupt: bool,
+}
+
+/// Returns the stream CR value for `cfg`, EN excluded.
+fn cr_bits(cfg: &DmaConfig) -> u32 {
+    let flag = |set, bit: Cr| if set { bit as u32 } else { 0 };
+
+    cfg.channel << 25
+        | cfg.priority as u32
+        | (cfg.data_size as u32) << 13
+        | (cfg.data_size as u32) << 11
+        | cfg.direction as u32
+        | flag(cfg.memory_increment, Cr::MINC)
+        | flag(cfg.peripheral_increment, Cr::PINC)
+        | flag(cfg.circular, Cr::CIRC)
+        | flag(cfg.transfer_complete_interrupt, Cr::TCIE)
+}
+
+/// Interrupt flags of a stream.
+#[derive(Copy, Clone, Debug)]
+#[repr(u32)]
+pub enum Flag {
+    /// FIFO error.
+    FE = 1 << 0,
+    /// Direct mode error.
+    DME = 1 << 2,
+    /// Transfer error.
+    TE = 1 << 3,
+    /// Half transfer.
+    HT = 1 << 4,
+    /// Transfer complete.
+    TC = 1 << 5,
+}
+
+/// All flags of a stream.
+const ALL_FLAGS: u32 =
+    Flag::FE as u32 | Flag::DME as u32 | Flag::TE as u32 | Flag::HT as u32 | Flag::TC as u32;
+
+/// Returns whether the flags of `stream` are in the high registers
+/// (HISR/HIFCR), and their offset.
+fn flag_position(stream: usize) -> (bool, u32) {
+    debug_assert!(stream < 8);
+    let offset = [0, 6, 16, 22][stream % 4];
+    (stream >= 4, offset)
+}
+
+impl Dma {
+    pub fn flag_status(&self, stream: usize, flag: Flag) -> bool {
+        let (high, offset) = flag_position(stream);
+        let isr = if high { &self.hisr } else { &self.lisr };
+        unsafe { isr.get() & (flag as u32) << offset != 0 }
+    }
+
+    pub fn clear_flag(&self, stream: usize, flag: Flag) {
+        self.clear_flags(stream, flag as u32);
+    }
+
+    fn clear_flags(&self, stream: usize, flags: u32) {
+        let (high, offset) = flag_position(stream);
+        let ifcr = if high { &self.hifcr } else { &self.lifcr };
+        unsafe { ifcr.set(flags << offset) }
+    }
+
+    pub fn is_transfer_complete(&self, stream: usize) -> bool {
+        self.flag_status(stream, Flag::TC)
+    }
+
+    pub fn clear_transfer_complete(&self, stream: usize) {
+        self.clear_flag(stream, Flag::TC);
+    }
+
+    /// Configures the stream. Stale flags of the stream are cleared.
+    ///
+    /// The stream must be disabled. The transfer starts on `enable`.
+    ///
+    /// # Safety
+    /// The memory must stay valid until the transfer completes.
+    #[allow(clippy::cast_possible_truncation)] // addresses are 32-bit
+    pub unsafe fn configure_transfer(&self, stream: usize, cfg: &DmaConfig) {
+        debug_assert!(!self.is_enabled(stream));
+        debug_assert!(cfg.channel < 8);
+        debug_assert!(!(cfg.circular && cfg.direction == Direction::MemoryToMemory));
+
+        self.clear_flags(stream, ALL_FLAGS);
+
+        let s = &self.streams[stream];
+        s.par.set(cfg.peripheral as u32);
+        s.m0ar.set(cfg.memory as u32);
+        s.ndtr.set(u32::from(cfg.len));
+        s.fcr.set(0);
+        s.cr.set(cr_bits(cfg));
+    }
+
+    pub fn enable(&self, stream: usize) {
+        unsafe {
+            self.streams[stream].cr.set_flag(Cr::EN as u32);
+        }
+    }
+
+    /// Starts transferring `len` bytes from `mem` to the peripheral
+    /// register at `periph`. The transfer complete interrupt is
+    /// enabled.
+    ///
+    /// The stream must be disabled.
+    ///
+    /// # Safety
+    /// The memory must stay valid until the transfer completes.
+    pub unsafe fn start_mem_to_periph(
+        &self,
+        stream: usize,
+        channel: u32,
+        periph: *const u32,
+        mem: *const u8,
+        len: u16,
+    ) {
+        self.configure_transfer(
+            stream,
+            &DmaConfig {
+                channel,
+                direction: Direction::MemoryToPeripheral,
+                priority: Priority::Low,
+                peripheral: periph as usize,
+                memory: mem as usize,
+                len,
+                data_size: DataSize::Byte,
+                peripheral_increment: false,
+                memory_increment: true,
+                circular: false,
+                transfer_complete_interrupt: true,
+            },
+        );
+        self.enable(stream);
+    }
+
+    /// Returns the number of transfers left.
+    #[allow(clippy::cast_possible_truncation)] // NDTR is 16-bit
+    pub fn remaining(&self, stream: usize) -> u16 {
+        unsafe { self.streams[stream].ndtr.get() as u16 }
+    }
+
+    pub fn is_enabled(&self, stream: usize) -> bool {
+        unsafe { self.streams[stream].cr.get() & Cr::EN as u32 != 0 }
+    }
+
+    /// Disables the stream and waits until the current transfer
+    /// stops.
+    pub fn disable(&self, stream: usize) {
+        unsafe {
+            self.streams[stream].cr.clear_flag(Cr::EN as u32);
+        }
+        while self.is_enabled(stream) {}
+    }
+}
+
+#[cfg(test)]
+fn mock_dma() -> Dma {
+    unsafe { ::core::mem::zeroed() }
+}
+
+#[test]
+fn test_register_size() {
+    assert_eq!(0x18, ::core::mem::size_of::<Stream>());
+    assert_eq!(0xD0, ::core::mem::size_of::<Dma>());
+}
+
+#[test]
+fn test_flag_status() {
+    let dma = mock_dma();
+    unsafe {
+        // TCIF1 and TCIF6
+        dma.lisr.set(1 << 11);
+        dma.hisr.set(1 << 21);
+    }
+
+    assert!(dma.flag_status(1, Flag::TC));
+    assert!(dma.flag_status(6, Flag::TC));
+    assert!(!dma.flag_status(5, Flag::TC));
+    assert!(!dma.flag_status(2, Flag::TC));
+    assert!(!dma.flag_status(1, Flag::HT));
+}
+
+#[test]
+fn test_clear_flag() {
+    let dma = mock_dma();
+
+    dma.clear_flag(3, Flag::TC);
+    assert_eq!(1 << 27, unsafe { dma.lifcr.get() });
+
+    dma.clear_flag(4, Flag::TE);
+    assert_eq!(1 << 3, unsafe { dma.hifcr.get() });
+}
+
+#[test]
+fn test_start_mem_to_periph() {
+    let dma = mock_dma();
+    let data = [0_u8; 4];
+
+    unsafe {
+        dma.start_mem_to_periph(6, 4, 0x4000_4404 as *const u32, data.as_ptr(), 3);
+    }
+
+    let s = &dma.streams[6];
+    unsafe {
+        assert_eq!(0x4000_4404, s.par.get());
+        assert_eq!(data.as_ptr() as usize as u32, s.m0ar.get());
+        assert_eq!(3, s.ndtr.get());
+        assert_eq!(
+            4 << 25 | 1 << 6 | Cr::MINC as u32 | Cr::TCIE as u32 | Cr::EN as u32,
+            s.cr.get()
+        );
+        // Stale flags of the stream are cleared.
+        assert_eq!(ALL_FLAGS << 16, dma.hifcr.get());
+    }
+    assert!(dma.is_enabled(6));
+}
+
+#[test]
+fn test_configure_transfer() {
+    let dma = mock_dma();
+
+    unsafe {
+        dma.configure_transfer(
+            0,
+            &DmaConfig {
+                channel: 0,
+                direction: Direction::PeripheralToMemory,
+                priority: Priority::High,
+                peripheral: 0x4001_204C,
+                memory: 0x2000_0000,
+                len: 16,
+                data_size: DataSize::HalfWord,
+                peripheral_increment: false,
+                memory_increment: true,
+                circular: true,
+                transfer_complete_interrupt: false,
+            },
+        );
+    }
+
+    let s = &dma.streams[0];
+    unsafe {
+        assert_eq!(0x4001_204C, s.par.get());
+        assert_eq!(0x2000_0000, s.m0ar.get());
+        assert_eq!(16, s.ndtr.get());
+        // PL = 10, MSIZE = PSIZE = 01, MINC, CIRC, DIR = 00
+        assert_eq!(
+            0x2 << 16 | 0x1 << 13 | 0x1 << 11 | 1 << 10 | 1 << 8,
+            s.cr.get()
+        );
+    }
+    // Not started yet.
+    assert!(!dma.is_enabled(0));
+
+    dma.enable(0);
+    assert!(dma.is_enabled(0));
+}
+
+#[test]
+fn test_configure_memory_to_memory() {
+    let dma = mock_dma();
+
+    unsafe {
+        dma.configure_transfer(
+            7,
+            &DmaConfig {
+                channel: 2,
+                direction: Direction::MemoryToMemory,
+                priority: Priority::VeryHigh,
+                peripheral: 0x2000_0000,
+                memory: 0x2000_1000,
+                len: 4,
+                data_size: DataSize::Word,
+                peripheral_increment: true,
+                memory_increment: true,
+                circular: false,
+                transfer_complete_interrupt: true,
+            },
+        );
+    }
+
+    // CHSEL = 2, PL = 11, MSIZE = PSIZE = 10, MINC, PINC, DIR = 10,
+    // TCIE
+    assert_eq!(
+        2 << 25 | 0x3 << 16 | 0x2 << 13 | 0x2 << 11 | 1 << 10 | 1 << 9 | 0x2 << 6 | 1 << 4,
+        unsafe { dma.streams[7].cr.get() }
+    );
+}
+
+#[test]
+fn test_transfer_complete_flag() {
+    let dma = mock_dma();
+    unsafe {
+        // TCIF5
+        dma.hisr.set(1 << 11);
+        dma.streams[5].ndtr.set(3);
+    }
+
+    assert!(dma.is_transfer_complete(5));
+    assert!(!dma.is_transfer_complete(4));
+    assert_eq!(3, dma.remaining(5));
+
+    dma.clear_transfer_complete(5);
+    assert_eq!(1 << 11, unsafe { dma.hifcr.get() });
+}
@@ -0,0 +1,40 @@
+//! Data Watchpoint and Trace unit.
+//!
+//! Only the cycle counter is supported.
+
+use crate::volatile::RW;
+
+extern "C" {
+    pub static DWT_CTRL: RW<u32>;
+    pub static DWT_CYCCNT: RW<u32>;
+
+    /// Debug Exception and Monitor Control Register.
+    pub static DEMCR: RW<u32>;
+}
+
+/// Global enable for DWT and ITM.
+const DEMCR_TRCENA: u32 = 0x1 << 24;
+
+/// Enables CYCCNT.
+const CTRL_CYCCNTENA: u32 = 0x1;
+
+/// Enables the cycle counter.
+///
+/// The counter runs at the core clock and wraps around every 2^32
+/// cycles, so intervals must be computed with `wrapping_sub`.
+pub fn enable_cycle_counter() {
+    unsafe {
+        DEMCR.set_flag(DEMCR_TRCENA);
+        DWT_CYCCNT.set(0);
+        DWT_CTRL.set_flag(CTRL_CYCCNTENA);
+    }
+}
+
+pub fn is_cycle_counter_enabled() -> bool {
+    unsafe { DEMCR.get() & DEMCR_TRCENA != 0 && DWT_CTRL.get() & CTRL_CYCCNTENA != 0 }
+}
+
+/// Returns the current value of the cycle counter.
+pub fn cycle_count() -> u32 {
+    unsafe { DWT_CYCCNT.get() }
+}
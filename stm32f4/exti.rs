@@ -0,0 +1,207 @@
+//! External interrupt/event controller.
+//!
+//! Each EXTI line 0..15 can be routed to the pin with the same number
+//! of any GPIO port. Lines 0..4 have their own interrupts; lines 5..9
+//! and 10..15 share `EXTI9_5` and `EXTI15_10`.
+
+use crate::volatile::RW;
+
+extern "C" {
+    pub static EXTI: Exti;
+    pub static SYSCFG: Syscfg;
+}
+
+#[repr(C)]
+#[allow(missing_debug_implementations)]
+pub struct Exti {
+    imr: RW<u32>,   // 0x00
+    emr: RW<u32>,   // 0x04
+    rtsr: RW<u32>,  // 0x08
+    ftsr: RW<u32>,  // 0x0C
+    swier: RW<u32>, // 0x10
+    pr: RW<u32>,    // 0x14
+}
+
+/// System configuration controller. Only the EXTI routing is used.
+///
+/// Don't forget to enable the SYSCFG clock before use.
+///
+/// ```no_run
+/// # use stm32f4::rcc;
+/// unsafe {
+///   rcc::RCC.apb2_clock_enable(rcc::Apb2Enable::SYSCFG);
+/// }
+/// ```
+#[repr(C)]
+#[allow(missing_debug_implementations)]
+pub struct Syscfg {
+    memrmp: RW<u32>,      // 0x00
+    pmc: RW<u32>,         // 0x04
+    exticr: [RW<u32>; 4], // 0x08
+    _reserved: [u32; 2],  // 0x18
+    cmpcr: RW<u32>,       // 0x20
+}
+
+#[test]
+fn test_register_size() {
+    assert_eq!(0x18, ::core::mem::size_of::<Exti>());
+    assert_eq!(0x24, ::core::mem::size_of::<Syscfg>());
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(u32)]
+pub enum Port {
+    A = 0x0,
+    B = 0x1,
+    C = 0x2,
+    D = 0x3,
+    E = 0x4,
+    F = 0x5,
+    G = 0x6,
+    H = 0x7,
+    I = 0x8,
+    J = 0x9,
+    K = 0xA,
+}
+
+/// Signal edge that triggers the interrupt.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Edge {
+    Rising,
+    Falling,
+    Both,
+}
+
+/// Returns the EXTICR register and the offset of the 4-bit field that
+/// selects the port for `pin`.
+fn exticr_field(pin: u32) -> (usize, u32) {
+    debug_assert!(pin < 16);
+    ((pin / 4) as usize, (pin % 4) * 4)
+}
+
+impl Syscfg {
+    /// Routes EXTI line `pin` to the pin of `port`.
+    pub fn select_exti_port(&self, pin: u32, port: Port) {
+        let (reg, offset) = exticr_field(pin);
+        unsafe {
+            self.exticr[reg].update_with_mask(0xf << offset, (port as u32) << offset);
+        }
+    }
+}
+
+impl Exti {
+    /// Routes the pin to its EXTI line and unmasks the line interrupt.
+    ///
+    /// The NVIC channel of the line must be enabled separately.
+    ///
+    /// # Examples
+    ///
+    /// Interrupt on pressing the user button (PA0) of
+    /// STM32F4DISCOVERY.
+    ///
+    /// ```no_run
+    /// use stm32f4::exti::{Edge, Port, EXTI, SYSCFG};
+    ///
+    /// unsafe {
+    ///   EXTI.enable_interrupt(&SYSCFG, Port::A, 0, Edge::Rising);
+    /// }
+    /// ```
+    pub fn enable_interrupt(&self, syscfg: &Syscfg, port: Port, pin: u32, edge: Edge) {
+        syscfg.select_exti_port(pin, port);
+
+        let line = 0x1 << pin;
+        unsafe {
+            if edge == Edge::Falling {
+                self.rtsr.clear_flag(line);
+            } else {
+                self.rtsr.set_flag(line);
+            }
+            if edge == Edge::Rising {
+                self.ftsr.clear_flag(line);
+            } else {
+                self.ftsr.set_flag(line);
+            }
+
+            // Unmask after the triggers are set, so a stale edge
+            // doesn't fire.
+            self.clear_pending(pin);
+            self.imr.set_flag(line);
+        }
+    }
+
+    pub fn disable_interrupt(&self, line: u32) {
+        unsafe {
+            self.imr.clear_flag(0x1 << line);
+        }
+    }
+
+    pub fn is_pending(&self, line: u32) -> bool {
+        unsafe { self.pr.get() & (0x1 << line) != 0 }
+    }
+
+    /// Clears the pending bit of the line. Must be called from the
+    /// interrupt handler, or the interrupt fires again.
+    pub fn clear_pending(&self, line: u32) {
+        // PR bits are cleared by writing 1, zeros have no effect.
+        unsafe {
+            self.pr.set(0x1 << line);
+        }
+    }
+}
+
+#[cfg(test)]
+fn mock_exti() -> (Exti, Syscfg) {
+    unsafe { (::core::mem::zeroed(), ::core::mem::zeroed()) }
+}
+
+#[test]
+fn test_exticr_field() {
+    assert_eq!((0, 0), exticr_field(0));
+    assert_eq!((0, 12), exticr_field(3));
+    assert_eq!((1, 0), exticr_field(4));
+    assert_eq!((2, 8), exticr_field(10));
+    assert_eq!((3, 12), exticr_field(15));
+}
+
+#[test]
+fn test_select_exti_port() {
+    let (_, syscfg) = mock_exti();
+    unsafe { syscfg.exticr[2].set(0xffff) };
+
+    syscfg.select_exti_port(10, Port::D);
+    syscfg.select_exti_port(5, Port::K);
+    unsafe {
+        assert_eq!(0xf3ff, syscfg.exticr[2].get());
+        assert_eq!(0xa0, syscfg.exticr[1].get());
+        assert_eq!(0, syscfg.exticr[0].get());
+    }
+}
+
+#[test]
+fn test_enable_interrupt() {
+    let (exti, syscfg) = mock_exti();
+
+    exti.enable_interrupt(&syscfg, Port::C, 13, Edge::Both);
+    exti.enable_interrupt(&syscfg, Port::A, 0, Edge::Rising);
+    unsafe {
+        assert_eq!(0x2001, exti.imr.get());
+        assert_eq!(0x2001, exti.rtsr.get());
+        assert_eq!(0x2000, exti.ftsr.get());
+        assert_eq!(0x0020, syscfg.exticr[3].get());
+    }
+
+    exti.enable_interrupt(&syscfg, Port::A, 13, Edge::Falling);
+    unsafe {
+        assert_eq!(0x0001, exti.rtsr.get());
+        assert_eq!(0x2000, exti.ftsr.get());
+        assert_eq!(0, syscfg.exticr[3].get());
+    }
+}
+
+#[test]
+fn test_clear_pending() {
+    let (exti, _) = mock_exti();
+
+    exti.clear_pending(5);
+    assert_eq!(1 << 5, unsafe { exti.pr.get() });
+}
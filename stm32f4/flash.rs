@@ -0,0 +1,268 @@
+//! Flash memory interface.
+//!
+//! Supports the access control, and erasing and programming sectors.
+//! Options bytes are not supported.
+
+use crate::volatile::RW;
+
+extern "C" {
+    pub static FLASH: Flash;
+}
+
+#[repr(C)]
+#[allow(missing_debug_implementations)]
+pub struct Flash {
+    acr: RW<u32>,     // 0x00
+    keyr: RW<u32>,    // 0x04
+    optkeyr: RW<u32>, // 0x08
+    sr: RW<u32>,      // 0x0C
+    cr: RW<u32>,      // 0x10
+    optcr: RW<u32>,   // 0x14
+    optcr1: RW<u32>,  // 0x18
+}
+
+#[test]
+fn test_register_size() {
+    assert_eq!(0x1C, ::core::mem::size_of::<Flash>());
+}
+
+/// Number of wait states.
+const ACR_LATENCY: u32 = 0xF;
+
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+
+const SR_OPERR: u32 = 1 << 1;
+const SR_WRPERR: u32 = 1 << 4;
+const SR_PGAERR: u32 = 1 << 5;
+const SR_PGPERR: u32 = 1 << 6;
+const SR_PGSERR: u32 = 1 << 7;
+const SR_BSY: u32 = 1 << 16;
+const SR_ERRORS: u32 = SR_OPERR | SR_WRPERR | SR_PGAERR | SR_PGPERR | SR_PGSERR;
+
+const CR_PG: u32 = 1 << 0;
+const CR_SER: u32 = 1 << 1;
+const CR_SNB: u32 = 0xF << 3;
+/// Program parallelism. x8 works at any supply voltage.
+const CR_PSIZE: u32 = 0x3 << 8;
+const CR_PSIZE_X8: u32 = 0x0 << 8;
+const CR_STRT: u32 = 1 << 16;
+const CR_LOCK: u32 = 1 << 31;
+
+/// Number of sectors of a 1 MB device.
+pub const SECTORS: u32 = 12;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Error {
+    /// The sector is write-protected.
+    WriteProtection,
+    /// Programming sequence, parallelism, or alignment error.
+    Programming,
+    /// The operation failed (e.g., the control register is locked).
+    Operation,
+}
+
+/// Max HCLK frequency for each wait state, at 2.7-3.6 V.
+const HCLK_PER_WAIT_STATE: u32 = 30_000_000;
+
+/// Returns the number of wait states needed to read the flash at
+/// `hclk` Hz, assuming 2.7-3.6 V supply.
+pub fn latency_for(hclk: u32) -> u32 {
+    hclk.saturating_sub(1) / HCLK_PER_WAIT_STATE
+}
+
+impl Flash {
+    /// Sets the number of wait states and waits until it applies.
+    ///
+    /// Must be increased before raising HCLK, and decreased after
+    /// lowering it.
+    pub fn set_latency(&self, wait_states: u32) {
+        debug_assert!(wait_states <= ACR_LATENCY);
+        unsafe {
+            self.acr.update_with_mask(ACR_LATENCY, wait_states);
+            while self.acr.get() & ACR_LATENCY != wait_states {}
+        }
+    }
+
+    pub fn latency(&self) -> u32 {
+        unsafe { self.acr.get() & ACR_LATENCY }
+    }
+
+    /// Unlocks the control register, so the flash can be erased and
+    /// programmed. Use `lock` when done.
+    pub fn unlock(&self) {
+        unsafe {
+            if self.cr.get() & CR_LOCK != 0 {
+                self.keyr.set(KEY1);
+                self.keyr.set(KEY2);
+            }
+        }
+    }
+
+    /// Locks the control register until the next `unlock`.
+    pub fn lock(&self) {
+        unsafe {
+            self.cr.set_flag(CR_LOCK);
+        }
+    }
+
+    /// Waits for the running operation, and returns its errors.
+    ///
+    /// The error flags are cleared, so the next operation can start.
+    fn wait_idle(&self) -> Result<(), Error> {
+        unsafe {
+            while self.sr.get() & SR_BSY != 0 {}
+
+            let errors = self.sr.get() & SR_ERRORS;
+            // The flags are cleared by writing 1.
+            self.sr.set(errors);
+
+            if errors & SR_WRPERR != 0 {
+                Err(Error::WriteProtection)
+            } else if errors & (SR_PGAERR | SR_PGPERR | SR_PGSERR) != 0 {
+                Err(Error::Programming)
+            } else if errors & SR_OPERR != 0 {
+                Err(Error::Operation)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    /// Sets all bytes of the sector to `0xff`. The control register
+    /// must be unlocked.
+    ///
+    /// Blocks until the sector is erased, which takes up to seconds.
+    /// Code running from flash (including interrupt handlers) stalls
+    /// meanwhile.
+    pub fn erase_sector(&self, sector: u32) -> Result<(), Error> {
+        debug_assert!(sector < SECTORS);
+        // Errors left from an earlier operation would fail this one.
+        let _ = self.wait_idle();
+
+        unsafe {
+            self.cr.update_with_mask(
+                CR_PSIZE | CR_SNB | CR_SER,
+                CR_PSIZE_X8 | sector << 3 | CR_SER,
+            );
+            self.cr.set_flag(CR_STRT);
+            let res = self.wait_idle();
+            self.cr.clear_flag(CR_SER | CR_SNB);
+            res
+        }
+    }
+
+    /// Programs `data` at `address`, a byte at a time. The control
+    /// register must be unlocked.
+    ///
+    /// Programming can only clear bits, so the bytes must be erased
+    /// first.
+    ///
+    /// # Safety
+    /// `address` must point to `data.len()` bytes of flash that no
+    /// one else is accessing.
+    pub unsafe fn program(&self, address: *mut u8, data: &[u8]) -> Result<(), Error> {
+        // Errors left from an earlier operation would fail this one.
+        let _ = self.wait_idle();
+
+        self.cr.update_with_mask(CR_PSIZE, CR_PSIZE_X8);
+        self.cr.set_flag(CR_PG);
+        let mut res = Ok(());
+        for (i, &x) in data.iter().enumerate() {
+            ::core::ptr::write_volatile(address.add(i), x);
+            res = self.wait_idle();
+            if res.is_err() {
+                break;
+            }
+        }
+        self.cr.clear_flag(CR_PG);
+        res
+    }
+}
+
+#[test]
+fn test_latency_for() {
+    assert_eq!(0, latency_for(16_000_000));
+    assert_eq!(0, latency_for(30_000_000));
+    assert_eq!(1, latency_for(30_000_001));
+    assert_eq!(5, latency_for(168_000_000));
+    assert_eq!(5, latency_for(180_000_000));
+}
+
+#[test]
+fn test_unlock() {
+    let flash: Flash = unsafe { ::core::mem::zeroed() };
+    unsafe { flash.cr.set(CR_LOCK) };
+
+    flash.unlock();
+    // KEYR of the mock reads back the last key written.
+    assert_eq!(KEY2, unsafe { flash.keyr.get() });
+
+    // Writing the keys to an unlocked register would lock it until
+    // reset.
+    unsafe {
+        flash.keyr.set(0);
+        flash.cr.set(0);
+    }
+    flash.unlock();
+    assert_eq!(0, unsafe { flash.keyr.get() });
+
+    flash.lock();
+    assert_eq!(CR_LOCK, unsafe { flash.cr.get() });
+}
+
+#[test]
+fn test_erase_sector() {
+    let flash: Flash = unsafe { ::core::mem::zeroed() };
+    unsafe { flash.cr.set(0x3 << 8) };
+
+    assert_eq!(Ok(()), flash.erase_sector(11));
+    // Started with x8 parallelism, and the sector is deselected.
+    assert_eq!(CR_STRT, unsafe { flash.cr.get() });
+}
+
+#[test]
+fn test_erase_write_protected() {
+    let flash: Flash = unsafe { ::core::mem::zeroed() };
+    unsafe { flash.sr.set(SR_WRPERR) };
+
+    assert_eq!(Err(Error::WriteProtection), flash.erase_sector(0));
+    assert_eq!(0, unsafe { flash.cr.get() } & CR_SER);
+}
+
+#[test]
+fn test_program() {
+    let flash: Flash = unsafe { ::core::mem::zeroed() };
+    let mut sector = [0xff_u8; 4];
+
+    assert_eq!(Ok(()), unsafe {
+        flash.program(sector.as_mut_ptr().add(1), &[1, 2])
+    });
+    assert_eq!([0xff, 1, 2, 0xff], sector);
+    assert_eq!(0, unsafe { flash.cr.get() } & CR_PG);
+}
+
+#[test]
+fn test_program_error() {
+    let flash: Flash = unsafe { ::core::mem::zeroed() };
+    let mut sector = [0xff_u8; 4];
+    unsafe { flash.sr.set(SR_PGSERR) };
+
+    assert_eq!(Err(Error::Programming), unsafe {
+        flash.program(sector.as_mut_ptr(), &[1, 2])
+    });
+    // Stops at the first error.
+    assert_eq!([1, 0xff, 0xff, 0xff], sector);
+    assert_eq!(0, unsafe { flash.cr.get() } & CR_PG);
+}
+
+#[test]
+fn test_set_latency() {
+    let flash: Flash = unsafe { ::core::mem::zeroed() };
+    unsafe { flash.acr.set(0x700) };
+
+    flash.set_latency(5);
+    assert_eq!(5, flash.latency());
+    // Caches and prefetch are untouched.
+    assert_eq!(0x705, unsafe { flash.acr.get() });
+}
@@ -156,4 +156,70 @@ impl Gpio {
             self.bsrr.set(0x1 << (pin + 16));
         }
     }
+
+    /// Inverts the output of a pin.
+    ///
+    /// The new level is written through BSRR, so other pins of the
+    /// port are not affected even if they change meanwhile.
+    pub fn toggle_bit(&self, pin: u32) {
+        let odr = unsafe { self.odr.get() };
+        if odr & (0x1 << pin) != 0 {
+            self.clear_bit(pin);
+        } else {
+            self.set_bit(pin);
+        }
+    }
+
+    /// Returns the input level of a pin.
+    pub fn read_bit(&self, pin: u32) -> bool {
+        unsafe { (self.idr.get() >> pin) & 0x1 != 0 }
+    }
+
+    /// Returns the input levels of all pins of the port.
+    #[allow(clippy::cast_possible_truncation)] // IDR is 16-bit
+    pub fn read_port(&self) -> u16 {
+        unsafe { self.idr.get() as u16 }
+    }
+}
+
+#[cfg(test)]
+fn mock_gpio() -> Gpio {
+    unsafe { ::core::mem::zeroed() }
+}
+
+#[test]
+fn test_read_input() {
+    let gpio = mock_gpio();
+    unsafe {
+        gpio.idr.set(0x8001);
+    }
+
+    assert!(gpio.read_bit(0));
+    assert!(!gpio.read_bit(1));
+    assert!(gpio.read_bit(15));
+    assert_eq!(0x8001, gpio.read_port());
+}
+
+#[test]
+fn test_toggle_bit() {
+    let gpio = mock_gpio();
+    // Applies BSRR to ODR, as the hardware does.
+    let apply_bsrr = || unsafe {
+        let bsrr = gpio.bsrr.get();
+        gpio.odr
+            .set((gpio.odr.get() | (bsrr & 0xffff)) & !(bsrr >> 16));
+    };
+    unsafe {
+        gpio.odr.set(0x0001);
+    }
+
+    gpio.toggle_bit(13);
+    assert_eq!(1 << 13, unsafe { gpio.bsrr.get() });
+    apply_bsrr();
+    assert_eq!(0x2001, unsafe { gpio.odr.get() });
+
+    gpio.toggle_bit(13);
+    assert_eq!(1 << 29, unsafe { gpio.bsrr.get() });
+    apply_bsrr();
+    assert_eq!(0x0001, unsafe { gpio.odr.get() });
 }
@@ -425,12 +425,12 @@ pub enum Event {
     /// MasterTransmitterModeSelected event is set.
     /// 3. In case of 10-bit addressing mode, the master (just after
     /// generating the START and checking on EV5) has to send the
-    /// header of 10-bit addressing mode (I2c::send_data
+    /// header of 10-bit addressing mode (I2c::send_10bit_header
     /// function). Then master should wait on EV9. It means that the
     /// 10-bit addressing header has been correctly sent on the
     /// bus. Then master should send the second part of the 10-bit
     /// address (LSB) using the function
-    /// I2c::send_7bit_address(). Then master should wait for event
+    /// I2c::send_10bit_address(). Then master should wait for event
     /// EV6.
     // EV6
     MasterTransmitterModeSelected = 0x0007_0082, // BUSY, MSL, ADDR, TXE, TRA
@@ -553,6 +553,55 @@ pub enum Event {
     __NonExhaustive,
 }
 
+/// Image of both status registers, as returned by `get_last_event`.
+///
+/// Several flags may be set at once, so the status doesn't always
+/// equal one of the `Event` values. Check individual flags instead.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Status(pub u32);
+
+impl Status {
+    pub fn sr1(self, flag: Sr1Masks) -> bool {
+        self.0 & (flag as u32) != 0
+    }
+
+    pub fn sr2(self, flag: Sr2Masks) -> bool {
+        self.0 & ((flag as u32) << 16) != 0
+    }
+
+    /// Returns true if all flags of the event are set.
+    pub fn contains(self, event: Event) -> bool {
+        self.0 & (event as u32) == event as u32
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)] // masked
+fn header_10bit(address: u16, direction: Direction) -> u8 {
+    debug_assert!(address <= 0x3ff);
+    0xf0 | ((address >> 7) & 0x6) as u8 | direction as u8
+}
+
+#[test]
+fn test_header_10bit() {
+    assert_eq!(0xf0, header_10bit(0x0a5, Direction::Transmitter));
+    assert_eq!(0xf4, header_10bit(0x2a5, Direction::Transmitter));
+    assert_eq!(0xf7, header_10bit(0x3ff, Direction::Receiver));
+}
+
+#[test]
+fn test_status_flags() {
+    // MasterByteReceived with BTF set.
+    let status = Status(0x0003_0044);
+    assert!(status.sr1(Sr1Masks::RxNE));
+    assert!(status.sr1(Sr1Masks::BTF));
+    assert!(status.sr2(Sr2Masks::MSL));
+    assert!(!status.sr2(Sr2Masks::TRA));
+    assert!(!status.sr1(Sr1Masks::SB));
+
+    assert!(status.contains(Event::MasterByteReceived));
+    assert!(!status.contains(Event::MasterByteTransmitted));
+}
+
 pub const I2C_INIT: I2cInit = I2cInit {
     clock_speed: 5000,
     mode: Mode::I2C,
@@ -573,7 +622,10 @@ impl I2c {
         debug_assert!(init.clock_speed >= 0x1 && init.clock_speed <= 400_000);
         debug_assert!(init.own_address1 <= 0x3ff);
 
-        let pclk1 = RCC.clock_freqs().pclk1;
+        let pclk1 = RCC
+            .clock_freqs()
+            .expect("invalid clock configuration")
+            .pclk1;
 
         // Set frequency bits depending on pclk1 value
         let freqrange = pclk1 / 1_000_000;
@@ -649,6 +701,14 @@ impl I2c {
             .set((init.acknowledged_address as u32) | u32::from(init.own_address1));
     }
 
+    /// Resets the peripheral, e.g., to get it out of the busy state
+    /// after the bus is stuck. All registers are reset, so it must be
+    /// initialized again.
+    pub unsafe fn software_reset(&self) {
+        self.cr1.set_flag(Cr1Masks::SWRST as u32);
+        self.cr1.clear_flag(Cr1Masks::SWRST as u32);
+    }
+
     /// Generates I2C communication start condition.
     pub unsafe fn generate_start(&self) {
         self.cr1.set_flag(Cr1Masks::START as u32);
@@ -668,6 +728,23 @@ impl I2c {
         );
     }
 
+    /// Sends the header of a 10-bit address: `11110` followed by the
+    /// two most significant address bits and the direction bit.
+    ///
+    /// A transmitter sends the second part of the address with
+    /// `send_10bit_address` after `Event::MasterModeAddress10`. A
+    /// receiver does so too, then repeats the start condition and
+    /// sends the header again with `Direction::Receiver`.
+    pub unsafe fn send_10bit_header(&self, address: u16, direction: Direction) {
+        self.send_data(header_10bit(address, direction));
+    }
+
+    /// Sends the 8 least significant bits of a 10-bit address.
+    #[allow(clippy::cast_possible_truncation)] // intended
+    pub unsafe fn send_10bit_address(&self, address: u16) {
+        self.send_data(address as u8);
+    }
+
     pub unsafe fn send_data(&self, data: u8) {
         self.dr.set(u32::from(data));
     }
@@ -685,11 +762,17 @@ impl I2c {
         }
     }
 
+    /// Returns whether received bytes are acknowledged.
+    pub unsafe fn get_acknowledge(&self) -> bool {
+        self.cr1.get() & Cr1Masks::ACK as u32 != 0
+    }
+
     /// Returns the image of both status registers in a single word
     /// (u32) (SR2 value is shiftedd left by 16 bits and concatenated
     /// to SR1).
     ///
-    /// The result event could be checked against `Event` enum.
+    /// The result event could be checked against `Event` enum. Use
+    /// `get_last_status` to check individual flags.
     pub unsafe fn get_last_event(&self) -> u32 {
         // Do NOT inline these reads. They should be done in that
         // order.
@@ -698,6 +781,10 @@ impl I2c {
         (sr1 | (sr2 << 16)) & FLAG_MASK
     }
 
+    pub unsafe fn get_last_status(&self) -> Status {
+        Status(self.get_last_event())
+    }
+
     pub unsafe fn it_enable(&self, it: Interrupt) {
         self.cr2.set_flag(it as u32);
     }
@@ -54,8 +54,7 @@ pub unsafe extern "C" fn __isr_reset() {
 #[cfg(target_arch = "arm")]
 #[no_mangle]
 pub unsafe extern "C" fn __isr_default() {
-    let ipsr: u32;
-    asm!("mrs $0, IPSR" : "=r" (ipsr));
+    let ipsr = crate::__get_ipsr();
     if ipsr >= 16 {
         panic!("Unknown ISR handler: {} (IRQ {})!", ipsr, ipsr - 16);
     } else {
@@ -8,12 +8,20 @@ pub mod isr_vector;
 
 #[macro_use]
 pub mod volatile;
+pub mod adc;
+pub mod altfn;
 pub mod crc;
+pub mod delay;
+pub mod dma;
+pub mod dwt;
+pub mod exti;
+pub mod flash;
 pub mod gpio;
 pub mod i2c;
 pub mod nvic;
 pub mod rcc;
 pub mod rng;
+pub mod spi;
 pub mod timer;
 pub mod usart;
 
@@ -43,11 +51,11 @@ pub unsafe fn __wait_for_event() {
     asm!("wfe" : : : : "volatile");
 }
 
+/// There is no one to wake up on host, so this is a no-op. This
+/// allows running the reactor in host tests.
 #[inline(always)]
 #[cfg(not(target_arch = "arm"))]
-pub unsafe fn __set_event() {
-    panic!("__set_event is not implemented");
-}
+pub unsafe fn __set_event() {}
 
 #[inline(always)]
 #[cfg(target_arch = "arm")]
@@ -55,23 +63,59 @@ pub unsafe fn __set_event() {
     asm!("sev" : : : : "volatile");
 }
 
+/// Emulated PRIMASK.
+///
+/// There are no interrupts on host, but tracking the mask allows
+/// running and checking code guarded by `IrqLock` in host tests.
+#[cfg(not(target_arch = "arm"))]
+static PRIMASK: core::sync::atomic::AtomicU32 = core::sync::atomic::AtomicU32::new(0);
+
+/// See `PRIMASK`.
 #[inline(always)]
 #[cfg(not(target_arch = "arm"))]
 pub unsafe fn __enable_irq() {
-    panic!("enable_irq is not implemented");
+    PRIMASK.store(0, core::sync::atomic::Ordering::SeqCst);
 }
 
+/// See `PRIMASK`.
 #[inline(always)]
 #[cfg(not(target_arch = "arm"))]
 pub unsafe fn __disable_irq() {
-    panic!("disable_irq is not implemented");
+    PRIMASK.store(1, core::sync::atomic::Ordering::SeqCst);
 }
 
 /// Get priority mask.
+///
+/// See `PRIMASK`.
 #[inline(always)]
 #[cfg(not(target_arch = "arm"))]
 pub unsafe fn __get_primask() -> u32 {
-    panic!("get_primask is not implemented");
+    PRIMASK.load(core::sync::atomic::Ordering::SeqCst)
+}
+
+/// Emulated IPSR.
+///
+/// Host code always runs in thread mode. Tests can set the exception
+/// number to run code as if it was called from an interrupt handler.
+#[cfg(not(target_arch = "arm"))]
+static IPSR: core::sync::atomic::AtomicU32 = core::sync::atomic::AtomicU32::new(0);
+
+/// Get the number of the current exception, 0 in thread mode.
+///
+/// See `IPSR`.
+#[inline(always)]
+#[cfg(not(target_arch = "arm"))]
+pub unsafe fn __get_ipsr() -> u32 {
+    IPSR.load(core::sync::atomic::Ordering::SeqCst)
+}
+
+/// Set the emulated exception number.
+///
+/// See `IPSR`.
+#[inline(always)]
+#[cfg(not(target_arch = "arm"))]
+pub unsafe fn __set_ipsr(exception: u32) {
+    IPSR.store(exception, core::sync::atomic::Ordering::SeqCst);
 }
 
 #[inline(always)]
@@ -94,6 +138,15 @@ pub unsafe fn __get_primask() -> u32 {
     result
 }
 
+/// Get the number of the current exception, 0 in thread mode.
+#[inline(always)]
+#[cfg(target_arch = "arm")]
+pub unsafe fn __get_ipsr() -> u32 {
+    let result: u32;
+    asm!("MRS $0, IPSR" : "=r" (result) : : : "volatile");
+    result
+}
+
 /// Saves current irq status and disables interrupts.
 /// Interrupts should always be restored with `restore_irq()`.
 ///
@@ -142,6 +195,117 @@ impl Drop for IrqLock {
     }
 }
 
+/// A lock for data shared between interrupt handlers and tasks.
+///
+/// Interrupts are disabled while the lock is held, so the critical
+/// section must be short. Use it where a lock-free structure is
+/// overkill.
+///
+/// Locking it again from the same context deadlocks, as there is no
+/// one to release it.
+#[derive(Debug)]
+pub struct SpinLock<T> {
+    locked: core::sync::atomic::AtomicBool,
+    data: core::cell::UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> SpinLock<T> {
+        SpinLock {
+            locked: core::sync::atomic::AtomicBool::new(false),
+            data: core::cell::UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+        }
+    }
+
+    /// Returns `None` if the lock is already held.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        let irq = unsafe { IrqLock::new() };
+        if self
+            .locked
+            .compare_and_swap(false, true, core::sync::atomic::Ordering::Acquire)
+        {
+            None
+        } else {
+            Some(SpinLockGuard {
+                lock: self,
+                _irq: irq,
+            })
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+/// Releases the lock and restores interrupts on drop.
+#[derive(Debug)]
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    // Dropped after the lock is released.
+    _irq: IrqLock,
+}
+
+impl<'a, T> core::ops::Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> core::ops::DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock
+            .locked
+            .store(false, core::sync::atomic::Ordering::Release);
+    }
+}
+
+#[test]
+fn test_spin_lock() {
+    let lock = SpinLock::new(0_u32);
+
+    {
+        let mut guard = lock.lock();
+        *guard += 1;
+
+        assert_eq!(1, unsafe { __get_primask() });
+        assert!(lock.try_lock().is_none());
+        // A failed attempt doesn't enable interrupts.
+        assert_eq!(1, unsafe { __get_primask() });
+    }
+    assert_eq!(0, unsafe { __get_primask() });
+
+    {
+        let mut guard = lock.try_lock().unwrap();
+        *guard += 1;
+
+        // Nested critical section doesn't enable interrupts early.
+        drop(unsafe { IrqLock::new() });
+        assert_eq!(1, unsafe { __get_primask() });
+    }
+    assert_eq!(0, unsafe { __get_primask() });
+
+    assert_eq!(2, lock.into_inner());
+}
+
 /// Returns the unique device identifier.
 ///
 /// The 96-bit unique device identifier provides a reference number
@@ -153,6 +317,54 @@ pub fn get_device_id() -> u128 {
     }
 }
 
+/// Splits the device id into words in the order they are stored in
+/// memory.
+#[allow(clippy::cast_possible_truncation)] // intended
+fn device_id_words(id: u128) -> [u32; 3] {
+    [id as u32, (id >> 32) as u32, (id >> 64) as u32]
+}
+
+/// Hashes the device id with the CRC unit.
+pub fn hash_device_id(crc: &crc::Crc, id: u128) -> u32 {
+    crc.reset();
+    crc.block_crc(&device_id_words(id))
+}
+
+/// Returns a stable 32-bit hash of the unique device identifier.
+///
+/// Suitable for deriving per-device identifiers (e.g., a default
+/// hostname). The CRC peripheral clock must be enabled.
+pub fn device_hash() -> u32 {
+    hash_device_id(unsafe { &crc::CRC }, get_device_id())
+}
+
+/// Formats the lower 24 bits of the device hash as 6 uppercase hex
+/// digits, to be used as a default SSID suffix.
+#[allow(clippy::cast_possible_truncation)] // masked to a nibble
+pub fn ssid_suffix(hash: u32) -> [u8; 6] {
+    const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+    let mut res = [0; 6];
+    for (i, x) in res.iter_mut().enumerate() {
+        *x = DIGITS[((hash >> (20 - 4 * i)) & 0xf) as usize];
+    }
+    res
+}
+
+#[test]
+fn test_device_hash() {
+    let id = 0x0030_0025_3436_5111_3533_3537;
+    assert_eq!([0x3533_3537, 0x3436_5111, 0x0030_0025], device_id_words(id));
+    // The hardware unit computes the same CRC.
+    assert_eq!(0x77a7_0b9c, crc::software_crc(&device_id_words(id)));
+}
+
+#[test]
+fn test_ssid_suffix() {
+    assert_eq!(*b"A70B9C", ssid_suffix(0x77a7_0b9c));
+    assert_eq!(*b"000001", ssid_suffix(0xff00_0001));
+}
+
 /// Returns the flash memory size in kbytes.
 pub fn get_flash_size() -> u16 {
     const REG: *const u16 = 0x1FFF_7A22 as _;
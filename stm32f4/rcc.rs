@@ -1,6 +1,7 @@
 //! Reset and clock control.
 #![allow(clippy::identity_op)]
 
+use crate::flash::{self, Flash};
 use crate::volatile::{RES, RW};
 
 extern "C" {
@@ -68,6 +69,28 @@ fn test_register_size() {
     assert_eq!(0x90, ::core::mem::size_of::<Rcc>());
 }
 
+#[allow(dead_code)]
+#[derive(Copy, Clone)]
+#[repr(u32)]
+enum CrMask {
+    /// Internal high-speed clock enable
+    HSION = 0x1 << 0,
+    /// Internal high-speed clock ready flag
+    HSIRDY = 0x1 << 1,
+    /// HSE clock enable
+    HSEON = 0x1 << 16,
+    /// HSE clock ready flag
+    HSERDY = 0x1 << 17,
+    /// HSE clock bypass
+    HSEBYP = 0x1 << 18,
+    /// Clock security system enable
+    CSSON = 0x1 << 19,
+    /// Main PLL (PLL) enable
+    PLLON = 0x1 << 24,
+    /// Main PLL (PLL) clock ready flag
+    PLLRDY = 0x1 << 25,
+}
+
 #[allow(dead_code)]
 #[derive(Copy, Clone)]
 #[repr(u32)]
@@ -79,7 +102,7 @@ enum PllCfgrMask {
 
     // 14:6
     /// Mail PLL (PLL) multiplication factor for VCO.
-    PLLN = 0xFF << 6,
+    PLLN = 0x1FF << 6,
 
     // 17:16
     /// Main PLL (PLL) division factor for main system clock.
@@ -114,7 +137,7 @@ enum CfgrMask {
 
     // 7:4
     /// AHB prescaler
-    HPRE = 0x7 << 4,
+    HPRE = 0xF << 4,
 
     // 9:8 reserved
     // 12:10
@@ -150,6 +173,77 @@ enum CfgrMask {
     MCO2 = 0x3 << 30,
 }
 
+/// SW and SWS value for PLL.
+const CFGR_SW_PLL: u32 = 0x2;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PllSource {
+    Hsi,
+    Hse,
+}
+
+/// Main PLL and bus prescaler configuration for
+/// `Rcc::configure_pll()`.
+///
+/// SYSCLK = source / `pllm` * `plln` / `pllp`. VCO input
+/// (source / `pllm`) must be 1-2 MHz, VCO output 100-432 MHz.
+/// Source / `pllm` * `plln` / `pllq` should be 48 MHz if USB OTG FS,
+/// SDIO or RNG are used.
+///
+/// # Examples
+///
+/// 168 MHz from the internal oscillator.
+///
+/// ```no_run
+/// use stm32f4::flash::FLASH;
+/// use stm32f4::rcc::{PllConfig, PllSource, RCC};
+///
+/// unsafe {
+///   RCC.configure_pll(
+///       &PllConfig {
+///           source: PllSource::Hsi,
+///           pllm: 16,
+///           plln: 336,
+///           pllp: 2,
+///           pllq: 7,
+///           apb1_div: 4,
+///           apb2_div: 2,
+///       },
+///       &FLASH,
+///   );
+/// }
+/// ```
+#[derive(Copy, Clone, Debug)]
+pub struct PllConfig {
+    pub source: PllSource,
+    /// 2..63
+    pub pllm: u32,
+    /// 50..432
+    pub plln: u32,
+    /// 2, 4, 6, or 8
+    pub pllp: u32,
+    /// 2..15
+    pub pllq: u32,
+    /// APB1 prescaler: 1, 2, 4, 8, or 16. PCLK1 must not exceed
+    /// 42 MHz.
+    pub apb1_div: u32,
+    /// APB2 prescaler: 1, 2, 4, 8, or 16. PCLK2 must not exceed
+    /// 84 MHz.
+    pub apb2_div: u32,
+}
+
+/// Returns the PPRE1/PPRE2 field value for dividing by `div`.
+fn ppre(div: u32) -> u32 {
+    match div {
+        1 => 0b000,
+        2 => 0b100,
+        4 => 0b101,
+        8 => 0b110,
+        16 => 0b111,
+        _ => panic!("invalid APB prescaler"),
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 #[repr(u32)]
 pub enum Ahb1Enable {
@@ -251,7 +345,7 @@ pub enum Apb2Enable {
     LTDC = 1 << 26,
 }
 
-#[allow(missing_debug_implementations)]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub struct Clocks {
     /// SYSCLK clock frequency expressed in Hz
     pub sysclk: u32,
@@ -266,6 +360,27 @@ pub struct Clocks {
     pub pclk2: u32,
 }
 
+/// Errors returned by `Rcc::clock_freqs()`.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ClockError {
+    /// SWS reports a value that is not a valid system clock source.
+    UnknownClockSource(u32),
+    /// PLL is the system clock source, but PLLM is zero.
+    InvalidPllm,
+}
+
+/// Returns the number of bits to shift the clock by for the given
+/// HPRE/PPRE1/PPRE2 field value.
+///
+/// PPRE fields are 3 bits wide, and their encoding matches the lower
+/// half of the HPRE one.
+fn prescaler_shift(presc: u32) -> u32 {
+    const APBAHB_PRESC_TABLE: [u8; 16] = [0, 0, 0, 0, 1, 2, 3, 4, 1, 2, 3, 4, 6, 7, 8, 9];
+
+    // Masking keeps the index in range whatever the caller passes.
+    u32::from(APBAHB_PRESC_TABLE[(presc & 0xF) as usize])
+}
+
 impl Rcc {
     pub fn ahb1_clock_enable(&self, value: Ahb1Enable) {
         unsafe {
@@ -297,7 +412,115 @@ impl Rcc {
         }
     }
 
-    pub fn clock_freqs(&self) -> Clocks {
+    pub fn ahb1_clock_disable(&self, value: Ahb1Enable) {
+        unsafe {
+            self.ahb1enr.update(|x| x & !(value as u32));
+        }
+    }
+
+    pub fn ahb2_clock_disable(&self, value: Ahb2Enable) {
+        unsafe {
+            self.ahb2enr.update(|x| x & !(value as u32));
+        }
+    }
+
+    pub fn ahb3_clock_disable(&self, value: Ahb3Enable) {
+        unsafe {
+            self.ahb3enr.update(|x| x & !(value as u32));
+        }
+    }
+
+    pub fn apb1_clock_disable(&self, value: Apb1Enable) {
+        unsafe {
+            self.apb1enr.update(|x| x & !(value as u32));
+        }
+    }
+
+    pub fn apb2_clock_disable(&self, value: Apb2Enable) {
+        unsafe {
+            self.apb2enr.update(|x| x & !(value as u32));
+        }
+    }
+
+    /// Pulses the reset line of an APB1 peripheral, resetting all its
+    /// registers.
+    ///
+    /// Reset bits share positions with the clock enable bits.
+    pub fn apb1_reset(&self, value: Apb1Enable) {
+        unsafe {
+            self.apb1rstr.update(|x| x | value as u32);
+            self.apb1rstr.update(|x| x & !(value as u32));
+        }
+    }
+
+    /// Pulses the reset line of an APB2 peripheral, resetting all its
+    /// registers.
+    pub fn apb2_reset(&self, value: Apb2Enable) {
+        unsafe {
+            self.apb2rstr.update(|x| x | value as u32);
+            self.apb2rstr.update(|x| x & !(value as u32));
+        }
+    }
+
+    /// Configures the main PLL and switches the system clock to it.
+    /// Waits for HSE (if selected) and the PLL to become ready, and
+    /// raises the flash latency to match the new HCLK.
+    ///
+    /// Must not be called while the system clock is the PLL, as it
+    /// can't be reconfigured while enabled.
+    pub fn configure_pll(&self, cfg: &PllConfig, flash: &Flash) {
+        debug_assert!((2..=63).contains(&cfg.pllm));
+        debug_assert!((50..=432).contains(&cfg.plln));
+        debug_assert!((2..=8).contains(&cfg.pllp) && cfg.pllp % 2 == 0);
+        debug_assert!((2..=15).contains(&cfg.pllq));
+
+        let source = match cfg.source {
+            PllSource::Hsi => HSI_VALUE,
+            PllSource::Hse => HSE_VALUE,
+        };
+        let sysclk = source / cfg.pllm * cfg.plln / cfg.pllp;
+
+        unsafe {
+            if cfg.source == PllSource::Hse {
+                self.cr.set_flag(CrMask::HSEON as u32);
+                while self.cr.get() & CrMask::HSERDY as u32 == 0 {}
+            }
+
+            self.cr.clear_flag(CrMask::PLLON as u32);
+            self.pllcfgr.update_with_mask(
+                PllCfgrMask::PLLM as u32
+                    | PllCfgrMask::PLLN as u32
+                    | PllCfgrMask::PLLP as u32
+                    | PllCfgrMask::PLLSRC as u32
+                    | PllCfgrMask::PLLQ as u32,
+                cfg.pllm
+                    | (cfg.plln << 6)
+                    | ((cfg.pllp / 2 - 1) << 16)
+                    | ((cfg.source == PllSource::Hse) as u32) << 22
+                    | (cfg.pllq << 24),
+            );
+            self.cr.set_flag(CrMask::PLLON as u32);
+            while self.cr.get() & CrMask::PLLRDY as u32 == 0 {}
+
+            // AHB is not prescaled, so HCLK is SYSCLK.
+            if flash::latency_for(sysclk) > flash.latency() {
+                flash.set_latency(flash::latency_for(sysclk));
+            }
+
+            self.cfgr.update_with_mask(
+                CfgrMask::HPRE as u32 | CfgrMask::PPRE1 as u32 | CfgrMask::PPRE2 as u32,
+                (ppre(cfg.apb1_div) << 10) | (ppre(cfg.apb2_div) << 13),
+            );
+            self.cfgr.update_with_mask(CfgrMask::SW as u32, CFGR_SW_PLL);
+            while (self.cfgr.get() & CfgrMask::SWS as u32) >> 2 != CFGR_SW_PLL {}
+        }
+    }
+
+    /// Computes clock frequencies from the current configuration.
+    ///
+    /// Fails if the configuration is invalid, so misconfiguration is
+    /// detected instead of producing wrong frequencies.
+    pub fn clock_freqs(&self) -> Result<Clocks, ClockError> {
         let cfgr = unsafe { self.cfgr.get() };
 
         let sysclk = match cfgr & (CfgrMask::SWS as u32) {
@@ -314,41 +537,209 @@ impl Rcc {
                 let plln = (pllcfgr & PllCfgrMask::PLLN as u32) >> 6;
                 let pllp = (((pllcfgr & PllCfgrMask::PLLP as u32) >> 16) + 1) * 2;
 
+                if pllm == 0 {
+                    return Err(ClockError::InvalidPllm);
+                }
+
                 let pllvco_base = if pllsource != 0 { HSE_VALUE } else { HSI_VALUE };
                 let pllvco = pllvco_base / pllm * plln;
 
                 pllvco / pllp
             }
-            _ => {
-                debug_assert!(false);
-                // TODO(rasen): not applicable (assert? unreachable?)
-                HSI_VALUE
-            }
+            sws => return Err(ClockError::UnknownClockSource(sws >> 2)),
         };
 
         // Compute HCLK, PCLK1 and PCLK2 clocks frequencies
-        const APBAHB_PRESC_TABLE: [u8; 16] = [0, 0, 0, 0, 1, 2, 3, 4, 1, 2, 3, 4, 6, 7, 8, 9];
-
-        let hclk = {
-            let presc = APBAHB_PRESC_TABLE[((cfgr & CfgrMask::HPRE as u32) >> 4) as usize];
-            sysclk >> presc
-        };
-
-        let pclk1 = {
-            let presc = APBAHB_PRESC_TABLE[((cfgr & CfgrMask::PPRE1 as u32) >> 10) as usize];
-            hclk >> presc
-        };
-
-        let pclk2 = {
-            let presc = APBAHB_PRESC_TABLE[((cfgr & CfgrMask::PPRE2 as u32) >> 13) as usize];
-            hclk >> presc
-        };
+        let hclk = sysclk >> prescaler_shift((cfgr & CfgrMask::HPRE as u32) >> 4);
+        let pclk1 = hclk >> prescaler_shift((cfgr & CfgrMask::PPRE1 as u32) >> 10);
+        let pclk2 = hclk >> prescaler_shift((cfgr & CfgrMask::PPRE2 as u32) >> 13);
 
-        Clocks {
+        Ok(Clocks {
             sysclk,
             hclk,
             pclk1,
             pclk2,
-        }
+        })
+    }
+}
+
+#[test]
+fn test_clock_disable() {
+    let rcc: Rcc = unsafe { ::core::mem::zeroed() };
+
+    rcc.ahb1_clock_enable(Ahb1Enable::GPIOD);
+    rcc.ahb1_clock_enable(Ahb1Enable::DMA1);
+    rcc.apb1_clock_enable(Apb1Enable::I2C1);
+    rcc.apb2_clock_enable(Apb2Enable::SYSCFG);
+
+    rcc.ahb1_clock_disable(Ahb1Enable::DMA1);
+    rcc.apb1_clock_disable(Apb1Enable::I2C1);
+    rcc.apb2_clock_disable(Apb2Enable::USART6);
+
+    unsafe {
+        assert_eq!(Ahb1Enable::GPIOD as u32, rcc.ahb1enr.get());
+        assert_eq!(0, rcc.apb1enr.get());
+        assert_eq!(Apb2Enable::SYSCFG as u32, rcc.apb2enr.get());
+    }
+}
+
+#[test]
+fn test_reset_pulse_releases_line() {
+    let rcc: Rcc = unsafe { ::core::mem::zeroed() };
+    unsafe {
+        rcc.apb1rstr.set(Apb1Enable::TIM2 as u32);
     }
+
+    rcc.apb1_reset(Apb1Enable::USART2);
+    rcc.apb2_reset(Apb2Enable::USART6);
+
+    // Only the pulsed line is released, others are untouched.
+    assert_eq!(Apb1Enable::TIM2 as u32, unsafe { rcc.apb1rstr.get() });
+    assert_eq!(0, unsafe { rcc.apb2rstr.get() });
+}
+
+#[test]
+fn test_configure_pll_168mhz() {
+    // The hardware would set the ready flags and SWS.
+    let rcc = mock_rcc(CFGR_SW_PLL << 2, 0x2000_0000);
+    let flash: Flash = unsafe { ::core::mem::zeroed() };
+    unsafe {
+        rcc.cr.set(CrMask::HSERDY as u32 | CrMask::PLLRDY as u32);
+    }
+
+    rcc.configure_pll(
+        &PllConfig {
+            source: PllSource::Hse,
+            pllm: 25,
+            plln: 336,
+            pllp: 2,
+            pllq: 7,
+            apb1_div: 4,
+            apb2_div: 2,
+        },
+        &flash,
+    );
+
+    unsafe {
+        // Reserved bit 29 is kept.
+        assert_eq!(0x2740_5419, rcc.pllcfgr.get());
+        assert_eq!(
+            CrMask::HSEON as u32 | CrMask::PLLON as u32,
+            rcc.cr.get() & (CrMask::HSEON as u32 | CrMask::PLLON as u32)
+        );
+        assert_eq!(
+            (0b101 << 10) | (0b100 << 13) | CFGR_SW_PLL,
+            rcc.cfgr.get() & !(CfgrMask::SWS as u32)
+        );
+    }
+    assert_eq!(5, flash.latency());
+    assert_eq!(
+        Ok(Clocks {
+            sysclk: 168_000_000,
+            hclk: 168_000_000,
+            pclk1: 42_000_000,
+            pclk2: 84_000_000,
+        }),
+        rcc.clock_freqs()
+    );
+}
+
+#[test]
+fn test_configure_pll_hsi() {
+    let rcc = mock_rcc(CFGR_SW_PLL << 2, 0);
+    let flash: Flash = unsafe { ::core::mem::zeroed() };
+    unsafe {
+        rcc.cr.set(CrMask::PLLRDY as u32);
+    }
+
+    // 16 MHz / 16 * 192 / 4 = 48 MHz
+    rcc.configure_pll(
+        &PllConfig {
+            source: PllSource::Hsi,
+            pllm: 16,
+            plln: 192,
+            pllp: 4,
+            pllq: 4,
+            apb1_div: 2,
+            apb2_div: 1,
+        },
+        &flash,
+    );
+
+    unsafe {
+        assert_eq!(0x0401_3010, rcc.pllcfgr.get());
+        assert_eq!(0, rcc.cr.get() & CrMask::HSEON as u32);
+    }
+    assert_eq!(1, flash.latency());
+    assert_eq!(48_000_000, rcc.clock_freqs().unwrap().sysclk);
+}
+
+#[cfg(test)]
+fn mock_rcc(cfgr: u32, pllcfgr: u32) -> Rcc {
+    let rcc: Rcc = unsafe { ::core::mem::zeroed() };
+    unsafe {
+        rcc.cfgr.set(cfgr);
+        rcc.pllcfgr.set(pllcfgr);
+    }
+    rcc
+}
+
+#[test]
+fn test_clock_freqs_hsi() {
+    assert_eq!(
+        Ok(Clocks {
+            sysclk: HSI_VALUE,
+            hclk: HSI_VALUE,
+            pclk1: HSI_VALUE,
+            pclk2: HSI_VALUE,
+        }),
+        mock_rcc(0x00, 0).clock_freqs()
+    );
+}
+
+#[test]
+fn test_clock_freqs_hse() {
+    assert_eq!(HSE_VALUE, mock_rcc(0x04, 0).clock_freqs().unwrap().sysclk);
+}
+
+#[test]
+fn test_clock_freqs_pll() {
+    // HSE / 25 * 336 / 2 = 168 MHz, AHB /1, APB1 /4, APB2 /2
+    let pllcfgr = (1 << 22) | 25 | (336 << 6);
+    let cfgr = 0x08 | (0b101 << 10) | (0b100 << 13);
+    assert_eq!(
+        Ok(Clocks {
+            sysclk: 168_000_000,
+            hclk: 168_000_000,
+            pclk1: 42_000_000,
+            pclk2: 84_000_000,
+        }),
+        mock_rcc(cfgr, pllcfgr).clock_freqs()
+    );
+
+    assert_eq!(
+        Err(ClockError::InvalidPllm),
+        mock_rcc(0x08, 336 << 6).clock_freqs()
+    );
+}
+
+#[test]
+fn test_clock_freqs_ahb_prescaler() {
+    // HPRE = 1000 (/2) and 1111 (/512)
+    assert_eq!(
+        HSI_VALUE / 2,
+        mock_rcc(0b1000 << 4, 0).clock_freqs().unwrap().hclk
+    );
+    assert_eq!(
+        HSI_VALUE / 512,
+        mock_rcc(0b1111 << 4, 0).clock_freqs().unwrap().hclk
+    );
+}
+
+#[test]
+fn test_clock_freqs_unknown_source() {
+    assert_eq!(
+        Err(ClockError::UnknownClockSource(0b11)),
+        mock_rcc(0x0C, 0).clock_freqs()
+    );
 }
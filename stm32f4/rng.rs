@@ -99,6 +99,31 @@ pub enum Error {
     ClockError,
 }
 
+/// Number of times `Rng::get_blocking` restarts the generator after
+/// a seed error before giving up.
+const SEED_ERROR_RETRIES: u32 = 3;
+
+/// Polls `get` until it returns a number. Calls `recover` after each
+/// seed error.
+fn get_blocking_with<G, R>(mut get: G, mut recover: R) -> Result<u32, Error>
+where
+    G: FnMut() -> Result<Option<u32>, Error>,
+    R: FnMut(),
+{
+    let mut retries = SEED_ERROR_RETRIES;
+    loop {
+        match get() {
+            Ok(Some(x)) => return Ok(x),
+            Ok(None) => {}
+            Err(Error::SeedError) if retries > 0 => {
+                retries -= 1;
+                recover();
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
 impl Rng {
     pub fn enable(&self) {
         unsafe {
@@ -154,4 +179,94 @@ impl Rng {
     pub unsafe fn get_data_unchecked(&self) -> u32 {
         self.dr.get()
     }
+
+    /// Restarts the generator after a seed error, as the reference
+    /// manual prescribes. The number in DR is discarded.
+    pub fn recover_seed_error(&self) {
+        unsafe {
+            self.sr.clear_flag(SrMask::SEIS as u32);
+        }
+        self.disable();
+        self.enable();
+    }
+
+    /// Waits for a random number.
+    ///
+    /// The generator is restarted on seed errors, so a number with
+    /// not enough entropy is never returned. Fails if seed errors
+    /// persist, or on a clock error (the RNG clock is too slow).
+    pub fn get_blocking(&self) -> Result<u32, Error> {
+        get_blocking_with(|| self.get(), || self.recover_seed_error())
+    }
+
+    /// Fills `buf` with random bytes. See `get_blocking`.
+    ///
+    /// Each number gives 4 bytes, little-endian. The unused bytes of
+    /// the last one are discarded.
+    pub fn fill_bytes(&self, buf: &mut [u8]) -> Result<(), Error> {
+        for chunk in buf.chunks_mut(4) {
+            let bytes = self.get_blocking()?.to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn mock_rng(sr: u32, dr: u32) -> Rng {
+    let mut rng: Rng = unsafe { ::core::mem::zeroed() };
+    unsafe {
+        rng.sr.set(sr);
+        // DR is read-only.
+        *(&mut rng as *mut Rng as *mut u32).add(2) = dr;
+    }
+    rng
+}
+
+#[test]
+fn test_get_blocking() {
+    let rng = mock_rng(SrMask::DRDY as u32, 0x1234_5678);
+    assert_eq!(Ok(0x1234_5678), rng.get_blocking());
+}
+
+#[test]
+fn test_get_blocking_persistent_seed_error() {
+    // SECS never clears, data must not be used.
+    let rng = mock_rng(
+        SrMask::SEIS as u32 | SrMask::SECS as u32 | SrMask::DRDY as u32,
+        0x1234_5678,
+    );
+    assert_eq!(Err(Error::SeedError), rng.get_blocking());
+
+    // The generator is restarted.
+    assert!(!rng.sr_status(SrMask::SEIS));
+    assert_eq!(CrMask::RNDGEN as u32, unsafe { rng.cr.get() });
+}
+
+#[test]
+fn test_get_blocking_clock_error() {
+    let rng = mock_rng(SrMask::CECS as u32, 0);
+    assert_eq!(Err(Error::ClockError), rng.get_blocking());
+}
+
+#[test]
+fn test_get_blocking_retries_seed_error() {
+    let mut results = [Ok(None), Err(Error::SeedError), Ok(None), Ok(Some(5))].iter();
+    let mut recovered = 0;
+
+    let res = get_blocking_with(|| *results.next().unwrap(), || recovered += 1);
+    assert_eq!(Ok(5), res);
+    assert_eq!(1, recovered);
+}
+
+#[test]
+fn test_fill_bytes() {
+    let rng = mock_rng(SrMask::DRDY as u32, 0x0403_0201);
+
+    let mut buf = [0; 6];
+    assert_eq!(Ok(()), rng.fill_bytes(&mut buf));
+    assert_eq!([1, 2, 3, 4, 1, 2], buf);
+
+    let rng = mock_rng(SrMask::CECS as u32, 0);
+    assert_eq!(Err(Error::ClockError), rng.fill_bytes(&mut buf));
 }
@@ -0,0 +1,253 @@
+//! Serial peripheral interface.
+//!
+//! Only full-duplex 8-bit transfers are supported. I2S mode is not.
+
+// allow `<< 0`
+#![allow(clippy::identity_op)]
+
+use crate::volatile::RW;
+
+extern "C" {
+    pub static SPI1: Spi;
+    pub static SPI2: Spi;
+    pub static SPI3: Spi;
+}
+
+/// Don't forget to enable the SPI clock before use, and to switch the
+/// SCK, MISO, and MOSI pins to the alternate function (AF5 for SPI1
+/// and SPI2, AF6 for SPI3).
+///
+/// ```no_run
+/// # use stm32f4::rcc;
+/// unsafe {
+///   rcc::RCC.apb2_clock_enable(rcc::Apb2Enable::SPI1);
+/// }
+/// ```
+#[repr(C)]
+#[allow(missing_debug_implementations)]
+pub struct Spi {
+    cr1: RW<u32>,     // 0x00
+    cr2: RW<u32>,     // 0x04
+    sr: RW<u32>,      // 0x08
+    dr: RW<u32>,      // 0x0C
+    crcpr: RW<u32>,   // 0x10
+    rxcrcr: RW<u32>,  // 0x14
+    txcrcr: RW<u32>,  // 0x18
+    i2scfgr: RW<u32>, // 0x1C
+    i2spr: RW<u32>,   // 0x20
+}
+
+#[test]
+fn test_register_size() {
+    assert_eq!(0x24, ::core::mem::size_of::<Spi>());
+}
+
+#[allow(dead_code)]
+#[repr(u32)]
+enum Cr1 {
+    CPHA = 1 << 0,
+    CPOL = 1 << 1,
+    MSTR = 1 << 2,
+    BR = 0x7 << 3,
+    /// SPI enable
+    SPE = 1 << 6,
+    LSBFIRST = 1 << 7,
+    SSI = 1 << 8,
+    SSM = 1 << 9,
+    RXONLY = 1 << 10,
+    DFF = 1 << 11,
+    CRCNEXT = 1 << 12,
+    CRCEN = 1 << 13,
+    BIDIOE = 1 << 14,
+    BIDIMODE = 1 << 15,
+}
+
+#[allow(dead_code)]
+#[repr(u32)]
+enum Sr {
+    RXNE = 1 << 0,
+    TXE = 1 << 1,
+    CHSIDE = 1 << 2,
+    UDR = 1 << 3,
+    CRCERR = 1 << 4,
+    MODF = 1 << 5,
+    OVR = 1 << 6,
+    BSY = 1 << 7,
+    FRE = 1 << 8,
+}
+
+/// CR1 bits set by `enable`.
+const CR1_CONFIG: u32 = Cr1::CPHA as u32
+    | Cr1::CPOL as u32
+    | Cr1::MSTR as u32
+    | Cr1::BR as u32
+    | Cr1::LSBFIRST as u32
+    | Cr1::SSI as u32
+    | Cr1::SSM as u32
+    | Cr1::RXONLY as u32
+    | Cr1::DFF as u32
+    | Cr1::CRCEN as u32
+    | Cr1::BIDIMODE as u32;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Mode {
+    /// NSS is managed by software, so the NSS pin is free. Slaves
+    /// must be selected with GPIO.
+    Master,
+    /// The NSS pin selects the slave.
+    Slave,
+}
+
+/// Clock polarity: SCK level when idle.
+#[derive(Copy, Clone, Debug)]
+#[repr(u32)]
+pub enum Cpol {
+    IdleLow = 0,
+    IdleHigh = Cr1::CPOL as u32,
+}
+
+/// Clock phase: the SCK edge data is captured on.
+#[derive(Copy, Clone, Debug)]
+#[repr(u32)]
+pub enum Cpha {
+    FirstEdge = 0,
+    SecondEdge = Cr1::CPHA as u32,
+}
+
+/// SCK frequency as a fraction of the bus clock (PCLK2 for SPI1,
+/// PCLK1 for SPI2 and SPI3).
+#[derive(Copy, Clone, Debug)]
+#[repr(u32)]
+pub enum BaudRatePrescaler {
+    Div2 = 0x0 << 3,
+    Div4 = 0x1 << 3,
+    Div8 = 0x2 << 3,
+    Div16 = 0x3 << 3,
+    Div32 = 0x4 << 3,
+    Div64 = 0x5 << 3,
+    Div128 = 0x6 << 3,
+    Div256 = 0x7 << 3,
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(u32)]
+pub enum BitOrder {
+    MsbFirst = 0,
+    LsbFirst = Cr1::LSBFIRST as u32,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct SpiConfig {
+    pub mode: Mode,
+    pub cpol: Cpol,
+    pub cpha: Cpha,
+    /// Only used in master mode.
+    pub baud_rate: BaudRatePrescaler,
+    pub bit_order: BitOrder,
+}
+
+/// Returns the CR1 value for `config`, SPE excluded.
+fn cr1_bits(config: &SpiConfig) -> u32 {
+    let mode = match config.mode {
+        // SSI keeps the internal NSS high, or the master detects a
+        // mode fault.
+        Mode::Master => Cr1::MSTR as u32 | Cr1::SSM as u32 | Cr1::SSI as u32,
+        Mode::Slave => 0,
+    };
+
+    mode | config.cpol as u32
+        | config.cpha as u32
+        | config.baud_rate as u32
+        | config.bit_order as u32
+}
+
+impl Spi {
+    /// Configures and enables the SPI.
+    pub fn enable(&self, config: &SpiConfig) {
+        unsafe {
+            // The configuration can't be changed while enabled.
+            self.cr1.clear_flag(Cr1::SPE as u32);
+            self.cr1.update_with_mask(CR1_CONFIG, cr1_bits(config));
+            self.cr1.set_flag(Cr1::SPE as u32);
+        }
+    }
+
+    /// Disables the SPI after the current transfer completes.
+    pub fn disable(&self) {
+        unsafe {
+            while self.sr.get() & Sr::TXE as u32 == 0 {}
+            while self.sr.get() & Sr::BSY as u32 != 0 {}
+
+            self.cr1.clear_flag(Cr1::SPE as u32);
+        }
+    }
+
+    /// Sends `byte` and returns the byte received meanwhile. Blocks
+    /// until the transfer completes.
+    ///
+    /// In slave mode, waits until the master clocks the byte out.
+    #[allow(clippy::cast_possible_truncation)] // data frame is 8-bit
+    pub fn transfer(&self, byte: u8) -> u8 {
+        unsafe {
+            while self.sr.get() & Sr::TXE as u32 == 0 {}
+            self.dr.set(u32::from(byte));
+
+            while self.sr.get() & Sr::RXNE as u32 == 0 {}
+            self.dr.get() as u8
+        }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        unsafe { self.sr.get() & Sr::BSY as u32 != 0 }
+    }
+}
+
+#[cfg(test)]
+fn mock_spi() -> Spi {
+    unsafe { ::core::mem::zeroed() }
+}
+
+#[test]
+fn test_master_config_bits() {
+    let spi = mock_spi();
+
+    spi.enable(&SpiConfig {
+        mode: Mode::Master,
+        cpol: Cpol::IdleHigh,
+        cpha: Cpha::SecondEdge,
+        baud_rate: BaudRatePrescaler::Div32,
+        bit_order: BitOrder::MsbFirst,
+    });
+
+    // SSM, SSI, SPE, BR = 100, MSTR, CPOL, CPHA
+    assert_eq!(0x0367, unsafe { spi.cr1.get() });
+}
+
+#[test]
+fn test_slave_config_bits() {
+    let spi = mock_spi();
+    unsafe { spi.cr1.set(0x0367) };
+
+    spi.enable(&SpiConfig {
+        mode: Mode::Slave,
+        cpol: Cpol::IdleLow,
+        cpha: Cpha::FirstEdge,
+        baud_rate: BaudRatePrescaler::Div2,
+        bit_order: BitOrder::LsbFirst,
+    });
+
+    // Previous configuration is cleared.
+    assert_eq!(Cr1::LSBFIRST as u32 | Cr1::SPE as u32, unsafe {
+        spi.cr1.get()
+    });
+}
+
+#[test]
+fn test_transfer() {
+    let spi = mock_spi();
+    unsafe { spi.sr.set(Sr::TXE as u32 | Sr::RXNE as u32) };
+
+    // DR of the mock reads back what was written.
+    assert_eq!(0xa5, spi.transfer(0xa5));
+    assert_eq!(0xa5, unsafe { spi.dr.get() });
+}
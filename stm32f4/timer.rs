@@ -3,6 +3,7 @@
 // allow `<< 0`
 #![allow(clippy::identity_op)]
 
+use crate::rcc::Clocks;
 use crate::volatile::{RES, RW};
 
 extern "C" {
@@ -87,6 +88,18 @@ enum Egr {
     TG = 1 << 6,
 }
 
+/// Output compare preload enable of channel 1 in CCMR1. Each channel
+/// takes 8 bits.
+const CCMR_OC1PE: u32 = 1 << 3;
+
+/// PWM mode 1 of channel 1 in CCMR1 (OC1M): the output is active
+/// while the counter is below the compare value.
+const CCMR_OC1M_PWM1: u32 = 6 << 4;
+
+/// Capture/compare output enable of channel 1 in CCER. Each channel
+/// takes 4 bits.
+const CCER_CC1E: u32 = 1 << 0;
+
 #[derive(Debug)]
 pub struct TimInit {
     pub prescaler: u16,
@@ -114,6 +127,51 @@ pub enum ClockDivision {
     Div3 = 0x0200,
 }
 
+/// Returns the clock frequency of TIM2..TIM5.
+///
+/// Timers on APB1 run at twice PCLK1 unless APB1 is not prescaled.
+fn timer_clock(clocks: &Clocks) -> u32 {
+    if clocks.pclk1 == clocks.hclk {
+        clocks.pclk1
+    } else {
+        clocks.pclk1 * 2
+    }
+}
+
+impl TimInit {
+    /// Returns an up-counting configuration that updates the timer
+    /// closest to `hz` times per second, and the achieved frequency.
+    ///
+    /// The prescaler is as small as possible, so the period has the
+    /// finest resolution. The period fits 16 bits, so the
+    /// configuration works for TIM3 and TIM4 too.
+    #[allow(clippy::cast_possible_truncation)] // checked by min
+    pub fn from_frequency(hz: u32, clocks: &Clocks) -> (TimInit, u32) {
+        debug_assert!(hz > 0);
+
+        let clock = u64::from(timer_clock(clocks));
+        let hz = u64::from(hz);
+
+        let counts = (clock + hz / 2) / hz;
+        let prescaler = ((counts + 0xffff) / 0x1_0000).max(1).min(0x1_0000);
+        let period = ((clock + prescaler * hz / 2) / (prescaler * hz))
+            .max(2)
+            .min(0x1_0000);
+        let achieved = (clock + prescaler * period / 2) / (prescaler * period);
+
+        (
+            TimInit {
+                prescaler: (prescaler - 1) as u16,
+                counter_mode: CounterMode::Up,
+                period: (period - 1) as u32,
+                clock_division: ClockDivision::Div1,
+                repetition_counter: 0,
+            },
+            achieved as u32,
+        )
+    }
+}
+
 impl Tim {
     pub fn init(&self, tim: &TimInit) {
         unsafe {
@@ -144,6 +202,38 @@ impl Tim {
         unsafe { self.cnt.get() }
     }
 
+    /// Generates PWM on `channel` (1 to 4). The period is the
+    /// auto-reload value (`TimInit::period`), and the output is active
+    /// for the first `duty` counts of it.
+    ///
+    /// The compare value is preloaded, so changing the duty of a
+    /// running timer takes effect on the next period. The channel pin
+    /// must be switched to the timer alternate function. E.g.,
+    /// STM32F4DISCOVERY LEDs on PD12..PD15 are TIM4 channels 1..4
+    /// (AF2).
+    pub fn config_pwm(&self, channel: u8, duty: u16) {
+        let (ccmr, ccr) = match channel {
+            1 => (&self.ccmr1, &self.ccr1),
+            2 => (&self.ccmr1, &self.ccr2),
+            3 => (&self.ccmr2, &self.ccr3),
+            4 => (&self.ccmr2, &self.ccr4),
+            _ => panic!("timer has no channel {}", channel),
+        };
+        // Channels 2 and 4 are in the high half of CCMRx.
+        let ccmr_offset = if channel % 2 == 0 { 8 } else { 0 };
+        let ccer_offset = u32::from(channel - 1) * 4;
+
+        unsafe {
+            ccr.set(u32::from(duty));
+            // Clearing the whole byte selects output mode (CCxS = 0).
+            ccmr.update_with_mask(
+                0xff << ccmr_offset,
+                (CCMR_OC1M_PWM1 | CCMR_OC1PE) << ccmr_offset,
+            );
+            self.ccer.set_flag(CCER_CC1E << ccer_offset);
+        }
+    }
+
     pub fn it_enable(&self, it: Dier) {
         unsafe {
             self.dier.set_flag(it as u32);
@@ -170,3 +260,98 @@ impl Tim {
         }
     }
 }
+
+#[test]
+fn test_from_frequency() {
+    let clocks = Clocks {
+        sysclk: 168_000_000,
+        hclk: 168_000_000,
+        pclk1: 42_000_000,
+        pclk2: 84_000_000,
+    };
+    assert_eq!(84_000_000, timer_clock(&clocks));
+
+    for &hz in &[1, 7, 100, 1000, 44_100, 1_000_000] {
+        let (init, achieved) = TimInit::from_frequency(hz, &clocks);
+        let counts = (u64::from(init.prescaler) + 1) * (u64::from(init.period) + 1);
+
+        assert_eq!(u64::from(achieved), (84_000_000 + counts / 2) / counts);
+        // Within 0.1%
+        assert!(achieved.max(hz) - achieved.min(hz) <= hz / 1000);
+        assert!(init.period <= 0xffff);
+    }
+
+    // The period gets all the resolution it can.
+    let (init, _) = TimInit::from_frequency(100, &clocks);
+    assert_eq!(12, init.prescaler);
+    assert_eq!(64_614, init.period);
+    let (init, _) = TimInit::from_frequency(10_000, &clocks);
+    assert_eq!(0, init.prescaler);
+    assert_eq!(8_399, init.period);
+}
+
+#[test]
+fn test_from_frequency_out_of_range() {
+    // HSI, no prescalers
+    let clocks = Clocks {
+        sysclk: 16_000_000,
+        hclk: 16_000_000,
+        pclk1: 16_000_000,
+        pclk2: 16_000_000,
+    };
+
+    let (init, achieved) = TimInit::from_frequency(3_000_000, &clocks);
+    assert_eq!((0, 4), (init.prescaler, init.period));
+    assert_eq!(3_200_000, achieved);
+
+    // The period is at least 2 counts.
+    let (init, achieved) = TimInit::from_frequency(16_000_000, &clocks);
+    assert_eq!((0, 1), (init.prescaler, init.period));
+    assert_eq!(8_000_000, achieved);
+}
+
+#[cfg(test)]
+fn mock_tim() -> Tim {
+    unsafe { ::core::mem::zeroed() }
+}
+
+#[test]
+fn test_config_pwm() {
+    let tim = mock_tim();
+
+    tim.config_pwm(1, 100);
+    tim.config_pwm(4, 400);
+    unsafe {
+        assert_eq!(0x0068, tim.ccmr1.get());
+        assert_eq!(0x6800, tim.ccmr2.get());
+        assert_eq!(0x1001, tim.ccer.get());
+        assert_eq!(100, tim.ccr1.get());
+        assert_eq!(400, tim.ccr4.get());
+    }
+
+    tim.config_pwm(2, 200);
+    tim.config_pwm(3, 300);
+    unsafe {
+        assert_eq!(0x6868, tim.ccmr1.get());
+        assert_eq!(0x6868, tim.ccmr2.get());
+        assert_eq!(0x1111, tim.ccer.get());
+        assert_eq!(200, tim.ccr2.get());
+        assert_eq!(300, tim.ccr3.get());
+    }
+}
+
+#[test]
+fn test_config_pwm_switches_to_output() {
+    let tim = mock_tim();
+    // Channel 2 in input capture mode with a filter.
+    unsafe { tim.ccmr1.set(0xf100) };
+
+    tim.config_pwm(2, 0);
+    assert_eq!(0x6800, unsafe { tim.ccmr1.get() });
+}
+
+#[test]
+#[should_panic]
+fn test_config_pwm_invalid_channel() {
+    mock_tim().config_pwm(5, 0);
+}
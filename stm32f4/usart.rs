@@ -7,12 +7,145 @@
 
 use core::fmt;
 
+use crate::nvic::IrqChannel;
+use crate::rcc::{Apb1Enable, Apb2Enable, Clocks, Rcc};
 use crate::volatile::RW;
 
 extern "C" {
     pub static USART1: Usart;
     pub static USART2: Usart;
     pub static USART3: Usart;
+    /// UART4 and UART5 are basic UARTs: they have no synchronous
+    /// mode, smartcard mode, or hardware flow control.
+    pub static UART4: Usart;
+    pub static UART5: Usart;
+    pub static USART6: Usart;
+}
+
+/// Identifies a USART peripheral.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UsartId {
+    USART1,
+    USART2,
+    USART3,
+    UART4,
+    UART5,
+    USART6,
+}
+
+/// The bus a USART is clocked from.
+#[derive(Copy, Clone, Debug)]
+pub enum UsartClock {
+    Apb1(Apb1Enable),
+    Apb2(Apb2Enable),
+}
+
+impl UsartId {
+    /// Returns the register block address. Must match
+    /// `peripherals.ld`.
+    pub fn base_address(self) -> usize {
+        match self {
+            UsartId::USART1 => 0x4001_1000,
+            UsartId::USART2 => 0x4000_4400,
+            UsartId::USART3 => 0x4000_4800,
+            UsartId::UART4 => 0x4000_4C00,
+            UsartId::UART5 => 0x4000_5000,
+            UsartId::USART6 => 0x4001_1400,
+        }
+    }
+
+    pub fn usart(self) -> &'static Usart {
+        unsafe {
+            match self {
+                UsartId::USART1 => &USART1,
+                UsartId::USART2 => &USART2,
+                UsartId::USART3 => &USART3,
+                UsartId::UART4 => &UART4,
+                UsartId::UART5 => &UART5,
+                UsartId::USART6 => &USART6,
+            }
+        }
+    }
+
+    pub fn clock(self) -> UsartClock {
+        match self {
+            UsartId::USART1 => UsartClock::Apb2(Apb2Enable::USART1),
+            UsartId::USART2 => UsartClock::Apb1(Apb1Enable::USART2),
+            UsartId::USART3 => UsartClock::Apb1(Apb1Enable::USART3),
+            UsartId::UART4 => UsartClock::Apb1(Apb1Enable::USART4),
+            UsartId::UART5 => UsartClock::Apb1(Apb1Enable::USART5),
+            UsartId::USART6 => UsartClock::Apb2(Apb2Enable::USART6),
+        }
+    }
+
+    pub fn irq_channel(self) -> IrqChannel {
+        match self {
+            UsartId::USART1 => IrqChannel::USART1,
+            UsartId::USART2 => IrqChannel::USART2,
+            UsartId::USART3 => IrqChannel::USART3,
+            UsartId::UART4 => IrqChannel::UART4,
+            UsartId::UART5 => IrqChannel::UART5,
+            UsartId::USART6 => IrqChannel::USART6,
+        }
+    }
+
+    /// Returns true for basic UARTs (UART4 and UART5).
+    pub fn is_basic(self) -> bool {
+        match self {
+            UsartId::UART4 | UsartId::UART5 => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the frequency of the bus the USART is clocked from.
+    pub fn pclk(self, clocks: &Clocks) -> u32 {
+        match self.clock() {
+            UsartClock::Apb1(_) => clocks.pclk1,
+            UsartClock::Apb2(_) => clocks.pclk2,
+        }
+    }
+
+    /// Changes the baud rate for the current clock configuration. See
+    /// `Usart::set_baud_rate`.
+    pub fn set_baud_rate(self, rcc: &Rcc, baud_rate: u32) {
+        let clocks = rcc.clock_freqs().expect("invalid clock configuration");
+        self.usart().set_baud_rate(baud_rate, self.pclk(&clocks));
+    }
+
+    pub fn enable_clock(self, rcc: &Rcc) {
+        match self.clock() {
+            UsartClock::Apb1(x) => rcc.apb1_clock_enable(x),
+            UsartClock::Apb2(x) => rcc.apb2_clock_enable(x),
+        }
+    }
+
+    /// Pulses the RCC reset line. Can be passed to `Usart::deinit`.
+    pub fn reset(self, rcc: &Rcc) {
+        match self.clock() {
+            UsartClock::Apb1(x) => rcc.apb1_reset(x),
+            UsartClock::Apb2(x) => rcc.apb2_reset(x),
+        }
+    }
+
+    /// Enables the clock, configures the peripheral, and enables the
+    /// RXNE interrupt in both the peripheral and NVIC.
+    ///
+    /// Pins must be switched to the alternate function separately.
+    pub fn init(self, rcc: &Rcc, config: &UsartConfig, priority: u8, subpriority: u8) {
+        self.enable_clock(rcc);
+
+        let clocks = rcc.clock_freqs().expect("invalid clock configuration");
+        let usart = self.usart();
+        usart.enable(config, self.pclk(&clocks));
+        usart.it_enable(Interrupt::RXNE);
+
+        crate::nvic::init(&crate::nvic::NvicInit {
+            irq_channel: self.irq_channel(),
+            priority,
+            subpriority,
+            enable: true,
+        });
+    }
 }
 
 #[repr(C)]
@@ -47,6 +180,13 @@ enum Sr {
     CTS = 1 << 9,
 }
 
+/// SR bits that are cleared by writing 0.
+const SR_RC_W0: u32 = Sr::CTS as u32 | Sr::LBD as u32 | Sr::TC as u32 | Sr::RXNE as u32;
+
+/// SR bits that are cleared by reading SR followed by reading DR.
+const SR_READ_CLEARED: u32 =
+    Sr::IDLE as u32 | Sr::ORE as u32 | Sr::NF as u32 | Sr::FE as u32 | Sr::PE as u32;
+
 #[allow(dead_code)]
 #[repr(u32)]
 enum Brr {
@@ -122,9 +262,34 @@ enum Gtpr {
     GT = 0xFF00,
 }
 
+/// Hardware flow control.
+///
+/// The RTS and CTS pins must be switched to the alternate function by
+/// the caller, as TX and RX are. For USART3, CTS is PB13 or PD11 and
+/// RTS is PB14 or PD12 (AF7). Note that PD12 drives LD4 on the
+/// STM32F4DISCOVERY board.
+///
+/// Basic UARTs (UART4 and UART5) have no flow control.
 #[derive(Copy, Clone, Debug)]
+#[repr(u32)]
 pub enum FlowControl {
-    No,
+    No = 0,
+    /// RTS is asserted while the receiver can accept data.
+    Rts = Cr3::RTSE as u32,
+    /// Transmission waits until CTS is asserted.
+    Cts = Cr3::CTSE as u32,
+    RtsCts = Cr3::RTSE as u32 | Cr3::CTSE as u32,
+}
+
+/// Computes BRR for 16x oversampling (OVER8 is not used).
+///
+/// BRR holds USARTDIV = pclk / (16 * baud_rate) as a 12-bit mantissa
+/// and a 4-bit fraction, which is pclk / baud_rate rounded to the
+/// nearest integer.
+fn brr(baud_rate: u32, pclk: u32) -> u32 {
+    let div = (pclk + baud_rate / 2) / baud_rate;
+    debug_assert!((16..=0xffff).contains(&div), "baud rate out of range");
+    div
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -134,14 +299,39 @@ pub enum DataBits {
     Bits9 = Cr1::M as u32,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct UsartConfig {
     pub data_bits: DataBits,
     pub stop_bits: StopBits,
     pub flow_control: FlowControl,
+    pub parity: Parity,
     pub baud_rate: u32,
 }
 
+/// Returns the CR1 bits for the frame format.
+///
+/// The parity bit is sent in place of the most significant bit of
+/// the word, so 8 data bits with parity need a 9-bit word.
+fn frame_bits(data_bits: DataBits, parity: Parity) -> u32 {
+    let parity_bits = match parity {
+        Parity::None => return data_bits as u32,
+        Parity::Even => Cr1::PCE as u32,
+        Parity::Odd => Cr1::PCE as u32 | Cr1::PS as u32,
+    };
+
+    match data_bits {
+        DataBits::Bits8 => Cr1::M as u32 | parity_bits,
+        DataBits::Bits9 => panic!("Usart: 9 data bits with parity don't fit a frame"),
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 #[repr(u32)]
 pub enum Interrupt {
@@ -175,27 +365,60 @@ pub enum InterruptFlag {
 }
 
 impl Usart {
-    /// Enables USART with given config.
+    /// Enables USART with given config. `pclk` is the frequency of the
+    /// bus the USART is clocked from (see `UsartId::pclk`).
     /// # Known bugs
-    /// - No hardware flow control is supported.
-    /// - Only works with default sysclk.
     /// - Generally, this driver is a piece of crap.
-    pub fn enable(&self, config: &UsartConfig) {
+    pub fn enable(&self, config: &UsartConfig, pclk: u32) {
         unsafe {
             self.cr2
                 .update_with_mask(Cr2::STOP as u32, config.stop_bits as u32);
             self.cr1.update_with_mask(
-                Cr1::M as u32 | Cr1::PCE as u32 | Cr1::TE as u32 | Cr1::RE as u32,
-                config.data_bits as u32 | Cr1::TE as u32 | Cr1::RE as u32,
+                Cr1::M as u32 | Cr1::PCE as u32 | Cr1::PS as u32 | Cr1::TE as u32 | Cr1::RE as u32,
+                frame_bits(config.data_bits, config.parity) | Cr1::TE as u32 | Cr1::RE as u32,
             );
-            self.cr3.clear_flag(0x3FF); // No Hardware Flow-Control
-            self.brr.set(0x00F4_2400 / config.baud_rate); // Default SysClk Rate / Baud Rate
+            self.cr3.update_with_mask(0x3FF, config.flow_control as u32);
+            self.set_baud_rate(config.baud_rate, pclk);
 
             // finally this enables the complete USART peripheral
             self.cr1.set_flag(Cr1::UE as u32);
         }
     }
 
+    /// Changes the baud rate for the USART clocked at `pclk` Hz (see
+    /// `UsartId::pclk`).
+    ///
+    /// The USART should be idle: a frame that is being transmitted or
+    /// received meanwhile is garbled.
+    pub fn set_baud_rate(&self, baud_rate: u32, pclk: u32) {
+        unsafe {
+            self.brr.set(brr(baud_rate, pclk));
+        }
+    }
+
+    /// Disables USART after the current transmission completes.
+    ///
+    /// Configuration is preserved, so the USART can be re-enabled
+    /// with `enable`.
+    pub fn disable(&self) {
+        unsafe {
+            // Wait for the last frame to leave the shift register.
+            while self.sr.get() & Sr::TC as u32 == 0 {}
+
+            self.cr1.clear_flag(Cr1::UE as u32);
+        }
+    }
+
+    /// Disables USART and resets all its registers.
+    ///
+    /// `reset` should pulse the USART reset line in RCC, e.g.,
+    /// `|| RCC.apb1_reset(Apb1Enable::USART2)`. The clock is left
+    /// enabled.
+    pub fn deinit<F: FnOnce()>(&self, reset: F) {
+        self.disable();
+        reset();
+    }
+
     pub fn puts_synchronous(&self, s: &str) {
         for c in s.bytes() {
             self.put_char(u32::from(c));
@@ -237,6 +460,25 @@ impl Usart {
         self.dr.set(u32::from(c));
     }
 
+    /// Address of the data register, for DMA transfers.
+    pub fn dr_address(&self) -> *const u32 {
+        &self.dr as *const RW<u32> as *const u32
+    }
+
+    /// Enables or disables DMA requests for transmission.
+    ///
+    /// `enable` clears the setting, so it should be set again after
+    /// re-enabling the USART.
+    pub fn set_dma_transmit(&self, enable: bool) {
+        unsafe {
+            if enable {
+                self.cr3.set_flag(Cr3::DMAT as u32);
+            } else {
+                self.cr3.clear_flag(Cr3::DMAT as u32);
+            }
+        }
+    }
+
     pub fn it_enable(&self, it: Interrupt) {
         self.it_set(it, true);
     }
@@ -269,10 +511,15 @@ impl Usart {
         unsafe { self.sr.get() & it as u32 != 0 }
     }
 
+    /// Clears the flag the way the reference manual prescribes.
+    ///
+    /// - CTS, LBD, TC and RXNE are cleared by writing 0.
+    /// - IDLE, ORE, NE, FE and PE are cleared by reading SR followed
+    ///   by reading DR, so any received data is discarded.
+    /// - TXE is only cleared by writing new data to DR, so it is left
+    ///   as is.
     pub fn it_clear_flag(&self, it: InterruptFlag) {
-        unsafe {
-            self.sr.set(u32::from(!(it as u16)));
-        }
+        self.clear_sr(it as u32);
     }
 
     pub fn it_enabled(&self, it: Interrupt) -> bool {
@@ -313,15 +560,284 @@ impl Usart {
         }
     }
 
+    /// Clears the pending bit of `it`. See `it_clear_flag()` for how
+    /// the different bits are cleared.
     pub fn it_clear_pending(&self, it: Interrupt) {
+        let bitpos = it as u32 >> 8;
+        self.clear_sr(1 << bitpos);
+    }
+
+    /// Clears SR bits in `mask`, leaving other flags untouched.
+    fn clear_sr(&self, mask: u32) {
         unsafe {
-            let bitpos = it as u32 >> 8;
-            let itmask = 1_u16 << bitpos;
-            self.sr.set(u32::from(!itmask));
+            if mask & SR_RC_W0 != 0 {
+                // Writing 1 to rc_w0 bits has no effect, and reserved
+                // bits must be kept at reset value (0).
+                self.sr.set(SR_RC_W0 & !mask);
+            }
+
+            if mask & SR_READ_CLEARED != 0 {
+                self.sr.get();
+                self.dr.get();
+            }
         }
     }
 }
 
+#[test]
+fn test_usart_id_base_address() {
+    let expected = [
+        (UsartId::USART1, 0x4001_1000),
+        (UsartId::USART2, 0x4000_4400),
+        (UsartId::USART3, 0x4000_4800),
+        (UsartId::UART4, 0x4000_4C00),
+        (UsartId::UART5, 0x4000_5000),
+        (UsartId::USART6, 0x4001_1400),
+    ];
+    for &(id, address) in &expected {
+        assert_eq!(address, id.base_address(), "{:?}", id);
+    }
+}
+
+#[test]
+fn test_usart_id_clock() {
+    let expected = [
+        (UsartId::USART1, None, Some(1 << 4)),
+        (UsartId::USART2, Some(1 << 17), None),
+        (UsartId::USART3, Some(1 << 18), None),
+        (UsartId::UART4, Some(1 << 19), None),
+        (UsartId::UART5, Some(1 << 20), None),
+        (UsartId::USART6, None, Some(1 << 5)),
+    ];
+    for &(id, apb1, apb2) in &expected {
+        let (actual_apb1, actual_apb2) = match id.clock() {
+            UsartClock::Apb1(x) => (Some(x as u32), None),
+            UsartClock::Apb2(x) => (None, Some(x as u32)),
+        };
+        assert_eq!((apb1, apb2), (actual_apb1, actual_apb2), "{:?}", id);
+    }
+
+    assert!(UsartId::UART4.is_basic());
+    assert!(!UsartId::USART6.is_basic());
+    assert_eq!(71, UsartId::USART6.irq_channel() as u32);
+}
+
+#[cfg(test)]
+fn mock_usart() -> Usart {
+    unsafe { ::core::mem::zeroed() }
+}
+
+#[test]
+fn test_brr() {
+    // Examples from the reference manual, as mantissa and fraction.
+    assert_eq!(52 << 4 | 1, brr(9600, 8_000_000));
+    assert_eq!(4 << 4 | 5, brr(115_200, 8_000_000));
+
+    // PCLK1 and PCLK2 at 168 MHz SYSCLK
+    assert_eq!(273 << 4 | 7, brr(9600, 42_000_000));
+    assert_eq!(22 << 4 | 13, brr(115_200, 42_000_000));
+    assert_eq!(546 << 4 | 14, brr(9600, 84_000_000));
+    assert_eq!(45 << 4 | 9, brr(115_200, 84_000_000));
+    assert_eq!(11 << 4 | 6, brr(460_800, 84_000_000));
+}
+
+#[test]
+fn test_set_baud_rate() {
+    let usart = mock_usart();
+
+    usart.set_baud_rate(115_200, 42_000_000);
+    assert_eq!(0x16d, unsafe { usart.brr.get() });
+
+    // 16 MHz / 115200 = 138.9
+    usart.set_baud_rate(115_200, 16_000_000);
+    assert_eq!(139, unsafe { usart.brr.get() });
+
+    let clocks = Clocks {
+        sysclk: 168_000_000,
+        hclk: 168_000_000,
+        pclk1: 42_000_000,
+        pclk2: 84_000_000,
+    };
+    assert_eq!(42_000_000, UsartId::USART2.pclk(&clocks));
+    assert_eq!(84_000_000, UsartId::USART6.pclk(&clocks));
+}
+
+#[test]
+fn test_parity_frame_bits() {
+    let usart = mock_usart();
+    let frame = |data_bits, parity| {
+        usart.enable(
+            &UsartConfig {
+                data_bits,
+                stop_bits: StopBits::Bits1,
+                flow_control: FlowControl::No,
+                parity,
+                baud_rate: 115_200,
+            },
+            16_000_000,
+        );
+        let cr1 = unsafe { usart.cr1.get() };
+        cr1 & (Cr1::M as u32 | Cr1::PCE as u32 | Cr1::PS as u32)
+    };
+
+    assert_eq!(0, frame(DataBits::Bits8, Parity::None));
+    assert_eq!(Cr1::M as u32, frame(DataBits::Bits9, Parity::None));
+    assert_eq!(
+        Cr1::M as u32 | Cr1::PCE as u32,
+        frame(DataBits::Bits8, Parity::Even)
+    );
+    assert_eq!(
+        Cr1::M as u32 | Cr1::PCE as u32 | Cr1::PS as u32,
+        frame(DataBits::Bits8, Parity::Odd)
+    );
+    // Switching back clears the parity bits.
+    assert_eq!(0, frame(DataBits::Bits8, Parity::None));
+}
+
+#[test]
+#[should_panic]
+fn test_parity_with_9_data_bits() {
+    frame_bits(DataBits::Bits9, Parity::Even);
+}
+
+#[test]
+fn test_flow_control_bits() {
+    let usart = mock_usart();
+    let flow_control_bits = |flow_control| {
+        usart.enable(
+            &UsartConfig {
+                data_bits: DataBits::Bits8,
+                stop_bits: StopBits::Bits1,
+                flow_control,
+                parity: Parity::None,
+                baud_rate: 115_200,
+            },
+            16_000_000,
+        );
+        let cr3 = unsafe { usart.cr3.get() };
+        cr3 & (Cr3::RTSE as u32 | Cr3::CTSE as u32)
+    };
+
+    assert_eq!(Cr3::RTSE as u32, flow_control_bits(FlowControl::Rts));
+    assert_eq!(Cr3::CTSE as u32, flow_control_bits(FlowControl::Cts));
+    assert_eq!(
+        Cr3::RTSE as u32 | Cr3::CTSE as u32,
+        flow_control_bits(FlowControl::RtsCts)
+    );
+    assert_eq!(0, flow_control_bits(FlowControl::No));
+}
+
+#[test]
+fn test_dma_transmit() {
+    let usart = mock_usart();
+    assert_eq!(
+        &usart as *const Usart as usize + 0x04,
+        usart.dr_address() as usize
+    );
+
+    unsafe { usart.cr3.set(Cr3::CTSE as u32) };
+    usart.set_dma_transmit(true);
+    assert_eq!(Cr3::CTSE as u32 | Cr3::DMAT as u32, unsafe {
+        usart.cr3.get()
+    });
+    usart.set_dma_transmit(false);
+    assert_eq!(Cr3::CTSE as u32, unsafe { usart.cr3.get() });
+}
+
+#[test]
+fn test_disable_clears_ue() {
+    let usart = mock_usart();
+    unsafe {
+        usart
+            .cr1
+            .set(Cr1::UE as u32 | Cr1::TE as u32 | Cr1::RE as u32);
+        usart.sr.set(Sr::TC as u32);
+    }
+
+    usart.disable();
+
+    assert_eq!(Cr1::TE as u32 | Cr1::RE as u32, unsafe { usart.cr1.get() });
+}
+
+#[test]
+fn test_deinit_resets_after_disable() {
+    let usart = mock_usart();
+    unsafe {
+        usart.cr1.set(Cr1::UE as u32);
+        usart.sr.set(Sr::TC as u32);
+    }
+
+    let mut resets = 0;
+    usart.deinit(|| {
+        assert_eq!(0, unsafe { usart.cr1.get() } & Cr1::UE as u32);
+        resets += 1;
+    });
+
+    assert_eq!(1, resets);
+}
+
+/// Applies a write to the mock SR the way hardware does: rc_w0 bits
+/// written with 0 are cleared, other bits are left intact.
+#[cfg(test)]
+fn write_sr(old: u32, written: u32) -> u32 {
+    old & (written | !SR_RC_W0)
+}
+
+#[test]
+fn test_clear_flag_only_clears_target() {
+    let usart = mock_usart();
+    let all = 0x3FF;
+
+    for &(flag, bit) in &[
+        (InterruptFlag::CTS, Sr::CTS as u32),
+        (InterruptFlag::LBD, Sr::LBD as u32),
+        (InterruptFlag::TC, Sr::TC as u32),
+        (InterruptFlag::RXNE, Sr::RXNE as u32),
+    ] {
+        unsafe { usart.sr.set(all) };
+        usart.it_clear_flag(flag);
+
+        let written = unsafe { usart.sr.get() };
+        // No reserved bits are written.
+        assert_eq!(0, written & !all, "{:?}", flag);
+        assert_eq!(all & !bit, write_sr(all, written), "{:?}", flag);
+    }
+}
+
+#[test]
+fn test_clear_flag_does_not_write_read_cleared_flags() {
+    let usart = mock_usart();
+    let sr = Sr::ORE as u32 | Sr::TC as u32 | Sr::TXE as u32;
+
+    for &flag in &[
+        InterruptFlag::IDLE,
+        InterruptFlag::ORE,
+        InterruptFlag::NE,
+        InterruptFlag::FE,
+        InterruptFlag::PE,
+        InterruptFlag::TXE,
+    ] {
+        unsafe { usart.sr.set(sr) };
+        usart.it_clear_flag(flag);
+
+        // These are cleared by an access sequence, not by writing SR.
+        assert_eq!(sr, unsafe { usart.sr.get() }, "{:?}", flag);
+    }
+}
+
+#[test]
+fn test_clear_pending_only_clears_target() {
+    let usart = mock_usart();
+    let all = 0x3FF;
+
+    unsafe { usart.sr.set(all) };
+    usart.it_clear_pending(Interrupt::TC);
+
+    let written = unsafe { usart.sr.get() };
+    assert_eq!(0, written & !all);
+    assert_eq!(all & !(Sr::TC as u32), write_sr(all, written));
+}
+
 // TODO(rasen): remove this implementation. Nobody should write
 // directly to the USART (except debugging).
 impl<'a> fmt::Write for &'a Usart {
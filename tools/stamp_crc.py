@@ -0,0 +1,57 @@
+#!/usr/bin/env python3
+"""Stores the CRC of a kernel image into its last word.
+
+The image must end with the IMAGE_CRC placeholder (see stm32_flash.ld).
+The CRC is CRC-32/MPEG-2 over the rest of the image taken as
+little-endian 32-bit words, the same way the CRC unit computes it in
+`stm32f4::crc::check_flash`.
+
+Usage: stamp_crc.py kernel.bin
+"""
+
+import struct
+import sys
+
+POLYNOMIAL = 0x04C11DB7
+# Erased flash, also the value of an image that has not been stamped.
+UNSTAMPED = 0xFFFFFFFF
+
+
+def crc32_mpeg2_words(words):
+    crc = 0xFFFFFFFF
+    for word in words:
+        crc ^= word
+        for _ in range(32):
+            if crc & 0x80000000:
+                crc = ((crc << 1) ^ POLYNOMIAL) & 0xFFFFFFFF
+            else:
+                crc = (crc << 1) & 0xFFFFFFFF
+    return crc
+
+
+def main(path):
+    with open(path, 'rb') as f:
+        image = bytearray(f.read())
+
+    if len(image) < 4 or len(image) % 4 != 0:
+        sys.exit('{}: size is not a multiple of 4'.format(path))
+    placeholder, = struct.unpack_from('<I', image, len(image) - 4)
+    if placeholder != UNSTAMPED:
+        sys.exit('{}: no CRC placeholder at the end'.format(path))
+
+    words = struct.unpack('<{}I'.format(len(image) // 4 - 1), image[:-4])
+    crc = crc32_mpeg2_words(words)
+    if crc == UNSTAMPED:
+        # The kernel would skip the check. Any change to the image
+        # gives a different CRC.
+        sys.exit('{}: CRC equals the unstamped value'.format(path))
+
+    struct.pack_into('<I', image, len(image) - 4, crc)
+    with open(path, 'wb') as f:
+        f.write(image)
+
+
+if __name__ == '__main__':
+    if len(sys.argv) != 2:
+        sys.exit(__doc__)
+    main(sys.argv[1])